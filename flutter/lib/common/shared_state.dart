@@ -51,6 +51,29 @@ class BlockInputState {
   static RxBool find(String id) => Get.find<RxBool>(tag: tag(id));
 }
 
+class MacroRecordingState {
+  static String tag(String id) => 'macro_recording_$id';
+
+  static void init(String id) {
+    final key = tag(id);
+    if (!Get.isRegistered<RxBool>(tag: key)) {
+      final RxBool state = false.obs;
+      Get.put<RxBool>(state, tag: key);
+    } else {
+      Get.find<RxBool>(tag: key).value = false;
+    }
+  }
+
+  static void delete(String id) {
+    final key = tag(id);
+    if (Get.isRegistered<RxBool>(tag: key)) {
+      Get.delete<RxBool>(tag: key);
+    }
+  }
+
+  static RxBool find(String id) => Get.find<RxBool>(tag: tag(id));
+}
+
 class CurrentDisplayState {
   static String tag(String id) => 'current_display_$id';
 
@@ -342,6 +365,7 @@ class UnreadChatCountState {
 initSharedStates(String id) {
   PrivacyModeState.init(id);
   BlockInputState.init(id);
+  MacroRecordingState.init(id);
   CurrentDisplayState.init(id);
   KeyboardEnabledState.init(id);
   ShowRemoteCursorState.init(id);
@@ -356,6 +380,7 @@ initSharedStates(String id) {
 removeSharedStates(String id) {
   PrivacyModeState.delete(id);
   BlockInputState.delete(id);
+  MacroRecordingState.delete(id);
   CurrentDisplayState.delete(id);
   ShowRemoteCursorState.delete(id);
   ShowRemoteCursorLockState.delete(id);
@@ -1831,6 +1831,107 @@ void showConfirmSwitchSidesDialog(
   });
 }
 
+void showStartMacroRecordingDialog(
+    SessionID sessionId, String id, OverlayDialogManager dialogManager) {
+  final controller = TextEditingController();
+  String? errorText;
+  dialogManager.show((setState, close, context) {
+    submit() async {
+      final err = await bind.sessionStartMacroRecording(
+          sessionId: sessionId, name: controller.text.trim());
+      if (err.isNotEmpty) {
+        setState(() => errorText = err);
+        return;
+      }
+      MacroRecordingState.find(id).value = true;
+      close();
+    }
+
+    return CustomAlertDialog(
+      title: Text(translate('Record macro')),
+      content: DialogTextField(
+        title: translate('Macro name'),
+        controller: controller,
+        errorText: errorText,
+      ),
+      actions: [
+        dialogButton('Cancel', onPressed: close, isOutline: true),
+        dialogButton('OK', onPressed: submit),
+      ],
+      onSubmit: submit,
+      onCancel: close,
+    );
+  });
+}
+
+void stopMacroRecording(
+    SessionID sessionId, String id, OverlayDialogManager dialogManager) async {
+  MacroRecordingState.find(id).value = false;
+  final err = await bind.sessionStopMacroRecording(sessionId: sessionId);
+  if (err.isNotEmpty) {
+    msgBox(sessionId, 'custom-nook-nocancel-hasclose-error', 'Record macro',
+        err, '', dialogManager);
+  }
+}
+
+void showPlayMacroDialog(
+    SessionID sessionId, OverlayDialogManager dialogManager) async {
+  List<String> names = [];
+  try {
+    names = List<String>.from(jsonDecode(await bind.mainGetMacros()));
+  } catch (e) {
+    debugPrint('Failed to decode macros: $e');
+  }
+  String? errorText;
+  dialogManager.show((setState, close, context) {
+    play(String name) async {
+      final err = await bind.sessionPlayMacro(
+          sessionId: sessionId, name: name, speed: 1.0);
+      if (err.isNotEmpty) {
+        setState(() => errorText = err);
+        return;
+      }
+      close();
+    }
+
+    remove(String name) {
+      bind.mainRemoveMacro(name: name);
+      setState(() => names.remove(name));
+    }
+
+    return CustomAlertDialog(
+      title: Text(translate('Play macro')),
+      content: Column(
+        mainAxisSize: MainAxisSize.min,
+        crossAxisAlignment: CrossAxisAlignment.start,
+        children: [
+          if (names.isEmpty) Text(translate('No macros')),
+          ...names.map((name) => ListTile(
+                contentPadding: EdgeInsets.zero,
+                title: Text(name),
+                onTap: () => play(name),
+                trailing: IconButton(
+                  icon: Icon(Icons.delete_outline),
+                  onPressed: () => remove(name),
+                ),
+              )),
+          Text(translate('macro-abort-tip')).paddingOnly(top: 8),
+          if (errorText != null)
+            Text(errorText!,
+                    style: TextStyle(
+                        color: Theme.of(context).colorScheme.error,
+                        fontSize: 12))
+                .paddingOnly(top: 8),
+        ],
+      ),
+      actions: [
+        dialogButton('Close', onPressed: close, isOutline: true),
+      ],
+      onCancel: close,
+    );
+  });
+}
+
 customImageQualityDialog(SessionID sessionId, String id, FFI ffi) async {
   double initQuality = kDefaultQuality;
   double initFps = kDefaultFps;
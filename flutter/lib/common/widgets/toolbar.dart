@@ -144,8 +144,12 @@ List<TTextMenu> toolbarControls(BuildContext context, String id, FFI ffi) {
         onPressed: () async {
           ClipboardData? data = await Clipboard.getData(Clipboard.kTextPlain);
           if (data != null && data.text != null) {
-            bind.sessionInputString(
-                sessionId: sessionId, value: data.text ?? "");
+            if (isDesktop) {
+              bind.sessionTypeText(sessionId: sessionId, text: data.text ?? "");
+            } else {
+              bind.sessionInputString(
+                  sessionId: sessionId, value: data.text ?? "");
+            }
           }
         }));
   }
@@ -253,6 +257,23 @@ List<TTextMenu> toolbarControls(BuildContext context, String id, FFI ffi) {
           onPressed: () => bind.sessionLockScreen(sessionId: sessionId)),
     );
   }
+  // macro
+  if (isDefaultConn && isDesktop && !ffiModel.viewOnly && ffiModel.keyboard) {
+    final recording = MacroRecordingState.find(id);
+    v.add(TTextMenu(
+        child: Obx(() => Text(translate(
+            recording.value ? 'Stop macro recording' : 'Record macro'))),
+        onPressed: () {
+          if (recording.value) {
+            stopMacroRecording(sessionId, id, ffi.dialogManager);
+          } else {
+            showStartMacroRecordingDialog(sessionId, id, ffi.dialogManager);
+          }
+        }));
+    v.add(TTextMenu(
+        child: Text(translate('Play macro')),
+        onPressed: () => showPlayMacroDialog(sessionId, ffi.dialogManager)));
+  }
   // blockUserInput
   if (isDefaultConn &&
       ffi.ffiModel.keyboard &&
@@ -353,6 +353,9 @@ Widget buildConnectionCard(Client client) {
       key: ValueKey(client.id),
       children: [
         _CmHeader(client: client),
+        client.recordingBanner && !client.disconnected
+            ? _RecordingBanner()
+            : Offstage(),
         client.type_() == ClientType.file ||
                 client.type_() == ClientType.portForward ||
                 client.type_() == ClientType.terminal ||
@@ -370,6 +373,35 @@ Widget buildConnectionCard(Client client) {
   );
 }
 
+class _RecordingBanner extends StatelessWidget {
+  const _RecordingBanner({Key? key}) : super(key: key);
+
+  @override
+  Widget build(BuildContext context) {
+    return Container(
+      width: double.infinity,
+      margin: EdgeInsets.only(top: 4.0),
+      padding: EdgeInsets.symmetric(vertical: 4.0, horizontal: 8.0),
+      decoration: BoxDecoration(
+        color: Colors.red.withOpacity(0.1),
+        borderRadius: BorderRadius.circular(4.0),
+      ),
+      child: Row(
+        children: [
+          Icon(Icons.fiber_manual_record, color: Colors.red, size: 14),
+          const SizedBox(width: 6),
+          Expanded(
+            child: Text(
+              translate('session-recorded-tip'),
+              style: TextStyle(color: Colors.red, fontSize: 12),
+            ),
+          ),
+        ],
+      ),
+    );
+  }
+}
+
 class _AppIcon extends StatelessWidget {
   const _AppIcon({Key? key}) : super(key: key);
 
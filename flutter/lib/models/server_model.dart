@@ -793,6 +793,19 @@ class ServerModel with ChangeNotifier {
     }
   }
 
+  void updateRecordingBanner(Map<String, dynamic> evt) {
+    try {
+      final client = Client.fromJson(jsonDecode(evt["client"]));
+      final index = _clients.indexWhere((element) => element.id == client.id);
+      if (index != -1) {
+        _clients[index].recordingBanner = client.recordingBanner;
+        notifyListeners();
+      }
+    } catch (e) {
+      debugPrint("updateRecordingBanner failed: $e");
+    }
+  }
+
   void androidUpdatekeepScreenOn() async {
     if (!isAndroid) return;
     // 默认未设置时视为禁用，仅当显式为 'N' 时视为启用
@@ -846,6 +859,7 @@ class Client {
   bool fromSwitch = false;
   bool inVoiceCall = false;
   bool incomingVoiceCall = false;
+  bool recordingBanner = false;
 
   RxInt unreadChatMessageCount = 0.obs;
 
@@ -873,6 +887,7 @@ class Client {
     fromSwitch = json['from_switch'];
     inVoiceCall = json['in_voice_call'];
     incomingVoiceCall = json['incoming_voice_call'];
+    recordingBanner = json['recording_banner'] ?? false;
   }
 
   Map<String, dynamic> toJson() {
@@ -896,6 +911,7 @@ class Client {
     data['from_switch'] = fromSwitch;
     data['in_voice_call'] = inVoiceCall;
     data['incoming_voice_call'] = incomingVoiceCall;
+    data['recording_banner'] = recordingBanner;
     return data;
   }
 
@@ -481,6 +481,16 @@ class RustdeskImpl {
         () => js.context.callMethod('setByName', ['input_string', value]));
   }
 
+  Future<void> sessionTypeText(
+      {required UuidValue sessionId, required String text, dynamic hint}) {
+    return sessionInputString(sessionId: sessionId, value: text);
+  }
+
+  Future<void> sessionCancelTyping(
+      {required UuidValue sessionId, dynamic hint}) {
+    return Future.value();
+  }
+
   Future<void> sessionSendChat(
       {required UuidValue sessionId, required String text, dynamic hint}) {
     return Future(
@@ -61,15 +61,19 @@ where
     Ok(())
 }
 
+/// A token of the DSL.
 #[derive(Debug, PartialEq, Eq)]
-enum Token {
+pub enum Token {
+    /// Characters typed one by one with the keys of the layout.
     Sequence(String),
+    /// Characters entered as a whole, between {+UNICODE} and {-UNICODE}.
     Unicode(String),
     KeyUp(Key),
     KeyDown(Key),
 }
 
-fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+/// Split the DSL into tokens, without pressing any key.
+pub fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
     let mut unicode = false;
 
     let mut tokens = Vec::new();
@@ -127,7 +131,13 @@ fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
                         "-META" => tokens.push(Token::KeyUp(Key::Meta)),
                         "+ALT" => tokens.push(Token::KeyDown(Key::Alt)),
                         "-ALT" => tokens.push(Token::KeyUp(Key::Alt)),
-                        _ => return Err(ParseError::UnknownTag(tag)),
+                        _ => match named_key(&tag) {
+                            Some(key) => {
+                                tokens.push(Token::KeyDown(key));
+                                tokens.push(Token::KeyUp(key));
+                            }
+                            None => return Err(ParseError::UnknownTag(tag)),
+                        },
                     }
                 }
                 None => return Err(ParseError::UnmatchedOpen),
@@ -147,6 +157,27 @@ fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
     Ok(tokens)
 }
 
+// Keys clicked by a single tag, e.g. {ENTER}.
+fn named_key(tag: &str) -> Option<Key> {
+    Some(match tag {
+        "ENTER" => Key::Return,
+        "TAB" => Key::Tab,
+        "ESC" => Key::Escape,
+        "BACKSPACE" => Key::Backspace,
+        "DELETE" => Key::Delete,
+        "SPACE" => Key::Space,
+        "UP" => Key::UpArrow,
+        "DOWN" => Key::DownArrow,
+        "LEFT" => Key::LeftArrow,
+        "RIGHT" => Key::RightArrow,
+        "HOME" => Key::Home,
+        "END" => Key::End,
+        "PGUP" => Key::PageUp,
+        "PGDN" => Key::PageDown,
+        _ => return None,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -164,6 +195,24 @@ mod tests {
         );
     }
     #[test]
+    fn named_keys() {
+        assert_eq!(
+            tokenize("a{TAB}b{ENTER}"),
+            Ok(vec![
+                Token::Sequence("a".into()),
+                Token::KeyDown(Key::Tab),
+                Token::KeyUp(Key::Tab),
+                Token::Sequence("b".into()),
+                Token::KeyDown(Key::Return),
+                Token::KeyUp(Key::Return)
+            ])
+        );
+        assert_eq!(
+            tokenize("{TEST}"),
+            Err(ParseError::UnknownTag("TEST".into()))
+        );
+    }
+    #[test]
     fn unexpected_open() {
         assert_eq!(tokenize("{hello{}world}"), Err(ParseError::UnexpectedOpen));
     }
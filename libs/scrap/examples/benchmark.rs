@@ -6,7 +6,9 @@ use hbb_common::{
 use scrap::{
     aom::{AomDecoder, AomEncoder, AomEncoderConfig},
     codec::{EncoderApi, EncoderCfg},
-    Capturer, Display, TraitCapturer, VpxDecoder, VpxDecoderConfig, VpxEncoder, VpxEncoderConfig,
+    dirty_rect::DirtyTracker,
+    Capturer, Display, Frame, TraitCapturer, VpxDecoder, VpxDecoderConfig, VpxEncoder,
+    VpxEncoderConfig,
     VpxVideoCodecId::{self, *},
     STRIDE_ALIGN,
 };
@@ -21,7 +23,7 @@ const USAGE: &'static str = "
 Codec benchmark.
 
 Usage:
-  benchmark [--count=COUNT] [--quality=QUALITY] [--i444]
+  benchmark [--count=COUNT] [--quality=QUALITY] [--i444] [--dirty-rect]
   benchmark (-h | --help)
 
 Options:
@@ -29,6 +31,7 @@ Options:
   --count=COUNT         Capture frame count [default: 100].
   --quality=QUALITY     Video quality [default: 1.0].
   --i444                I444.
+  --dirty-rect          Skip the unchanged blocks with active maps.
 ";
 
 #[derive(Debug, serde::Deserialize, Clone, Copy)]
@@ -36,6 +39,7 @@ struct Args {
     flag_count: usize,
     flag_quality: f32,
     flag_i444: bool,
+    flag_dirty_rect: bool,
 }
 
 fn main() {
@@ -59,8 +63,8 @@ fn main() {
     let height = c.height();
 
     println!(
-        "benchmark {}x{} quality:{:?}, i444:{:?}",
-        width, height, quality, args.flag_i444
+        "benchmark {}x{} quality:{:?}, i444:{:?}, dirty_rect:{:?}",
+        width, height, quality, args.flag_i444, args.flag_dirty_rect
     );
     [VP8, VP9].map(|codec| {
         test_vpx(
@@ -71,9 +75,18 @@ fn main() {
             quality,
             yuv_count,
             if codec == VP8 { false } else { args.flag_i444 },
+            args.flag_dirty_rect,
         )
     });
-    test_av1(&mut c, width, height, quality, yuv_count, args.flag_i444);
+    test_av1(
+        &mut c,
+        width,
+        height,
+        quality,
+        yuv_count,
+        args.flag_i444,
+        args.flag_dirty_rect,
+    );
     #[cfg(feature = "hwcodec")]
     {
         hw::test(&mut c, width, height, quality, yuv_count);
@@ -88,6 +101,7 @@ fn test_vpx(
     quality: f32,
     yuv_count: usize,
     i444: bool,
+    dirty_rect: bool,
 ) {
     let config = EncoderCfg::VPX(VpxEncoderConfig {
         width: width as _,
@@ -104,10 +118,14 @@ fn test_vpx(
     let mut mid_data = Vec::new();
     let mut counter = 0;
     let mut time_sum = Duration::ZERO;
+    let mut tracker = DirtyTracker::default();
     loop {
         match c.frame(std::time::Duration::from_millis(30)) {
             Ok(frame) => {
                 let tmp_timer = Instant::now();
+                if let (true, Frame::PixelBuffer(f)) = (dirty_rect, &frame) {
+                    encoder.set_active_map(tracker.update(f).as_ref()).unwrap();
+                }
                 let frame = frame.to(encoder.yuvfmt(), &mut yuv, &mut mid_data).unwrap();
                 let yuv = frame.yuv().unwrap();
                 for ref frame in encoder
@@ -167,6 +185,7 @@ fn test_av1(
     quality: f32,
     yuv_count: usize,
     i444: bool,
+    dirty_rect: bool,
 ) {
     let config = EncoderCfg::AOM(AomEncoderConfig {
         width: width as _,
@@ -182,10 +201,14 @@ fn test_av1(
     let mut mid_data = Vec::new();
     let mut counter = 0;
     let mut time_sum = Duration::ZERO;
+    let mut tracker = DirtyTracker::default();
     loop {
         match c.frame(std::time::Duration::from_millis(30)) {
             Ok(frame) => {
                 let tmp_timer = Instant::now();
+                if let (true, Frame::PixelBuffer(f)) = (dirty_rect, &frame) {
+                    encoder.set_active_map(tracker.update(f).as_ref()).unwrap();
+                }
                 let frame = frame.to(encoder.yuvfmt(), &mut yuv, &mut mid_data).unwrap();
                 let yuv = frame.yuv().unwrap();
                 for ref frame in encoder
@@ -7,6 +7,7 @@
 include!(concat!(env!("OUT_DIR"), "/aom_ffi.rs"));
 
 use crate::codec::{base_bitrate, codec_thread_num};
+use crate::dirty_rect::ActiveMap;
 use crate::{codec::EncoderApi, EncodeFrame, STRIDE_ALIGN};
 use crate::{common::GoogleImage, generate_call_macro, generate_call_ptr_macro, Error, Result};
 use crate::{EncodeInput, EncodeYuvFormat, Pixfmt};
@@ -284,6 +285,25 @@ impl EncoderApi for AomEncoder {
     }
 
     fn disable(&self) {}
+
+    fn support_active_map(&self) -> bool {
+        true
+    }
+
+    fn set_active_map(&mut self, map: Option<&ActiveMap>) -> ResultType<()> {
+        // libaom copies the map, a null map encodes every block.
+        let mut active_map = aom_active_map_t {
+            active_map: map.map_or(ptr::null_mut(), |m| m.map.as_ptr() as _),
+            rows: map.map_or(0, |m| m.rows as _),
+            cols: map.map_or(0, |m| m.cols as _),
+        };
+        call_aom!(aom_codec_control(
+            &mut self.ctx,
+            aome_enc_control_id::AOME_SET_ACTIVEMAP as i32,
+            &mut active_map as *mut aom_active_map_t
+        ));
+        Ok(())
+    }
 }
 
 impl AomEncoder {
@@ -14,6 +14,7 @@ use crate::vram::*;
 use crate::{
     aom::{self, AomDecoder, AomEncoder, AomEncoderConfig},
     common::GoogleImage,
+    dirty_rect::ActiveMap,
     vpxcodec::{self, VpxDecoder, VpxDecoderConfig, VpxEncoder, VpxEncoderConfig, VpxVideoCodecId},
     CodecFormat, EncodeInput, EncodeYuvFormat, ImageRgb, ImageTexture,
 };
@@ -43,9 +44,14 @@ lazy_static::lazy_static! {
     static ref ENCODE_CODEC_FORMAT: Arc<Mutex<CodecFormat>> = Arc::new(Mutex::new(CodecFormat::VP9));
     static ref THREAD_LOG_TIME: Arc<Mutex<Option<Instant>>> = Arc::new(Mutex::new(None));
     static ref USABLE_ENCODING: Arc<Mutex<Option<SupportedEncoding>>> = Arc::new(Mutex::new(None));
+    // H264 and H265 are recorded to mp4 by the hwcodec muxer, which has no audio track,
+    // so with `OPTION_RECORD_AUDIO_CODEC_FALLBACK` they are not used while the video is recorded.
+    static ref RECORDING: Arc<Mutex<bool>> = Default::default();
 }
 
 pub const ENCODE_NEED_SWITCH: &'static str = "ENCODE_NEED_SWITCH";
+/// Prefer a recording with audio over H264 and H265, for all the viewers while recording.
+pub const OPTION_RECORD_AUDIO_CODEC_FALLBACK: &str = "record-audio-codec-fallback";
 
 #[derive(Debug, Clone)]
 pub enum EncoderCfg {
@@ -80,6 +86,16 @@ pub trait EncoderApi {
     fn is_hardware(&self) -> bool;
 
     fn disable(&self);
+
+    // Whether the encoder skips the inactive blocks of `set_active_map`.
+    fn support_active_map(&self) -> bool {
+        false
+    }
+
+    // The blocks changed since the last frame, `None` to encode the whole frame.
+    fn set_active_map(&mut self, _map: Option<&ActiveMap>) -> ResultType<()> {
+        Ok(())
+    }
 }
 
 pub struct Encoder {
@@ -230,6 +246,12 @@ impl Encoder {
                     HwRamEncoder::try_get(CodecFormat::H265).map_or(None, |c| Some(c.name));
             }
         }
+        if *RECORDING.lock().unwrap() {
+            h264vram_encoding = false;
+            h265vram_encoding = false;
+            h264hw_encoding = None;
+            h265hw_encoding = None;
+        }
         let h264_useable =
             _all_support_h264_decoding && (h264vram_encoding || h264hw_encoding.is_some());
         let h265_useable =
@@ -358,6 +380,22 @@ impl Encoder {
         USABLE_ENCODING.lock().unwrap().clone()
     }
 
+    /// With `OPTION_RECORD_AUDIO_CODEC_FALLBACK`, fall back to a WebM codec while the video is
+    /// recorded, so the recording has audio. Off by default, it overrides the codec preference
+    /// and the hardware encoding of every viewer.
+    pub fn set_recording(recording: bool) {
+        let recording = recording && Config::get_option(OPTION_RECORD_AUDIO_CODEC_FALLBACK) == "Y";
+        let changed = {
+            let mut lock = RECORDING.lock().unwrap();
+            let changed = *lock != recording;
+            *lock = recording;
+            changed
+        };
+        if changed {
+            Self::update(EncodingUpdate::Check);
+        }
+    }
+
     pub fn set_fallback(config: &EncoderCfg) {
         let format = match config {
             EncoderCfg::VPX(vpx) => match vpx.codec {
@@ -0,0 +1,181 @@
+// Detect the changed blocks of the captured frames, so that the encoders skip the others.
+//
+// The map is in blocks of 16x16 pixels, the macroblocks of the active maps of libvpx and libaom.
+// A block stays active for a few frames after its last change, so that the encoder refines it
+// after a frame encoded at low quality or dropped by the rate control.
+
+use crate::{Pixfmt, TraitPixelBuffer};
+
+pub const BLOCK_SIZE: usize = 16;
+// Frames a block stays active after its last change.
+const REFINE_FRAMES: u8 = 30;
+// Above this part of active blocks, the whole frame is encoded.
+const MAX_ACTIVE_RATIO: f32 = 0.8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActiveMap {
+    pub rows: usize,
+    pub cols: usize,
+    // 1 for the blocks to encode, row by row.
+    pub map: Vec<u8>,
+}
+
+impl ActiveMap {
+    pub fn active_blocks(&self) -> usize {
+        self.map.iter().filter(|b| **b != 0).count()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DirtyTracker {
+    width: usize,
+    height: usize,
+    bytes_per_pixel: usize,
+    // The last frame, without the stride padding.
+    prev: Vec<u8>,
+    // Frames since the last change of each block.
+    ages: Vec<u8>,
+}
+
+impl DirtyTracker {
+    /// The active map of a captured frame, `None` to encode the whole frame.
+    pub fn update(&mut self, frame: &dyn TraitPixelBuffer) -> Option<ActiveMap> {
+        match frame.pixfmt() {
+            Pixfmt::BGRA | Pixfmt::RGBA => {}
+            _ => return None,
+        }
+        let stride = frame.stride().first().copied().unwrap_or_default();
+        self.update_data(frame.data(), stride, frame.width(), frame.height(), 4)
+    }
+
+    fn update_data(
+        &mut self,
+        data: &[u8],
+        stride: usize,
+        width: usize,
+        height: usize,
+        bytes_per_pixel: usize,
+    ) -> Option<ActiveMap> {
+        let row_bytes = width * bytes_per_pixel;
+        if width == 0 || height == 0 || stride < row_bytes || data.len() < stride * height {
+            self.prev.clear();
+            return None;
+        }
+        let rows = height.div_ceil(BLOCK_SIZE);
+        let cols = width.div_ceil(BLOCK_SIZE);
+        if (self.width, self.height, self.bytes_per_pixel) != (width, height, bytes_per_pixel)
+            || self.prev.len() != row_bytes * height
+        {
+            self.width = width;
+            self.height = height;
+            self.bytes_per_pixel = bytes_per_pixel;
+            self.prev.resize(row_bytes * height, 0);
+            for y in 0..height {
+                self.prev[y * row_bytes..(y + 1) * row_bytes]
+                    .copy_from_slice(&data[y * stride..y * stride + row_bytes]);
+            }
+            self.ages = vec![0; rows * cols];
+            return None;
+        }
+        let block_bytes = BLOCK_SIZE * bytes_per_pixel;
+        let mut changed = vec![false; cols];
+        for by in 0..rows {
+            changed.iter_mut().for_each(|c| *c = false);
+            for y in by * BLOCK_SIZE..((by + 1) * BLOCK_SIZE).min(height) {
+                let cur = &data[y * stride..y * stride + row_bytes];
+                let prev = &mut self.prev[y * row_bytes..(y + 1) * row_bytes];
+                if *cur == *prev {
+                    continue;
+                }
+                for (bx, changed) in changed.iter_mut().enumerate() {
+                    let start = bx * block_bytes;
+                    let end = (start + block_bytes).min(row_bytes);
+                    if !*changed && cur[start..end] != prev[start..end] {
+                        *changed = true;
+                    }
+                }
+                prev.copy_from_slice(cur);
+            }
+            for (bx, changed) in changed.iter().enumerate() {
+                let age = &mut self.ages[by * cols + bx];
+                if *changed {
+                    *age = 0;
+                } else if *age < REFINE_FRAMES {
+                    *age += 1;
+                }
+            }
+        }
+        let map = ActiveMap {
+            rows,
+            cols,
+            map: self
+                .ages
+                .iter()
+                .map(|age| (*age < REFINE_FRAMES) as u8)
+                .collect(),
+        };
+        if map.active_blocks() as f32 > (rows * cols) as f32 * MAX_ACTIVE_RATIO {
+            return None;
+        }
+        Some(map)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const WIDTH: usize = 100;
+    const HEIGHT: usize = 40;
+    const STRIDE: usize = WIDTH * 4 + 16;
+
+    fn set_pixel(data: &mut [u8], x: usize, y: usize, v: u8) {
+        data[y * STRIDE + x * 4] = v;
+    }
+
+    #[test]
+    fn test_dirty_blocks() {
+        let mut tracker = DirtyTracker::default();
+        let mut data = vec![0u8; STRIDE * HEIGHT];
+        // The first frame is encoded as a whole until refined.
+        for _ in 0..REFINE_FRAMES {
+            assert_eq!(tracker.update_data(&data, STRIDE, WIDTH, HEIGHT, 4), None);
+        }
+        let map = tracker
+            .update_data(&data, STRIDE, WIDTH, HEIGHT, 4)
+            .unwrap();
+        assert_eq!((map.rows, map.cols, map.active_blocks()), (3, 7, 0));
+
+        // 7x3 blocks, the last ones are partial.
+        set_pixel(&mut data, 99, 39, 1);
+        set_pixel(&mut data, 17, 0, 1);
+        // Stride padding is ignored.
+        data[STRIDE - 1] = 1;
+        let map = tracker
+            .update_data(&data, STRIDE, WIDTH, HEIGHT, 4)
+            .unwrap();
+        let mut expected = vec![0; 21];
+        expected[1] = 1;
+        expected[20] = 1;
+        assert_eq!(map.map, expected);
+
+        // Refined for a while after the change.
+        for _ in 1..REFINE_FRAMES {
+            let map = tracker
+                .update_data(&data, STRIDE, WIDTH, HEIGHT, 4)
+                .unwrap();
+            assert_eq!(map.active_blocks(), 2);
+        }
+        let map = tracker
+            .update_data(&data, STRIDE, WIDTH, HEIGHT, 4)
+            .unwrap();
+        assert_eq!(map.active_blocks(), 0);
+
+        // Most of the frame changed.
+        data.iter_mut().for_each(|v| *v = 2);
+        assert_eq!(tracker.update_data(&data, STRIDE, WIDTH, HEIGHT, 4), None);
+        // Size changed.
+        assert_eq!(tracker.update_data(&data, STRIDE, 80, HEIGHT, 4), None);
+        assert_eq!(tracker.width, 80);
+    }
+}
@@ -51,7 +51,10 @@ pub const HW_STRIDE_ALIGN: usize = 0; // recommended by av_frame_get_buffer
 pub mod aom;
 #[cfg(not(any(target_os = "ios")))]
 pub mod camera;
+pub mod dirty_rect;
 pub mod record;
+pub mod record_crypt;
+pub mod record_inspect;
 mod vpx;
 
 #[repr(usize)]
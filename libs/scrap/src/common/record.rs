@@ -1,24 +1,111 @@
-use crate::CodecFormat;
+use crate::{
+    record_crypt::{self, EncryptedWriter},
+    CodecFormat,
+};
 #[cfg(feature = "hwcodec")]
 use hbb_common::anyhow::anyhow;
 use hbb_common::{
     bail, chrono, log,
-    message_proto::{message, video_frame, EncodedVideoFrame, Message},
+    message_proto::{message, video_frame, AudioFrame, EncodedVideoFrame, Message},
+    serde_json::{json, Value},
     ResultType,
 };
 #[cfg(feature = "hwcodec")]
 use hwcodec::mux::{MuxContext, Muxer};
 use std::{
+    collections::HashSet,
     fs::{File, OpenOptions},
-    io,
+    io::{self, Seek, SeekFrom, Write},
     ops::{Deref, DerefMut},
-    path::PathBuf,
-    sync::mpsc::Sender,
+    path::{Path, PathBuf},
+    sync::{mpsc::Sender, Mutex},
     time::Instant,
 };
-use webm::mux::{self, Segment, Track, VideoTrack, Writer};
+use webm::mux::{self, AudioTrack, Segment, Track, VideoTrack, Writer};
 
 const MIN_SECS: u64 = 1;
+// Opus packets carry their own channel count, a stereo decoder plays mono packets too.
+// https://datatracker.ietf.org/doc/html/rfc7845#section-5.1
+const OPUS_SAMPLE_RATE: u32 = 48000;
+const OPUS_CHANNELS: u8 = 2;
+/// Appended to the name of a video file, before `record_crypt::EXTENSION`, for its session events.
+pub const EVENTS_EXTENSION: &str = ".events.jsonl";
+/// Extension of the manifest which lists the files of a recording session, one per line.
+pub const MANIFEST_EXTENSION: &str = ".manifest.jsonl";
+// Events are kept until the first frame of a file, as they are keyed to its pts.
+const MAX_PENDING_EVENTS: usize = 100;
+
+lazy_static::lazy_static! {
+    // Files being written by the recorders of this process.
+    static ref WRITING: Mutex<HashSet<String>> = Default::default();
+}
+
+/// Mark a recording file as being written, so that it is not pruned.
+pub fn set_writing(filename: &str, writing: bool) {
+    let mut files = WRITING.lock().unwrap();
+    if writing {
+        files.insert(filename.to_owned());
+    } else {
+        files.remove(filename);
+    }
+}
+
+pub fn is_writing(filename: &str) -> bool {
+    WRITING.lock().unwrap().contains(filename)
+}
+
+/// The manifest of the recording session `session_id` in `dir`.
+pub fn manifest_filename(dir: &str, server: bool, id: &str, session_id: &str) -> String {
+    let file = format!(
+        "{}_{}_{}{}",
+        if server { "incoming" } else { "outgoing" },
+        id,
+        session_id,
+        MANIFEST_EXTENSION
+    );
+    PathBuf::from(dir).join(file).to_string_lossy().to_string()
+}
+
+// Append a line to the manifest of the session, it is not encrypted as it only lists the files.
+fn append_manifest(ctx: &RecorderContext, mut entry: Value) {
+    if ctx.manifest.is_empty() {
+        return;
+    }
+    entry["session_id"] = json!(ctx.session_id);
+    entry["display"] = json!(ctx.display_idx);
+    entry["camera"] = json!(ctx.camera);
+    let res = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&ctx.manifest)
+        .and_then(|mut f| f.write_all(format!("{}\n", stamp(entry)).as_bytes()));
+    if let Err(e) = res {
+        log::error!("Failed to write the manifest {}: {}", ctx.manifest, e);
+    }
+}
+
+// Files shorter than `MIN_SECS` or without frames are removed.
+fn append_removed(ctx: &RecorderContext, ctx2: &RecorderContext2) {
+    append_manifest(
+        ctx,
+        json!({"type": "removed", "file": file_name(&ctx2.filename)}),
+    );
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// The session events file of the video file `video`.
+pub fn events_filename(video: &str) -> String {
+    match video.strip_suffix(record_crypt::EXTENSION) {
+        Some(base) => format!("{}{}{}", base, EVENTS_EXTENSION, record_crypt::EXTENSION),
+        None => format!("{}{}", video, EVENTS_EXTENSION),
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct RecorderContext {
@@ -28,6 +115,12 @@ pub struct RecorderContext {
     pub display_idx: usize,
     pub camera: bool,
     pub tx: Option<Sender<RecordState>>,
+    // `record_crypt::OPTION_RECORD_PUBLIC_KEY`, empty to not encrypt.
+    pub public_key: String,
+    // Shared by the recorders of all displays of a session, may be empty.
+    pub session_id: String,
+    // JSON lines manifest of the files of the session, empty for none.
+    pub manifest: String,
 }
 
 #[derive(Debug, Clone)]
@@ -60,6 +153,11 @@ impl RecorderContext2 {
                 ".webm"
             } else {
                 ".mp4"
+            }
+            + if ctx.public_key.is_empty() {
+                ""
+            } else {
+                record_crypt::EXTENSION
             };
         self.filename = PathBuf::from(&ctx.dir)
             .join(file)
@@ -77,6 +175,10 @@ pub trait RecorderApi {
     where
         Self: Sized;
     fn write_video(&mut self, frame: &EncodedVideoFrame) -> bool;
+    // Opus packet from `audio_service`
+    fn write_audio(&mut self, _data: &[u8]) -> bool {
+        false
+    }
 }
 
 #[derive(Debug)]
@@ -89,9 +191,16 @@ pub enum RecordState {
 
 pub struct Recorder {
     pub inner: Option<Box<dyn RecorderApi>>,
+    // Dropped after `inner`, which may remove a short video file.
+    events: Option<EventLog>,
     ctx: RecorderContext,
     ctx2: Option<RecorderContext2>,
     pts: Option<i64>,
+    // When the frame of `pts` was written.
+    pts_time: Option<Instant>,
+    pending_events: Vec<Value>,
+    // Written at the start of every file while set, e.g. the login of a connected peer.
+    session_events: Vec<(String, Value)>,
     check_failed: bool,
 }
 
@@ -111,11 +220,17 @@ impl DerefMut for Recorder {
 
 impl Recorder {
     pub fn new(ctx: RecorderContext) -> ResultType<Self> {
+        // Refuse to record rather than write plain files if the key is invalid.
+        record_crypt::parse_public_key(&ctx.public_key)?;
         Ok(Self {
             inner: None,
+            events: None,
             ctx,
             ctx2: None,
             pts: None,
+            pts_time: None,
+            pending_events: Vec::new(),
+            session_events: Vec::new(),
             check_failed: false,
         })
     }
@@ -165,6 +280,38 @@ impl Recorder {
             };
             // pts is None when new inner is created
             self.pts = None;
+            self.events = match EventLog::new(
+                &events_filename(&ctx2.filename),
+                &self.ctx.public_key,
+                Some(ctx2.filename.clone()),
+            ) {
+                Ok(events) => Some(events),
+                Err(e) => {
+                    log::error!("Failed to create the events of {}: {}", ctx2.filename, e);
+                    None
+                }
+            };
+            append_manifest(
+                &self.ctx,
+                json!({
+                    "type": "file",
+                    "file": file_name(&ctx2.filename),
+                    "events": file_name(&events_filename(&ctx2.filename)),
+                    "width": w,
+                    "height": h,
+                    "codec": format.to_string(),
+                }),
+            );
+            let mut events = vec![stamp(json!({
+                "type": "resolution",
+                "session_id": self.ctx.session_id,
+                "width": w,
+                "height": h,
+                "codec": format.to_string(),
+            }))];
+            events.extend(self.session_events.iter().map(|(_, e)| e.clone()));
+            events.append(&mut self.pending_events);
+            self.pending_events = events;
             self.send_state(RecordState::NewFile(ctx2.filename.clone()));
         }
         Ok(())
@@ -232,10 +379,64 @@ impl Recorder {
             }
             _ => bail!("unsupported frame type"),
         }
+        if !self.pending_events.is_empty() {
+            for event in std::mem::take(&mut self.pending_events) {
+                self.write_event_line(event);
+            }
+        }
         self.send_state(RecordState::NewFrame);
         Ok(())
     }
 
+    /// Write a session event, a JSON object, to the events file of the current video file.
+    /// It is keyed to the video with `pts` in ms and stamped with the local `time`.
+    pub fn write_event(&mut self, event: Value) {
+        let event = stamp(event);
+        if self.pts.is_some() {
+            self.write_event_line(event);
+        } else if self.pending_events.len() < MAX_PENDING_EVENTS {
+            self.pending_events.push(event);
+        }
+    }
+
+    /// Set or clear an event which is written now and at the start of every following file.
+    pub fn set_session_event(&mut self, key: &str, event: Option<Value>) {
+        self.session_events.retain(|(k, _)| k != key);
+        if let Some(event) = event {
+            let event = stamp(event);
+            self.session_events.push((key.to_owned(), event.clone()));
+            // Otherwise it is written with the next file.
+            if self.pts.is_some() {
+                self.write_event_line(event);
+            }
+        }
+    }
+
+    fn write_event_line(&mut self, mut event: Value) {
+        let Some(events) = self.events.as_mut() else {
+            return;
+        };
+        let pts = self.pts.unwrap_or_default()
+            + self
+                .pts_time
+                .map(|t| t.elapsed().as_millis() as i64)
+                .unwrap_or_default();
+        if let Value::Object(map) = &mut event {
+            map.insert("pts".to_owned(), json!(pts));
+        }
+        if let Err(e) = events.write(&event) {
+            log::error!("Failed to write the event to {}: {}", events.filename(), e);
+            self.events = None;
+        }
+    }
+
+    pub fn write_audio(&mut self, frame: &AudioFrame) {
+        if self.check_failed {
+            return;
+        }
+        self.as_mut().map(|x| x.write_audio(&frame.data));
+    }
+
     fn check_pts(
         &mut self,
         pts: i64,
@@ -250,6 +451,7 @@ impl Recorder {
         }
         let old_pts = self.pts;
         self.pts = Some(pts);
+        self.pts_time = Some(Instant::now());
         if old_pts.clone().unwrap_or_default() > pts {
             log::info!("pts {:?} -> {}, change record filename", old_pts, pts);
             self.inner = None;
@@ -270,14 +472,115 @@ impl Recorder {
     }
 }
 
+fn stamp(mut event: Value) -> Value {
+    if let Value::Object(map) = &mut event {
+        if !map.contains_key("time") {
+            map.insert("time".to_owned(), json!(chrono::Local::now().to_rfc3339()));
+        }
+    }
+    event
+}
+
+/// JSON lines of session events, next to a video file and removed with it,
+/// or on their own for sessions without video.
+pub struct EventLog {
+    writer: Option<Box<dyn Write + Send>>,
+    filename: String,
+    video: Option<String>,
+}
+
+impl EventLog {
+    /// `public_key` is `record_crypt::OPTION_RECORD_PUBLIC_KEY`, `filename` gets
+    /// `record_crypt::EXTENSION` if it is set.
+    pub fn new(filename: &str, public_key: &str, video: Option<String>) -> ResultType<Self> {
+        let public_key = record_crypt::parse_public_key(public_key)?;
+        let filename = match public_key {
+            Some(_) if !filename.ends_with(record_crypt::EXTENSION) => {
+                format!("{}{}", filename, record_crypt::EXTENSION)
+            }
+            _ => filename.to_owned(),
+        };
+        let file = File::create(&filename)?;
+        let writer: Box<dyn Write + Send> = match public_key {
+            Some(pk) => Box::new(EncryptedWriter::new(file, &pk)?),
+            None => Box::new(file),
+        };
+        set_writing(&filename, true);
+        Ok(Self {
+            writer: Some(writer),
+            filename,
+            video,
+        })
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// Write an event, a JSON object, stamped with the local `time` if it has none.
+    pub fn write(&mut self, event: &Value) -> io::Result<()> {
+        let Some(writer) = self.writer.as_mut() else {
+            return Ok(());
+        };
+        writer.write_all(format!("{}\n", stamp(event.clone())).as_bytes())?;
+        writer.flush()
+    }
+}
+
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        self.writer = None;
+        if let Some(video) = &self.video {
+            if !Path::new(video).exists() {
+                std::fs::remove_file(&self.filename).ok();
+            }
+        }
+        set_writing(&self.filename, false);
+    }
+}
+
+enum RecordFile {
+    Plain(File),
+    Encrypted(EncryptedWriter),
+}
+
+impl Write for RecordFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            RecordFile::Plain(f) => f.write(buf),
+            RecordFile::Encrypted(f) => f.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            RecordFile::Plain(f) => f.flush(),
+            RecordFile::Encrypted(f) => f.flush(),
+        }
+    }
+}
+
+impl Seek for RecordFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            RecordFile::Plain(f) => f.seek(pos),
+            RecordFile::Encrypted(f) => f.seek(pos),
+        }
+    }
+}
+
 struct WebmRecorder {
     vt: VideoTrack,
-    webm: Option<Segment<Writer<File>>>,
+    at: AudioTrack,
+    webm: Option<Segment<Writer<RecordFile>>>,
     ctx: RecorderContext,
     ctx2: RecorderContext2,
     key: bool,
     written: bool,
     start: Instant,
+    // Audio frames have no pts, they are timed from the first key frame: (received, pts in ms).
+    first_key: Option<(Instant, i64)>,
+    last_audio_ns: Option<u64>,
 }
 
 impl RecorderApi for WebmRecorder {
@@ -292,6 +595,10 @@ impl RecorderApi for WebmRecorder {
             Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => File::create(&ctx2.filename)?,
             Err(e) => return Err(e.into()),
         };
+        let out = match record_crypt::parse_public_key(&ctx.public_key)? {
+            Some(pk) => RecordFile::Encrypted(EncryptedWriter::new(out, &pk)?),
+            None => RecordFile::Plain(out),
+        };
         let mut webm = match mux::Segment::new(mux::Writer::new(out)) {
             Some(v) => v,
             None => bail!("Failed to create webm mux"),
@@ -315,20 +622,39 @@ impl RecorderApi for WebmRecorder {
                 bail!("Failed to set codec private");
             }
         }
+        let at = webm.add_audio_track(
+            OPUS_SAMPLE_RATE as _,
+            OPUS_CHANNELS as _,
+            None,
+            mux::AudioCodecId::Opus,
+        );
+        if !webm.set_codec_private(
+            at.track_number(),
+            &opus_head(OPUS_CHANNELS, OPUS_SAMPLE_RATE),
+        ) {
+            bail!("Failed to set opus codec private");
+        }
+        set_writing(&ctx2.filename, true);
         Ok(WebmRecorder {
             vt,
+            at,
             webm: Some(webm),
             ctx,
             ctx2,
             key: false,
             written: false,
             start: Instant::now(),
+            first_key: None,
+            last_audio_ns: None,
         })
     }
 
     fn write_video(&mut self, frame: &EncodedVideoFrame) -> bool {
         if frame.key {
             self.key = true;
+            if self.first_key.is_none() {
+                self.first_key = Some((Instant::now(), frame.pts));
+            }
         }
         if self.key {
             let ok = self
@@ -342,6 +668,31 @@ impl RecorderApi for WebmRecorder {
             false
         }
     }
+
+    fn write_audio(&mut self, data: &[u8]) -> bool {
+        // The file starts with a key frame, earlier audio is dropped.
+        let Some((instant, pts)) = self.first_key else {
+            return false;
+        };
+        let mut ns = pts as u64 * 1_000_000 + instant.elapsed().as_nanos() as u64;
+        if let Some(last) = self.last_audio_ns {
+            ns = ns.max(last + 1);
+        }
+        self.last_audio_ns = Some(ns);
+        self.at.add_frame(data, ns, true)
+    }
+}
+
+// https://datatracker.ietf.org/doc/html/rfc7845#section-5.1
+fn opus_head(channels: u8, sample_rate: u32) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1); // version
+    head.push(channels);
+    head.extend_from_slice(&0u16.to_le_bytes()); // pre-skip
+    head.extend_from_slice(&sample_rate.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+    head.push(0); // channel mapping family
+    head
 }
 
 impl Drop for WebmRecorder {
@@ -351,11 +702,17 @@ impl Drop for WebmRecorder {
         if !self.written || self.start.elapsed().as_secs() < MIN_SECS {
             std::fs::remove_file(&self.ctx2.filename).ok();
             state = RecordState::RemoveFile;
+            append_removed(&self.ctx, &self.ctx2);
         }
+        set_writing(&self.ctx2.filename, false);
         self.ctx.tx.as_ref().map(|tx| tx.send(state));
     }
 }
 
+// The hwcodec muxer has no audio track, mp4 recordings are silent. With
+// `OPTION_RECORD_AUDIO_CODEC_FALLBACK` the encoder falls back to a WebM codec while the video
+// is recorded (`Encoder::set_recording`), so only the recordings of older peers are silent.
+// It writes the file itself and can't be encrypted on the fly, so it refuses to record if a key is set.
 #[cfg(feature = "hwcodec")]
 struct HwRecorder {
     muxer: Option<Muxer>,
@@ -369,6 +726,9 @@ struct HwRecorder {
 #[cfg(feature = "hwcodec")]
 impl RecorderApi for HwRecorder {
     fn new(ctx: RecorderContext, ctx2: RecorderContext2) -> ResultType<Self> {
+        if record_crypt::parse_public_key(&ctx.public_key)?.is_some() {
+            bail!("Encrypted recording of {} is not supported", ctx2.format);
+        }
         let muxer = Muxer::new(MuxContext {
             filename: ctx2.filename.clone(),
             width: ctx2.width,
@@ -377,6 +737,7 @@ impl RecorderApi for HwRecorder {
             framerate: crate::hwcodec::DEFAULT_FPS as _,
         })
         .map_err(|_| anyhow!("Failed to create hardware muxer"))?;
+        set_writing(&ctx2.filename, true);
         Ok(HwRecorder {
             muxer: Some(muxer),
             ctx,
@@ -417,7 +778,9 @@ impl Drop for HwRecorder {
             self.muxer = None;
             std::fs::remove_file(&self.ctx2.filename).ok();
             state = RecordState::RemoveFile;
+            append_removed(&self.ctx, &self.ctx2);
         }
+        set_writing(&self.ctx2.filename, false);
         self.ctx.tx.as_ref().map(|tx| tx.send(state));
     }
 }
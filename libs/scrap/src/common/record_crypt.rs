@@ -0,0 +1,312 @@
+// Encryption at rest of recordings.
+//
+// The muxers seek back to patch the headers, so an encrypted file is an append-only log of the
+// writes of the muxer rather than an encrypted copy of the container:
+//
+//   MAGIC | sealed stream key | secretstream header | record...
+//   record = u32 LE length | secretstream message of (u64 LE offset | data)
+//
+// The stream key is sealed to the public key of the auditor, only the holder of the secret key
+// can replay the writes. The last record is tagged `Final` and carries the length of the
+// container. A file without it was not finished, e.g. after a crash, and still decrypts to the
+// part written so far.
+
+use hbb_common::{
+    anyhow::anyhow,
+    bail, log,
+    sodiumoxide::{
+        base64,
+        crypto::{box_, sealedbox, secretstream::xchacha20poly1305 as stream},
+    },
+    ResultType,
+};
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    time::{Duration, Instant},
+};
+
+/// Base64 public key of the auditor, recordings are encrypted when it is set.
+pub const OPTION_RECORD_PUBLIC_KEY: &str = "record-encryption-public-key";
+/// Appended to the file name of encrypted recordings.
+pub const EXTENSION: &str = ".enc";
+
+const MAGIC: &[u8; 8] = b"RDRECENC";
+const SEALED_KEY_BYTES: usize = sealedbox::SEALBYTES + stream::KEYBYTES;
+// Sequential writes are collected up to this size or age before they are encrypted.
+const FLUSH_SIZE: usize = 64 * 1024;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RECORD_LEN: usize = 64 * 1024 * 1024;
+
+/// Parse the value of `OPTION_RECORD_PUBLIC_KEY`, `None` if it is empty.
+pub fn parse_public_key(s: &str) -> ResultType<Option<box_::PublicKey>> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(None);
+    }
+    let bytes = base64::decode(s, base64::Variant::Original)
+        .map_err(|_| anyhow!("Invalid base64 of the record public key"))?;
+    match box_::PublicKey::from_slice(&bytes) {
+        Some(pk) => Ok(Some(pk)),
+        None => bail!("Invalid record public key"),
+    }
+}
+
+pub fn parse_secret_key(s: &str) -> ResultType<box_::SecretKey> {
+    let bytes = base64::decode(s.trim(), base64::Variant::Original)
+        .map_err(|_| anyhow!("Invalid base64 of the record secret key"))?;
+    match box_::SecretKey::from_slice(&bytes) {
+        Some(sk) => Ok(sk),
+        None => bail!("Invalid record secret key"),
+    }
+}
+
+/// New key pair of the auditor in base64, (public key, secret key).
+pub fn gen_keypair() -> (String, String) {
+    let (pk, sk) = box_::gen_keypair();
+    (
+        base64::encode(pk, base64::Variant::Original),
+        base64::encode(sk, base64::Variant::Original),
+    )
+}
+
+pub fn is_encrypted(path: &str) -> bool {
+    let mut magic = [0u8; MAGIC.len()];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_ok()
+        && &magic == MAGIC
+}
+
+/// A `Write + Seek` container file which is encrypted as it is written.
+pub struct EncryptedWriter {
+    file: File,
+    stream: stream::Stream<stream::Push>,
+    // Position and length in the container.
+    pos: u64,
+    len: u64,
+    // Sequential writes not encrypted yet, starting at `buf_offset`.
+    buf_offset: u64,
+    buf: Vec<u8>,
+    last_flush: Instant,
+    finished: bool,
+}
+
+impl EncryptedWriter {
+    pub fn new(mut file: File, public_key: &box_::PublicKey) -> ResultType<Self> {
+        let key = stream::gen_key();
+        let (stream, header) = stream::Stream::init_push(&key)
+            .map_err(|_| anyhow!("Failed to initialize the encryption stream"))?;
+        file.write_all(MAGIC)?;
+        file.write_all(&sealedbox::seal(&key.0, public_key))?;
+        file.write_all(&header.0)?;
+        Ok(Self {
+            file,
+            stream,
+            pos: 0,
+            len: 0,
+            buf_offset: 0,
+            buf: Vec::new(),
+            last_flush: Instant::now(),
+            finished: false,
+        })
+    }
+
+    fn push(&mut self, offset: u64, data: &[u8], tag: stream::Tag) -> io::Result<()> {
+        let mut m = Vec::with_capacity(8 + data.len());
+        m.extend_from_slice(&offset.to_le_bytes());
+        m.extend_from_slice(data);
+        let c = self
+            .stream
+            .push(&m, None, tag)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to encrypt"))?;
+        let mut record = Vec::with_capacity(4 + c.len());
+        record.extend_from_slice(&(c.len() as u32).to_le_bytes());
+        record.extend_from_slice(&c);
+        self.file.write_all(&record)
+    }
+
+    fn flush_buf(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let buf = std::mem::take(&mut self.buf);
+        self.push(self.buf_offset, &buf, stream::Tag::Message)
+    }
+
+    /// Write the final record, called on drop.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.flush_buf()?;
+        self.push(self.len, &[], stream::Tag::Final)?;
+        self.finished = true;
+        self.file.flush()
+    }
+}
+
+impl Write for EncryptedWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(io::Error::new(io::ErrorKind::Other, "Already finished"));
+        }
+        if self.buf.is_empty() {
+            self.buf_offset = self.pos;
+        } else if self.buf_offset + self.buf.len() as u64 != self.pos {
+            self.flush_buf()?;
+            self.buf_offset = self.pos;
+        }
+        self.buf.extend_from_slice(data);
+        self.pos += data.len() as u64;
+        self.len = self.len.max(self.pos);
+        if self.buf.len() >= FLUSH_SIZE || self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush_buf()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buf()?;
+        self.file.flush()
+    }
+}
+
+impl Seek for EncryptedWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative position",
+            )),
+        }
+    }
+}
+
+impl Drop for EncryptedWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            log::error!("Failed to finish the encrypted recording: {}", e);
+        }
+    }
+}
+
+/// Replay the encrypted recording `input` to the container `output`.
+/// Returns false if the recording was not finished, `output` is the part written so far.
+pub fn decrypt(
+    input: &mut impl Read,
+    output: &mut File,
+    secret_key: &box_::SecretKey,
+) -> ResultType<bool> {
+    let mut magic = [0u8; MAGIC.len()];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("Not an encrypted recording");
+    }
+    let mut sealed_key = [0u8; SEALED_KEY_BYTES];
+    input.read_exact(&mut sealed_key)?;
+    let key = sealedbox::open(&sealed_key, &secret_key.public_key(), secret_key)
+        .map_err(|_| anyhow!("The recording is not encrypted for this key"))?;
+    let Some(key) = stream::Key::from_slice(&key) else {
+        bail!("Invalid stream key");
+    };
+    let mut header = [0u8; stream::HEADERBYTES];
+    input.read_exact(&mut header)?;
+    let Some(header) = stream::Header::from_slice(&header) else {
+        bail!("Invalid stream header");
+    };
+    let mut stream = stream::Stream::init_pull(&header, &key)
+        .map_err(|_| anyhow!("Failed to initialize the decryption stream"))?;
+    loop {
+        let mut len = [0u8; 4];
+        if !read_record(input, &mut len)? {
+            return Ok(false);
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_RECORD_LEN {
+            bail!("Invalid record length {}", len);
+        }
+        let mut c = vec![0u8; len];
+        if !read_record(input, &mut c)? {
+            return Ok(false);
+        }
+        let (m, tag) = stream
+            .pull(&c, None)
+            .map_err(|_| anyhow!("The recording is corrupted"))?;
+        if m.len() < 8 {
+            bail!("Invalid record");
+        }
+        let offset = u64::from_le_bytes(m[..8].try_into()?);
+        if tag == stream::Tag::Final {
+            output.set_len(offset)?;
+            output.flush()?;
+            return Ok(true);
+        }
+        output.seek(SeekFrom::Start(offset))?;
+        output.write_all(&m[8..])?;
+    }
+}
+
+// Returns false at the end of a truncated file.
+fn read_record(input: &mut impl Read, buf: &mut [u8]) -> ResultType<bool> {
+    match input.read_exact(buf) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let dir = std::env::temp_dir().join(format!("record_crypt_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (enc, dec) = (dir.join("a.webm.enc"), dir.join("a.webm"));
+        let (pk, sk) = gen_keypair();
+        let pk = parse_public_key(&pk).unwrap().unwrap();
+        let sk = parse_secret_key(&sk).unwrap();
+
+        let mut writer = EncryptedWriter::new(File::create(&enc).unwrap(), &pk).unwrap();
+        writer.write_all(b"header").unwrap();
+        writer.write_all(&[1u8; FLUSH_SIZE]).unwrap();
+        writer.seek(SeekFrom::Start(0)).unwrap();
+        writer.write_all(b"HEAD").unwrap();
+        writer.flush().unwrap();
+        let unfinished = std::fs::read(&enc).unwrap();
+        drop(writer);
+        assert!(is_encrypted(enc.to_str().unwrap()));
+
+        let mut out = File::create(&dec).unwrap();
+        let finished = decrypt(&mut File::open(&enc).unwrap(), &mut out, &sk).unwrap();
+        assert!(finished);
+        let plain = std::fs::read(&dec).unwrap();
+        assert_eq!(&plain[..6], b"HEADer");
+        assert_eq!(plain.len(), 6 + FLUSH_SIZE);
+
+        // A crash before the final record leaves a decryptable prefix.
+        let mut out = File::create(&dec).unwrap();
+        let finished = decrypt(&mut &unfinished[..unfinished.len() - 3], &mut out, &sk).unwrap();
+        assert!(!finished);
+        assert_eq!(&std::fs::read(&dec).unwrap()[..6], b"header");
+
+        let (_, other) = gen_keypair();
+        let other = parse_secret_key(&other).unwrap();
+        let mut out = File::create(&dec).unwrap();
+        assert!(decrypt(&mut File::open(&enc).unwrap(), &mut out, &other).is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
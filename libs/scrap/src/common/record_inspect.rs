@@ -0,0 +1,709 @@
+// Read the WebM recordings written by `record::WebmRecorder` offline: inspect, repair and
+// decode a frame for a thumbnail.
+//
+// A recording interrupted by a crash has no cues, no duration and an unknown segment size,
+// its last element is usually cut. `repair` muxes the complete frames to a new file, which
+// rebuilds the cues and the duration.
+//
+// Only what `WebmRecorder` writes is read: a video track, an optional Opus track, and frames
+// in SimpleBlocks or BlockGroups without lacing. The mp4 recordings of the hardware codecs are
+// muxed by ffmpeg and not supported.
+
+use crate::{codec::Decoder, record_crypt, CodecFormat, ImageFormat, ImageRgb, ImageTexture};
+use hbb_common::{
+    bail,
+    message_proto::{video_frame, EncodedVideoFrame, EncodedVideoFrames},
+    ResultType,
+};
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::{self, BufReader, Cursor, Read},
+};
+use webm::mux::{self, Track};
+
+const ID_EBML: u32 = 0x1A45DFA3;
+const ID_SEGMENT: u32 = 0x18538067;
+const ID_INFO: u32 = 0x1549A966;
+const ID_TIMECODE_SCALE: u32 = 0x2AD7B1;
+const ID_DURATION: u32 = 0x4489;
+const ID_TRACKS: u32 = 0x1654AE6B;
+const ID_TRACK_ENTRY: u32 = 0xAE;
+const ID_TRACK_NUMBER: u32 = 0xD7;
+const ID_TRACK_TYPE: u32 = 0x83;
+const ID_CODEC_ID: u32 = 0x86;
+const ID_CODEC_PRIVATE: u32 = 0x63A2;
+const ID_VIDEO: u32 = 0xE0;
+const ID_PIXEL_WIDTH: u32 = 0xB0;
+const ID_PIXEL_HEIGHT: u32 = 0xBA;
+const ID_AUDIO: u32 = 0xE1;
+const ID_SAMPLING_FREQUENCY: u32 = 0xB5;
+const ID_CHANNELS: u32 = 0x9F;
+const ID_CUES: u32 = 0x1C53BB6B;
+const ID_CLUSTER: u32 = 0x1F43B675;
+const ID_CLUSTER_TIMECODE: u32 = 0xE7;
+const ID_SIMPLE_BLOCK: u32 = 0xA3;
+const ID_BLOCK_GROUP: u32 = 0xA0;
+const ID_BLOCK: u32 = 0xA1;
+const ID_REFERENCE_BLOCK: u32 = 0xFB;
+
+const TRACK_TYPE_VIDEO: u64 = 1;
+const TRACK_TYPE_AUDIO: u64 = 2;
+const DEFAULT_TIMECODE_SCALE: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Default)]
+pub struct TrackInfo {
+    pub number: u64,
+    pub track_type: u64,
+    pub codec_id: String,
+    pub codec_private: Vec<u8>,
+    pub width: u64,
+    pub height: u64,
+    pub sampling_frequency: f64,
+    pub channels: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub track: u64,
+    pub pts_ns: i64,
+    pub key: bool,
+    pub data: Vec<u8>,
+}
+
+/// Reads the blocks of a WebM file in order, stopping at the first cut element.
+pub struct WebmReader<R: Read> {
+    r: R,
+    timecode_scale: u64,
+    // In `timecode_scale` units, written when the file is finalized.
+    duration: Option<f64>,
+    tracks: Vec<TrackInfo>,
+    segment_size_known: bool,
+    has_cues: bool,
+    truncated: bool,
+    cluster_timecode: i64,
+    // The header of the first cluster, read with the file header.
+    pending: Option<(u32, Option<u64>)>,
+}
+
+impl<R: Read> WebmReader<R> {
+    pub fn new(r: R) -> ResultType<Self> {
+        let mut reader = Self {
+            r,
+            timecode_scale: DEFAULT_TIMECODE_SCALE,
+            duration: None,
+            tracks: Vec::new(),
+            segment_size_known: false,
+            has_cues: false,
+            truncated: false,
+            cluster_timecode: 0,
+            pending: None,
+        };
+        let mut first = true;
+        loop {
+            let Some((id, size)) = reader.read_header()? else {
+                break;
+            };
+            if first && id != ID_EBML {
+                bail!("Not a WebM file");
+            }
+            first = false;
+            match id {
+                ID_SEGMENT => reader.segment_size_known = size.is_some(),
+                ID_INFO => {
+                    let Some(body) = reader.read_body(size)? else {
+                        break;
+                    };
+                    reader.parse_info(&body)?;
+                }
+                ID_TRACKS => {
+                    let Some(body) = reader.read_body(size)? else {
+                        break;
+                    };
+                    reader.parse_tracks(&body)?;
+                }
+                ID_CLUSTER => {
+                    reader.pending = Some((id, size));
+                    break;
+                }
+                _ => {
+                    if !reader.skip(id, size)? {
+                        break;
+                    }
+                }
+            }
+        }
+        if reader.tracks.is_empty() {
+            bail!("No tracks, the file may be cut before its header");
+        }
+        Ok(reader)
+    }
+
+    pub fn tracks(&self) -> &[TrackInfo] {
+        &self.tracks
+    }
+
+    pub fn video_track(&self) -> Option<&TrackInfo> {
+        self.tracks
+            .iter()
+            .find(|t| t.track_type == TRACK_TYPE_VIDEO)
+    }
+
+    pub fn audio_track(&self) -> Option<&TrackInfo> {
+        self.tracks
+            .iter()
+            .find(|t| t.track_type == TRACK_TYPE_AUDIO)
+    }
+
+    /// Whether the file was finalized, call it after reading all blocks.
+    pub fn finished(&self) -> bool {
+        !self.truncated
+            && self.segment_size_known
+            && self.has_cues
+            && self.duration.unwrap_or_default() > 0.0
+    }
+
+    /// The next block, `None` at the end of the file or at a cut element.
+    pub fn next_block(&mut self) -> ResultType<Option<Block>> {
+        loop {
+            let header = match self.pending.take() {
+                Some(header) => Some(header),
+                None => self.read_header()?,
+            };
+            let Some((id, size)) = header else {
+                return Ok(None);
+            };
+            match id {
+                // Masters of the blocks, their size is unknown if the file was not finalized.
+                ID_SEGMENT | ID_CLUSTER => {}
+                ID_CLUSTER_TIMECODE => {
+                    let Some(body) = self.read_body(size)? else {
+                        return Ok(None);
+                    };
+                    self.cluster_timecode = read_uint(&body) as i64;
+                }
+                ID_SIMPLE_BLOCK => {
+                    let Some(body) = self.read_body(size)? else {
+                        return Ok(None);
+                    };
+                    return self.parse_block(&body, None).map(Some);
+                }
+                ID_BLOCK_GROUP => {
+                    let Some(body) = self.read_body(size)? else {
+                        return Ok(None);
+                    };
+                    let children = children(&body)?;
+                    let key = !children.iter().any(|(id, _)| *id == ID_REFERENCE_BLOCK);
+                    if let Some((_, block)) = children.iter().find(|(id, _)| *id == ID_BLOCK) {
+                        return self.parse_block(block, Some(key)).map(Some);
+                    }
+                }
+                ID_CUES => {
+                    self.has_cues = true;
+                    if !self.skip(id, size)? {
+                        return Ok(None);
+                    }
+                }
+                _ => {
+                    if !self.skip(id, size)? {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+
+    // `None` at the end of the file, an element cut in its header marks the file truncated.
+    fn read_header(&mut self) -> ResultType<Option<(u32, Option<u64>)>> {
+        let mut first = [0u8; 1];
+        if self.r.read(&mut first)? == 0 {
+            return Ok(None);
+        }
+        let header = read_vint(first[0], &mut self.r, true).and_then(|(id, _)| {
+            let mut first = [0u8; 1];
+            self.r.read_exact(&mut first)?;
+            let (size, len) = read_vint(first[0], &mut self.r, false)?;
+            let unknown = size == (1u64 << (7 * len)) - 1;
+            Ok((id as u32, if unknown { None } else { Some(size) }))
+        });
+        match header {
+            Ok(header) => Ok(Some(header)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                self.truncated = true;
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // `None` if the body is cut.
+    fn read_body(&mut self, size: Option<u64>) -> ResultType<Option<Vec<u8>>> {
+        let Some(size) = size else {
+            bail!("Element of unknown size");
+        };
+        let mut body = Vec::new();
+        (&mut self.r).take(size).read_to_end(&mut body)?;
+        if (body.len() as u64) < size {
+            self.truncated = true;
+            return Ok(None);
+        }
+        Ok(Some(body))
+    }
+
+    // false if the element is cut.
+    fn skip(&mut self, id: u32, size: Option<u64>) -> ResultType<bool> {
+        let Some(size) = size else {
+            bail!("Element {:X} of unknown size", id);
+        };
+        if io::copy(&mut (&mut self.r).take(size), &mut io::sink())? < size {
+            self.truncated = true;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn parse_info(&mut self, body: &[u8]) -> ResultType<()> {
+        for (id, data) in children(body)? {
+            match id {
+                ID_TIMECODE_SCALE => self.timecode_scale = read_uint(data),
+                ID_DURATION => self.duration = Some(read_float(data)?),
+                _ => {}
+            }
+        }
+        if self.timecode_scale == 0 {
+            bail!("Invalid timecode scale");
+        }
+        Ok(())
+    }
+
+    fn parse_tracks(&mut self, body: &[u8]) -> ResultType<()> {
+        for (id, entry) in children(body)? {
+            if id != ID_TRACK_ENTRY {
+                continue;
+            }
+            let mut track = TrackInfo::default();
+            for (id, data) in children(entry)? {
+                match id {
+                    ID_TRACK_NUMBER => track.number = read_uint(data),
+                    ID_TRACK_TYPE => track.track_type = read_uint(data),
+                    ID_CODEC_ID => track.codec_id = String::from_utf8_lossy(data).to_string(),
+                    ID_CODEC_PRIVATE => track.codec_private = data.to_vec(),
+                    ID_VIDEO => {
+                        for (id, data) in children(data)? {
+                            match id {
+                                ID_PIXEL_WIDTH => track.width = read_uint(data),
+                                ID_PIXEL_HEIGHT => track.height = read_uint(data),
+                                _ => {}
+                            }
+                        }
+                    }
+                    ID_AUDIO => {
+                        for (id, data) in children(data)? {
+                            match id {
+                                ID_SAMPLING_FREQUENCY => {
+                                    track.sampling_frequency = read_float(data)?
+                                }
+                                ID_CHANNELS => track.channels = read_uint(data),
+                                _ => {}
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            self.tracks.push(track);
+        }
+        Ok(())
+    }
+
+    fn parse_block(&self, body: &[u8], key: Option<bool>) -> ResultType<Block> {
+        let mut cur = Cursor::new(body);
+        let mut first = [0u8; 1];
+        cur.read_exact(&mut first)?;
+        let (track, _) = read_vint(first[0], &mut cur, false)?;
+        let pos = cur.position() as usize;
+        if body.len() < pos + 3 {
+            bail!("Invalid block");
+        }
+        let timecode = i16::from_be_bytes([body[pos], body[pos + 1]]) as i64;
+        let flags = body[pos + 2];
+        if flags & 0x06 != 0 {
+            bail!("Laced blocks are not supported");
+        }
+        Ok(Block {
+            track,
+            pts_ns: (self.cluster_timecode + timecode) * self.timecode_scale as i64,
+            key: key.unwrap_or(flags & 0x80 != 0),
+            data: body[pos + 3..].to_vec(),
+        })
+    }
+}
+
+// An EBML variable size integer starting with `first`, returns the value and its length.
+// The length marker is kept in IDs.
+fn read_vint<R: Read>(first: u8, r: &mut R, keep_marker: bool) -> io::Result<(u64, usize)> {
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid vint"));
+    }
+    let mut value = if keep_marker {
+        first as u64
+    } else {
+        (first as u64) & ((1u64 << (8 - len)) - 1)
+    };
+    let mut rest = [0u8; 7];
+    r.read_exact(&mut rest[..len - 1])?;
+    for b in &rest[..len - 1] {
+        value = (value << 8) | *b as u64;
+    }
+    Ok((value, len))
+}
+
+// The children of a master element of known size.
+fn children(body: &[u8]) -> ResultType<Vec<(u32, &[u8])>> {
+    let mut children = Vec::new();
+    let mut cur = Cursor::new(body);
+    while (cur.position() as usize) < body.len() {
+        let mut first = [0u8; 1];
+        cur.read_exact(&mut first)?;
+        let (id, _) = read_vint(first[0], &mut cur, true)?;
+        cur.read_exact(&mut first)?;
+        let (size, _) = read_vint(first[0], &mut cur, false)?;
+        let start = cur.position() as usize;
+        let Some(data) = body.get(start..start.saturating_add(size as usize)) else {
+            bail!("Element {:X} exceeds its parent", id);
+        };
+        children.push((id as u32, data));
+        cur.set_position((start + data.len()) as u64);
+    }
+    Ok(children)
+}
+
+fn read_uint(data: &[u8]) -> u64 {
+    data.iter().fold(0, |v, b| (v << 8) | *b as u64)
+}
+
+fn read_float(data: &[u8]) -> ResultType<f64> {
+    Ok(match data.len() {
+        0 => 0.0,
+        4 => f32::from_be_bytes(data.try_into()?) as f64,
+        8 => f64::from_be_bytes(data.try_into()?),
+        _ => bail!("Invalid float"),
+    })
+}
+
+pub fn codec_format(codec_id: &str) -> CodecFormat {
+    match codec_id {
+        "V_VP8" => CodecFormat::VP8,
+        "V_VP9" => CodecFormat::VP9,
+        "V_AV1" => CodecFormat::AV1,
+        _ => CodecFormat::Unknown,
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RecordingInfo {
+    pub codec: String,
+    pub width: u64,
+    pub height: u64,
+    // Of the first and the last video frame.
+    pub start_ms: i64,
+    pub duration_ms: i64,
+    pub video_frames: usize,
+    pub audio_frames: usize,
+    // pts of the key frames in ms.
+    pub key_frames: Vec<i64>,
+    pub finished: bool,
+}
+
+impl RecordingInfo {
+    pub fn key_frame_intervals(&self) -> Vec<i64> {
+        self.key_frames.windows(2).map(|w| w[1] - w[0]).collect()
+    }
+
+    fn add(&mut self, block: &Block, video_track: u64) {
+        if block.track != video_track {
+            self.audio_frames += 1;
+            return;
+        }
+        let pts = block.pts_ns / 1_000_000;
+        if self.video_frames == 0 {
+            self.start_ms = pts;
+        }
+        self.video_frames += 1;
+        self.duration_ms = self.duration_ms.max(pts - self.start_ms);
+        if block.key {
+            self.key_frames.push(pts);
+        }
+    }
+}
+
+fn open(path: &str) -> ResultType<WebmReader<BufReader<File>>> {
+    if record_crypt::is_encrypted(path) {
+        bail!("{} is encrypted, decrypt it first", path);
+    }
+    WebmReader::new(BufReader::new(File::open(path)?))
+}
+
+fn new_info(reader: &WebmReader<impl Read>) -> ResultType<(RecordingInfo, TrackInfo)> {
+    let Some(video) = reader.video_track().cloned() else {
+        bail!("No video track");
+    };
+    let format = codec_format(&video.codec_id);
+    let info = RecordingInfo {
+        codec: if format == CodecFormat::Unknown {
+            video.codec_id.clone()
+        } else {
+            format.to_string()
+        },
+        width: video.width,
+        height: video.height,
+        ..Default::default()
+    };
+    Ok((info, video))
+}
+
+/// Read a whole recording.
+pub fn inspect(path: &str) -> ResultType<RecordingInfo> {
+    let mut reader = open(path)?;
+    let (mut info, video) = new_info(&reader)?;
+    while let Some(block) = reader.next_block()? {
+        info.add(&block, video.number);
+    }
+    info.finished = reader.finished();
+    Ok(info)
+}
+
+/// Mux the complete frames of `input` to `output`, from the first key frame.
+pub fn repair(input: &str, output: &str) -> ResultType<RecordingInfo> {
+    if std::path::Path::new(input) == std::path::Path::new(output) {
+        bail!("The output must not be the input");
+    }
+    let mut reader = open(input)?;
+    let (mut info, video) = new_info(&reader)?;
+    let codec = match codec_format(&video.codec_id) {
+        CodecFormat::VP8 => mux::VideoCodecId::VP8,
+        CodecFormat::VP9 => mux::VideoCodecId::VP9,
+        CodecFormat::AV1 => mux::VideoCodecId::AV1,
+        _ => bail!("Unsupported codec {}", video.codec_id),
+    };
+    let audio = reader
+        .audio_track()
+        .filter(|t| t.codec_id == "A_OPUS")
+        .cloned();
+    let Some(mut webm) = mux::Segment::new(mux::Writer::new(File::create(output)?)) else {
+        bail!("Failed to create webm mux");
+    };
+    let mut vt = webm.add_video_track(video.width as _, video.height as _, None, codec);
+    if !video.codec_private.is_empty()
+        && !webm.set_codec_private(vt.track_number(), &video.codec_private)
+    {
+        bail!("Failed to set codec private");
+    }
+    let mut at = match &audio {
+        Some(audio) => {
+            let at = webm.add_audio_track(
+                audio.sampling_frequency as _,
+                audio.channels as _,
+                None,
+                mux::AudioCodecId::Opus,
+            );
+            if !webm.set_codec_private(at.track_number(), &audio.codec_private) {
+                bail!("Failed to set opus codec private");
+            }
+            Some(at)
+        }
+        None => None,
+    };
+    let mut key = false;
+    while let Some(block) = reader.next_block()? {
+        let pts_ns = block.pts_ns.max(0) as u64;
+        let ok = if block.track == video.number {
+            key |= block.key;
+            key && vt.add_frame(&block.data, pts_ns, block.key)
+        } else if audio.as_ref().map(|a| a.number) == Some(block.track) {
+            key && at
+                .as_mut()
+                .map(|at| at.add_frame(&block.data, pts_ns, true))
+                .unwrap_or_default()
+        } else {
+            false
+        };
+        if ok {
+            info.add(&block, video.number);
+        }
+    }
+    if info.video_frames == 0 {
+        bail!("No complete key frame");
+    }
+    if !webm.finalize(None) {
+        bail!("Failed to finalize {}", output);
+    }
+    info.finished = true;
+    Ok(info)
+}
+
+/// Decode the video frame shown at `at_ms`, from the key frame before it.
+/// `rgb.raw` is RGBA without row padding.
+pub fn decode_frame(path: &str, at_ms: i64) -> ResultType<ImageRgb> {
+    let mut reader = open(path)?;
+    let (_, video) = new_info(&reader)?;
+    let format = codec_format(&video.codec_id);
+    let mut frames: Vec<EncodedVideoFrame> = Vec::new();
+    while let Some(block) = reader.next_block()? {
+        if block.track != video.number {
+            continue;
+        }
+        let pts = block.pts_ns / 1_000_000;
+        if pts > at_ms && !frames.is_empty() {
+            break;
+        }
+        if block.key {
+            frames.clear();
+        }
+        if block.key || !frames.is_empty() {
+            frames.push(EncodedVideoFrame {
+                data: block.data.into(),
+                key: block.key,
+                pts,
+                ..Default::default()
+            });
+        }
+    }
+    if frames.is_empty() {
+        bail!("No key frame before {} ms", at_ms);
+    }
+    let frames = EncodedVideoFrames {
+        frames,
+        ..Default::default()
+    };
+    let union = match format {
+        CodecFormat::VP8 => video_frame::Union::Vp8s(frames),
+        CodecFormat::VP9 => video_frame::Union::Vp9s(frames),
+        CodecFormat::AV1 => video_frame::Union::Av1s(frames),
+        _ => bail!("Unsupported codec {}", video.codec_id),
+    };
+    let mut decoder = Decoder::new(format, None);
+    let mut rgb = ImageRgb::new(ImageFormat::ABGR, 1);
+    if !decoder.handle_video_frame(
+        &union,
+        &mut rgb,
+        &mut ImageTexture::default(),
+        &mut false,
+        &mut None,
+    )? {
+        bail!("No frame decoded");
+    }
+    Ok(rgb)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn element(id: u32, body: &[u8]) -> Vec<u8> {
+        let mut v: Vec<u8> = id
+            .to_be_bytes()
+            .iter()
+            .skip_while(|b| **b == 0)
+            .cloned()
+            .collect();
+        v.push(0x01); // 8 bytes size
+        v.extend_from_slice(&(body.len() as u64).to_be_bytes()[1..]);
+        v.extend_from_slice(body);
+        v
+    }
+
+    fn simple_block(track: u8, timecode: i16, key: bool, data: &[u8]) -> Vec<u8> {
+        let mut body = vec![0x80 | track];
+        body.extend_from_slice(&timecode.to_be_bytes());
+        body.push(if key { 0x80 } else { 0 });
+        body.extend_from_slice(data);
+        element(ID_SIMPLE_BLOCK, &body)
+    }
+
+    // A file as left by a crash: unknown segment and cluster sizes, no cues.
+    fn unfinished() -> Vec<u8> {
+        let mut v = element(ID_EBML, &element(0x4282, b"webm"));
+        v.extend(ID_SEGMENT.to_be_bytes());
+        v.extend([0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        v.extend(element(
+            ID_INFO,
+            &element(ID_TIMECODE_SCALE, &1_000_000u32.to_be_bytes()),
+        ));
+        let video = [
+            element(ID_TRACK_NUMBER, &[1]),
+            element(ID_TRACK_TYPE, &[1]),
+            element(ID_CODEC_ID, b"V_VP9"),
+            element(
+                ID_VIDEO,
+                &[
+                    element(ID_PIXEL_WIDTH, &1920u16.to_be_bytes()),
+                    element(ID_PIXEL_HEIGHT, &1080u16.to_be_bytes()),
+                ]
+                .concat(),
+            ),
+        ]
+        .concat();
+        let audio = [
+            element(ID_TRACK_NUMBER, &[2]),
+            element(ID_TRACK_TYPE, &[2]),
+            element(ID_CODEC_ID, b"A_OPUS"),
+        ]
+        .concat();
+        v.extend(element(
+            ID_TRACKS,
+            &[
+                element(ID_TRACK_ENTRY, &video),
+                element(ID_TRACK_ENTRY, &audio),
+            ]
+            .concat(),
+        ));
+        for (cluster, timecode) in [(0u16, 0), (1, 2000)] {
+            v.extend(ID_CLUSTER.to_be_bytes());
+            v.push(0xFF);
+            v.extend(element(
+                ID_CLUSTER_TIMECODE,
+                &(timecode as u16).to_be_bytes(),
+            ));
+            v.extend(simple_block(1, 0, true, &[cluster as u8; 10]));
+            v.extend(simple_block(2, 10, true, &[0; 4]));
+            v.extend(simple_block(1, 1000, false, &[0; 10]));
+        }
+        v
+    }
+
+    #[test]
+    fn test_read_unfinished() {
+        let data = unfinished();
+        let mut reader = WebmReader::new(Cursor::new(&data)).unwrap();
+        let video = reader.video_track().unwrap();
+        assert_eq!(
+            (video.codec_id.as_str(), video.width, video.height),
+            ("V_VP9", 1920, 1080)
+        );
+        let (mut info, video) = new_info(&reader).unwrap();
+        while let Some(block) = reader.next_block().unwrap() {
+            info.add(&block, video.number);
+        }
+        assert!(!reader.finished());
+        assert_eq!(info.codec, "VP9");
+        assert_eq!((info.video_frames, info.audio_frames), (4, 2));
+        assert_eq!(info.key_frames, vec![0, 2000]);
+        assert_eq!(info.key_frame_intervals(), vec![2000]);
+        assert_eq!(info.duration_ms, 3000);
+
+        // Cut in the last block.
+        let cut = &data[..data.len() - 5];
+        let mut reader = WebmReader::new(Cursor::new(cut)).unwrap();
+        let mut blocks = 0;
+        while reader.next_block().unwrap().is_some() {
+            blocks += 1;
+        }
+        assert_eq!(blocks, 5);
+        assert!(reader.truncated);
+
+        assert!(WebmReader::new(Cursor::new(&data[..40])).is_err());
+        assert!(WebmReader::new(Cursor::new(b"not a webm file")).is_err());
+    }
+}
@@ -9,6 +9,7 @@ use hbb_common::message_proto::{Chroma, EncodedVideoFrame, EncodedVideoFrames, V
 use hbb_common::ResultType;
 
 use crate::codec::{base_bitrate, codec_thread_num, EncoderApi};
+use crate::dirty_rect::ActiveMap;
 use crate::{EncodeInput, EncodeYuvFormat, GoogleImage, Pixfmt, STRIDE_ALIGN};
 
 use super::vpx::{vp8e_enc_control_id::*, vpx_codec_err_t::*, *};
@@ -228,6 +229,25 @@ impl EncoderApi for VpxEncoder {
     }
 
     fn disable(&self) {}
+
+    fn support_active_map(&self) -> bool {
+        true
+    }
+
+    fn set_active_map(&mut self, map: Option<&ActiveMap>) -> ResultType<()> {
+        // libvpx copies the map, a null map encodes every block.
+        let mut active_map = vpx_active_map_t {
+            active_map: map.map_or(ptr::null_mut(), |m| m.map.as_ptr() as _),
+            rows: map.map_or(0, |m| m.rows as _),
+            cols: map.map_or(0, |m| m.cols as _),
+        };
+        call_vpx!(vpx_codec_control_(
+            &mut self.ctx,
+            VP8E_SET_ACTIVEMAP as _,
+            &mut active_map as *mut vpx_active_map_t
+        ));
+        Ok(())
+    }
 }
 
 impl VpxEncoder {
@@ -181,7 +181,6 @@ pub async fn start_one_port_forward(
         receiver,
         &key,
         &token,
-        handler.lc.clone(),
         remote_host,
         remote_port,
     )
@@ -191,3 +190,34 @@ pub async fn start_one_port_forward(
     }
     log::info!("port forward (:{}) exit", port);
 }
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn start_one_reverse_port_forward(
+    id: String,
+    remote_port: i32,
+    local_host: String,
+    local_port: i32,
+    key: String,
+    token: String,
+) {
+    crate::common::test_rendezvous_server();
+    crate::common::test_nat_type();
+    let (sender, receiver) = mpsc::unbounded_channel::<Data>();
+    let handler = Session::new(&id, sender);
+    if let Err(err) = crate::port_forward::listen_reverse(
+        handler.id.clone(),
+        handler.password.clone(),
+        remote_port,
+        handler.clone(),
+        receiver,
+        &key,
+        &token,
+        local_host,
+        local_port,
+    )
+    .await
+    {
+        log::error!("Failed to forward remote port {}: {}", remote_port, err);
+    }
+    log::info!("reverse port forward (remote :{}) exit", remote_port);
+}
@@ -0,0 +1,209 @@
+// Run one command on a peer without an interactive terminal, for scripts.
+//
+// rustdesk --exec <id> [--password <password>] [--timeout <seconds>] [--] <command>...
+//
+// Stdout and stderr of the remote command are written to the local stdout and stderr,
+// the process exits with the exit code of the remote command.
+
+use crate::{client::Data, terminal_service};
+use hbb_common::{
+    bail, compress,
+    config::READ_TIMEOUT,
+    futures::StreamExt,
+    message_proto::*,
+    protobuf::Message as _,
+    rendezvous_proto::ConnType,
+    tokio::{
+        self,
+        time::{self, Duration, Instant},
+    },
+    ResultType,
+};
+use std::io::Write;
+
+pub const EXIT_USAGE: i32 = 2;
+// Same as `timeout(1)`.
+pub const EXIT_TIMEOUT: i32 = 124;
+// Same as `ssh`, the remote command can't be distinguished from a local failure otherwise.
+pub const EXIT_FAILURE: i32 = 255;
+
+// Any id that `terminal_service::is_exec_id` accepts, one command per connection.
+const EXEC_ID: i32 = -1;
+
+const USAGE: &str =
+    "Usage: rustdesk --exec <id> [--password <password>] [--timeout <seconds>] [--] <command>...";
+
+struct Options {
+    id: String,
+    password: String,
+    timeout: Option<Duration>,
+    command: String,
+}
+
+fn parse_args(args: &[String]) -> Option<Options> {
+    let mut id = None;
+    let mut password = String::new();
+    let mut timeout = None;
+    let mut iter = args.iter();
+    let mut command = Vec::new();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--password" if command.is_empty() => password = iter.next()?.clone(),
+            "--timeout" if command.is_empty() => {
+                timeout = Some(Duration::from_secs(iter.next()?.parse().ok()?));
+            }
+            "--" if command.is_empty() => {
+                command.extend(iter.by_ref().cloned());
+            }
+            _ if id.is_none() => id = Some(arg.clone()),
+            _ => command.push(arg.clone()),
+        }
+    }
+    if command.is_empty() {
+        return None;
+    }
+    Some(Options {
+        id: id?,
+        password,
+        timeout,
+        command: command.join(" "),
+    })
+}
+
+/// Run `--exec`, returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let Some(mut opts) = parse_args(args) else {
+        eprintln!("{}", USAGE);
+        return EXIT_USAGE;
+    };
+    if opts.password.is_empty() {
+        if let Ok(password) = std::env::var("RUSTDESK_PASSWORD") {
+            opts.password = password;
+        }
+    }
+    run_(opts)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn run_(opts: Options) -> i32 {
+    crate::common::test_rendezvous_server();
+    crate::common::test_nat_type();
+    match exec(&opts).await {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            EXIT_FAILURE
+        }
+    }
+}
+
+async fn exec(opts: &Options) -> ResultType<i32> {
+    let (_session, mut peer, mut receiver, _keep_it) =
+        crate::cli_session::connect(&opts.id, ConnType::TERMINAL, opts.password.clone()).await?;
+    let mut action = TerminalAction::new();
+    action.set_data(TerminalData {
+        terminal_id: EXEC_ID,
+        data: opts.command.clone().into_bytes().into(),
+        ..Default::default()
+    });
+    let mut msg_out = Message::new();
+    msg_out.set_terminal_action(action);
+    peer.send(&msg_out).await?;
+
+    let deadline = opts.timeout.map(|t| Instant::now() + t);
+    let mut timed_out = false;
+    // Peers without exec support ignore the request.
+    let start_deadline = Instant::now() + READ_TIMEOUT;
+    let mut started = false;
+    loop {
+        tokio::select! {
+            res = peer.next() => match res {
+                Some(Ok(bytes)) => {
+                    let msg_in = Message::parse_from_bytes(&bytes)?;
+                    match msg_in.union {
+                        Some(message::Union::TerminalResponse(response)) => {
+                            if let Some(terminal_response::Union::Opened(opened)) = &response.union {
+                                if opened.terminal_id == EXEC_ID {
+                                    started = true;
+                                }
+                            }
+                            if let Some(code) = handle_response(response)? {
+                                return Ok(if timed_out { EXIT_TIMEOUT } else { code_to_exit(code) });
+                            }
+                        }
+                        Some(message::Union::TestDelay(t)) => {
+                            crate::client::handle_test_delay(t, &mut peer).await;
+                        }
+                        Some(message::Union::Misc(misc)) => {
+                            if let Some(misc::Union::CloseReason(reason)) = misc.union {
+                                bail!("Closed by the peer: {}", reason);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                Some(Err(err)) => bail!("Connection closed: {}", err),
+                None => bail!("Reset by the peer"),
+            },
+            d = receiver.recv() => match d {
+                Some(Data::Message(msg)) => peer.send(&msg).await?,
+                Some(Data::Close) | None => bail!("Aborted"),
+                _ => {}
+            },
+            _ = time::sleep_until(start_deadline), if !started => {
+                bail!("The peer does not support running commands");
+            }
+            _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() && !timed_out => {
+                eprintln!("Timed out, closing the remote command");
+                timed_out = true;
+                let mut action = TerminalAction::new();
+                action.set_close(CloseTerminal {
+                    terminal_id: EXEC_ID,
+                    ..Default::default()
+                });
+                let mut msg_out = Message::new();
+                msg_out.set_terminal_action(action);
+                peer.send(&msg_out).await?;
+            }
+        }
+    }
+}
+
+// Returns the exit code once the command is finished.
+fn handle_response(response: TerminalResponse) -> ResultType<Option<i32>> {
+    match response.union {
+        Some(terminal_response::Union::Data(data)) => {
+            let output = if data.compressed {
+                compress::decompress(&data.data)
+            } else {
+                data.data.to_vec()
+            };
+            if data.terminal_id == EXEC_ID {
+                let mut stdout = std::io::stdout();
+                stdout.write_all(&output)?;
+                stdout.flush()?;
+            } else if data.terminal_id == terminal_service::exec_stderr_id(EXEC_ID) {
+                let mut stderr = std::io::stderr();
+                stderr.write_all(&output)?;
+                stderr.flush()?;
+            }
+        }
+        Some(terminal_response::Union::Closed(closed)) if closed.terminal_id == EXEC_ID => {
+            return Ok(Some(closed.exit_code));
+        }
+        Some(terminal_response::Union::Error(error)) => {
+            bail!("{}", error.message);
+        }
+        _ => {}
+    }
+    Ok(None)
+}
+
+// Negative codes are signals or a forced close.
+fn code_to_exit(code: i32) -> i32 {
+    if code < 0 {
+        128 - code
+    } else {
+        code
+    }
+}
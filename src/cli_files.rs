@@ -0,0 +1,542 @@
+// Headless file transfer against a peer ID, for scripts.
+//
+// rustdesk --cli-files <id> [--password <password>] [--include-hidden] [--no-overwrite] <command>
+//   ls <remote-path>
+//   get <remote-path> <local-path>
+//   put <local-path> <remote-path>
+//   rm [-r] <remote-path>
+//
+// Partially transferred files are resumed with the digest/offset logic of `fs::TransferJob`,
+// identical files are skipped. The process exits with a non-zero code on failure.
+
+use crate::{
+    cli_session::Session,
+    client::{Data, Interface},
+};
+use hbb_common::{
+    bail,
+    config::READ_TIMEOUT,
+    fs::{self, can_enable_overwrite_detection, get_string, new_send_confirm, DigestCheckResult},
+    futures::StreamExt,
+    log,
+    message_proto::*,
+    protobuf::Message as _,
+    rendezvous_proto::ConnType,
+    timeout,
+    tokio::{
+        self,
+        sync::mpsc,
+        time::{self, Duration},
+    },
+    ResultType, Stream,
+};
+use std::path::PathBuf;
+
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+
+const USAGE: &str = "Usage: rustdesk --cli-files <id> [--password <password>] [--include-hidden] [--no-overwrite] <command>
+  ls <remote-path>
+  get <remote-path> <local-path>
+  put <local-path> <remote-path>
+  rm [-r] <remote-path>";
+
+enum Command {
+    Ls(String),
+    Get(String, String),
+    Put(String, String),
+    Rm(String, bool),
+}
+
+struct Options {
+    id: String,
+    password: String,
+    include_hidden: bool,
+    overwrite: bool,
+    command: Command,
+}
+
+fn parse_args(args: &[String]) -> Option<Options> {
+    let mut positional = Vec::new();
+    let mut password = String::new();
+    let mut include_hidden = false;
+    let mut overwrite = true;
+    let mut recursive = false;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--password" => password = iter.next()?.clone(),
+            "--include-hidden" => include_hidden = true,
+            "--no-overwrite" => overwrite = false,
+            "-r" => recursive = true,
+            _ => positional.push(arg.clone()),
+        }
+    }
+    if positional.len() < 3 {
+        return None;
+    }
+    let id = positional.remove(0);
+    let command = match (positional[0].as_str(), positional.len()) {
+        ("ls", 2) => Command::Ls(positional[1].clone()),
+        ("get", 3) => Command::Get(positional[1].clone(), positional[2].clone()),
+        ("put", 3) => Command::Put(positional[1].clone(), positional[2].clone()),
+        ("rm", 2) => Command::Rm(positional[1].clone(), recursive),
+        _ => return None,
+    };
+    Some(Options {
+        id,
+        password,
+        include_hidden,
+        overwrite,
+        command,
+    })
+}
+
+/// Run `--cli-files`, returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let Some(opts) = parse_args(args) else {
+        eprintln!("{}", USAGE);
+        return EXIT_USAGE;
+    };
+    if opts.password.is_empty() {
+        if let Ok(password) = std::env::var("RUSTDESK_PASSWORD") {
+            return run_(Options { password, ..opts });
+        }
+    }
+    run_(opts)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn run_(opts: Options) -> i32 {
+    crate::common::test_rendezvous_server();
+    crate::common::test_nat_type();
+    match FileClient::connect(&opts).await {
+        Ok(mut client) => match client.run(opts.command).await {
+            Ok(()) => EXIT_OK,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                EXIT_FAILURE
+            }
+        },
+        Err(err) => {
+            eprintln!("Failed to connect to {}: {}", opts.id, err);
+            EXIT_FAILURE
+        }
+    }
+}
+
+struct FileClient {
+    session: Session,
+    peer: Stream,
+    receiver: mpsc::UnboundedReceiver<Data>,
+    include_hidden: bool,
+    overwrite: bool,
+    read_jobs: Vec<fs::TransferJob>,
+    write_jobs: Vec<fs::TransferJob>,
+    _keep_it: Option<mpsc::UnboundedSender<()>>,
+}
+
+impl FileClient {
+    async fn connect(opts: &Options) -> ResultType<Self> {
+        let (session, peer, receiver, _keep_it) =
+            crate::cli_session::connect(&opts.id, ConnType::FILE_TRANSFER, opts.password.clone())
+                .await?;
+        Ok(Self {
+            session,
+            peer,
+            receiver,
+            include_hidden: opts.include_hidden,
+            overwrite: opts.overwrite,
+            read_jobs: Vec::new(),
+            write_jobs: Vec::new(),
+            _keep_it,
+        })
+    }
+
+    async fn run(&mut self, command: Command) -> ResultType<()> {
+        match command {
+            Command::Ls(path) => self.ls(path).await,
+            Command::Get(remote, local) => self.get(remote, local).await,
+            Command::Put(local, remote) => self.put(local, remote).await,
+            Command::Rm(path, recursive) => self.rm(path, recursive).await,
+        }
+    }
+
+    fn peer_version(&self) -> i64 {
+        self.session.get_lch().read().unwrap().version
+    }
+
+    fn is_peer_windows(&self) -> bool {
+        self.session.get_lch().read().unwrap().info.platform == "Windows"
+    }
+
+    async fn send_file_action(&mut self, file_action: FileAction) -> ResultType<()> {
+        let mut msg_out = Message::new();
+        msg_out.set_file_action(file_action);
+        self.peer.send(&msg_out).await
+    }
+
+    async fn ls(&mut self, path: String) -> ResultType<()> {
+        let mut file_action = FileAction::new();
+        file_action.set_read_dir(ReadDir {
+            path: path.clone(),
+            include_hidden: self.include_hidden,
+            ..Default::default()
+        });
+        self.send_file_action(file_action).await?;
+        loop {
+            match self.next_file_response().await? {
+                Some(file_response::Union::Dir(fd)) => {
+                    for entry in fd.entries.iter() {
+                        println!("{}", format_entry(entry));
+                    }
+                    return Ok(());
+                }
+                Some(file_response::Union::Error(e)) => bail!("{}: {}", path, e.error),
+                _ => {}
+            }
+        }
+    }
+
+    async fn rm(&mut self, path: String, recursive: bool) -> ResultType<()> {
+        let id = fs::get_next_job_id();
+        let mut file_action = FileAction::new();
+        if recursive {
+            file_action.set_remove_dir(FileRemoveDir {
+                id,
+                path: path.clone(),
+                recursive: true,
+                ..Default::default()
+            });
+        } else {
+            file_action.set_remove_file(FileRemoveFile {
+                id,
+                path: path.clone(),
+                file_num: 0,
+                ..Default::default()
+            });
+        }
+        self.send_file_action(file_action).await?;
+        loop {
+            match self.next_file_response().await? {
+                Some(file_response::Union::Done(d)) if d.id == id => return Ok(()),
+                Some(file_response::Union::Error(e)) if e.id == id => {
+                    bail!("{}: {}", path, e.error)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn get(&mut self, remote: String, local: String) -> ResultType<()> {
+        let mut to = PathBuf::from(&local);
+        if to.is_dir() {
+            to = to.join(base_name(&remote));
+        }
+        let id = fs::get_next_job_id();
+        let od = can_enable_overwrite_detection(self.peer_version());
+        let mut job = fs::TransferJob::new_write(
+            id,
+            fs::JobType::Generic,
+            remote.clone(),
+            fs::DataSource::FilePath(to.clone()),
+            0,
+            self.include_hidden,
+            true,
+            Vec::new(),
+            od,
+        );
+        job.is_resume = true;
+        self.write_jobs.push(job);
+        self.peer
+            .send(&fs::new_send(
+                id,
+                fs::JobType::Generic,
+                remote.clone(),
+                0,
+                self.include_hidden,
+            ))
+            .await?;
+        self.wait_job(id).await?;
+        eprintln!("{} -> {}", remote, get_string(&to));
+        Ok(())
+    }
+
+    async fn put(&mut self, local: String, remote: String) -> ResultType<()> {
+        let mut to = remote;
+        if to.ends_with('/') || to.ends_with('\\') {
+            to.push_str(&base_name(&local));
+        }
+        let id = fs::get_next_job_id();
+        let od = can_enable_overwrite_detection(self.peer_version());
+        let mut job = fs::TransferJob::new_read(
+            id,
+            fs::JobType::Generic,
+            to.clone(),
+            fs::DataSource::FilePath(PathBuf::from(&local)),
+            0,
+            self.include_hidden,
+            false,
+            od,
+        )?;
+        job.is_resume = true;
+        #[cfg(not(windows))]
+        let files = job.files().clone();
+        #[cfg(windows)]
+        let mut files = job.files().clone();
+        #[cfg(windows)]
+        if !self.is_peer_windows() {
+            // peer is not windows, need transform \ to /
+            fs::transform_windows_path(&mut files);
+        }
+        let total_size = job.total_size();
+        self.read_jobs.push(job);
+        self.peer
+            .send(&fs::new_receive(id, to.clone(), 0, files, total_size))
+            .await?;
+        self.wait_job(id).await?;
+        eprintln!("{} -> {}", local, to);
+        Ok(())
+    }
+
+    async fn next_file_response(&mut self) -> ResultType<Option<file_response::Union>> {
+        loop {
+            tokio::select! {
+                res = timeout(READ_TIMEOUT, self.peer.next()) => match res {
+                    Err(_) => bail!("Timeout"),
+                    Ok(Some(Ok(bytes))) => {
+                        if let Some(fr) = self.handle_msg(&bytes).await? {
+                            return Ok(fr);
+                        }
+                    }
+                    Ok(Some(Err(err))) => bail!("Connection closed: {}", err),
+                    Ok(None) => bail!("Reset by the peer"),
+                },
+                d = self.receiver.recv() => {
+                    self.handle_data(d).await?;
+                }
+            }
+        }
+    }
+
+    // Return the file response to be handled by the caller.
+    async fn handle_msg(
+        &mut self,
+        bytes: &[u8],
+    ) -> ResultType<Option<Option<file_response::Union>>> {
+        let msg_in = Message::parse_from_bytes(bytes)?;
+        match msg_in.union {
+            Some(message::Union::FileResponse(fr)) => return Ok(Some(fr.union)),
+            Some(message::Union::TestDelay(t)) => {
+                self.session.handle_test_delay(t, &mut self.peer).await;
+            }
+            Some(message::Union::MessageBox(msgbox)) => {
+                self.session
+                    .msgbox(&msgbox.msgtype, &msgbox.title, &msgbox.text, "");
+            }
+            Some(message::Union::Misc(misc)) => {
+                if let Some(misc::Union::CloseReason(reason)) = misc.union {
+                    bail!("Closed by the peer: {}", reason);
+                }
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    async fn handle_data(&mut self, d: Option<Data>) -> ResultType<()> {
+        match d {
+            Some(Data::Message(msg)) => self.peer.send(&msg).await,
+            Some(Data::Close) | None => bail!("Aborted"),
+            _ => Ok(()),
+        }
+    }
+
+    /// Drive a transfer job until the peer reports it done or failed.
+    async fn wait_job(&mut self, id: i32) -> ResultType<()> {
+        let mut timer = crate::rustdesk_interval(time::interval(Duration::from_millis(1)));
+        loop {
+            tokio::select! {
+                res = self.peer.next() => match res {
+                    Some(Ok(bytes)) => {
+                        if let Some(fr) = self.handle_msg(&bytes).await? {
+                            if self.handle_file_response(id, fr).await? {
+                                return Ok(());
+                            }
+                        }
+                    }
+                    Some(Err(err)) => bail!("Connection closed: {}", err),
+                    None => bail!("Reset by the peer"),
+                },
+                _ = timer.tick(), if !self.read_jobs.is_empty() => {
+                    fs::handle_read_jobs(&mut self.read_jobs, &mut self.peer).await?;
+                }
+                d = self.receiver.recv() => {
+                    self.handle_data(d).await?;
+                }
+            }
+        }
+    }
+
+    // Returns true once the job is done.
+    async fn handle_file_response(
+        &mut self,
+        id: i32,
+        fr: Option<file_response::Union>,
+    ) -> ResultType<bool> {
+        match fr {
+            Some(file_response::Union::Dir(fd)) => {
+                #[cfg(windows)]
+                let entries = fd.entries.to_vec();
+                #[cfg(not(windows))]
+                let mut entries = fd.entries.to_vec();
+                #[cfg(not(windows))]
+                if self.is_peer_windows() {
+                    fs::transform_windows_path(&mut entries);
+                }
+                if let Some(job) = fs::get_job(fd.id, &mut self.write_jobs) {
+                    log::info!("job {} has {} files", fd.id, entries.len());
+                    job.set_files(entries);
+                    job.set_finished_size_on_resume();
+                }
+            }
+            Some(file_response::Union::Digest(digest)) => {
+                let req = if digest.is_upload {
+                    self.confirm_upload(&digest)
+                } else {
+                    self.confirm_download(&digest)?
+                };
+                if let Some(req) = req {
+                    let job = if digest.is_upload {
+                        fs::get_job(digest.id, &mut self.read_jobs)
+                    } else {
+                        fs::get_job(digest.id, &mut self.write_jobs)
+                    };
+                    if let Some(job) = job {
+                        job.confirm(&req).await;
+                    }
+                    self.peer.send(&new_send_confirm(req)).await?;
+                }
+            }
+            Some(file_response::Union::Block(block)) => {
+                if let Some(job) = fs::get_job(block.id, &mut self.write_jobs) {
+                    job.write(block).await?;
+                }
+            }
+            Some(file_response::Union::Done(d)) if d.id == id => {
+                if let Some(job) = fs::remove_job(d.id, &mut self.write_jobs) {
+                    job.modify_time();
+                    if let Some(err) = job.job_error() {
+                        bail!("{}", err);
+                    }
+                }
+                return Ok(true);
+            }
+            Some(file_response::Union::Error(e)) if e.id == id => {
+                fs::remove_job(e.id, &mut self.write_jobs);
+                fs::remove_job(e.id, &mut self.read_jobs);
+                bail!("{}", e.error);
+            }
+            _ => {}
+        }
+        Ok(false)
+    }
+
+    fn confirm_request(
+        id: i32,
+        file_num: i32,
+        offset: Option<u32>,
+    ) -> FileTransferSendConfirmRequest {
+        FileTransferSendConfirmRequest {
+            id,
+            file_num,
+            union: Some(match offset {
+                Some(offset) => file_transfer_send_confirm_request::Union::OffsetBlk(offset),
+                None => file_transfer_send_confirm_request::Union::Skip(true),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn confirm_upload(
+        &mut self,
+        digest: &FileTransferDigest,
+    ) -> Option<FileTransferSendConfirmRequest> {
+        let job = fs::get_job(digest.id, &mut self.read_jobs)?;
+        let offset = if digest.is_identical && job.is_resume && digest.transferred_size > 0 {
+            Some(digest.transferred_size as _)
+        } else if digest.is_identical || !self.overwrite {
+            None
+        } else {
+            Some(0)
+        };
+        Some(Self::confirm_request(digest.id, digest.file_num, offset))
+    }
+
+    fn confirm_download(
+        &mut self,
+        digest: &FileTransferDigest,
+    ) -> ResultType<Option<FileTransferSendConfirmRequest>> {
+        let overwrite = self.overwrite;
+        let peer_ver = self.peer_version();
+        let Some(job) = fs::get_job(digest.id, &mut self.write_jobs) else {
+            return Ok(None);
+        };
+        let Some(file) = job.files().get(digest.file_num as usize) else {
+            return Ok(None);
+        };
+        let fs::DataSource::FilePath(p) = &job.data_source else {
+            return Ok(None);
+        };
+        let write_path = get_string(&fs::TransferJob::join(p, &file.name));
+        job.set_digest(digest.file_size, digest.last_modified);
+        let is_support_resume = crate::is_support_file_transfer_resume_num(peer_ver);
+        let offset = match fs::is_write_need_confirmation(
+            is_support_resume && job.is_resume,
+            &write_path,
+            digest,
+        )? {
+            DigestCheckResult::IsSame => None,
+            DigestCheckResult::NeedConfirm(digest) => {
+                if digest.is_identical && job.is_resume && digest.transferred_size > 0 {
+                    Some(digest.transferred_size as _)
+                } else if overwrite {
+                    Some(0)
+                } else {
+                    None
+                }
+            }
+            DigestCheckResult::NoSuchFile => Some(0),
+        };
+        Ok(Some(Self::confirm_request(
+            digest.id,
+            digest.file_num,
+            offset,
+        )))
+    }
+}
+
+fn base_name(path: &str) -> String {
+    path.trim_end_matches(|c| c == '/' || c == '\\')
+        .rsplit(|c| c == '/' || c == '\\')
+        .next()
+        .unwrap_or_default()
+        .to_owned()
+}
+
+fn format_entry(entry: &FileEntry) -> String {
+    let kind = match entry.entry_type.enum_value() {
+        Ok(FileType::Dir) | Ok(FileType::DirDrive) => 'd',
+        Ok(FileType::DirLink) | Ok(FileType::FileLink) => 'l',
+        _ => '-',
+    };
+    let modified = chrono::DateTime::from_timestamp(entry.modified_time as _, 0)
+        .map(|t| {
+            t.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_default();
+    format!("{} {:>12} {} {}", kind, entry.size, modified, entry.name)
+}
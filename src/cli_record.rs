@@ -0,0 +1,222 @@
+// Offline tools for recordings.
+//
+// rustdesk --recording keygen
+//   Print a new key pair, set the public key as the `record-encryption-public-key` option
+//   and keep the secret key with the auditor.
+// rustdesk --recording decrypt [--key <secret-key>] <input> [<output>]
+//   Decrypt a recording to a playable file, the secret key may also be passed in
+//   `RUSTDESK_RECORD_KEY`. The output defaults to the input without `.enc`.
+// rustdesk --recording inspect <input>
+//   Print the codec, resolution, duration and key frame intervals of a webm recording,
+//   and whether it was finished.
+// rustdesk --recording repair <input> [<output>]
+//   Rebuild the cues and the duration of a webm recording cut by a crash, the output
+//   defaults to the input with `.repaired.webm`.
+// rustdesk --recording thumbnail [--at <seconds>] <input> [<output>]
+//   Save the frame shown at `--at`, default 0, of a webm recording as a png. The output
+//   defaults to the input with `.png`.
+
+use hbb_common::{bail, ResultType};
+use scrap::{record_crypt, record_inspect};
+use std::{fs::File, io::BufReader};
+
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+
+const USAGE: &str = "Usage: rustdesk --recording <command>
+  keygen
+  decrypt [--key <secret-key>] <input> [<output>]
+  inspect <input>
+  repair <input> [<output>]
+  thumbnail [--at <seconds>] <input> [<output>]";
+
+enum Command {
+    Keygen,
+    Decrypt {
+        key: String,
+        input: String,
+        output: Option<String>,
+    },
+    Inspect {
+        input: String,
+    },
+    Repair {
+        input: String,
+        output: Option<String>,
+    },
+    Thumbnail {
+        at_ms: i64,
+        input: String,
+        output: Option<String>,
+    },
+}
+
+// One or two paths: the input and the output.
+fn input_output(paths: Vec<String>) -> Option<(String, Option<String>)> {
+    if paths.is_empty() || paths.len() > 2 {
+        return None;
+    }
+    let mut paths = paths.into_iter();
+    Some((paths.next()?, paths.next()))
+}
+
+fn parse_args(args: &[String]) -> Option<Command> {
+    let mut iter = args.iter();
+    match iter.next()?.as_str() {
+        "keygen" => Some(Command::Keygen),
+        "decrypt" => {
+            let mut key = None;
+            let mut paths = Vec::new();
+            while let Some(arg) = iter.next() {
+                match arg.as_str() {
+                    "--key" => key = Some(iter.next()?.clone()),
+                    _ => paths.push(arg.clone()),
+                }
+            }
+            let (input, output) = input_output(paths)?;
+            let key = key.or_else(|| std::env::var("RUSTDESK_RECORD_KEY").ok())?;
+            Some(Command::Decrypt { key, input, output })
+        }
+        "inspect" => {
+            let input = iter.next()?.clone();
+            if iter.next().is_some() {
+                return None;
+            }
+            Some(Command::Inspect { input })
+        }
+        "repair" => {
+            let (input, output) = input_output(iter.cloned().collect())?;
+            Some(Command::Repair { input, output })
+        }
+        "thumbnail" => {
+            let mut at = 0.0;
+            let mut paths = Vec::new();
+            while let Some(arg) = iter.next() {
+                match arg.as_str() {
+                    "--at" => at = iter.next()?.parse::<f64>().ok().filter(|at| *at >= 0.0)?,
+                    _ => paths.push(arg.clone()),
+                }
+            }
+            let (input, output) = input_output(paths)?;
+            Some(Command::Thumbnail {
+                at_ms: (at * 1000.0) as i64,
+                input,
+                output,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Run `--recording`, returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let Some(command) = parse_args(args) else {
+        eprintln!("{}", USAGE);
+        return EXIT_USAGE;
+    };
+    let res = match command {
+        Command::Keygen => {
+            let (pk, sk) = record_crypt::gen_keypair();
+            println!("public key: {}", pk);
+            println!("secret key: {}", sk);
+            Ok(())
+        }
+        Command::Decrypt { key, input, output } => decrypt(&key, &input, output),
+        Command::Inspect { input } => inspect(&input),
+        Command::Repair { input, output } => repair(&input, output),
+        Command::Thumbnail {
+            at_ms,
+            input,
+            output,
+        } => thumbnail(at_ms, &input, output),
+    };
+    match res {
+        Ok(_) => EXIT_OK,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            EXIT_FAILURE
+        }
+    }
+}
+
+fn decrypt(key: &str, input: &str, output: Option<String>) -> ResultType<()> {
+    let sk = record_crypt::parse_secret_key(key)?;
+    let output = match output {
+        Some(output) => output,
+        None => match input.strip_suffix(record_crypt::EXTENSION) {
+            Some(output) => output.to_owned(),
+            None => bail!("The output path is required"),
+        },
+    };
+    if !record_crypt::is_encrypted(input) {
+        bail!("{} is not an encrypted recording", input);
+    }
+    let mut out = File::create(&output)?;
+    let finished = record_crypt::decrypt(&mut BufReader::new(File::open(input)?), &mut out, &sk)?;
+    if !finished {
+        eprintln!("Warning: the recording was not finished, only the written part is decrypted");
+    }
+    println!("{}", output);
+    Ok(())
+}
+
+fn print_info(info: &record_inspect::RecordingInfo) {
+    println!("codec: {}", info.codec);
+    println!("resolution: {}x{}", info.width, info.height);
+    println!(
+        "duration: {:.3}s, from {:.3}s",
+        info.duration_ms as f64 / 1000.0,
+        info.start_ms as f64 / 1000.0
+    );
+    println!(
+        "frames: {} video, {} audio",
+        info.video_frames, info.audio_frames
+    );
+    let intervals = info.key_frame_intervals();
+    if intervals.is_empty() {
+        println!("key frames: {}", info.key_frames.len());
+    } else {
+        println!(
+            "key frames: {}, interval min {:.3}s, avg {:.3}s, max {:.3}s",
+            info.key_frames.len(),
+            *intervals.iter().min().unwrap_or(&0) as f64 / 1000.0,
+            intervals.iter().sum::<i64>() as f64 / intervals.len() as f64 / 1000.0,
+            *intervals.iter().max().unwrap_or(&0) as f64 / 1000.0
+        );
+    }
+    println!("finished: {}", if info.finished { "yes" } else { "no" });
+}
+
+fn inspect(input: &str) -> ResultType<()> {
+    let info = record_inspect::inspect(input)?;
+    print_info(&info);
+    if !info.finished {
+        eprintln!(
+            "Warning: the recording was not finished, run `--recording repair {}`",
+            input
+        );
+    }
+    Ok(())
+}
+
+fn with_suffix(input: &str, extension: &str) -> String {
+    let base = input.strip_suffix(".webm").unwrap_or(input);
+    format!("{}{}", base, extension)
+}
+
+fn repair(input: &str, output: Option<String>) -> ResultType<()> {
+    let output = output.unwrap_or_else(|| with_suffix(input, ".repaired.webm"));
+    let info = record_inspect::repair(input, &output)?;
+    print_info(&info);
+    println!("{}", output);
+    Ok(())
+}
+
+fn thumbnail(at_ms: i64, input: &str, output: Option<String>) -> ResultType<()> {
+    let output = output.unwrap_or_else(|| with_suffix(input, ".png"));
+    let rgb = record_inspect::decode_frame(input, at_ms)?;
+    repng::encode(File::create(&output)?, rgb.w as _, rgb.h as _, &rgb.raw)?;
+    println!("{}", output);
+    Ok(())
+}
@@ -0,0 +1,183 @@
+// Connection and login for the headless command line tools.
+
+use crate::client::{self, Client, Data, Interface, LoginConfigHandler};
+use async_trait::async_trait;
+use hbb_common::{
+    bail,
+    config::{LocalConfig, READ_TIMEOUT},
+    futures::StreamExt,
+    log,
+    message_proto::*,
+    protobuf::Message as _,
+    rendezvous_proto::ConnType,
+    timeout,
+    tokio::{self, sync::mpsc},
+    ResultType, Stream,
+};
+use std::sync::{Arc, RwLock};
+
+/// A session without UI, password prompts go to the terminal.
+#[derive(Clone)]
+pub struct Session {
+    lc: Arc<RwLock<LoginConfigHandler>>,
+    sender: mpsc::UnboundedSender<Data>,
+    password: String,
+}
+
+impl Session {
+    pub fn new(
+        id: &str,
+        conn_type: ConnType,
+        sender: mpsc::UnboundedSender<Data>,
+        password: String,
+    ) -> Self {
+        let session = Self {
+            lc: Default::default(),
+            sender,
+            password,
+        };
+        session.lc.write().unwrap().initialize(
+            id.to_owned(),
+            conn_type,
+            None,
+            false,
+            None,
+            None,
+            None,
+        );
+        session
+    }
+}
+
+#[async_trait]
+impl Interface for Session {
+    fn get_lch(&self) -> Arc<RwLock<LoginConfigHandler>> {
+        self.lc.clone()
+    }
+
+    fn send(&self, data: Data) {
+        self.sender.send(data).ok();
+    }
+
+    fn msgbox(&self, msgtype: &str, title: &str, text: &str, _link: &str) {
+        match msgtype {
+            "input-password" => {
+                let password = if self.password.is_empty() {
+                    rpassword::prompt_password("Password: ").unwrap_or_default()
+                } else {
+                    self.password.clone()
+                };
+                self.send(Data::Login(("".to_owned(), "".to_owned(), password, false)));
+            }
+            "re-input-password" => {
+                eprintln!("{}: {}", title, text);
+                self.send(Data::Close);
+            }
+            msg if msg.contains("error") => {
+                eprintln!("{}: {}", title, text);
+                self.send(Data::Close);
+            }
+            _ => {
+                log::info!("{}: {}: {}", msgtype, title, text);
+            }
+        }
+    }
+
+    fn handle_login_error(&self, err: &str) -> bool {
+        client::handle_login_error(self.lc.clone(), err, self)
+    }
+
+    fn handle_peer_info(&self, pi: PeerInfo) {
+        self.lc.write().unwrap().handle_peer_info(&pi);
+    }
+
+    fn set_multiple_windows_session(&self, _sessions: Vec<WindowsSession>) {}
+
+    async fn handle_hash(&self, pass: &str, hash: Hash, peer: &mut Stream) {
+        client::handle_hash(self.lc.clone(), pass, hash, self, peer).await;
+    }
+
+    async fn handle_login_from_ui(
+        &self,
+        os_username: String,
+        os_password: String,
+        password: String,
+        remember: bool,
+        peer: &mut Stream,
+    ) {
+        client::handle_login_from_ui(
+            self.lc.clone(),
+            os_username,
+            os_password,
+            password,
+            remember,
+            peer,
+        )
+        .await;
+    }
+
+    async fn handle_test_delay(&self, t: TestDelay, peer: &mut Stream) {
+        client::handle_test_delay(t, peer).await;
+    }
+}
+
+/// Connect to the peer and log in, returns once the peer info is received.
+pub async fn connect(
+    id: &str,
+    conn_type: ConnType,
+    password: String,
+) -> ResultType<(
+    Session,
+    Stream,
+    mpsc::UnboundedReceiver<Data>,
+    Option<mpsc::UnboundedSender<()>>,
+)> {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
+    let session = Session::new(id, conn_type, sender, password.clone());
+    let key = crate::get_key(false).await;
+    let token = LocalConfig::get_option("access_token");
+    let ((mut peer, _direct, _pk, _kcp, _stream_type), (feedback, rendezvous_server)) =
+        Client::start(id, &key, &token, conn_type, session.clone()).await?;
+    let keep_it = client::hc_connection(feedback, rendezvous_server, &token).await;
+    loop {
+        tokio::select! {
+            res = timeout(READ_TIMEOUT, peer.next()) => match res {
+                Err(_) => bail!("Timeout"),
+                Ok(Some(Ok(bytes))) => {
+                    let msg_in = Message::parse_from_bytes(&bytes)?;
+                    match msg_in.union {
+                        Some(message::Union::Hash(hash)) => {
+                            session.handle_hash(&password, hash, &mut peer).await;
+                        }
+                        Some(message::Union::LoginResponse(lr)) => match lr.union {
+                            Some(login_response::Union::Error(err)) => {
+                                if !session.handle_login_error(&err) {
+                                    bail!("{}", err);
+                                }
+                            }
+                            Some(login_response::Union::PeerInfo(pi)) => {
+                                session.handle_peer_info(pi);
+                                break;
+                            }
+                            _ => {}
+                        },
+                        Some(message::Union::TestDelay(t)) => {
+                            session.handle_test_delay(t, &mut peer).await;
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(Some(Err(err))) => bail!("Connection closed: {}", err),
+                Ok(None) => bail!("Reset by the peer"),
+            },
+            d = receiver.recv() => match d {
+                Some(Data::Login((os_username, os_password, password, remember))) => {
+                    session.handle_login_from_ui(os_username, os_password, password, remember, &mut peer).await;
+                }
+                Some(Data::Close) | None => bail!("Login failed"),
+                _ => {}
+            },
+        }
+    }
+    Ok((session, peer, receiver, keep_it))
+}
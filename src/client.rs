@@ -89,10 +89,14 @@ use crate::ui_session_interface::SessionPermissionConfig;
 
 pub use super::lang::*;
 
+pub mod clipboard_history;
 pub mod file_trait;
 pub mod helper;
 pub mod io_loop;
+pub mod macros;
 pub mod screenshot;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+pub mod type_keys;
 
 pub const MILLI1: Duration = Duration::from_millis(1);
 pub const SEC30: Duration = Duration::from_secs(30);
@@ -1650,14 +1654,18 @@ impl VideoHandler {
     /// Start or stop screen record.
     pub fn record_screen(&mut self, start: bool, id: String, display_idx: usize, camera: bool) {
         self.record = false;
-        if start {
+        let dir = crate::ui_interface::video_save_directory(false);
+        if start && crate::record_retention::prepare(&dir).is_ok() {
             self.recorder = Recorder::new(RecorderContext {
                 server: false,
                 id,
-                dir: crate::ui_interface::video_save_directory(false),
+                dir,
                 display_idx,
                 camera,
                 tx: None,
+                public_key: LocalConfig::get_option(scrap::record_crypt::OPTION_RECORD_PUBLIC_KEY),
+                session_id: Default::default(),
+                manifest: Default::default(),
             })
             .map_or(Default::default(), |r| Arc::new(Mutex::new(Some(r))));
         } else {
@@ -1666,6 +1674,17 @@ impl VideoHandler {
 
         self.record = start;
     }
+
+    /// Write the audio to the screen record.
+    pub fn record_audio(&mut self, frame: &AudioFrame) {
+        if self.record {
+            self.recorder
+                .lock()
+                .unwrap()
+                .as_mut()
+                .map(|r| r.write_audio(frame));
+        }
+    }
 }
 
 // The source of sent password
@@ -2917,6 +2936,11 @@ pub fn start_video_thread<F, T>(
                             handler.record_screen(start, id, display, is_view_camera);
                         }
                     }
+                    MediaData::AudioFrame(af) => {
+                        if let Some(handler) = video_handler.as_mut() {
+                            handler.record_audio(&af);
+                        }
+                    }
                     _ => {}
                 }
             } else {
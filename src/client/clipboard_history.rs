@@ -0,0 +1,541 @@
+// The clipboards received from and sent to a peer, newest last, to push an older one to the
+// peer again without copying it locally.
+//
+// The history is kept in memory, bounded by `clipboard-history-size` entries and `MAX_BYTES`.
+// With `clipboard-history-persist`, the newest entries up to `MAX_PERSIST_BYTES` are also saved
+// encrypted to the config directory, one file per peer. A saver thread loads the file, adds the
+// new entries to it and writes it at most once per `SAVE_DELAY`, not to block the IO loop.
+
+use crate::{
+    client::{Data, Interface},
+    ui_session_interface::{InvokeUiSession, Session},
+};
+use hbb_common::{
+    bail,
+    compress::decompress,
+    config::Config,
+    get_time, lazy_static, log,
+    message_proto::*,
+    password_security::{decrypt_vec_or_original, encrypt_vec_or_original},
+    protobuf::Message as _,
+    sodiumoxide::base64,
+    ResultType,
+};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
+
+pub const OPTION_CLIPBOARD_HISTORY_SIZE: &str = "clipboard-history-size";
+pub const OPTION_CLIPBOARD_HISTORY_PERSIST: &str = "clipboard-history-persist";
+
+const DEFAULT_SIZE: usize = 20;
+const MAX_SIZE: usize = 200;
+// The total size of the contents, the oldest entries are dropped beyond it.
+const MAX_BYTES: usize = 64 * 1024 * 1024;
+// The total size of the persisted contents, the oldest entries are not saved beyond it.
+const MAX_PERSIST_BYTES: usize = 8 * 1024 * 1024;
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+const PREVIEW_CHARS: usize = 100;
+
+// By peer ID, handled by `run_saver` in order.
+enum SaveRequest {
+    // Add the persisted entries to the history of this generation, before its entries.
+    Load(String, Arc<Mutex<ClipboardHistory>>, u64, usize),
+    Add(String, Entry, usize),
+    Clear(String),
+}
+
+lazy_static::lazy_static! {
+    static ref SAVER: Mutex<Option<mpsc::Sender<SaveRequest>>> = Default::default();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    id: u64,
+    // Milliseconds since the epoch.
+    time: i64,
+    direction: Direction,
+    clipboards: MultiClipboards,
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        self.clipboards
+            .clipboards
+            .iter()
+            .map(|c| c.content.len())
+            .sum()
+    }
+
+    fn same_content(&self, clipboards: &MultiClipboards) -> bool {
+        self.clipboards.clipboards.len() == clipboards.clipboards.len()
+            && self
+                .clipboards
+                .clipboards
+                .iter()
+                .zip(clipboards.clipboards.iter())
+                .all(|(a, b)| {
+                    a.format == b.format
+                        && a.compress == b.compress
+                        && a.special_name == b.special_name
+                        && a.content == b.content
+                })
+    }
+
+    fn to_json(&self) -> Value {
+        let mut formats = vec![];
+        let mut preview = String::new();
+        for c in self.clipboards.clipboards.iter() {
+            let format = match c.format.enum_value() {
+                Ok(ClipboardFormat::Text) => "text",
+                Ok(ClipboardFormat::Rtf) => "rtf",
+                Ok(ClipboardFormat::Html) => "html",
+                Ok(ClipboardFormat::ImageRgba)
+                | Ok(ClipboardFormat::ImagePng)
+                | Ok(ClipboardFormat::ImageSvg) => "image",
+                Ok(ClipboardFormat::Special) => c.special_name.as_str(),
+                _ => "unknown",
+            };
+            if !formats.contains(&format) {
+                formats.push(format);
+            }
+            if preview.is_empty() && c.format.enum_value() == Ok(ClipboardFormat::Text) {
+                let content = if c.compress {
+                    decompress(&c.content)
+                } else {
+                    c.content.to_vec()
+                };
+                preview = String::from_utf8_lossy(&content)
+                    .chars()
+                    .take(PREVIEW_CHARS)
+                    .collect();
+            }
+        }
+        json!({
+            "id": self.id,
+            "time": self.time,
+            "direction": self.direction,
+            "formats": formats,
+            "size": self.size(),
+            "preview": preview,
+        })
+    }
+}
+
+// The persisted entry, `clipboards` is the base64 of the serialized `MultiClipboards`.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredEntry {
+    time: i64,
+    direction: Direction,
+    clipboards: String,
+}
+
+#[derive(Default)]
+pub struct ClipboardHistory {
+    // Whether the persisted history of the peer has been asked to load.
+    loaded: bool,
+    // Incremented on clear, not to load the persisted history cleared meanwhile.
+    generation: u64,
+    next_id: u64,
+    entries: VecDeque<Entry>,
+}
+
+impl ClipboardHistory {
+    // Returns whether the entry is added, the same content as the newest entry is skipped.
+    fn push(
+        &mut self,
+        direction: Direction,
+        clipboards: MultiClipboards,
+        time: i64,
+        max_size: usize,
+    ) -> bool {
+        if max_size == 0 || clipboards.clipboards.is_empty() {
+            return false;
+        }
+        if let Some(last) = self.entries.back() {
+            if last.same_content(&clipboards) {
+                return false;
+            }
+        }
+        let entry = Entry {
+            id: self.next_id,
+            time,
+            direction,
+            clipboards,
+        };
+        if entry.size() > MAX_BYTES {
+            log::debug!("Skip clipboard history entry of {} bytes", entry.size());
+            return false;
+        }
+        self.next_id += 1;
+        self.entries.push_back(entry);
+        self.truncate(max_size);
+        true
+    }
+
+    fn truncate(&mut self, max_size: usize) {
+        truncate(&mut self.entries, max_size, MAX_BYTES);
+    }
+
+    // Add the older entries before the current ones.
+    fn prepend(&mut self, older: Vec<Entry>, max_size: usize) {
+        for mut e in older.into_iter().rev() {
+            if self.entries.len() >= max_size {
+                break;
+            }
+            e.id = self.next_id;
+            self.next_id += 1;
+            self.entries.push_front(e);
+        }
+        self.truncate(max_size);
+    }
+
+    fn get(&self, id: u64) -> Option<&Entry> {
+        self.entries.iter().find(|e| e.id == id)
+    }
+}
+
+// Drop the oldest entries beyond `max_size` entries or `max_bytes` of contents.
+fn truncate(entries: &mut VecDeque<Entry>, max_size: usize, max_bytes: usize) {
+    let mut bytes: usize = entries.iter().map(|e| e.size()).sum();
+    while entries.len() > max_size || bytes > max_bytes {
+        match entries.pop_front() {
+            Some(e) => bytes -= e.size(),
+            None => break,
+        }
+    }
+}
+
+fn max_size<T: InvokeUiSession>(session: &Session<T>) -> usize {
+    session
+        .lc
+        .read()
+        .unwrap()
+        .get_option(OPTION_CLIPBOARD_HISTORY_SIZE)
+        .parse::<usize>()
+        .map(|s| s.min(MAX_SIZE))
+        .unwrap_or(DEFAULT_SIZE)
+}
+
+fn is_persist<T: InvokeUiSession>(session: &Session<T>) -> bool {
+    session
+        .lc
+        .read()
+        .unwrap()
+        .get_option(OPTION_CLIPBOARD_HISTORY_PERSIST)
+        == "Y"
+}
+
+fn path(id: &str) -> PathBuf {
+    // The peer ID may hold a server address.
+    let name: String = id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    Config::path("clipboard_history").join(name)
+}
+
+fn load(id: &str) -> ResultType<Vec<Entry>> {
+    let path = path(id);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let (data, success, _) = decrypt_vec_or_original(&std::fs::read(path)?, "00");
+    if !success {
+        bail!("Failed to decrypt the clipboard history");
+    }
+    let stored: Vec<StoredEntry> = serde_json::from_slice(&data)?;
+    let mut entries = vec![];
+    for s in stored {
+        let Ok(bytes) = base64::decode(&s.clipboards, base64::Variant::Original) else {
+            continue;
+        };
+        if let Ok(clipboards) = MultiClipboards::parse_from_bytes(&bytes) {
+            entries.push(Entry {
+                id: 0,
+                time: s.time,
+                direction: s.direction,
+                clipboards,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+fn save(id: &str, entries: &VecDeque<Entry>) -> ResultType<()> {
+    let mut stored = vec![];
+    for e in entries.iter() {
+        stored.push(StoredEntry {
+            time: e.time,
+            direction: e.direction,
+            clipboards: base64::encode(e.clipboards.write_to_bytes()?, base64::Variant::Original),
+        });
+    }
+    let path = path(id);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let data = encrypt_vec_or_original(&serde_json::to_vec(&stored)?, "00", usize::MAX);
+    std::fs::write(path, data)?;
+    Ok(())
+}
+
+fn remove(id: &str) {
+    let path = path(id);
+    if path.exists() {
+        if let Err(e) = std::fs::remove_file(path) {
+            log::error!("Failed to remove the clipboard history: {}", e);
+        }
+    }
+}
+
+// The persisted entries to write by peer ID, `None` to remove the file.
+type Pending = HashMap<String, Option<VecDeque<Entry>>>;
+
+fn handle_save_request(req: SaveRequest, pending: &mut Pending) {
+    match req {
+        SaveRequest::Load(id, history, generation, max_size) => match load(&id) {
+            Ok(entries) => {
+                let mut history = history.lock().unwrap();
+                if history.generation == generation {
+                    history.prepend(entries, max_size);
+                }
+            }
+            Err(e) => log::error!("Failed to load the clipboard history: {}", e),
+        },
+        SaveRequest::Add(id, entry, max_size) => {
+            let mut entries = match pending.remove(&id) {
+                Some(Some(entries)) => entries,
+                Some(None) => VecDeque::new(),
+                None => match load(&id) {
+                    Ok(entries) => entries.into(),
+                    Err(e) => {
+                        log::error!("Failed to load the clipboard history: {}", e);
+                        VecDeque::new()
+                    }
+                },
+            };
+            entries.push_back(entry);
+            truncate(&mut entries, max_size, MAX_PERSIST_BYTES);
+            pending.insert(id, Some(entries));
+        }
+        SaveRequest::Clear(id) => {
+            pending.insert(id, None);
+        }
+    }
+}
+
+// Writes the files changed within `SAVE_DELAY` of the first request.
+fn run_saver(rx: mpsc::Receiver<SaveRequest>) {
+    while let Ok(req) = rx.recv() {
+        let mut pending = Pending::new();
+        handle_save_request(req, &mut pending);
+        std::thread::sleep(SAVE_DELAY);
+        for req in rx.try_iter() {
+            handle_save_request(req, &mut pending);
+        }
+        for (id, entries) in pending {
+            match entries {
+                Some(entries) => {
+                    if let Err(e) = save(&id, &entries) {
+                        log::error!("Failed to save the clipboard history: {}", e);
+                    }
+                }
+                None => remove(&id),
+            }
+        }
+    }
+}
+
+fn queue_save(req: SaveRequest) {
+    let mut saver = SAVER.lock().unwrap();
+    let tx = saver.get_or_insert_with(|| {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || run_saver(rx));
+        tx
+    });
+    tx.send(req).ok();
+}
+
+// Asks the saver to load the persisted history once, before the first access.
+fn ensure_loaded<T: InvokeUiSession>(session: &Session<T>, history: &mut ClipboardHistory) {
+    if history.loaded {
+        return;
+    }
+    history.loaded = true;
+    if is_persist(session) {
+        queue_save(SaveRequest::Load(
+            session.get_id(),
+            session.clipboard_history.clone(),
+            history.generation,
+            max_size(session),
+        ));
+    }
+}
+
+/// Add the clipboards received from or sent to the peer.
+pub fn record<T: InvokeUiSession>(
+    session: &Session<T>,
+    direction: Direction,
+    clipboards: MultiClipboards,
+) {
+    let max_size = max_size(session);
+    let mut history = session.clipboard_history.lock().unwrap();
+    ensure_loaded(session, &mut history);
+    if history.push(direction, clipboards, get_time(), max_size) && is_persist(session) {
+        if let Some(entry) = history.entries.back() {
+            queue_save(SaveRequest::Add(session.get_id(), entry.clone(), max_size));
+        }
+    }
+}
+
+/// The entries as a JSON list, oldest first.
+pub fn list<T: InvokeUiSession>(session: &Session<T>) -> String {
+    let mut history = session.clipboard_history.lock().unwrap();
+    ensure_loaded(session, &mut history);
+    let entries: Vec<Value> = history.entries.iter().map(|e| e.to_json()).collect();
+    serde_json::to_string(&entries).unwrap_or_default()
+}
+
+/// Push the clipboards of the entry to the peer, it is recorded as sent again.
+pub fn resend<T: InvokeUiSession>(session: &Session<T>, id: u64) -> ResultType<()> {
+    if session.lc.read().unwrap().disable_clipboard.v
+        || !*session.server_clipboard_enabled.read().unwrap()
+    {
+        bail!("Clipboard is disabled");
+    }
+    let clipboards = {
+        let mut history = session.clipboard_history.lock().unwrap();
+        ensure_loaded(session, &mut history);
+        match history.get(id) {
+            Some(e) => e.clipboards.clone(),
+            None => bail!("No clipboard history entry {}", id),
+        }
+    };
+    #[cfg(not(target_os = "ios"))]
+    {
+        let pi = session.lc.read().unwrap().peer_info.clone();
+        if let Some(pi) = pi.as_ref() {
+            if let Some(msg_out) = crate::clipboard::get_msg_if_not_support_multi_clip(
+                &pi.version,
+                &pi.platform,
+                &clipboards,
+            ) {
+                session.send(Data::Message(msg_out));
+                return Ok(());
+            }
+        }
+    }
+    let mut msg_out = Message::new();
+    msg_out.set_multi_clipboards(clipboards);
+    session.send(Data::Message(msg_out));
+    Ok(())
+}
+
+pub fn clear<T: InvokeUiSession>(session: &Session<T>) {
+    let mut history = session.clipboard_history.lock().unwrap();
+    history.loaded = true;
+    history.generation += 1;
+    history.entries.clear();
+    // Queued, not to be overwritten by a pending save.
+    queue_save(SaveRequest::Clear(session.get_id()));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn text(s: &str) -> MultiClipboards {
+        MultiClipboards {
+            clipboards: vec![Clipboard {
+                content: s.as_bytes().to_vec().into(),
+                format: ClipboardFormat::Text.into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_clipboard_history() {
+        let mut history = ClipboardHistory::default();
+        assert!(!history.push(Direction::Sent, text("a"), 0, 0));
+        assert!(history.push(Direction::Sent, text("a"), 0, 3));
+        assert!(!history.push(Direction::Received, text("a"), 1, 3));
+        assert!(history.push(Direction::Received, text("b"), 2, 3));
+        assert!(history.push(Direction::Sent, text("a"), 3, 3));
+        assert!(history.push(Direction::Sent, text("c"), 4, 3));
+        let ids: Vec<u64> = history.entries.iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert!(history.get(0).is_none());
+        let e = history.get(1).unwrap();
+        assert_eq!(e.direction, Direction::Received);
+        assert_eq!(e.to_json()["preview"], "b");
+        assert_eq!(e.to_json()["formats"], json!(["text"]));
+
+        let big = MultiClipboards {
+            clipboards: vec![Clipboard {
+                content: vec![0u8; MAX_BYTES / 2 + 1].into(),
+                format: ClipboardFormat::ImagePng.into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(history.push(Direction::Received, big.clone(), 5, 3));
+        let mut big2 = big;
+        big2.clipboards[0].content = vec![1u8; MAX_BYTES / 2 + 1].into();
+        assert!(history.push(Direction::Received, big2, 6, 3));
+        assert_eq!(history.entries.len(), 1);
+        assert_eq!(history.entries[0].id, 5);
+
+        let mut entries: VecDeque<Entry> = history.entries.clone();
+        truncate(&mut entries, 3, MAX_PERSIST_BYTES);
+        assert!(entries.is_empty());
+
+        let mut history = ClipboardHistory::default();
+        assert!(history.push(Direction::Sent, text("new"), 3, 3));
+        let older = vec![
+            Entry {
+                id: 0,
+                time: 1,
+                direction: Direction::Received,
+                clipboards: text("x"),
+            },
+            Entry {
+                id: 0,
+                time: 2,
+                direction: Direction::Sent,
+                clipboards: text("y"),
+            },
+            Entry {
+                id: 0,
+                time: 0,
+                direction: Direction::Sent,
+                clipboards: text("z"),
+            },
+        ];
+        history.prepend(older, 3);
+        let times: Vec<i64> = history.entries.iter().map(|e| e.time).collect();
+        assert_eq!(times, vec![2, 0, 3]);
+        let ids: Vec<u64> = history.entries.iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![2, 1, 0]);
+    }
+}
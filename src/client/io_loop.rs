@@ -4,8 +4,8 @@ use crate::clipboard::{update_clipboard, ClipboardSide};
 use crate::{audio_service, clipboard::CLIPBOARD_INTERVAL, ConnInner, CLIENT_SERVER};
 use crate::{
     client::{
-        self, new_voice_call_request, Client, Data, Interface, MediaData, MediaSender,
-        QualityStatus, MILLI1, SEC30,
+        self, clipboard_history, new_voice_call_request, Client, Data, Interface, MediaData,
+        MediaSender, QualityStatus, MILLI1, SEC30,
     },
     common::get_default_sound_input,
     ui_session_interface::{InvokeUiSession, Session},
@@ -563,6 +563,23 @@ impl<T: InvokeUiSession> Remote<T> {
                         }
                         _ => {}
                     },
+                    Some(message::Union::Clipboard(cb)) => {
+                        clipboard_history::record(
+                            &self.handler,
+                            clipboard_history::Direction::Sent,
+                            MultiClipboards {
+                                clipboards: vec![cb.clone()],
+                                ..Default::default()
+                            },
+                        );
+                    }
+                    Some(message::Union::MultiClipboards(mcb)) => {
+                        clipboard_history::record(
+                            &self.handler,
+                            clipboard_history::Direction::Sent,
+                            mcb.clone(),
+                        );
+                    }
                     _ => {}
                 }
                 allow_err!(peer.send(&msg).await);
@@ -1425,6 +1442,14 @@ impl<T: InvokeUiSession> Remote<T> {
                 }
                 Some(message::Union::Clipboard(cb)) => {
                     if !self.handler.lc.read().unwrap().disable_clipboard.v {
+                        clipboard_history::record(
+                            &self.handler,
+                            clipboard_history::Direction::Received,
+                            MultiClipboards {
+                                clipboards: vec![cb.clone()],
+                                ..Default::default()
+                            },
+                        );
                         #[cfg(not(any(target_os = "android", target_os = "ios")))]
                         update_clipboard(vec![cb], ClipboardSide::Client);
                         #[cfg(target_os = "ios")]
@@ -1444,6 +1469,11 @@ impl<T: InvokeUiSession> Remote<T> {
                 }
                 Some(message::Union::MultiClipboards(_mcb)) => {
                     if !self.handler.lc.read().unwrap().disable_clipboard.v {
+                        clipboard_history::record(
+                            &self.handler,
+                            clipboard_history::Direction::Received,
+                            _mcb.clone(),
+                        );
                         #[cfg(not(any(target_os = "android", target_os = "ios")))]
                         update_clipboard(_mcb.clipboards, ClipboardSide::Client);
                         #[cfg(target_os = "android")]
@@ -1910,6 +1940,13 @@ impl<T: InvokeUiSession> Remote<T> {
                 }
                 Some(message::Union::AudioFrame(frame)) => {
                     if !self.handler.lc.read().unwrap().disable_audio.v {
+                        if self.last_record_state {
+                            for (_, v) in self.video_threads.iter() {
+                                v.video_sender
+                                    .send(MediaData::AudioFrame(Box::new(frame.clone())))
+                                    .ok();
+                            }
+                        }
                         self.audio_sender
                             .send(MediaData::AudioFrame(Box::new(frame)))
                             .ok();
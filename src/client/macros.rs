@@ -0,0 +1,579 @@
+// Record the input sent to a peer into a named macro, and replay it into any session.
+//
+// The key events are recorded before the modifier keys are swapped, and replayed through
+// `Session::send_key_event`. The mouse events are recorded as passed to `client::send_mouse`,
+// with the positions relative to the display of the session, and replayed on its current display,
+// scaled if the size differs.
+//
+// The keys of the map and translate modes are key codes of the peer platform, so a macro with
+// such keys is only replayed into a peer of the platform it was recorded on.
+//
+// Pressing Escape locally aborts all the replays.
+
+use crate::{
+    client::send_mouse,
+    common::input::*,
+    ui_session_interface::{InvokeUiSession, Session},
+};
+use hbb_common::{
+    bail, config::Config, log, message_proto::*, protobuf::EnumOrUnknown, ResultType,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+const MACRO_VERSION: u32 = 1;
+const MIN_SPEED: f64 = 0.1;
+const MAX_SPEED: f64 = 10.0;
+const ABORT_KEY: rdev::Key = rdev::Key::Escape;
+
+// Incremented by the abort key, the replays started in an older round stop.
+static ABORT_ROUND: AtomicUsize = AtomicUsize::new(0);
+static REPLAYS: AtomicUsize = AtomicUsize::new(0);
+// The release of the abort key is not sent either.
+static ABORT_KEY_DOWN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct DisplayRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Input {
+    Key {
+        mode: i32,
+        down: bool,
+        #[serde(default)]
+        press: bool,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        modifiers: Vec<i32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        chr: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        control_key: Option<i32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        unicode: Option<u32>,
+    },
+    Mouse {
+        mask: i32,
+        x: i32,
+        y: i32,
+        #[serde(default)]
+        alt: bool,
+        #[serde(default)]
+        ctrl: bool,
+        #[serde(default)]
+        shift: bool,
+        #[serde(default)]
+        command: bool,
+    },
+}
+
+// A key or a mouse button, to release the ones left down by an aborted replay.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Button {
+    Chr(u32),
+    ControlKey(i32),
+    Mouse(i32),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Step {
+    // Milliseconds since the previous step.
+    delay: u64,
+    #[serde(flatten)]
+    input: Input,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Macro {
+    version: u32,
+    // The platform of the peer the macro was recorded on.
+    #[serde(default)]
+    platform: String,
+    // The size of the display the mouse positions are relative to.
+    width: i32,
+    height: i32,
+    steps: Vec<Step>,
+}
+
+#[derive(Default)]
+pub struct MacroState {
+    display: DisplayRect,
+    recording: Option<Recording>,
+    replaying: Option<Arc<AtomicBool>>,
+}
+
+struct Recording {
+    name: String,
+    last: Instant,
+    mac: Macro,
+}
+
+impl Recording {
+    fn push(&mut self, input: Input) {
+        let now = Instant::now();
+        let delay = if self.mac.steps.is_empty() {
+            0
+        } else {
+            now.duration_since(self.last).as_millis() as _
+        };
+        self.last = now;
+        self.mac.steps.push(Step { delay, input });
+    }
+}
+
+#[inline]
+fn is_position(mask: i32) -> bool {
+    matches!(
+        mask & MOUSE_TYPE_MASK,
+        MOUSE_TYPE_MOVE | MOUSE_TYPE_DOWN | MOUSE_TYPE_UP
+    )
+}
+
+#[inline]
+fn scale(v: i32, from: i32, to: i32) -> i32 {
+    if from <= 0 || to <= 0 || from == to {
+        return v;
+    }
+    (v as f64 * to as f64 / from as f64).round() as _
+}
+
+impl Macro {
+    // Whether a key code is specific to the platform of the peer.
+    fn has_platform_keys(&self) -> bool {
+        self.steps.iter().any(|s| match &s.input {
+            Input::Key {
+                mode, chr: Some(_), ..
+            } => *mode != KeyboardMode::Legacy as i32,
+            _ => false,
+        })
+    }
+}
+
+impl Input {
+    fn from_key_event(evt: &KeyEvent) -> Option<Self> {
+        let (mut chr, mut control_key, mut seq, mut unicode) = (None, None, None, None);
+        match &evt.union {
+            Some(key_event::Union::Chr(c)) => chr = Some(*c),
+            Some(key_event::Union::ControlKey(k)) => control_key = Some(k.value()),
+            Some(key_event::Union::Seq(s)) => seq = Some(s.clone()),
+            Some(key_event::Union::Unicode(u)) => unicode = Some(*u),
+            _ => return None,
+        }
+        Some(Input::Key {
+            mode: evt.mode.value(),
+            down: evt.down,
+            press: evt.press,
+            modifiers: evt.modifiers.iter().map(|m| m.value()).collect(),
+            chr,
+            control_key,
+            seq,
+            unicode,
+        })
+    }
+
+    fn to_key_event(&self) -> Option<KeyEvent> {
+        let Input::Key {
+            mode,
+            down,
+            press,
+            modifiers,
+            chr,
+            control_key,
+            seq,
+            unicode,
+        } = self
+        else {
+            return None;
+        };
+        let mut evt = KeyEvent {
+            mode: EnumOrUnknown::from_i32(*mode),
+            down: *down,
+            press: *press,
+            modifiers: modifiers
+                .iter()
+                .map(|m| EnumOrUnknown::from_i32(*m))
+                .collect(),
+            ..Default::default()
+        };
+        evt.union = Some(if let Some(c) = chr {
+            key_event::Union::Chr(*c)
+        } else if let Some(k) = control_key {
+            key_event::Union::ControlKey(EnumOrUnknown::from_i32(*k))
+        } else if let Some(s) = seq {
+            key_event::Union::Seq(s.clone())
+        } else {
+            key_event::Union::Unicode((*unicode)?)
+        });
+        Some(evt)
+    }
+
+    fn button(&self) -> Option<Button> {
+        match self {
+            Input::Key { chr: Some(c), .. } => Some(Button::Chr(*c)),
+            Input::Key {
+                control_key: Some(k),
+                ..
+            } => Some(Button::ControlKey(*k)),
+            Input::Mouse { mask, .. }
+                if matches!(mask & MOUSE_TYPE_MASK, MOUSE_TYPE_DOWN | MOUSE_TYPE_UP) =>
+            {
+                Some(Button::Mouse(mask >> 3))
+            }
+            _ => None,
+        }
+    }
+
+    // The input releasing the key or the mouse button pressed by this one, if any.
+    fn release(&self) -> Option<Self> {
+        match self {
+            Input::Key { down, press, .. } if *down && !*press && self.button().is_some() => {
+                let mut up = self.clone();
+                if let Input::Key { down, .. } = &mut up {
+                    *down = false;
+                }
+                Some(up)
+            }
+            Input::Mouse { mask, .. } if mask & MOUSE_TYPE_MASK == MOUSE_TYPE_DOWN => {
+                let mut up = self.clone();
+                if let Input::Mouse { mask, .. } = &mut up {
+                    *mask = (*mask & !MOUSE_TYPE_MASK) | MOUSE_TYPE_UP;
+                }
+                Some(up)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn check_name(name: &str) -> ResultType<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == ' ')
+    {
+        bail!("Invalid macro name: {}", name);
+    }
+    Ok(())
+}
+
+fn dir() -> PathBuf {
+    Config::path("macros")
+}
+
+fn path(name: &str) -> ResultType<PathBuf> {
+    check_name(name)?;
+    Ok(dir().join(format!("{}.json", name)))
+}
+
+fn load(name: &str) -> ResultType<Macro> {
+    let mac: Macro = serde_json::from_slice(&std::fs::read(path(name)?)?)?;
+    if mac.version > MACRO_VERSION {
+        bail!("Unsupported macro version {}", mac.version);
+    }
+    Ok(mac)
+}
+
+fn save(name: &str, mac: &Macro) -> ResultType<()> {
+    let path = path(name)?;
+    std::fs::create_dir_all(dir())?;
+    std::fs::write(path, serde_json::to_vec_pretty(mac)?)?;
+    Ok(())
+}
+
+/// The names of the saved macros.
+pub fn list() -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(dir()) else {
+        return vec![];
+    };
+    let mut names: Vec<String> = entries
+        .flatten()
+        .filter_map(|e| {
+            let path = e.path();
+            if path.extension()? != "json" {
+                return None;
+            }
+            Some(path.file_stem()?.to_string_lossy().to_string())
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+pub fn remove(name: &str) -> ResultType<()> {
+    std::fs::remove_file(path(name)?)?;
+    Ok(())
+}
+
+pub fn set_display<T: InvokeUiSession>(session: &Session<T>, display: DisplayRect) {
+    session.input_macro.lock().unwrap().display = display;
+}
+
+pub fn start_recording<T: InvokeUiSession>(session: &Session<T>, name: &str) -> ResultType<()> {
+    check_name(name)?;
+    let mut state = session.input_macro.lock().unwrap();
+    if state.replaying.is_some() {
+        bail!("A macro is being replayed");
+    }
+    state.recording = Some(Recording {
+        name: name.to_owned(),
+        last: Instant::now(),
+        mac: Macro {
+            version: MACRO_VERSION,
+            platform: session.peer_platform(),
+            width: state.display.width,
+            height: state.display.height,
+            steps: vec![],
+        },
+    });
+    Ok(())
+}
+
+/// Save the macro being recorded, returns the number of steps.
+pub fn stop_recording<T: InvokeUiSession>(session: &Session<T>) -> ResultType<usize> {
+    let Some(recording) = session.input_macro.lock().unwrap().recording.take() else {
+        bail!("No macro is being recorded");
+    };
+    save(&recording.name, &recording.mac)?;
+    log::info!(
+        "Saved macro {} with {} steps",
+        recording.name,
+        recording.mac.steps.len()
+    );
+    Ok(recording.mac.steps.len())
+}
+
+pub fn record_key<T: InvokeUiSession>(session: &Session<T>, evt: &KeyEvent) {
+    let mut state = session.input_macro.lock().unwrap();
+    if let Some(recording) = state.recording.as_mut() {
+        if let Some(input) = Input::from_key_event(evt) {
+            recording.push(input);
+        }
+    }
+}
+
+pub fn record_mouse<T: InvokeUiSession>(
+    session: &Session<T>,
+    mask: i32,
+    x: i32,
+    y: i32,
+    alt: bool,
+    ctrl: bool,
+    shift: bool,
+    command: bool,
+) {
+    let mut state = session.input_macro.lock().unwrap();
+    let display = state.display;
+    if let Some(recording) = state.recording.as_mut() {
+        let (x, y) = if is_position(mask) {
+            (
+                scale(x - display.x, display.width, recording.mac.width),
+                scale(y - display.y, display.height, recording.mac.height),
+            )
+        } else {
+            (x, y)
+        };
+        recording.push(Input::Mouse {
+            mask,
+            x,
+            y,
+            alt,
+            ctrl,
+            shift,
+            command,
+        });
+    }
+}
+
+fn send<T: InvokeUiSession>(session: &Session<T>, mac: &Macro, input: &Input) {
+    match input {
+        Input::Key { .. } => {
+            if let Some(evt) = input.to_key_event() {
+                session.send_key_event(&evt);
+            }
+        }
+        Input::Mouse {
+            mask,
+            x,
+            y,
+            alt,
+            ctrl,
+            shift,
+            command,
+        } => {
+            let (x, y) = if is_position(*mask) {
+                let display = session.input_macro.lock().unwrap().display;
+                (
+                    display.x + scale(*x, mac.width, display.width),
+                    display.y + scale(*y, mac.height, display.height),
+                )
+            } else {
+                (*x, *y)
+            };
+            send_mouse(*mask, x, y, *alt, *ctrl, *shift, *command, session);
+        }
+    }
+}
+
+/// Replay a saved macro in the background, `speed` 2.0 is twice as fast as recorded.
+pub fn replay<T: InvokeUiSession>(session: &Session<T>, name: &str, speed: f64) -> ResultType<()> {
+    let mac = load(name)?;
+    let platform = session.peer_platform();
+    if mac.platform != platform && mac.has_platform_keys() {
+        bail!(
+            "The macro recorded on {} can not be replayed on {}",
+            mac.platform,
+            platform
+        );
+    }
+    let speed = if speed.is_finite() {
+        speed.clamp(MIN_SPEED, MAX_SPEED)
+    } else {
+        1.0
+    };
+    let abort = Arc::new(AtomicBool::new(false));
+    {
+        let mut state = session.input_macro.lock().unwrap();
+        if state.recording.is_some() {
+            bail!("A macro is being recorded");
+        }
+        if state.replaying.is_some() {
+            bail!("A macro is being replayed");
+        }
+        state.replaying = Some(abort.clone());
+    }
+    let round = ABORT_ROUND.load(Ordering::SeqCst);
+    REPLAYS.fetch_add(1, Ordering::SeqCst);
+    let session = session.clone();
+    let name = name.to_owned();
+    std::thread::spawn(move || {
+        let aborted =
+            || abort.load(Ordering::SeqCst) || ABORT_ROUND.load(Ordering::SeqCst) != round;
+        let mut down: Vec<Input> = vec![];
+        for step in mac.steps.iter() {
+            let deadline =
+                Instant::now() + Duration::from_secs_f64(step.delay as f64 / 1000. / speed);
+            while !aborted() && Instant::now() < deadline {
+                std::thread::sleep((deadline - Instant::now()).min(Duration::from_millis(50)));
+            }
+            if aborted() {
+                log::info!("Aborted macro {}", name);
+                break;
+            }
+            send(&session, &mac, &step.input);
+            if let Some(up) = step.input.release() {
+                down.push(up);
+            } else if let Some(button) = step.input.button() {
+                down.retain(|up| up.button() != Some(button));
+            }
+        }
+        for up in down.iter().rev() {
+            send(&session, &mac, up);
+        }
+        session.input_macro.lock().unwrap().replaying.take();
+        REPLAYS.fetch_sub(1, Ordering::SeqCst);
+    });
+    Ok(())
+}
+
+pub fn abort<T: InvokeUiSession>(session: &Session<T>) {
+    if let Some(abort) = session.input_macro.lock().unwrap().replaying.as_ref() {
+        abort.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Abort the replays on the abort key, returns true if the local key must not be sent.
+pub fn on_local_key(event: &rdev::Event) -> bool {
+    match event.event_type {
+        rdev::EventType::KeyPress(key) if key == ABORT_KEY => {
+            if REPLAYS.load(Ordering::SeqCst) == 0 {
+                return false;
+            }
+            ABORT_ROUND.fetch_add(1, Ordering::SeqCst);
+            ABORT_KEY_DOWN.store(true, Ordering::SeqCst);
+            true
+        }
+        rdev::EventType::KeyRelease(key) if key == ABORT_KEY => {
+            ABORT_KEY_DOWN.swap(false, Ordering::SeqCst)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_macro_steps() {
+        let mut evt = KeyEvent::new();
+        evt.mode = KeyboardMode::Map.into();
+        evt.down = true;
+        evt.set_chr(30);
+        let key = Input::from_key_event(&evt).unwrap();
+        assert_eq!(key.to_key_event(), Some(evt));
+        let mut evt = KeyEvent::new();
+        evt.press = true;
+        evt.set_control_key(ControlKey::Return);
+        evt.modifiers.push(ControlKey::Shift.into());
+        let ret = Input::from_key_event(&evt).unwrap();
+        assert_eq!(ret.to_key_event(), Some(evt));
+        assert_eq!(ret.release(), None);
+
+        let click = Input::Mouse {
+            mask: MOUSE_BUTTON_LEFT << 3 | MOUSE_TYPE_DOWN,
+            x: 10,
+            y: 20,
+            alt: false,
+            ctrl: true,
+            shift: false,
+            command: false,
+        };
+        let Some(Input::Mouse { mask, x, .. }) = click.release() else {
+            panic!("no release");
+        };
+        assert_eq!((mask, x), (MOUSE_BUTTON_LEFT << 3 | MOUSE_TYPE_UP, 10));
+        assert_eq!(click.button(), Some(Button::Mouse(MOUSE_BUTTON_LEFT)));
+
+        let mut mac = Macro {
+            version: MACRO_VERSION,
+            platform: "Windows".to_owned(),
+            width: 1920,
+            height: 1080,
+            steps: vec![
+                Step {
+                    delay: 0,
+                    input: key,
+                },
+                Step {
+                    delay: 120,
+                    input: click,
+                },
+            ],
+        };
+        let json = serde_json::to_string(&mac).unwrap();
+        assert!(json.contains(r#""delay":120,"type":"mouse""#));
+        assert_eq!(serde_json::from_str::<Macro>(&json).unwrap(), mac);
+        assert!(mac.has_platform_keys());
+        mac.steps.remove(0);
+        assert!(!mac.has_platform_keys());
+
+        assert_eq!(scale(960, 1920, 1280), 640);
+        assert_eq!(scale(5, 0, 1280), 5);
+        assert!(check_name("open settings_1").is_ok());
+        assert!(check_name("../x").is_err());
+    }
+}
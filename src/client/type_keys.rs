@@ -0,0 +1,584 @@
+// Type text on the remote side as a paced sequence of key events, for the fields which don't
+// accept pasting, e.g. the login screens or the consoles of virtual machines.
+//
+// The characters are mapped to the keys of the remote keyboard layout, then sent in map mode
+// through `keyboard::event_to_key_events`, as if the keys were pressed locally.
+// The characters missing in the layout are sent as sequences in translate mode.
+// The text may contain `enigo::dsl` tags, e.g. `{ENTER}`, `{TAB}` or `{+CTRL}a{-CTRL}`.
+
+use crate::{
+    client::Interface,
+    keyboard,
+    ui_session_interface::{InvokeUiSession, Session},
+};
+use enigo::dsl::{self, Token};
+use hbb_common::{log, message_proto::*};
+use rdev::{Event, EventType, Key};
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, SystemTime},
+};
+
+/// Per peer option, the delay between two keys in milliseconds.
+pub const OPTION_TYPE_KEYS_DELAY: &str = "type-keys-delay";
+/// Per peer option, the keyboard layout of the remote side: "us", "uk", "de" or "fr".
+pub const OPTION_TYPE_KEYS_LAYOUT: &str = "type-keys-layout";
+
+const DEFAULT_DELAY_MS: u64 = 30;
+const MAX_DELAY_MS: u64 = 1_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Level {
+    Base,
+    Shift,
+    AltGr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Step {
+    Click(Key, Level),
+    Down(Key),
+    Up(Key),
+    // Characters missing in the layout.
+    Text(String),
+}
+
+struct Layout {
+    name: &'static str,
+    // The characters of `LETTER_KEYS`, the others are in `symbols`.
+    letters: &'static str,
+    // The base and shifted characters of the other keys.
+    symbols: &'static [(Key, &'static str)],
+    altgr: &'static [(Key, char)],
+    // The characters of the dead keys, typed with a space after.
+    dead: &'static str,
+}
+
+const LETTER_KEYS: [Key; 26] = [
+    Key::KeyA,
+    Key::KeyB,
+    Key::KeyC,
+    Key::KeyD,
+    Key::KeyE,
+    Key::KeyF,
+    Key::KeyG,
+    Key::KeyH,
+    Key::KeyI,
+    Key::KeyJ,
+    Key::KeyK,
+    Key::KeyL,
+    Key::KeyM,
+    Key::KeyN,
+    Key::KeyO,
+    Key::KeyP,
+    Key::KeyQ,
+    Key::KeyR,
+    Key::KeyS,
+    Key::KeyT,
+    Key::KeyU,
+    Key::KeyV,
+    Key::KeyW,
+    Key::KeyX,
+    Key::KeyY,
+    Key::KeyZ,
+];
+
+const LAYOUTS: &[Layout] = &[
+    Layout {
+        name: "us",
+        letters: "abcdefghijklmnopqrstuvwxyz",
+        symbols: &[
+            (Key::BackQuote, "`~"),
+            (Key::Num1, "1!"),
+            (Key::Num2, "2@"),
+            (Key::Num3, "3#"),
+            (Key::Num4, "4$"),
+            (Key::Num5, "5%"),
+            (Key::Num6, "6^"),
+            (Key::Num7, "7&"),
+            (Key::Num8, "8*"),
+            (Key::Num9, "9("),
+            (Key::Num0, "0)"),
+            (Key::Minus, "-_"),
+            (Key::Equal, "=+"),
+            (Key::LeftBracket, "[{"),
+            (Key::RightBracket, "]}"),
+            (Key::BackSlash, "\\|"),
+            (Key::SemiColon, ";:"),
+            (Key::Quote, "'\""),
+            (Key::Comma, ",<"),
+            (Key::Dot, ".>"),
+            (Key::Slash, "/?"),
+        ],
+        altgr: &[],
+        dead: "",
+    },
+    Layout {
+        name: "uk",
+        letters: "abcdefghijklmnopqrstuvwxyz",
+        symbols: &[
+            (Key::BackQuote, "`¬"),
+            (Key::Num1, "1!"),
+            (Key::Num2, "2\""),
+            (Key::Num3, "3£"),
+            (Key::Num4, "4$"),
+            (Key::Num5, "5%"),
+            (Key::Num6, "6^"),
+            (Key::Num7, "7&"),
+            (Key::Num8, "8*"),
+            (Key::Num9, "9("),
+            (Key::Num0, "0)"),
+            (Key::Minus, "-_"),
+            (Key::Equal, "=+"),
+            (Key::LeftBracket, "[{"),
+            (Key::RightBracket, "]}"),
+            (Key::BackSlash, "#~"),
+            (Key::SemiColon, ";:"),
+            (Key::Quote, "'@"),
+            (Key::IntlBackslash, "\\|"),
+            (Key::Comma, ",<"),
+            (Key::Dot, ".>"),
+            (Key::Slash, "/?"),
+        ],
+        altgr: &[(Key::Num4, '€'), (Key::BackQuote, '¦')],
+        dead: "",
+    },
+    Layout {
+        name: "de",
+        letters: "abcdefghijklmnopqrstuvwxzy",
+        symbols: &[
+            (Key::BackQuote, "^°"),
+            (Key::Num1, "1!"),
+            (Key::Num2, "2\""),
+            (Key::Num3, "3§"),
+            (Key::Num4, "4$"),
+            (Key::Num5, "5%"),
+            (Key::Num6, "6&"),
+            (Key::Num7, "7/"),
+            (Key::Num8, "8("),
+            (Key::Num9, "9)"),
+            (Key::Num0, "0="),
+            (Key::Minus, "ß?"),
+            (Key::Equal, "´`"),
+            (Key::LeftBracket, "üÜ"),
+            (Key::RightBracket, "+*"),
+            (Key::BackSlash, "#'"),
+            (Key::SemiColon, "öÖ"),
+            (Key::Quote, "äÄ"),
+            (Key::IntlBackslash, "<>"),
+            (Key::Comma, ",;"),
+            (Key::Dot, ".:"),
+            (Key::Slash, "-_"),
+        ],
+        altgr: &[
+            (Key::Num2, '²'),
+            (Key::Num3, '³'),
+            (Key::Num7, '{'),
+            (Key::Num8, '['),
+            (Key::Num9, ']'),
+            (Key::Num0, '}'),
+            (Key::Minus, '\\'),
+            (Key::RightBracket, '~'),
+            (Key::IntlBackslash, '|'),
+            (Key::KeyQ, '@'),
+            (Key::KeyE, '€'),
+            (Key::KeyM, 'µ'),
+        ],
+        dead: "^´`",
+    },
+    Layout {
+        name: "fr",
+        // The key of M is in `symbols`.
+        letters: "qbcdefghijkl,noparstuvzxyw",
+        symbols: &[
+            (Key::BackQuote, "²"),
+            (Key::Num1, "&1"),
+            (Key::Num2, "é2"),
+            (Key::Num3, "\"3"),
+            (Key::Num4, "'4"),
+            (Key::Num5, "(5"),
+            (Key::Num6, "-6"),
+            (Key::Num7, "è7"),
+            (Key::Num8, "_8"),
+            (Key::Num9, "ç9"),
+            (Key::Num0, "à0"),
+            (Key::Minus, ")°"),
+            (Key::Equal, "=+"),
+            (Key::LeftBracket, "^¨"),
+            (Key::RightBracket, "$£"),
+            (Key::SemiColon, "mM"),
+            (Key::Quote, "ù%"),
+            (Key::BackSlash, "*µ"),
+            (Key::IntlBackslash, "<>"),
+            (Key::KeyM, ",?"),
+            (Key::Comma, ";."),
+            (Key::Dot, ":/"),
+            (Key::Slash, "!§"),
+        ],
+        altgr: &[
+            (Key::Num2, '~'),
+            (Key::Num3, '#'),
+            (Key::Num4, '{'),
+            (Key::Num5, '['),
+            (Key::Num6, '|'),
+            (Key::Num7, '`'),
+            (Key::Num8, '\\'),
+            (Key::Num0, '@'),
+            (Key::Minus, ']'),
+            (Key::Equal, '}'),
+            (Key::RightBracket, '¤'),
+            (Key::KeyE, '€'),
+        ],
+        dead: "^¨~`",
+    },
+];
+
+// The accented characters typed with a dead key: (accent, base characters, accented characters).
+const COMPOSED: &[(char, &str, &str)] = &[
+    ('^', "aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+    ('¨', "aeiouyAEIOU", "äëïöüÿÄËÏÖÜ"),
+    ('´', "aeiouyAEIOUY", "áéíóúýÁÉÍÓÚÝ"),
+    ('`', "aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+    ('~', "aonAON", "ãõñÃÕÑ"),
+];
+
+impl Layout {
+    fn get(name: &str) -> &'static Layout {
+        LAYOUTS
+            .iter()
+            .find(|l| l.name == name)
+            .unwrap_or(&LAYOUTS[0])
+    }
+
+    fn find(&self, c: char) -> Option<(Key, Level)> {
+        match c {
+            ' ' => return Some((Key::Space, Level::Base)),
+            '\n' => return Some((Key::Return, Level::Base)),
+            '\t' => return Some((Key::Tab, Level::Base)),
+            _ => {}
+        }
+        for (key, chars) in self.symbols {
+            let mut chars = chars.chars();
+            if chars.next() == Some(c) {
+                return Some((*key, Level::Base));
+            }
+            if chars.next() == Some(c) {
+                return Some((*key, Level::Shift));
+            }
+        }
+        for (key, letter) in LETTER_KEYS.iter().zip(self.letters.chars()) {
+            if !letter.is_alphabetic() {
+                continue;
+            }
+            if letter == c {
+                return Some((*key, Level::Base));
+            }
+            if letter.to_uppercase().eq(std::iter::once(c)) {
+                return Some((*key, Level::Shift));
+            }
+        }
+        self.altgr
+            .iter()
+            .find(|(_, a)| *a == c)
+            .map(|(key, _)| (*key, Level::AltGr))
+    }
+
+    fn steps(&self, c: char) -> Option<Vec<Step>> {
+        if let Some((key, level)) = self.find(c) {
+            let mut steps = vec![Step::Click(key, level)];
+            if self.dead.contains(c) {
+                steps.push(Step::Click(Key::Space, Level::Base));
+            }
+            return Some(steps);
+        }
+        let (accent, base) = COMPOSED.iter().find_map(|(accent, bases, composed)| {
+            let i = composed.chars().position(|x| x == c)?;
+            Some((*accent, bases.chars().nth(i)?))
+        })?;
+        if !self.dead.contains(accent) {
+            return None;
+        }
+        let (accent_key, accent_level) = self.find(accent)?;
+        let (key, level) = self.find(base)?;
+        Some(vec![
+            Step::Click(accent_key, accent_level),
+            Step::Click(key, level),
+        ])
+    }
+}
+
+fn dsl_key(key: enigo::Key) -> Option<Key> {
+    Some(match key {
+        enigo::Key::Shift => Key::ShiftLeft,
+        enigo::Key::Control => Key::ControlLeft,
+        enigo::Key::Meta => Key::MetaLeft,
+        enigo::Key::Alt => Key::Alt,
+        enigo::Key::Return => Key::Return,
+        enigo::Key::Tab => Key::Tab,
+        enigo::Key::Escape => Key::Escape,
+        enigo::Key::Backspace => Key::Backspace,
+        enigo::Key::Delete => Key::Delete,
+        enigo::Key::Space => Key::Space,
+        enigo::Key::UpArrow => Key::UpArrow,
+        enigo::Key::DownArrow => Key::DownArrow,
+        enigo::Key::LeftArrow => Key::LeftArrow,
+        enigo::Key::RightArrow => Key::RightArrow,
+        enigo::Key::Home => Key::Home,
+        enigo::Key::End => Key::End,
+        enigo::Key::PageUp => Key::PageUp,
+        enigo::Key::PageDown => Key::PageDown,
+        _ => return None,
+    })
+}
+
+// The text which isn't valid DSL is typed as is.
+fn parse(layout: &Layout, text: &str) -> Vec<Step> {
+    let tokens = dsl::tokenize(text).unwrap_or_else(|_| vec![Token::Sequence(text.to_owned())]);
+    fn push_text(steps: &mut Vec<Step>, s: &str) {
+        if let Some(Step::Text(text)) = steps.last_mut() {
+            text.push_str(s);
+        } else {
+            steps.push(Step::Text(s.to_owned()));
+        }
+    }
+    let mut steps = Vec::new();
+    for token in tokens {
+        match token {
+            Token::Sequence(s) => {
+                // "\r\n" is a single Enter.
+                for c in s.chars().filter(|c| *c != '\r') {
+                    match layout.steps(c) {
+                        Some(mut s) => steps.append(&mut s),
+                        None => push_text(&mut steps, c.encode_utf8(&mut [0; 4])),
+                    }
+                }
+            }
+            Token::Unicode(s) => push_text(&mut steps, &s),
+            Token::KeyDown(key) => {
+                if let Some(key) = dsl_key(key) {
+                    steps.push(Step::Down(key));
+                }
+            }
+            Token::KeyUp(key) => {
+                if let Some(key) = dsl_key(key) {
+                    if steps.last() == Some(&Step::Down(key)) {
+                        steps.pop();
+                        steps.push(Step::Click(key, Level::Base));
+                    } else {
+                        steps.push(Step::Up(key));
+                    }
+                }
+            }
+        }
+    }
+    steps
+}
+
+fn rdev_event(key: Key, down: bool) -> Event {
+    #[cfg(target_os = "windows")]
+    let (platform_code, position_code) = (
+        rdev::win_code_from_key(key).unwrap_or(0),
+        rdev::win_scancode_from_key(key).unwrap_or(0),
+    );
+    #[cfg(not(target_os = "windows"))]
+    let (platform_code, position_code) = {
+        let code = rdev::code_from_key(key).unwrap_or(0);
+        (code, code)
+    };
+    Event {
+        time: SystemTime::now(),
+        unicode: None,
+        platform_code: platform_code as _,
+        position_code: position_code as _,
+        event_type: if down {
+            EventType::KeyPress(key)
+        } else {
+            EventType::KeyRelease(key)
+        },
+        usb_hid: 0,
+        #[cfg(any(target_os = "windows", target_os = "macos"))]
+        extra_data: 0,
+    }
+}
+
+// Lock modes are sent as off, the levels of the layout assume Caps Lock off.
+fn send_key<T: InvokeUiSession>(session: &Session<T>, peer: &str, key: Key, down: bool) {
+    let event = rdev_event(key, down);
+    for key_event in
+        keyboard::event_to_key_events(peer.to_owned(), &event, KeyboardMode::Map, Some(0))
+    {
+        session.send_key_event(&key_event);
+    }
+}
+
+// A click in map mode, which the keyboard hooks never send, so that the peer can refuse it.
+fn click_key<T: InvokeUiSession>(session: &Session<T>, peer: &str, key: Key) {
+    let event = rdev_event(key, true);
+    for mut key_event in
+        keyboard::event_to_key_events(peer.to_owned(), &event, KeyboardMode::Map, Some(0))
+    {
+        key_event.press = true;
+        session.send_key_event(&key_event);
+    }
+    // Only to keep the state of the local keys.
+    keyboard::event_to_key_events(
+        peer.to_owned(),
+        &rdev_event(key, false),
+        KeyboardMode::Map,
+        Some(0),
+    );
+}
+
+fn send_text<T: InvokeUiSession>(session: &Session<T>, text: String) {
+    let mut key_event = KeyEvent::new();
+    key_event.mode = KeyboardMode::Translate.into();
+    key_event.down = true;
+    key_event.press = true;
+    key_event.set_seq(text);
+    session.send_key_event(&key_event);
+}
+
+fn is_refused_by_peer<T: InvokeUiSession>(session: &Session<T>) -> bool {
+    let Some(pi) = session.lc.read().unwrap().peer_info.clone() else {
+        return false;
+    };
+    serde_json::from_str::<serde_json::Value>(&pi.platform_additions)
+        .ok()
+        .and_then(|v| v.get("type_keystrokes").and_then(|v| v.as_bool()))
+        == Some(false)
+}
+
+/// Type `text` in the background, one key every `OPTION_TYPE_KEYS_DELAY` milliseconds.
+pub fn type_text<T: InvokeUiSession>(session: &Session<T>, text: &str) {
+    if is_refused_by_peer(session) {
+        session.msgbox(
+            "custom-nocancel",
+            "Type text",
+            "type-keystrokes-refused-tip",
+            "",
+        );
+        return;
+    }
+    if session.typing.swap(true, Ordering::SeqCst) {
+        log::info!("Already typing text");
+        return;
+    }
+    let layout = Layout::get(&session.get_option(OPTION_TYPE_KEYS_LAYOUT.to_owned()));
+    let delay = session
+        .get_option(OPTION_TYPE_KEYS_DELAY.to_owned())
+        .parse()
+        .unwrap_or(DEFAULT_DELAY_MS)
+        .min(MAX_DELAY_MS);
+    let steps = parse(layout, text);
+    let session = session.clone();
+    std::thread::spawn(move || {
+        let peer = session.peer_platform().to_lowercase();
+        let mut down_keys = Vec::new();
+        for step in steps {
+            if !session.typing.load(Ordering::SeqCst) {
+                break;
+            }
+            match step {
+                Step::Click(key, level) => {
+                    let modifier = match level {
+                        Level::Base => None,
+                        Level::Shift => Some(Key::ShiftLeft),
+                        Level::AltGr => Some(Key::AltGr),
+                    };
+                    if let Some(modifier) = modifier {
+                        send_key(&session, &peer, modifier, true);
+                    }
+                    click_key(&session, &peer, key);
+                    if let Some(modifier) = modifier {
+                        send_key(&session, &peer, modifier, false);
+                    }
+                }
+                Step::Down(key) => {
+                    send_key(&session, &peer, key, true);
+                    down_keys.push(key);
+                }
+                Step::Up(key) => {
+                    send_key(&session, &peer, key, false);
+                    down_keys.retain(|k| *k != key);
+                }
+                Step::Text(text) => send_text(&session, text),
+            }
+            std::thread::sleep(Duration::from_millis(delay));
+        }
+        for key in down_keys {
+            send_key(&session, &peer, key, false);
+        }
+        session.typing.store(false, Ordering::SeqCst);
+    });
+}
+
+#[inline]
+pub fn cancel_typing<T: InvokeUiSession>(session: &Session<T>) {
+    session.typing.store(false, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let us = Layout::get("us");
+        assert_eq!(
+            parse(us, "aB!\r\n"),
+            vec![
+                Step::Click(Key::KeyA, Level::Base),
+                Step::Click(Key::KeyB, Level::Shift),
+                Step::Click(Key::Num1, Level::Shift),
+                Step::Click(Key::Return, Level::Base),
+            ]
+        );
+        assert_eq!(
+            parse(us, "é€{TAB}{+CTRL}a{-CTRL}"),
+            vec![
+                Step::Text("é€".to_owned()),
+                Step::Click(Key::Tab, Level::Base),
+                Step::Down(Key::ControlLeft),
+                Step::Click(Key::KeyA, Level::Base),
+                Step::Up(Key::ControlLeft),
+            ]
+        );
+        // Not DSL.
+        assert_eq!(
+            parse(us, "{x}"),
+            vec![
+                Step::Click(Key::LeftBracket, Level::Shift),
+                Step::Click(Key::KeyX, Level::Base),
+                Step::Click(Key::RightBracket, Level::Shift),
+            ]
+        );
+
+        let de = Layout::get("de");
+        assert_eq!(
+            parse(de, "zY@^"),
+            vec![
+                Step::Click(Key::KeyY, Level::Base),
+                Step::Click(Key::KeyZ, Level::Shift),
+                Step::Click(Key::KeyQ, Level::AltGr),
+                Step::Click(Key::BackQuote, Level::Base),
+                Step::Click(Key::Space, Level::Base),
+            ]
+        );
+        let fr = Layout::get("fr");
+        assert_eq!(
+            parse(fr, "aMê,"),
+            vec![
+                Step::Click(Key::KeyQ, Level::Base),
+                Step::Click(Key::SemiColon, Level::Shift),
+                Step::Click(Key::LeftBracket, Level::Base),
+                Step::Click(Key::KeyE, Level::Base),
+                Step::Click(Key::KeyM, Level::Base),
+            ]
+        );
+        // Unknown layouts are US.
+        assert_eq!(
+            parse(Layout::get("xx"), "y"),
+            vec![Step::Click(Key::KeyY, Level::Base)]
+        );
+    }
+}
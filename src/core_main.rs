@@ -626,6 +626,12 @@ pub fn core_main() -> Option<Vec<String>> {
             #[cfg(feature = "hwcodec")]
             crate::ipc::hwcodec_process();
             return None;
+        } else if args[0] == "--cli-files" {
+            std::process::exit(crate::cli_files::run(&args[1..]));
+        } else if args[0] == "--exec" {
+            std::process::exit(crate::cli_exec::run(&args[1..]));
+        } else if args[0] == "--recording" {
+            std::process::exit(crate::cli_record::run(&args[1..]));
         } else if args[0] == "--terminal-helper" {
             // Terminal helper process - runs as user to create ConPTY
             // This is needed because ConPTY has compatibility issues with CreateProcessAsUserW
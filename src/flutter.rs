@@ -1549,6 +1549,11 @@ pub mod connection_manager {
             self.push_event("update_voice_call_state", &[("client", &client_json)]);
         }
 
+        fn update_recording_banner(&self, client: &crate::ui_cm_interface::Client) {
+            let client_json = serde_json::to_string(&client).unwrap_or("".into());
+            self.push_event("update_recording_banner", &[("client", &client_json)]);
+        }
+
         fn file_transfer_log(&self, action: &str, log: &str) {
             self.push_event("cm_file_transfer_log", &[(action, log)]);
         }
@@ -705,6 +705,88 @@ pub fn session_input_string(session_id: SessionID, value: String) {
     }
 }
 
+pub fn session_type_text(_session_id: SessionID, _text: String) {
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    if let Some(session) = sessions::get_session_by_session_id(&_session_id) {
+        session.type_text(&_text);
+    }
+}
+
+pub fn session_cancel_typing(_session_id: SessionID) {
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    if let Some(session) = sessions::get_session_by_session_id(&_session_id) {
+        session.cancel_typing();
+    }
+}
+
+// Returns the error, empty on success.
+pub fn session_start_macro_recording(session_id: SessionID, name: String) -> String {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        if let Err(e) = session.start_macro_recording(&name) {
+            return e.to_string();
+        }
+    }
+    "".to_owned()
+}
+
+// Returns the error, empty on success.
+pub fn session_stop_macro_recording(session_id: SessionID) -> String {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        if let Err(e) = session.stop_macro_recording() {
+            return e.to_string();
+        }
+    }
+    "".to_owned()
+}
+
+// Returns the error, empty on success.
+pub fn session_play_macro(session_id: SessionID, name: String, speed: f64) -> String {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        if let Err(e) = session.play_macro(&name, speed) {
+            return e.to_string();
+        }
+    }
+    "".to_owned()
+}
+
+pub fn session_abort_macro(session_id: SessionID) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.abort_macro();
+    }
+}
+
+pub fn main_get_macros() -> String {
+    serde_json::to_string(&crate::client::macros::list()).unwrap_or_default()
+}
+
+pub fn main_remove_macro(name: String) {
+    allow_err!(crate::client::macros::remove(&name));
+}
+
+pub fn session_get_clipboard_history(session_id: SessionID) -> String {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.get_clipboard_history()
+    } else {
+        "".to_owned()
+    }
+}
+
+// Returns the error, empty on success.
+pub fn session_resend_clipboard_history(session_id: SessionID, id: u64) -> String {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        if let Err(e) = session.resend_clipboard_history(id) {
+            return e.to_string();
+        }
+    }
+    "".to_owned()
+}
+
+pub fn session_clear_clipboard_history(session_id: SessionID) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.clear_clipboard_history();
+    }
+}
+
 // chat_client_mode
 pub fn session_send_chat(session_id: SessionID, text: String) {
     if let Some(session) = sessions::get_session_by_session_id(&session_id) {
@@ -6,6 +6,7 @@ use scrap::record::RecordState;
 use serde::Serialize;
 use serde_json::Map;
 use std::{
+    collections::HashSet,
     fs::File,
     io::{prelude::*, SeekFrom},
     sync::{mpsc::Receiver, Arc, Mutex},
@@ -18,12 +19,18 @@ const SHOULD_SEND_SIZE: u64 = 1024 * 1024;
 
 lazy_static::lazy_static! {
     static ref ENABLE: Arc<Mutex<bool>> = Default::default();
+    // Files not uploaded completely yet.
+    static ref PENDING: Mutex<HashSet<String>> = Default::default();
 }
 
 pub fn is_enable() -> bool {
     ENABLE.lock().unwrap().clone()
 }
 
+pub fn is_pending(filepath: &str) -> bool {
+    PENDING.lock().unwrap().contains(filepath)
+}
+
 pub fn run(rx: Receiver<RecordState>) {
     std::thread::spawn(move || {
         let api_server = crate::get_api_server(
@@ -74,6 +81,7 @@ pub fn run(rx: Receiver<RecordState>) {
                 }
             } {
                 uploader.running = false;
+                PENDING.lock().unwrap().remove(&uploader.filepath);
                 log::error!("upload stop: {}", e);
             }
         }
@@ -118,6 +126,8 @@ impl RecordUploader {
         match std::path::PathBuf::from(&filepath).file_name() {
             Some(filename) => match filename.to_owned().into_string() {
                 Ok(filename) => {
+                    PENDING.lock().unwrap().remove(&self.filepath);
+                    PENDING.lock().unwrap().insert(filepath.clone());
                     self.filename = filename.clone();
                     self.filepath = filepath.clone();
                     self.upload_size = 0;
@@ -170,6 +180,8 @@ impl RecordUploader {
                 }
                 Err(e) => bail!(e.to_string()),
             },
+            // An encrypted mp4 is written when the recording is finished.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
             Err(e) => bail!(e.to_string()),
         }
     }
@@ -192,6 +204,7 @@ impl RecordUploader {
                             buf,
                         )?;
                         log::info!("upload success, file: {}", self.filename);
+                        PENDING.lock().unwrap().remove(&self.filepath);
                         Ok(())
                     }
                     Err(e) => bail!(e.to_string()),
@@ -206,6 +219,7 @@ impl RecordUploader {
             &[("type", "remove"), ("file", &self.filename)],
             Bytes::new(),
         )?;
+        PENDING.lock().unwrap().remove(&self.filepath);
         Ok(())
     }
 }
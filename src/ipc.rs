@@ -296,6 +296,8 @@ pub enum Data {
     #[cfg(windows)]
     SyncWinCpuUsage(Option<f64>),
     FileTransferLog((String, String)),
+    // The session is recorded by the record policy, shown to the local user in the CM.
+    RecordingBanner(bool),
     #[cfg(windows)]
     ControlledSessionCount(usize),
     CmErr(String),
@@ -130,7 +130,7 @@ pub mod client {
 
     pub fn process_event(keyboard_mode: &str, event: &Event, lock_modes: Option<i32>) {
         let keyboard_mode = get_keyboard_mode_enum(keyboard_mode);
-        if is_long_press(&event) {
+        if is_long_press(&event) || crate::client::macros::on_local_key(event) {
             return;
         }
         let peer = get_peer_platform().to_lowercase();
@@ -146,7 +146,7 @@ pub mod client {
         session: &Session<T>,
     ) {
         let keyboard_mode = get_keyboard_mode_enum(keyboard_mode);
-        if is_long_press(&event) {
+        if is_long_press(&event) || crate::client::macros::on_local_key(event) {
             return;
         }
         let peer = session.peer_platform().to_lowercase();
@@ -594,6 +594,14 @@ pub fn is_modifier_code(evt: &KeyEvent) -> bool {
     }
 }
 
+/// Whether the event is a key of the text typed by `client::type_keys`.
+/// The keyboard hooks only send clicks in legacy mode. Typed text sent as down and up pairs
+/// is not recognized, see `OPTION_ENABLE_TYPE_KEYSTROKES`.
+#[inline]
+pub fn is_typed_key_event(evt: &KeyEvent) -> bool {
+    evt.press && evt.mode.enum_value_or(KeyboardMode::Legacy) != KeyboardMode::Legacy
+}
+
 #[inline]
 pub fn is_numpad_rdev_key(key: &rdev::Key) -> bool {
     matches!(
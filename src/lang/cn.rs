@@ -736,5 +736,15 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("rel-mouse-lock-failed-tip", "无法锁定鼠标，相对鼠标模式已禁用"),
         ("rel-mouse-exit-{}-tip", "按下 {} 退出"),
         ("rel-mouse-permission-lost-tip", "键盘权限被撤销。相对鼠标模式已被禁用。"),
+        ("session-recorded-tip", ""),
+        ("type-keystrokes-refused-tip", ""),
+        ("sensitive-app-input-blocked-title-tip", ""),
+        ("sensitive-app-input-blocked-text-tip", ""),
+        ("Record macro", ""),
+        ("Stop macro recording", ""),
+        ("Play macro", ""),
+        ("Macro name", ""),
+        ("No macros", ""),
+        ("macro-abort-tip", ""),
     ].iter().cloned().collect();
 }
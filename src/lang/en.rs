@@ -267,5 +267,10 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("rel-mouse-lock-failed-tip", "Failed to lock cursor. Relative Mouse Mode has been disabled."),
         ("rel-mouse-exit-{}-tip", "Press {} to exit."),
         ("rel-mouse-permission-lost-tip", "Keyboard permission was revoked. Relative Mouse Mode has been disabled."),
+        ("session-recorded-tip", "This session is recorded."),
+        ("type-keystrokes-refused-tip", "Typing text as keystrokes is disabled on the remote side."),
+        ("sensitive-app-input-blocked-title-tip", "Remote input blocked"),
+        ("sensitive-app-input-blocked-text-tip", "The remote side blocks the keyboard and mouse while a sensitive application is focused."),
+        ("macro-abort-tip", "Press Escape to abort the replay."),
     ].iter().cloned().collect();
 }
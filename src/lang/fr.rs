@@ -736,5 +736,15 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("rel-mouse-lock-failed-tip", ""),
         ("rel-mouse-exit-{}-tip", ""),
         ("rel-mouse-permission-lost-tip", ""),
+        ("session-recorded-tip", ""),
+        ("type-keystrokes-refused-tip", ""),
+        ("sensitive-app-input-blocked-title-tip", ""),
+        ("sensitive-app-input-blocked-text-tip", ""),
+        ("Record macro", ""),
+        ("Stop macro recording", ""),
+        ("Play macro", ""),
+        ("Macro name", ""),
+        ("No macros", ""),
+        ("macro-abort-tip", ""),
     ].iter().cloned().collect();
 }
@@ -40,6 +40,14 @@ use common::*;
 mod auth_2fa;
 #[cfg(feature = "cli")]
 pub mod cli;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+mod cli_exec;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+mod cli_files;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+mod cli_record;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+mod cli_session;
 #[cfg(not(target_os = "ios"))]
 mod clipboard;
 #[cfg(not(any(target_os = "android", target_os = "ios", feature = "cli")))]
@@ -68,6 +76,8 @@ mod ui_session_interface;
 
 mod hbbs_http;
 
+pub mod record_retention;
+
 #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
 pub mod clipboard_file;
 
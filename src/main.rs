@@ -41,7 +41,8 @@ fn main() {
     use clap::App;
     use hbb_common::log;
     let args = format!(
-        "-p, --port-forward=[PORT-FORWARD-OPTIONS] 'Format: remote-id:local-port:remote-port[:remote-host]'
+        "-p, --port-forward=[PORT-FORWARD-OPTIONS] 'Format: remote-id:local-port:remote-port[:remote-host], remote-id:local-port:remote-port:udp:remote-host or remote-id:local-port:socks5'
+        -R, --reverse-port-forward=[REVERSE-PORT-FORWARD-OPTIONS] 'Format: remote-id:remote-listen-port:local-port[:local-host]'
         -c, --connect=[REMOTE_ID] 'test only'
         -k, --key=[KEY] ''
        -s, --server=[] 'Start server'",
@@ -68,14 +69,22 @@ fn main() {
             return;
         }
         let mut remote_port = 0;
+        let mut remote_host = "localhost".to_owned();
         if let Ok(v) = options[2].parse::<i32>() {
             remote_port = v;
+        } else if options[2].eq_ignore_ascii_case("socks5") {
+            if options.len() > 3 {
+                log::error!("Wrong port-forward options");
+                return;
+            }
+            remote_host = options[2].clone();
         } else {
             log::error!("Wrong remote-port");
             return;
         }
-        let mut remote_host = "localhost".to_owned();
-        if options.len() > 3 {
+        if options.len() > 4 && options[3] == "udp" {
+            remote_host = format!("udp:{}", options[4]);
+        } else if options.len() > 3 {
             remote_host = options[3].clone();
         }
         common::test_rendezvous_server();
@@ -90,6 +99,40 @@ fn main() {
             key,
             token,
         );
+    } else if let Some(p) = matches.value_of("reverse-port-forward") {
+        let options: Vec<String> = p.split(":").map(|x| x.to_owned()).collect();
+        if options.len() < 3 {
+            log::error!("Wrong reverse-port-forward options");
+            return;
+        }
+        let remote_port = if let Ok(v) = options[1].parse::<i32>() {
+            v
+        } else {
+            log::error!("Wrong remote-listen-port");
+            return;
+        };
+        let local_port = if let Ok(v) = options[2].parse::<i32>() {
+            v
+        } else {
+            log::error!("Wrong local-port");
+            return;
+        };
+        let mut local_host = "localhost".to_owned();
+        if options.len() > 3 {
+            local_host = options[3].clone();
+        }
+        common::test_rendezvous_server();
+        common::test_nat_type();
+        let key = matches.value_of("key").unwrap_or("").to_owned();
+        let token = LocalConfig::get_option("access_token");
+        cli::start_one_reverse_port_forward(
+            options[0].clone(),
+            remote_port,
+            local_host,
+            local_port,
+            key,
+            token,
+        );
     } else if let Some(p) = matches.value_of("connect") {
         common::test_rendezvous_server();
         common::test_nat_type();
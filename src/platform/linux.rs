@@ -113,6 +113,14 @@ extern "C" {
         width: *mut c_int,
         height: *mut c_int,
     ) -> c_int;
+    fn xdo_get_pid_window(xdo: Xdo, window: *mut c_void) -> c_int;
+    fn xdo_get_window_name(
+        xdo: Xdo,
+        window: *mut c_void,
+        name_ret: *mut *mut u8,
+        name_len_ret: *mut c_int,
+        name_type: *mut c_int,
+    ) -> c_int;
 }
 
 #[link(name = "X11")]
@@ -271,6 +279,75 @@ pub fn get_focused_display(displays: Vec<DisplayInfo>) -> Option<usize> {
     res
 }
 
+/// The process name and the title of the focused window, X11 only.
+pub fn get_focused_window() -> Option<(String, String)> {
+    let mut res = None;
+    XDO.with(|xdo| {
+        if let Ok(xdo) = xdo.try_borrow_mut() {
+            if xdo.is_null() {
+                return;
+            }
+            let mut window: *mut c_void = std::ptr::null_mut();
+            unsafe {
+                if xdo_get_active_window(*xdo, &mut window) != 0 {
+                    return;
+                }
+                let pid = xdo_get_pid_window(*xdo, window);
+                let process = if pid > 0 {
+                    get_process_name(pid as _).unwrap_or_default()
+                } else {
+                    String::new()
+                };
+                let mut name: *mut u8 = std::ptr::null_mut();
+                let mut len: c_int = 0;
+                let mut name_type: c_int = 0;
+                let mut title = String::new();
+                if xdo_get_window_name(*xdo, window, &mut name, &mut len, &mut name_type) == 0
+                    && !name.is_null()
+                {
+                    title = String::from_utf8_lossy(std::slice::from_raw_parts(
+                        name,
+                        len.max(0) as usize,
+                    ))
+                    .to_string();
+                    XFree(name as _);
+                }
+                res = Some((process, title));
+            }
+        }
+    });
+    res
+}
+
+/// The executable name of the process, `comm` is truncated to 15 characters so the command
+/// line is preferred.
+pub fn get_process_name(pid: u32) -> Option<String> {
+    if let Ok(cmdline) = std::fs::read(format!("/proc/{}/cmdline", pid)) {
+        let arg0 = cmdline.split(|c| *c == 0).next().unwrap_or_default();
+        // Some processes rewrite their command line into a single argument.
+        let arg0 = String::from_utf8_lossy(arg0);
+        let arg0 = arg0.split(' ').next().unwrap_or_default();
+        if let Some(name) = arg0.rsplit('/').next().filter(|n| !n.is_empty()) {
+            return Some(name.to_owned());
+        }
+    }
+    std::fs::read_to_string(format!("/proc/{}/comm", pid))
+        .ok()
+        .map(|s| s.trim_end().to_owned())
+}
+
+/// The names of all the running processes.
+pub fn get_process_names() -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+        .filter_map(get_process_name)
+        .collect()
+}
+
 pub fn get_cursor() -> ResultType<Option<u64>> {
     let mut res = None;
     DISPLAY.with(|conn| {
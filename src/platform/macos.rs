@@ -509,6 +509,60 @@ fn unsafe_get_focused_display(displays: Vec<DisplayInfo>) -> Option<usize> {
     }
 }
 
+/// The executable name of the frontmost application and the title of its frontmost window.
+pub fn get_focused_window() -> Option<(String, String)> {
+    autoreleasepool(|| unsafe_get_focused_window())
+}
+
+fn unsafe_get_focused_window() -> Option<(String, String)> {
+    let to_string = |s: id| unsafe {
+        if s.is_null() {
+            return String::new();
+        }
+        let s = NSString::UTF8String(s);
+        if s.is_null() {
+            return String::new();
+        }
+        std::ffi::CStr::from_ptr(s).to_string_lossy().to_string()
+    };
+    unsafe {
+        let workspace: id = msg_send![class!(NSWorkspace), sharedWorkspace];
+        let app: id = msg_send![workspace, frontmostApplication];
+        if app.is_null() {
+            return None;
+        }
+        let url: id = msg_send![app, executableURL];
+        let process = if url.is_null() {
+            String::new()
+        } else {
+            to_string(msg_send![url, lastPathComponent])
+        };
+        let app_pid: i32 = msg_send![app, processIdentifier];
+        let mut title = String::new();
+        // The windows on screen are ordered from front to back.
+        let window_list =
+            CGWindowListCopyWindowInfo(kCGWindowListOptionOnScreenOnly, kCGNullWindowID);
+        for i in 0..CFArrayGetCount(window_list) {
+            let w: id = CFArrayGetValueAtIndex(window_list, i) as _;
+            let pid: id = msg_send![w, valueForKey: kCGWindowOwnerPID as id];
+            if pid.is_null() {
+                continue;
+            }
+            let pid: i32 = msg_send![pid, intValue];
+            if pid != app_pid {
+                continue;
+            }
+            let name: id = msg_send![w, valueForKey: kCGWindowName as id];
+            if !name.is_null() {
+                title = to_string(name);
+                break;
+            }
+        }
+        CFRelease(window_list as _);
+        Some((process, title))
+    }
+}
+
 pub fn get_cursor() -> ResultType<Option<u64>> {
     autoreleasepool(|| unsafe_get_cursor())
 }
@@ -114,6 +114,62 @@ pub fn get_focused_display(displays: Vec<DisplayInfo>) -> Option<usize> {
     }
 }
 
+/// The executable name and the title of the foreground window.
+pub fn get_focused_window() -> Option<(String, String)> {
+    use hbb_common::platform::windows::RAIIHandle;
+    unsafe {
+        let hwnd = GetForegroundWindow();
+        if hwnd.is_null() {
+            return None;
+        }
+        let mut title = [0u16; 512];
+        let len = GetWindowTextW(hwnd, title.as_mut_ptr(), title.len() as _);
+        let title = String::from_utf16_lossy(&title[..len.max(0) as usize]);
+        let mut process_id: DWORD = 0;
+        GetWindowThreadProcessId(hwnd, &mut process_id);
+        let mut process = String::new();
+        if process_id != 0 {
+            let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, process_id);
+            if handle != NULL {
+                let _handle = RAIIHandle(handle);
+                let mut path = [0u16; MAX_PATH];
+                let mut size = path.len() as DWORD;
+                if QueryFullProcessImageNameW(handle, 0, path.as_mut_ptr(), &mut size) != FALSE {
+                    let path = String::from_utf16_lossy(&path[..size as usize]);
+                    process = path.rsplit('\\').next().unwrap_or_default().to_owned();
+                }
+            }
+        }
+        Some((process, title))
+    }
+}
+
+/// Whether the input desktop is the default one, not e.g. the secure desktop of UAC.
+pub fn is_input_desktop_default() -> bool {
+    unsafe {
+        let desktop = OpenInputDesktop(0, FALSE, DESKTOP_READOBJECTS);
+        if desktop.is_null() {
+            // The secure desktop can only be opened by the system account.
+            return false;
+        }
+        let mut name = [0u16; 256];
+        let mut size: DWORD = 0;
+        let res = GetUserObjectInformationW(
+            desktop as _,
+            UOI_NAME as _,
+            name.as_mut_ptr() as _,
+            (name.len() * 2) as _,
+            &mut size,
+        );
+        CloseDesktop(desktop);
+        if res == FALSE {
+            return false;
+        }
+        let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+        String::from_utf16_lossy(&name[..len]).eq_ignore_ascii_case("Default")
+    }
+}
+
 pub fn get_cursor_pos() -> Option<(i32, i32)> {
     unsafe {
         let mut out = mem::MaybeUninit::<POINT>::uninit();
@@ -1,20 +1,65 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
 
-use crate::client::*;
+use crate::{
+    client::*,
+    server::tunnel::{
+        decode_datagram, encode_datagram, udp_host, Mux, MuxOpenReply, MUX_HOST,
+        PLATFORM_ADDITION_MUX, UDP_FLOW_TIMEOUT,
+    },
+};
 use hbb_common::{
     allow_err, bail,
-    config::READ_TIMEOUT,
+    config::{LocalConfig, READ_TIMEOUT},
     futures::{SinkExt, StreamExt},
     log,
     message_proto::*,
     protobuf::Message as _,
     rendezvous_proto::ConnType,
     tcp, timeout,
-    tokio::{self, net::TcpStream, sync::mpsc},
+    tokio::{
+        self,
+        io::AsyncWriteExt,
+        net::{TcpStream, UdpSocket},
+        sync::mpsc,
+        time::{Duration, Instant},
+    },
     tokio_util::codec::{BytesCodec, Framed},
     ResultType, Stream,
 };
 
+/// Remote host placeholder of a dynamic (`ssh -D` style) forward.
+/// The local listener speaks SOCKS5 and every CONNECT request picks its own destination.
+pub const SOCKS5_REMOTE_HOST: &str = "socks5";
+
+#[inline]
+pub fn is_socks5(remote_host: &str, remote_port: i32) -> bool {
+    remote_port == 0 && remote_host.eq_ignore_ascii_case(SOCKS5_REMOTE_HOST)
+}
+
+/// Carry the connections of a forward over one session instead of logging in once per connection,
+/// on by default and only used once the peer info of a first session says the peer supports it.
+/// "N" always logs in once per connection.
+const OPTION_PORT_FORWARD_MUX: &str = "port-forward-mux";
+
+#[inline]
+fn is_mux_enabled() -> bool {
+    LocalConfig::get_option(OPTION_PORT_FORWARD_MUX) != "N"
+}
+
+fn is_mux_supported(pi: &PeerInfo) -> bool {
+    serde_json::from_str::<HashMap<String, serde_json::Value>>(&pi.platform_additions)
+        .ok()
+        .and_then(|m| m.get(PLATFORM_ADDITION_MUX).and_then(|v| v.as_bool()))
+        .unwrap_or(false)
+}
+
+/// Destination, accepted socket and the optional reply once the destination is connected.
+type MuxOpen = (String, TcpStream, Option<MuxOpenReply>);
+
 fn run_rdp(port: u16) {
     std::process::Command::new("cmdkey")
         .arg("/delete:localhost")
@@ -42,6 +87,68 @@ fn run_rdp(port: u16) {
         .ok();
 }
 
+/// What a connection accepted by a forward listener needs to log in to the peer on its own.
+#[derive(Clone)]
+struct Forwarder<T: Interface> {
+    id: String,
+    password: String,
+    interface: T,
+    key: String,
+    token: String,
+    /// Whether the peer supports multiplexing, `None` until a session logged in.
+    mux_supported: Arc<Mutex<Option<bool>>>,
+}
+
+impl<T: Interface> Forwarder<T> {
+    async fn login(
+        &self,
+        ui_receiver: &mut mpsc::UnboundedReceiver<Data>,
+        forward: Option<&mut Framed<TcpStream, BytesCodec>>,
+        port_forward: (String, i32),
+        is_rdp: bool,
+    ) -> ResultType<Option<Stream>> {
+        let res = connect_and_login(
+            &self.id,
+            &self.password,
+            ui_receiver,
+            self.interface.clone(),
+            forward,
+            &self.key,
+            &self.token,
+            port_forward,
+            is_rdp,
+        )
+        .await?;
+        Ok(res.map(|(stream, pi)| {
+            *self.mux_supported.lock().unwrap() = Some(is_mux_supported(&pi));
+            stream
+        }))
+    }
+
+    #[inline]
+    fn is_mux_supported(&self) -> bool {
+        *self.mux_supported.lock().unwrap() == Some(true)
+    }
+}
+
+/// The logins in progress of a forward, each one runs in its own task with its own copy of the UI data.
+#[derive(Default)]
+struct PendingLogins(Vec<mpsc::UnboundedSender<Data>>);
+
+impl PendingLogins {
+    fn add(&mut self) -> mpsc::UnboundedReceiver<Data> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        // The logins done or dropped, not to grow with every connection of a long-lived forward.
+        self.0.retain(|tx| !tx.is_closed());
+        self.0.push(tx);
+        rx
+    }
+
+    fn dispatch(&mut self, data: Data) {
+        self.0.retain(|tx| tx.send(data.clone()).is_ok());
+    }
+}
+
 pub async fn listen(
     id: String,
     password: String,
@@ -50,51 +157,296 @@ pub async fn listen(
     ui_receiver: mpsc::UnboundedReceiver<Data>,
     key: &str,
     token: &str,
-    lc: Arc<RwLock<LoginConfigHandler>>,
     remote_host: String,
     remote_port: i32,
 ) -> ResultType<()> {
-    let listener = tcp::new_listener(format!("0.0.0.0:{}", port), true).await?;
+    let forwarder = Forwarder {
+        id,
+        password,
+        interface,
+        key: key.to_owned(),
+        token: token.to_owned(),
+        mux_supported: Default::default(),
+    };
+    if udp_host(&remote_host).is_some() {
+        return listen_udp(forwarder, port, ui_receiver, remote_host, remote_port).await;
+    }
+    let is_socks5 = is_socks5(&remote_host, remote_port);
+    // The SOCKS5 listener has no authentication, it must not be an open proxy for the local network.
+    let bind_host = if is_socks5 { "127.0.0.1" } else { "0.0.0.0" };
+    let listener = tcp::new_listener(format!("{}:{}", bind_host, port), true).await?;
     let addr = listener.local_addr()?;
     log::info!("listening on port {:?}", addr);
     let is_rdp = port == 0;
     if is_rdp {
         run_rdp(addr.port());
     }
+    if is_socks5 {
+        log::info!("dynamic port forwarding (SOCKS5) on {:?}", addr);
+    }
+    let use_mux = !is_rdp && is_mux_enabled();
+    let mut mux_tx: Option<mpsc::UnboundedSender<MuxOpen>> = None;
+    let mut pending = PendingLogins::default();
     let mut ui_receiver = ui_receiver;
     loop {
         tokio::select! {
             Ok((forward, addr)) = listener.accept() => {
                 log::info!("new connection from {:?}", addr);
-                lc.write().unwrap().port_forward = (remote_host.clone(), remote_port);
-                let id = id.clone();
-                let password = password.clone();
-                let mut forward = Framed::new(forward, BytesCodec::new());
-                match connect_and_login(&id, &password, &mut ui_receiver, interface.clone(), &mut forward, key, token, is_rdp).await {
-                    Ok(Some(stream)) => {
-                        let interface = interface.clone();
-                        tokio::spawn(async move {
-                            if let Err(err) = run_forward(forward, stream).await {
-                                interface.msgbox("error", "Error", &err.to_string(), "");
-                            }
-                            log::info!("connection from {:?} closed", addr);
-                       });
-                    }
-                    Err(err) => {
-                        interface.on_establish_connection_error(err.to_string());
-                    }
-                    _ => {}
+                // Connections before the first login are forwarded on their own sessions,
+                // so a peer without multiplexing still works.
+                let use_mux = use_mux && forwarder.is_mux_supported();
+                if !use_mux {
+                    mux_tx = None;
+                } else if mux_tx.as_ref().map(|tx| tx.is_closed()).unwrap_or(true) {
+                    mux_tx = Some(spawn_mux(forwarder.clone(), pending.add()));
                 }
+                let dest = if is_socks5 {
+                    None
+                } else {
+                    Some((remote_host.clone(), remote_port))
+                };
+                tokio::spawn(forward_conn(
+                    forwarder.clone(),
+                    forward,
+                    addr,
+                    pending.add(),
+                    dest,
+                    is_rdp,
+                    mux_tx.clone(),
+                ));
             }
             d = ui_receiver.recv() => {
                 match d {
-                    Some(Data::Close) => {
+                    Some(Data::Close) | None => {
                         break;
                     }
                     Some(Data::NewRDP) => {
                         println!("receive run_rdp from ui_receiver");
                         run_rdp(addr.port());
                     }
+                    Some(d) => {
+                        pending.dispatch(d);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Log in the multiplexed session of a forward and run it, the opened streams queue up until it is ready.
+fn spawn_mux<T: Interface>(
+    forwarder: Forwarder<T>,
+    mut ui_receiver: mpsc::UnboundedReceiver<Data>,
+) -> mpsc::UnboundedSender<MuxOpen> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<MuxOpen>();
+    tokio::spawn(async move {
+        let interface = forwarder.interface.clone();
+        match forwarder
+            .login(&mut ui_receiver, None, (MUX_HOST.to_owned(), 0), false)
+            .await
+        {
+            Ok(Some(stream)) => {
+                drop(ui_receiver);
+                if let Err(err) = run_mux(stream, rx).await {
+                    interface.msgbox("error", "Error", &err.to_string(), "");
+                }
+                log::info!("multiplexed port forwarding session closed");
+                return;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                interface.on_establish_connection_error(err.to_string());
+            }
+        }
+        rx.close();
+        while let Some((_, mut socket, reply)) = rx.recv().await {
+            if let Some(reply) = reply {
+                socket.write_all(&reply.err).await.ok();
+            }
+        }
+    });
+    tx
+}
+
+/// Handle one accepted connection, `dest` is `None` if the destination comes from a SOCKS5 request.
+async fn forward_conn<T: Interface>(
+    forwarder: Forwarder<T>,
+    mut forward: TcpStream,
+    addr: SocketAddr,
+    mut ui_receiver: mpsc::UnboundedReceiver<Data>,
+    dest: Option<(String, i32)>,
+    is_rdp: bool,
+    mux_tx: Option<mpsc::UnboundedSender<MuxOpen>>,
+) {
+    let is_socks5 = dest.is_none();
+    let (dest_host, dest_port) = match dest {
+        Some(dest) => dest,
+        None => match timeout(READ_TIMEOUT, socks5::handshake(&mut forward)).await {
+            Ok(Ok((host, port))) => {
+                log::info!("SOCKS5 CONNECT {}:{} from {:?}", host, port, addr);
+                (host, port)
+            }
+            Ok(Err(err)) => {
+                log::warn!("SOCKS5 handshake with {:?} failed: {}", addr, err);
+                return;
+            }
+            Err(_) => {
+                log::warn!("SOCKS5 handshake with {:?} timed out", addr);
+                return;
+            }
+        },
+    };
+    if let Some(mux_tx) = mux_tx {
+        let reply = if is_socks5 {
+            Some(MuxOpenReply {
+                ok: socks5::reply(socks5::REP_SUCCEEDED),
+                err: socks5::reply(socks5::REP_CONNECTION_REFUSED),
+            })
+        } else {
+            None
+        };
+        if let Err(err) = mux_tx.send((format!("{}:{}", dest_host, dest_port), forward, reply)) {
+            // The multiplexed session is already gone.
+            let (_, mut forward, reply) = err.0;
+            if let Some(reply) = reply {
+                forward.write_all(&reply.err).await.ok();
+            }
+        }
+        return;
+    }
+    let interface = forwarder.interface.clone();
+    let mut forward = Framed::new(forward, BytesCodec::new());
+    match forwarder
+        .login(
+            &mut ui_receiver,
+            Some(&mut forward),
+            (dest_host, dest_port),
+            is_rdp,
+        )
+        .await
+    {
+        Ok(Some(stream)) => {
+            drop(ui_receiver);
+            if is_socks5 {
+                if let Err(err) = forward.send(socks5::reply(socks5::REP_SUCCEEDED)).await {
+                    log::warn!("Failed to send SOCKS5 reply to {:?}: {}", addr, err);
+                    return;
+                }
+            }
+            if let Err(err) = run_forward(forward, stream).await {
+                interface.msgbox("error", "Error", &err.to_string(), "");
+            }
+            log::info!("connection from {:?} closed", addr);
+        }
+        Err(err) => {
+            if is_socks5 {
+                // One unreachable destination must not tear down the whole dynamic forward.
+                log::error!("SOCKS5 connection from {:?} failed: {}", addr, err);
+                forward
+                    .send(socks5::reply(socks5::REP_GENERAL_FAILURE))
+                    .await
+                    .ok();
+            } else {
+                interface.on_establish_connection_error(err.to_string());
+            }
+        }
+        _ => {
+            if is_socks5 {
+                forward
+                    .send(socks5::reply(socks5::REP_CONNECTION_REFUSED))
+                    .await
+                    .ok();
+            }
+        }
+    }
+}
+
+/// UDP forwarding: all datagrams go through one session, each source address of the local socket
+/// is a flow, and the controlled side keeps one socket per flow to send the replies back.
+async fn listen_udp<T: Interface>(
+    forwarder: Forwarder<T>,
+    port: i32,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+    remote_host: String,
+    remote_port: i32,
+) -> ResultType<()> {
+    let socket = UdpSocket::bind(format!("0.0.0.0:{}", port)).await?;
+    log::info!("udp listening on port {:?}", socket.local_addr()?);
+    let mut ui_receiver = ui_receiver;
+    let mut stream = match forwarder
+        .login(&mut ui_receiver, None, (remote_host, remote_port), false)
+        .await
+    {
+        Ok(Some(stream)) => stream,
+        Ok(None) => return Ok(()),
+        Err(err) => {
+            forwarder
+                .interface
+                .on_establish_connection_error(err.to_string());
+            return Ok(());
+        }
+    };
+    log::info!("new udp port forwarding session started");
+    let mut flows: HashMap<SocketAddr, (u32, Instant)> = HashMap::new();
+    let mut flow_addrs: HashMap<u32, SocketAddr> = HashMap::new();
+    let mut next_flow: u32 = 0;
+    let mut buf = vec![0u8; 65536];
+    let mut timer = tokio::time::interval(Duration::from_secs(10));
+    loop {
+        tokio::select! {
+            res = socket.recv_from(&mut buf) => {
+                let (n, addr) = match res {
+                    Ok(v) => v,
+                    Err(err) => {
+                        log::debug!("udp recv: {}", err);
+                        continue;
+                    }
+                };
+                let flow = match flows.get_mut(&addr) {
+                    Some((flow, last_active)) => {
+                        *last_active = Instant::now();
+                        *flow
+                    }
+                    None => {
+                        next_flow = next_flow.wrapping_add(1);
+                        log::debug!("new udp flow {} from {:?}", next_flow, addr);
+                        flows.insert(addr, (next_flow, Instant::now()));
+                        flow_addrs.insert(next_flow, addr);
+                        next_flow
+                    }
+                };
+                stream.send_bytes(encode_datagram(flow, &buf[..n])).await?;
+            }
+            res = stream.next() => {
+                if let Some(Ok(bytes)) = res {
+                    if let Some((flow, payload)) = decode_datagram(&bytes) {
+                        if let Some(addr) = flow_addrs.get(&flow) {
+                            allow_err!(socket.send_to(payload, addr).await);
+                            if let Some((_, last_active)) = flows.get_mut(addr) {
+                                *last_active = Instant::now();
+                            }
+                        }
+                    }
+                } else {
+                    bail!("Reset by the peer");
+                }
+            }
+            _ = timer.tick() => {
+                flows.retain(|addr, (flow, last_active)| {
+                    let alive = last_active.elapsed() < UDP_FLOW_TIMEOUT;
+                    if !alive {
+                        log::debug!("udp flow {} from {:?} timed out", flow, addr);
+                        flow_addrs.remove(flow);
+                    }
+                    alive
+                });
+            }
+            d = ui_receiver.recv() => {
+                match d {
+                    Some(Data::Close) | None => {
+                        break;
+                    }
                     _ => {}
                 }
             }
@@ -103,16 +455,169 @@ pub async fn listen(
     Ok(())
 }
 
+/// Sessions kept logged in and waiting for the controlled side to accept a reverse forward connection.
+/// More than one, so the remote listener stays bound while a consumed session is being replaced.
+const REVERSE_IDLE_SESSIONS: usize = 2;
+
+enum ReverseSession {
+    /// A waiting session accepted a connection, another one has to take its place.
+    Consumed,
+    /// The session failed to log in or was dropped by the peer, `None` if the login was cancelled.
+    Failed(Option<String>),
+}
+
+/// Reverse (remote-to-local) forwarding: the controlled side listens on `remote_port` of its loopback,
+/// and every connection accepted there is tunnelled to `local_host:local_port` dialed from here.
+pub async fn listen_reverse(
+    id: String,
+    password: String,
+    remote_port: i32,
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+    local_host: String,
+    local_port: i32,
+) -> ResultType<()> {
+    if remote_port <= 0 || local_port <= 0 {
+        bail!("Invalid port");
+    }
+    let local_host = if local_host.is_empty() {
+        "localhost".to_owned()
+    } else {
+        local_host
+    };
+    let local_addr = format!("{}:{}", local_host, local_port);
+    log::info!(
+        "reverse port forwarding from remote 127.0.0.1:{} to {}",
+        remote_port,
+        local_addr
+    );
+    let forwarder = Forwarder {
+        id,
+        password,
+        interface,
+        key: key.to_owned(),
+        token: token.to_owned(),
+        mux_supported: Default::default(),
+    };
+    let (tx_session, mut rx_session) = mpsc::unbounded_channel::<ReverseSession>();
+    let mut idle = 0;
+    let mut pending = PendingLogins::default();
+    let mut ui_receiver = ui_receiver;
+    loop {
+        while idle < REVERSE_IDLE_SESSIONS {
+            idle += 1;
+            let forwarder = forwarder.clone();
+            let mut ui_receiver = pending.add();
+            let local_addr = local_addr.clone();
+            let tx_session = tx_session.clone();
+            tokio::spawn(async move {
+                let port_forward = (crate::server::tunnel::REVERSE_HOST.to_owned(), remote_port);
+                match forwarder
+                    .login(&mut ui_receiver, None, port_forward, false)
+                    .await
+                {
+                    Ok(Some(stream)) => {
+                        drop(ui_receiver);
+                        if let Err(err) = run_reverse(stream, &local_addr, tx_session).await {
+                            forwarder
+                                .interface
+                                .msgbox("error", "Error", &err.to_string(), "");
+                        }
+                    }
+                    Ok(None) => {
+                        tx_session.send(ReverseSession::Failed(None)).ok();
+                    }
+                    Err(err) => {
+                        tx_session
+                            .send(ReverseSession::Failed(Some(err.to_string())))
+                            .ok();
+                    }
+                }
+            });
+        }
+        tokio::select! {
+            Some(session) = rx_session.recv() => {
+                match session {
+                    ReverseSession::Consumed => {
+                        idle -= 1;
+                    }
+                    ReverseSession::Failed(err) => {
+                        if let Some(err) = err {
+                            forwarder.interface.on_establish_connection_error(err);
+                        }
+                        break;
+                    }
+                }
+            }
+            d = ui_receiver.recv() => {
+                match d {
+                    Some(Data::Close) | None => {
+                        break;
+                    }
+                    Some(d) => {
+                        pending.dispatch(d);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_reverse(
+    mut stream: Stream,
+    local_addr: &str,
+    tx_session: mpsc::UnboundedSender<ReverseSession>,
+) -> ResultType<()> {
+    // The controlled side sends a (maybe empty) frame once it accepted a connection,
+    // empty frames from here keep the waiting session from timing out.
+    let mut keep_alive = tokio::time::interval(Duration::from_secs(30));
+    let first = loop {
+        tokio::select! {
+            res = stream.next() => break res,
+            _ = keep_alive.tick() => {
+                allow_err!(stream.send_bytes(bytes::Bytes::new()).await);
+            }
+        }
+    };
+    let first = match first {
+        Some(Ok(bytes)) => {
+            tx_session.send(ReverseSession::Consumed).ok();
+            bytes
+        }
+        _ => {
+            tx_session
+                .send(ReverseSession::Failed(Some("Reset by the peer".to_owned())))
+                .ok();
+            return Ok(());
+        }
+    };
+    let forward = match timeout(READ_TIMEOUT, TcpStream::connect(local_addr)).await {
+        Ok(Ok(forward)) => forward,
+        Ok(Err(err)) => bail!("Failed to connect to {}: {}", local_addr, err),
+        Err(_) => bail!("Timeout connecting to {}", local_addr),
+    };
+    log::info!("new reverse port forwarding connection to {}", local_addr);
+    let mut forward = Framed::new(forward, BytesCodec::new());
+    if !first.is_empty() {
+        forward.send(first).await?;
+    }
+    run_forward(forward, stream).await
+}
+
 async fn connect_and_login(
     id: &str,
     password: &str,
     ui_receiver: &mut mpsc::UnboundedReceiver<Data>,
     interface: impl Interface,
-    forward: &mut Framed<TcpStream, BytesCodec>,
+    mut forward: Option<&mut Framed<TcpStream, BytesCodec>>,
     key: &str,
     token: &str,
+    port_forward: (String, i32),
     is_rdp: bool,
-) -> ResultType<Option<Stream>> {
+) -> ResultType<Option<(Stream, PeerInfo)>> {
     let conn_type = if is_rdp {
         ConnType::RDP
     } else {
@@ -126,7 +631,7 @@ async fn connect_and_login(
 
     let _keep_it = hc_connection(feedback, rendezvous_server, token).await;
 
-    loop {
+    let pi = loop {
         tokio::select! {
             res = timeout(READ_TIMEOUT, stream.next()) => match res {
                 Err(_) => {
@@ -140,6 +645,9 @@ async fn connect_and_login(
                     let msg_in = Message::parse_from_bytes(&bytes)?;
                     match msg_in.union {
                         Some(message::Union::Hash(hash)) => {
+                            // Other connections of the same forward log in concurrently with their own destinations,
+                            // the login message is built from `lc` right away.
+                            interface.get_lch().write().unwrap().port_forward = port_forward.clone();
                             interface.handle_hash(password, hash, &mut stream).await;
                         }
                         Some(message::Union::LoginResponse(lr)) => match lr.union {
@@ -149,8 +657,8 @@ async fn connect_and_login(
                                 }
                             }
                             Some(login_response::Union::PeerInfo(pi)) => {
-                                interface.handle_peer_info(pi);
-                                break;
+                                interface.handle_peer_info(pi.clone());
+                                break pi;
                             }
                             _ => {}
                         }
@@ -170,15 +678,24 @@ async fn connect_and_login(
             d = ui_receiver.recv() => {
                 match d {
                     Some(Data::Login((os_username, os_password, password, remember))) => {
+                        interface.get_lch().write().unwrap().port_forward = port_forward.clone();
                         interface.handle_login_from_ui(os_username, os_password, password, remember, &mut stream).await;
                     }
                     Some(Data::Message(msg)) => {
                         allow_err!(stream.send(&msg).await);
                     }
-                    _ => {}
+                    Some(_) => {}
+                    None => {
+                        return Ok(None);
+                    }
                 }
             },
-            res = forward.next() => {
+            res = async {
+                match forward.as_mut() {
+                    Some(forward) => forward.next().await,
+                    None => std::future::pending().await,
+                }
+            } => {
                 if let Some(Ok(bytes)) = res {
                     buffer.extend(bytes);
                 } else {
@@ -186,12 +703,48 @@ async fn connect_and_login(
                 }
             },
         }
-    }
+    };
     stream.set_raw();
     if !buffer.is_empty() {
         allow_err!(stream.send_bytes(buffer.into()).await);
     }
-    Ok(Some(stream))
+    Ok(Some((stream, pi)))
+}
+
+async fn run_mux(
+    mut stream: Stream,
+    mut rx_open: mpsc::UnboundedReceiver<MuxOpen>,
+) -> ResultType<()> {
+    log::info!("new multiplexed port forwarding session started");
+    let mut mux = Mux::new(false);
+    let mut listening = true;
+    loop {
+        tokio::select! {
+            res = rx_open.recv(), if listening => {
+                if let Some((dest, socket, reply)) = res {
+                    stream.send_bytes(mux.open(dest, socket, reply)).await?;
+                } else {
+                    listening = false;
+                }
+            }
+            Some(frame) = mux.next_frame() => {
+                stream.send_bytes(frame).await?;
+            }
+            res = stream.next() => {
+                if let Some(Ok(bytes)) = res {
+                    mux.handle(&bytes);
+                } else {
+                    bail!("Reset by the peer");
+                }
+            }
+            // Recheck whether the remaining streams are gone after the listener is closed.
+            _ = tokio::time::sleep(Duration::from_secs(1)), if !listening => {}
+        }
+        if !listening && mux.is_empty() {
+            break;
+        }
+    }
+    Ok(())
 }
 
 async fn run_forward(forward: Framed<TcpStream, BytesCodec>, stream: Stream) -> ResultType<()> {
@@ -218,3 +771,176 @@ async fn run_forward(forward: Framed<TcpStream, BytesCodec>, stream: Stream) ->
     }
     Ok(())
 }
+
+/// Minimal SOCKS5 server side (RFC 1928), no authentication and CONNECT only.
+mod socks5 {
+    use bytes::Bytes;
+    use hbb_common::{
+        bail,
+        tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        ResultType,
+    };
+
+    const VERSION: u8 = 0x05;
+    const METHOD_NO_AUTH: u8 = 0x00;
+    const METHOD_NOT_ACCEPTABLE: u8 = 0xFF;
+    const CMD_CONNECT: u8 = 0x01;
+    const ATYP_IPV4: u8 = 0x01;
+    const ATYP_DOMAIN: u8 = 0x03;
+    const ATYP_IPV6: u8 = 0x04;
+
+    pub const REP_SUCCEEDED: u8 = 0x00;
+    pub const REP_GENERAL_FAILURE: u8 = 0x01;
+    pub const REP_CONNECTION_REFUSED: u8 = 0x05;
+    const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+    const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+    /// Reply with a zero bound address, the real one is on the controlled side and irrelevant here.
+    pub fn reply(rep: u8) -> Bytes {
+        Bytes::from(vec![VERSION, rep, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+    }
+
+    /// Negotiate the method and read the CONNECT request, returning the requested destination.
+    /// The final reply is sent by the caller once the peer session is established.
+    pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+        stream: &mut S,
+    ) -> ResultType<(String, i32)> {
+        let mut head = [0u8; 2];
+        stream.read_exact(&mut head).await?;
+        if head[0] != VERSION {
+            bail!("unsupported SOCKS version {}", head[0]);
+        }
+        let mut methods = vec![0u8; head[1] as usize];
+        stream.read_exact(&mut methods).await?;
+        if !methods.contains(&METHOD_NO_AUTH) {
+            stream
+                .write_all(&[VERSION, METHOD_NOT_ACCEPTABLE])
+                .await
+                .ok();
+            bail!("no acceptable SOCKS5 authentication method");
+        }
+        stream.write_all(&[VERSION, METHOD_NO_AUTH]).await?;
+
+        let mut req = [0u8; 4];
+        stream.read_exact(&mut req).await?;
+        if req[0] != VERSION {
+            bail!("unsupported SOCKS version {}", req[0]);
+        }
+        if req[1] != CMD_CONNECT {
+            stream
+                .write_all(&reply(REP_COMMAND_NOT_SUPPORTED))
+                .await
+                .ok();
+            bail!("unsupported SOCKS5 command {}", req[1]);
+        }
+        let host = match req[3] {
+            ATYP_IPV4 => {
+                let mut ip = [0u8; 4];
+                stream.read_exact(&mut ip).await?;
+                std::net::Ipv4Addr::from(ip).to_string()
+            }
+            ATYP_IPV6 => {
+                let mut ip = [0u8; 16];
+                stream.read_exact(&mut ip).await?;
+                format!("[{}]", std::net::Ipv6Addr::from(ip))
+            }
+            ATYP_DOMAIN => {
+                let len = stream.read_u8().await? as usize;
+                let mut name = vec![0u8; len];
+                stream.read_exact(&mut name).await?;
+                String::from_utf8(name)?
+            }
+            atyp => {
+                stream
+                    .write_all(&reply(REP_ADDRESS_TYPE_NOT_SUPPORTED))
+                    .await
+                    .ok();
+                bail!("unsupported SOCKS5 address type {}", atyp);
+            }
+        };
+        let port = stream.read_u16().await?;
+        if host.is_empty() || port == 0 {
+            stream.write_all(&reply(REP_GENERAL_FAILURE)).await.ok();
+            bail!("invalid SOCKS5 destination {}:{}", host, port);
+        }
+        Ok((host, port as _))
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use hbb_common::tokio::{self, io::duplex};
+
+        // Runs the handshake on the input of the client, returns its result and the server replies.
+        async fn run(input: &[u8]) -> (ResultType<(String, i32)>, Vec<u8>) {
+            let (mut client, mut server) = duplex(1024);
+            client.write_all(input).await.unwrap();
+            let res = handshake(&mut server).await;
+            drop(server);
+            let mut out = vec![];
+            client.read_to_end(&mut out).await.unwrap();
+            (res, out)
+        }
+
+        #[tokio::test]
+        async fn test_connect() {
+            let (res, out) = run(&[5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0x1f, 0x90]).await;
+            assert_eq!(res.unwrap(), ("127.0.0.1".to_owned(), 8080));
+            assert_eq!(out, vec![5, 0]);
+
+            let mut input = vec![5, 2, 2, 0, 5, 1, 0, 3, 11];
+            input.extend_from_slice(b"example.com");
+            input.extend_from_slice(&[0, 80]);
+            let (res, out) = run(&input).await;
+            assert_eq!(res.unwrap(), ("example.com".to_owned(), 80));
+            assert_eq!(out, vec![5, 0]);
+
+            let mut input = vec![5, 1, 0, 5, 1, 0, 4];
+            input.extend_from_slice(&std::net::Ipv6Addr::LOCALHOST.octets());
+            input.extend_from_slice(&[1, 187]);
+            let (res, _) = run(&input).await;
+            assert_eq!(res.unwrap(), ("[::1]".to_owned(), 443));
+        }
+
+        #[tokio::test]
+        async fn test_refused() {
+            // Username/password authentication only.
+            let (res, out) = run(&[5, 1, 2]).await;
+            assert!(res.is_err());
+            assert_eq!(out, vec![5, METHOD_NOT_ACCEPTABLE]);
+
+            let (res, _) = run(&[4, 1, 0]).await;
+            assert!(res.is_err());
+
+            // BIND
+            let (res, out) = run(&[5, 1, 0, 5, 2, 0, 1, 127, 0, 0, 1, 0, 80]).await;
+            assert!(res.is_err());
+            assert_eq!(out[2..], reply(REP_COMMAND_NOT_SUPPORTED)[..]);
+
+            let (res, out) = run(&[5, 1, 0, 5, 1, 0, 9]).await;
+            assert!(res.is_err());
+            assert_eq!(out[2..], reply(REP_ADDRESS_TYPE_NOT_SUPPORTED)[..]);
+
+            let (res, _) = run(&[5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0, 0]).await;
+            assert!(res.is_err());
+        }
+
+        #[tokio::test]
+        async fn test_truncated() {
+            let inputs: [&[u8]; 5] = [
+                &[],
+                &[5, 2, 0],
+                &[5, 1, 0, 5, 1],
+                &[5, 1, 0, 5, 1, 0, 1, 127, 0],
+                &[5, 1, 0, 5, 1, 0, 3, 11, b'e', b'x'],
+            ];
+            for input in inputs {
+                let (mut client, mut server) = duplex(1024);
+                client.write_all(input).await.unwrap();
+                // The client closes its side, the reads hit the end of the stream.
+                drop(client);
+                assert!(handshake(&mut server).await.is_err());
+            }
+        }
+    }
+}
@@ -0,0 +1,350 @@
+// Retention of the files in the record directory.
+//
+// When a recording starts and every `PRUNE_INTERVAL`, the oldest recordings are removed until
+// none is older than `record-max-age-days` and there are at most `record-max-files` files of at
+// most `record-max-size` MB in total. Files still being written or waiting for `record_upload`
+// are kept. A recording is skipped if less than `record-min-free-space` MB is free afterwards.
+//
+// A recording is counted and removed as one with its sidecars: the session events and the
+// encrypted files share the name of their video, and a session manifest groups the videos it lists.
+//
+// Used by both sides, incoming recordings are audited by the caller in `server`.
+
+use hbb_common::{config::Config, lazy_static, log, sysinfo::Disks, ResultType};
+use scrap::{
+    record::{EVENTS_EXTENSION, MANIFEST_EXTENSION},
+    record_crypt,
+};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const OPTION_RECORD_MAX_AGE_DAYS: &str = "record-max-age-days";
+pub const OPTION_RECORD_MAX_FILES: &str = "record-max-files";
+pub const OPTION_RECORD_MAX_SIZE: &str = "record-max-size";
+pub const OPTION_RECORD_MIN_FREE_SPACE: &str = "record-min-free-space";
+
+const DEFAULT_MIN_FREE_SPACE_MB: u64 = 500;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Recordings of other processes are not known here, a recent write means in use.
+const IN_USE_MODIFIED_WITHIN: Duration = Duration::from_secs(60);
+const MB: u64 = 1024 * 1024;
+
+lazy_static::lazy_static! {
+    // Directories pruned periodically, the thread is started with the first one.
+    static ref DIRS: Mutex<HashSet<String>> = Default::default();
+}
+
+#[derive(Debug, Default)]
+struct Limits {
+    max_age: Option<Duration>,
+    max_files: Option<usize>,
+    max_size: Option<u64>,
+}
+
+impl Limits {
+    fn get() -> Self {
+        let get = |key: &str| {
+            Config::get_option(key)
+                .trim()
+                .parse::<u64>()
+                .ok()
+                .filter(|v| *v > 0)
+        };
+        Self {
+            max_age: get(OPTION_RECORD_MAX_AGE_DAYS).map(|d| Duration::from_secs(d * 24 * 60 * 60)),
+            max_files: get(OPTION_RECORD_MAX_FILES).map(|n| n as usize),
+            max_size: get(OPTION_RECORD_MAX_SIZE).map(|mb| mb * MB),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.max_age.is_none() && self.max_files.is_none() && self.max_size.is_none()
+    }
+}
+
+#[derive(Debug)]
+struct RecordFile {
+    name: String,
+    path: PathBuf,
+    modified: SystemTime,
+    size: u64,
+    in_use: bool,
+}
+
+// A recording with its sidecars, modified when its newest file is.
+#[derive(Debug)]
+struct Recording {
+    paths: Vec<PathBuf>,
+    modified: SystemTime,
+    size: u64,
+    in_use: bool,
+}
+
+// Recordings, video and terminal, with their sidecar and temporary files.
+fn is_record_file(name: &str) -> bool {
+    name.starts_with("incoming_") || name.starts_with("outgoing_")
+}
+
+// The name shared by a recording and its sidecars.
+fn base_name(name: &str) -> &str {
+    let name = name.strip_suffix(record_crypt::EXTENSION).unwrap_or(name);
+    name.strip_suffix(EVENTS_EXTENSION).unwrap_or(name)
+}
+
+// The base names of the files listed by a manifest.
+fn manifest_files(path: &Path) -> Vec<String> {
+    let Ok(data) = std::fs::read_to_string(path) else {
+        return vec![];
+    };
+    data.lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter_map(|v| v["file"].as_str().map(|f| base_name(f).to_owned()))
+        .collect()
+}
+
+fn list(dir: &str) -> ResultType<Vec<Recording>> {
+    let now = SystemTime::now();
+    let mut files = Vec::new();
+    // The manifest of each listed base name.
+    let mut manifests = HashMap::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !is_record_file(&name) {
+            continue;
+        }
+        let meta = entry.metadata()?;
+        if !meta.is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let modified = meta.modified()?;
+        let path_str = path.to_string_lossy();
+        let in_use = scrap::record::is_writing(&path_str)
+            || crate::hbbs_http::record_upload::is_pending(&path_str)
+            || now.duration_since(modified).unwrap_or_default() < IN_USE_MODIFIED_WITHIN;
+        if name.ends_with(MANIFEST_EXTENSION) {
+            for file in manifest_files(&path) {
+                manifests.insert(file, name.clone());
+            }
+        }
+        files.push(RecordFile {
+            name,
+            path,
+            modified,
+            size: meta.len(),
+            in_use,
+        });
+    }
+    Ok(group(files, &manifests))
+}
+
+fn group(files: Vec<RecordFile>, manifests: &HashMap<String, String>) -> Vec<Recording> {
+    let mut recordings: HashMap<String, Recording> = HashMap::new();
+    for f in files {
+        let base = base_name(&f.name);
+        let key = manifests
+            .get(base)
+            .cloned()
+            .unwrap_or_else(|| base.to_owned());
+        let r = recordings.entry(key).or_insert_with(|| Recording {
+            paths: vec![],
+            modified: UNIX_EPOCH,
+            size: 0,
+            in_use: false,
+        });
+        r.paths.push(f.path);
+        r.modified = r.modified.max(f.modified);
+        r.size += f.size;
+        r.in_use |= f.in_use;
+    }
+    recordings.into_values().collect()
+}
+
+// The files to remove, the newest recordings are kept first.
+fn select(mut files: Vec<Recording>, limits: &Limits, now: SystemTime) -> Vec<PathBuf> {
+    files.sort_by(|a, b| b.modified.cmp(&a.modified));
+    let mut count = 0;
+    let mut size = 0;
+    let mut remove = Vec::new();
+    for f in files {
+        if !f.in_use {
+            let too_old = limits.max_age.map_or(false, |max| {
+                now.duration_since(f.modified).unwrap_or_default() > max
+            });
+            let too_many = limits.max_files.map_or(false, |max| count + 1 > max);
+            let too_large = limits.max_size.map_or(false, |max| size + f.size > max);
+            if too_old || too_many || too_large {
+                remove.extend(f.paths);
+                continue;
+            }
+        }
+        count += 1;
+        size += f.size;
+    }
+    remove
+}
+
+fn prune(dir: &str) -> ResultType<()> {
+    let limits = Limits::get();
+    if limits.is_empty() || !Path::new(dir).exists() {
+        return Ok(());
+    }
+    for path in select(list(dir)?, &limits, SystemTime::now()) {
+        match std::fs::remove_file(&path) {
+            Ok(_) => log::info!("Removed recording {:?} by retention", path),
+            Err(e) => log::error!("Failed to remove recording {:?}: {}", path, e),
+        }
+    }
+    Ok(())
+}
+
+fn available_space(dir: &str) -> Option<u64> {
+    let disks = Disks::new_with_refreshed_list();
+    disks
+        .list()
+        .iter()
+        .filter(|d| Path::new(dir).starts_with(d.mount_point()))
+        .max_by_key(|d| d.mount_point().as_os_str().len())
+        .map(|d| d.available_space())
+}
+
+fn start_periodic_prune(dir: &str) {
+    let mut dirs = DIRS.lock().unwrap();
+    if dirs.is_empty() {
+        std::thread::spawn(|| loop {
+            std::thread::sleep(PRUNE_INTERVAL);
+            let dirs: Vec<String> = DIRS.lock().unwrap().iter().cloned().collect();
+            for dir in dirs {
+                if let Err(e) = prune(&dir) {
+                    log::error!("Failed to prune recordings in {}: {}", dir, e);
+                }
+            }
+        });
+    }
+    dirs.insert(dir.to_owned());
+}
+
+/// The space of a record directory in MB, when less is free than required.
+#[derive(Debug)]
+pub struct NoSpace {
+    pub free: u64,
+    pub required: u64,
+}
+
+/// Prune `dir` before a recording starts.
+/// Returns an error if the recording should be skipped for lack of disk space.
+pub fn prepare(dir: &str) -> Result<(), NoSpace> {
+    start_periodic_prune(dir);
+    if let Err(e) = prune(dir) {
+        log::error!("Failed to prune recordings in {}: {}", dir, e);
+    }
+    let min_free = Config::get_option(OPTION_RECORD_MIN_FREE_SPACE)
+        .trim()
+        .parse()
+        .unwrap_or(DEFAULT_MIN_FREE_SPACE_MB)
+        * MB;
+    match available_space(dir) {
+        Some(free) if free < min_free => {
+            log::error!(
+                "Recording skipped, {} MB free in {}, {} MB required",
+                free / MB,
+                dir,
+                min_free / MB
+            );
+            Err(NoSpace {
+                free: free / MB,
+                required: min_free / MB,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let file = |name: &str, days: u32, size: u64, in_use: bool| Recording {
+            paths: vec![PathBuf::from(name)],
+            modified: now - day * days,
+            size,
+            in_use,
+        };
+        let files = || {
+            vec![
+                file("a", 0, 10, true),
+                file("b", 1, 10, false),
+                file("c", 2, 10, true),
+                file("d", 3, 10, false),
+                file("e", 4, 10, false),
+            ]
+        };
+        let names = |paths: Vec<PathBuf>| -> Vec<String> {
+            paths
+                .iter()
+                .map(|p| p.to_string_lossy().to_string())
+                .collect()
+        };
+
+        assert!(select(files(), &Limits::default(), now).is_empty());
+        let limits = Limits {
+            max_age: Some(day * 2 + Duration::from_secs(60)),
+            ..Default::default()
+        };
+        assert_eq!(names(select(files(), &limits, now)), ["d", "e"]);
+        let limits = Limits {
+            max_files: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(names(select(files(), &limits, now)), ["b", "d", "e"]);
+        let limits = Limits {
+            max_size: Some(40),
+            ..Default::default()
+        };
+        assert_eq!(names(select(files(), &limits, now)), ["e"]);
+    }
+
+    #[test]
+    fn test_group() {
+        let now = SystemTime::now();
+        let file = |name: &str, secs: u64, in_use: bool| RecordFile {
+            name: name.to_owned(),
+            path: PathBuf::from(name),
+            modified: now - Duration::from_secs(secs),
+            size: 10,
+            in_use,
+        };
+        let manifest = "incoming_1_s.manifest.jsonl";
+        let manifests = HashMap::from([
+            ("incoming_1_a.webm".to_owned(), manifest.to_owned()),
+            ("incoming_1_b.webm".to_owned(), manifest.to_owned()),
+        ]);
+        let files = vec![
+            file(manifest, 30, false),
+            file("incoming_1_a.webm.enc", 20, false),
+            file("incoming_1_a.webm.events.jsonl.enc", 10, false),
+            file("incoming_1_b.webm", 5, true),
+            file("incoming_1_c.webm", 0, false),
+            file("incoming_1_c.webm.events.jsonl", 0, false),
+            file("incoming_1_t.cast", 0, false),
+        ];
+        let mut recordings = group(files, &manifests);
+        recordings.sort_by_key(|r| r.paths.len());
+        let sizes: Vec<(usize, u64, bool)> = recordings
+            .iter()
+            .map(|r| (r.paths.len(), r.size, r.in_use))
+            .collect();
+        assert_eq!(sizes, [(1, 10, false), (2, 20, false), (4, 40, true)]);
+        assert_eq!(recordings[2].modified, now - Duration::from_secs(5));
+    }
+}
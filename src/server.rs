@@ -36,6 +36,8 @@ pub mod audio_service;
 #[cfg(target_os = "windows")]
 pub mod terminal_helper;
 #[cfg(not(target_os = "ios"))]
+mod terminal_record;
+#[cfg(not(target_os = "ios"))]
 pub mod terminal_service;
 cfg_if::cfg_if! {
 if #[cfg(not(target_os = "ios"))] {
@@ -66,11 +68,20 @@ pub mod input_service {
     pub const NAME_WINDOW_FOCUS: &'static str = "";
 }
 
+mod access_schedule;
+mod audit;
+mod bandwidth_estimator;
+mod clipboard_policy;
 mod connection;
 pub mod display_service;
+mod peer_rules;
 #[cfg(windows)]
 pub mod portable_service;
+mod record_policy;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+mod sensitive_apps;
 mod service;
+pub mod tunnel;
 mod video_qos;
 pub mod video_service;
 
@@ -396,6 +407,30 @@ impl Server {
         self.connections.insert(conn.id(), conn);
     }
 
+    /// Subscribe `conn`, which has no channels, to the video services of all displays,
+    /// so that the displays the peer does not view are recorded too.
+    pub fn add_record_all_displays(&mut self, conn: ConnInner) {
+        #[cfg(target_os = "linux")]
+        if !scrap::is_x11() {
+            // wayland does not support multiple displays currently
+            return;
+        }
+        let displays = match display_service::try_get_displays() {
+            Ok(displays) => displays,
+            Err(e) => {
+                log::error!("Failed to get displays to record: {}", e);
+                return;
+            }
+        };
+        for idx in 0..displays.len() {
+            let name = video_service::get_service_name(VideoSource::Monitor, idx);
+            if !self.contains(&name) {
+                self.add_service(Box::new(video_service::new(VideoSource::Monitor, idx)));
+            }
+            self.subscribe(&name, conn.clone(), true);
+        }
+    }
+
     pub fn remove_connection(&mut self, conn: &ConnInner) {
         for s in self.services.values() {
             s.on_unsubscribe(conn.id());
@@ -546,6 +581,25 @@ pub fn check_zombie() {
     });
 }
 
+/// Prune the record directory before an incoming recording starts.
+/// Returns false if the recording is skipped for lack of disk space, which is audited.
+pub fn prepare_record_dir(dir: &str) -> bool {
+    match crate::record_retention::prepare(dir) {
+        Ok(_) => true,
+        Err(e) => {
+            Connection::post_alarm_audit(
+                AlarmAuditType::RecordSkippedNoSpace,
+                serde_json::json!({
+                    "dir": dir,
+                    "free": e.free,
+                    "required": e.required,
+                }),
+            );
+            false
+        }
+    }
+}
+
 /// Start the host server that allows the remote peer to control the current machine.
 ///
 /// # Arguments
@@ -596,6 +650,8 @@ pub async fn start_server(is_server: bool, no_server: bool) {
         });
         input_service::fix_key_down_timeout_loop();
         #[cfg(target_os = "linux")]
+        sensitive_apps::warn_wayland_process_rules();
+        #[cfg(target_os = "linux")]
         if input_service::wayland_use_uinput() {
             allow_err!(input_service::setup_uinput(0, 1920, 0, 1080).await);
         }
@@ -0,0 +1,207 @@
+// Time-of-day and weekday access schedules for incoming connections.
+//
+// A schedule is a list of rules separated by `;`, each rule is `<days> [<start>-<end>]` in local time:
+//
+//   mon-fri 08:00-18:00; sat 09:00-12:00
+//
+// Days are `mon`..`sun`, ranges like `mon-fri`, lists like `sat,sun`, or `*` for every day.
+// Without a time range the rule covers the whole day. `22:00-06:00` wraps past midnight, the
+// part after midnight belongs to the day the range starts on.
+// `none` never matches. An empty schedule allows every connection.
+
+use hbb_common::{
+    bail,
+    chrono::{self, Datelike, Timelike},
+    config::Config,
+    log, ResultType,
+};
+use std::collections::HashMap;
+
+/// The schedule for all peers.
+pub const OPTION_ACCESS_SCHEDULE: &str = "access-schedule";
+/// JSON object from peer ID to a schedule which replaces `OPTION_ACCESS_SCHEDULE` for that peer.
+pub const OPTION_ACCESS_SCHEDULE_PEERS: &str = "access-schedule-peers";
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+#[derive(Debug, PartialEq)]
+struct Rule {
+    // Indexed by `Weekday::num_days_from_monday`.
+    days: [bool; 7],
+    // Minutes since midnight, `start == end` is the whole day.
+    start: u32,
+    end: u32,
+}
+
+impl Rule {
+    fn matches(&self, weekday: usize, minute: u32) -> bool {
+        if self.start == self.end {
+            self.days[weekday]
+        } else if self.start < self.end {
+            self.days[weekday] && self.start <= minute && minute < self.end
+        } else if minute >= self.start {
+            self.days[weekday]
+        } else {
+            // The tail of the range started the day before.
+            minute < self.end && self.days[(weekday + 6) % 7]
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Schedule {
+    rules: Vec<Rule>,
+}
+
+impl Schedule {
+    fn parse(s: &str) -> ResultType<Self> {
+        let mut rules = Vec::new();
+        for rule in s.split(';').map(|r| r.trim()).filter(|r| !r.is_empty()) {
+            if rule.eq_ignore_ascii_case("none") {
+                continue;
+            }
+            let mut parts = rule.split_whitespace();
+            let days = parse_days(parts.next().unwrap_or_default())?;
+            let (start, end) = match parts.next() {
+                Some(range) => match range.split_once('-') {
+                    Some((start, end)) => (parse_time(start)?, parse_time(end)?),
+                    None => bail!("invalid time range: {}", range),
+                },
+                None => (0, 0),
+            };
+            if let Some(extra) = parts.next() {
+                bail!("unexpected: {}", extra);
+            }
+            rules.push(Rule {
+                days,
+                start: start % MINUTES_PER_DAY,
+                end: end % MINUTES_PER_DAY,
+            });
+        }
+        Ok(Self { rules })
+    }
+
+    fn matches(&self, weekday: usize, minute: u32) -> bool {
+        self.rules.iter().any(|r| r.matches(weekday, minute))
+    }
+}
+
+fn parse_day(s: &str) -> ResultType<usize> {
+    let s = s.to_lowercase();
+    match DAYS.iter().position(|d| s == *d) {
+        Some(i) => Ok(i),
+        None => bail!("invalid day: {}", s),
+    }
+}
+
+fn parse_days(s: &str) -> ResultType<[bool; 7]> {
+    let mut days = [false; 7];
+    for part in s.split(',') {
+        if part == "*" {
+            days = [true; 7];
+        } else if let Some((from, to)) = part.split_once('-') {
+            let (from, to) = (parse_day(from)?, parse_day(to)?);
+            let mut i = from;
+            loop {
+                days[i] = true;
+                if i == to {
+                    break;
+                }
+                i = (i + 1) % 7;
+            }
+        } else {
+            days[parse_day(part)?] = true;
+        }
+    }
+    Ok(days)
+}
+
+// "HH:MM" to minutes, "24:00" is allowed as an end time.
+fn parse_time(s: &str) -> ResultType<u32> {
+    let Some((h, m)) = s.split_once(':') else {
+        bail!("invalid time: {}", s);
+    };
+    let (h, m): (u32, u32) = (h.parse()?, m.parse()?);
+    if m >= 60 || h > 24 || (h == 24 && m > 0) {
+        bail!("invalid time: {}", s);
+    }
+    Ok(h * 60 + m)
+}
+
+fn get_schedule(peer_id: &str) -> String {
+    let peers = Config::get_option(OPTION_ACCESS_SCHEDULE_PEERS);
+    if !peers.is_empty() {
+        match serde_json::from_str::<HashMap<String, String>>(&peers) {
+            Ok(peers) => {
+                if let Some(schedule) = peers.get(peer_id) {
+                    return schedule.clone();
+                }
+            }
+            Err(e) => log::error!("Invalid {}: {}", OPTION_ACCESS_SCHEDULE_PEERS, e),
+        }
+    }
+    Config::get_option(OPTION_ACCESS_SCHEDULE)
+}
+
+/// Whether the non-empty `schedule` covers the current local time.
+pub fn matches_now(schedule: &str) -> ResultType<bool> {
+    let s = Schedule::parse(schedule)?;
+    let now = chrono::Local::now();
+    Ok(s.matches(
+        now.weekday().num_days_from_monday() as _,
+        now.hour() * 60 + now.minute(),
+    ))
+}
+
+/// Whether the peer may connect now. An invalid schedule refuses every connection.
+pub fn is_allowed_now(peer_id: &str) -> bool {
+    let schedule = get_schedule(peer_id);
+    if schedule.trim().is_empty() {
+        return true;
+    }
+    match matches_now(&schedule) {
+        Ok(allowed) => allowed,
+        Err(e) => {
+            log::error!("Invalid access schedule \"{}\": {}", schedule, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let s = Schedule::parse("mon-fri 08:00-18:00; sat 09:00-12:00").unwrap();
+        assert!(s.matches(0, 8 * 60));
+        assert!(!s.matches(0, 18 * 60));
+        assert!(s.matches(4, 17 * 60 + 59));
+        assert!(s.matches(5, 10 * 60));
+        assert!(!s.matches(5, 13 * 60));
+        assert!(!s.matches(6, 10 * 60));
+
+        let s = Schedule::parse("fri-mon 22:00-06:00").unwrap();
+        assert!(s.matches(4, 23 * 60));
+        assert!(s.matches(5, 3 * 60));
+        assert!(s.matches(0, 5 * 60));
+        assert!(s.matches(1, 5 * 60));
+        assert!(!s.matches(1, 23 * 60));
+        assert!(!s.matches(2, 5 * 60));
+        assert!(!s.matches(4, 3 * 60));
+        assert!(!s.matches(4, 12 * 60));
+
+        let s = Schedule::parse("* 00:00-24:00").unwrap();
+        assert!(s.matches(3, 0));
+        assert!(Schedule::parse("sat,sun").unwrap().matches(6, 3 * 60));
+        assert!(!Schedule::parse("none").unwrap().matches(0, 0));
+
+        assert!(Schedule::parse("mon 8-18").is_err());
+        assert!(Schedule::parse("xyz 08:00-18:00").is_err());
+        assert!(Schedule::parse("monkey 08:00-18:00").is_err());
+        assert!(Schedule::parse("MON 08:00-18:00").is_ok());
+        assert!(Schedule::parse("mon 08:00-24:30").is_err());
+    }
+}
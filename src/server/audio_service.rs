@@ -496,11 +496,13 @@ fn send_f32(data: &[f32], encoder: &mut Encoder, sp: &GenericService) {
                     .encode_vec_float(&data[i * BATCH_SIZE..(i + 1) * BATCH_SIZE], BATCH_SIZE)
                 {
                     Ok(data) => {
-                        let mut msg_out = Message::new();
-                        msg_out.set_audio_frame(AudioFrame {
+                        let frame = AudioFrame {
                             data: data.into(),
                             ..Default::default()
-                        });
+                        };
+                        super::video_service::record_audio(&frame);
+                        let mut msg_out = Message::new();
+                        msg_out.set_audio_frame(frame);
                         sp.send(msg_out);
                     }
                     Err(_) => {}
@@ -515,11 +517,13 @@ fn send_f32(data: &[f32], encoder: &mut Encoder, sp: &GenericService) {
     #[cfg(not(target_os = "android"))]
     match encoder.encode_vec_float(data, data.len() * 6) {
         Ok(data) => {
-            let mut msg_out = Message::new();
-            msg_out.set_audio_frame(AudioFrame {
+            let frame = AudioFrame {
                 data: data.into(),
                 ..Default::default()
-            });
+            };
+            super::video_service::record_audio(&frame);
+            let mut msg_out = Message::new();
+            msg_out.set_audio_frame(frame);
             sp.send(msg_out);
         }
         Err(_) => {}
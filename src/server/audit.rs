@@ -0,0 +1,532 @@
+// Audit event delivery.
+//
+// Events from `Connection::post_conn_audit`, `post_file_audit` and `post_alarm_audit` are queued
+// to one worker, which writes them to the sinks selected by `OPTION_AUDIT_SINKS`:
+//
+// - `http`: POST to the audit server (default), retried while the server is unreachable.
+// - `file`: JSON lines, rotated by size.
+// - `syslog`: RFC 5424 over UDP or a unix datagram socket.
+//
+// The file and syslog sinks are written at once, the HTTP posts run on their own task so an
+// unreachable server does not hold them. The events not posted yet are saved to
+// `audit/pending.jsonl` in the config directory and posted again after a restart. Beyond
+// `MAX_PENDING_HTTP` the oldest are dropped, which is reported by an alarm once posted again.
+
+use super::{AlarmAuditType, Connection};
+use hbb_common::{
+    chrono,
+    config::Config,
+    lazy_static, log,
+    tokio::{
+        self,
+        sync::mpsc,
+        time::{self, Duration},
+    },
+    ResultType,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+/// Comma separated list of `http`, `file` and `syslog`.
+pub const OPTION_AUDIT_SINKS: &str = "audit-sinks";
+/// Path of the JSON lines file, defaults to `audit/audit.jsonl` in the config directory.
+pub const OPTION_AUDIT_FILE: &str = "audit-file";
+/// Rotate the JSON lines file when it grows over this many MB.
+pub const OPTION_AUDIT_FILE_MAX_SIZE: &str = "audit-file-max-size";
+/// Number of rotated JSON lines files to keep.
+pub const OPTION_AUDIT_FILE_MAX_FILES: &str = "audit-file-max-files";
+/// `udp://host:port` or `unix:///dev/log`.
+pub const OPTION_AUDIT_SYSLOG: &str = "audit-syslog";
+
+const DEFAULT_SINKS: &str = "http";
+const DEFAULT_FILE_MAX_SIZE_MB: u64 = 10;
+const DEFAULT_FILE_MAX_FILES: usize = 5;
+// Events kept in memory while the audit server is unreachable, the oldest are dropped beyond.
+const MAX_PENDING_HTTP: usize = 10_000;
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+// RFC 5424 facility "log audit".
+const SYSLOG_FACILITY: u8 = 13;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditKind {
+    Conn,
+    File,
+    Alarm,
+}
+
+impl AuditKind {
+    fn name(&self) -> &'static str {
+        match self {
+            AuditKind::Conn => "conn",
+            AuditKind::File => "file",
+            AuditKind::Alarm => "alarm",
+        }
+    }
+
+    // RFC 5424 severity
+    fn severity(&self) -> u8 {
+        match self {
+            AuditKind::Alarm => 4, // warning
+            _ => 6,                // informational
+        }
+    }
+}
+
+struct AuditEvent {
+    kind: AuditKind,
+    // Empty if no audit server is configured.
+    url: String,
+    value: Value,
+    time: chrono::DateTime<chrono::Utc>,
+}
+
+lazy_static::lazy_static! {
+    static ref SENDER: Mutex<Option<mpsc::UnboundedSender<AuditEvent>>> = Default::default();
+}
+
+/// Queue an audit event, `url` is the audit server url from `crate::get_audit_server`.
+pub fn post(kind: AuditKind, url: String, value: Value) {
+    let event = AuditEvent {
+        kind,
+        url,
+        value,
+        time: chrono::Utc::now(),
+    };
+    let mut sender = SENDER.lock().unwrap();
+    let tx = sender.get_or_insert_with(|| {
+        let (tx, rx) = mpsc::unbounded_channel();
+        std::thread::spawn(move || run(rx));
+        tx
+    });
+    if let Err(e) = tx.send(event) {
+        log::error!("Failed to queue audit event: {}", e);
+    }
+}
+
+fn get_sinks() -> Vec<String> {
+    let sinks = Config::get_option(OPTION_AUDIT_SINKS);
+    let sinks = if sinks.is_empty() {
+        DEFAULT_SINKS.to_owned()
+    } else {
+        sinks
+    };
+    sinks
+        .split(',')
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn run(mut rx: mpsc::UnboundedReceiver<AuditEvent>) {
+    let mut file_sink = FileSink::default();
+    let mut syslog_sink = SyslogSink::default();
+    let (http_tx, http_rx) = mpsc::unbounded_channel();
+    tokio::spawn(run_http(http_rx));
+    while let Some(event) = rx.recv().await {
+        let sinks = get_sinks();
+        if sinks.iter().any(|s| s == "file") {
+            if let Err(e) = file_sink.write(&event) {
+                log::error!("Failed to write audit file: {}", e);
+            }
+        }
+        if sinks.iter().any(|s| s == "syslog") {
+            if let Err(e) = syslog_sink.write(&event) {
+                log::error!("Failed to send audit to syslog: {}", e);
+            }
+        }
+        if sinks.iter().any(|s| s == "http") && !event.url.is_empty() {
+            http_tx.send((event.url, event.value)).ok();
+        }
+    }
+    log::debug!("audit loop exited");
+}
+
+async fn run_http(mut rx: mpsc::UnboundedReceiver<(String, Value)>) {
+    let mut queue = HttpQueue::load(Config::path("audit").join("pending.jsonl"));
+    let mut retry_at = if queue.events.is_empty() {
+        None
+    } else {
+        Some(time::Instant::now())
+    };
+    loop {
+        tokio::select! {
+            event = rx.recv() => {
+                let Some((url, value)) = event else {
+                    break;
+                };
+                queue.push(url, value);
+            }
+            _ = time::sleep_until(retry_at.unwrap_or_else(time::Instant::now)), if retry_at.is_some() => {
+                retry_at = None;
+            }
+        }
+        if retry_at.is_some() {
+            continue;
+        }
+        while let Some((url, value)) = queue.events.front() {
+            match crate::post_request(url.clone(), value.to_string(), "").await {
+                Ok(_) => queue.posted(),
+                Err(e) => {
+                    let delay = queue.failed();
+                    log::warn!(
+                        "Failed to post audit, {} pending, retry in {:?}: {}",
+                        queue.events.len(),
+                        delay,
+                        e
+                    );
+                    retry_at = Some(time::Instant::now() + delay);
+                    break;
+                }
+            }
+        }
+        if queue.events.is_empty() && queue.dropped > 0 {
+            Connection::post_alarm_audit(
+                AlarmAuditType::AuditEventsDropped,
+                json!({ "dropped": queue.dropped }),
+            );
+            queue.dropped = 0;
+        }
+    }
+}
+
+// The events to post to the audit server, oldest first, with their copy in `path`.
+struct HttpQueue {
+    // Empty not to save the events.
+    path: PathBuf,
+    events: VecDeque<(String, Value)>,
+    // The events dropped since the queue was last empty.
+    dropped: usize,
+    retry_delay: Duration,
+}
+
+impl HttpQueue {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            events: VecDeque::new(),
+            dropped: 0,
+            retry_delay: MIN_RETRY_DELAY,
+        }
+    }
+
+    fn load(path: PathBuf) -> Self {
+        let mut queue = Self::new(path);
+        if let Ok(file) = File::open(&queue.path) {
+            for line in BufReader::new(file).lines().map_while(Result::ok) {
+                if let Ok((url, value)) = serde_json::from_str::<(String, Value)>(&line) {
+                    queue.add(url, value);
+                }
+            }
+            log::info!("{} pending audit events loaded", queue.events.len());
+        }
+        queue
+    }
+
+    fn add(&mut self, url: String, value: Value) {
+        if self.events.len() >= MAX_PENDING_HTTP {
+            if self.dropped == 0 {
+                log::error!("Too many pending audit events, dropping the oldest");
+            }
+            self.events.pop_front();
+            self.dropped += 1;
+        }
+        self.events.push_back((url, value));
+    }
+
+    fn push(&mut self, url: String, value: Value) {
+        if let Err(e) = self.append(&url, &value) {
+            log::error!("Failed to save pending audit event: {}", e);
+        }
+        self.add(url, value);
+    }
+
+    fn posted(&mut self) {
+        self.events.pop_front();
+        self.retry_delay = MIN_RETRY_DELAY;
+        if self.events.is_empty() && !self.path.as_os_str().is_empty() {
+            std::fs::remove_file(&self.path).ok();
+        }
+    }
+
+    // Returns the delay before the next try, doubled on each failure.
+    fn failed(&mut self) -> Duration {
+        if let Err(e) = self.save() {
+            log::error!("Failed to save pending audit events: {}", e);
+        }
+        let delay = self.retry_delay;
+        self.retry_delay = (self.retry_delay * 2).min(MAX_RETRY_DELAY);
+        delay
+    }
+
+    fn append(&self, url: &str, value: &Value) -> ResultType<()> {
+        if self.path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", json!([url, value]))?;
+        Ok(())
+    }
+
+    // Rewrite the file without the posted and dropped events.
+    fn save(&self) -> ResultType<()> {
+        if self.path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        let mut data = String::new();
+        for (url, value) in self.events.iter() {
+            data.push_str(&json!([url, value]).to_string());
+            data.push('\n');
+        }
+        std::fs::write(&self.path, data)?;
+        Ok(())
+    }
+}
+
+fn local_record(event: &AuditEvent) -> Value {
+    json!({
+        "time": event.time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        "kind": event.kind.name(),
+        "event": event.value,
+    })
+}
+
+#[derive(Default)]
+struct FileSink {
+    path: PathBuf,
+    file: Option<File>,
+    size: u64,
+}
+
+impl FileSink {
+    fn get_path() -> PathBuf {
+        let path = Config::get_option(OPTION_AUDIT_FILE);
+        if path.is_empty() {
+            Config::path("audit").join("audit.jsonl")
+        } else {
+            PathBuf::from(path)
+        }
+    }
+
+    fn write(&mut self, event: &AuditEvent) -> ResultType<()> {
+        let path = Self::get_path();
+        if self.file.is_none() || path != self.path {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            self.size = file.metadata()?.len();
+            self.file = Some(file);
+            self.path = path;
+        }
+        let mut line = local_record(event).to_string();
+        line.push('\n');
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(line.as_bytes())?;
+            self.size += line.len() as u64;
+        }
+        let max_size = Config::get_option(OPTION_AUDIT_FILE_MAX_SIZE)
+            .parse()
+            .unwrap_or(DEFAULT_FILE_MAX_SIZE_MB)
+            * 1024
+            * 1024;
+        if self.size >= max_size {
+            let max_files = Config::get_option(OPTION_AUDIT_FILE_MAX_FILES)
+                .parse()
+                .unwrap_or(DEFAULT_FILE_MAX_FILES);
+            self.rotate(max_files)?;
+        }
+        Ok(())
+    }
+
+    // audit.jsonl -> audit.jsonl.1 -> audit.jsonl.2 ...
+    fn rotate(&mut self, max_files: usize) -> ResultType<()> {
+        self.file = None;
+        let max_files = max_files.max(1);
+        let rotated = |i: usize| PathBuf::from(format!("{}.{}", self.path.to_string_lossy(), i));
+        std::fs::remove_file(rotated(max_files)).ok();
+        for i in (1..max_files).rev() {
+            let from = rotated(i);
+            if from.exists() {
+                std::fs::rename(&from, rotated(i + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, rotated(1))?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct SyslogSink {
+    target: String,
+    socket: Option<SyslogSocket>,
+}
+
+enum SyslogSocket {
+    Udp(std::net::UdpSocket, std::net::SocketAddr),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram),
+}
+
+impl SyslogSink {
+    fn connect(target: &str) -> ResultType<SyslogSocket> {
+        if let Some(addr) = target.strip_prefix("udp://") {
+            use std::net::ToSocketAddrs;
+            let Some(addr) = addr.to_socket_addrs()?.next() else {
+                hbb_common::bail!("Failed to resolve {}", addr);
+            };
+            let local = if addr.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            return Ok(SyslogSocket::Udp(std::net::UdpSocket::bind(local)?, addr));
+        }
+        #[cfg(unix)]
+        {
+            let path = target.strip_prefix("unix://").unwrap_or(target);
+            let socket = std::os::unix::net::UnixDatagram::unbound()?;
+            socket.connect(if path.is_empty() { "/dev/log" } else { path })?;
+            return Ok(SyslogSocket::Unix(socket));
+        }
+        #[cfg(not(unix))]
+        hbb_common::bail!("Unsupported syslog target: {}", target);
+    }
+
+    fn write(&mut self, event: &AuditEvent) -> ResultType<()> {
+        let target = Config::get_option(OPTION_AUDIT_SYSLOG);
+        if target.is_empty() {
+            hbb_common::bail!("{} is not set", OPTION_AUDIT_SYSLOG);
+        }
+        if self.socket.is_none() || target != self.target {
+            self.socket = Some(Self::connect(&target)?);
+            self.target = target;
+        }
+        let msg = format_syslog(event);
+        let res = match self.socket.as_ref() {
+            Some(SyslogSocket::Udp(socket, addr)) => socket.send_to(msg.as_bytes(), addr),
+            #[cfg(unix)]
+            Some(SyslogSocket::Unix(socket)) => socket.send(msg.as_bytes()),
+            None => return Ok(()),
+        };
+        if let Err(e) = res {
+            // Reconnect next time, e.g. syslogd restarted.
+            self.socket = None;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+// <PRI>VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG
+fn format_syslog(event: &AuditEvent) -> String {
+    let nil_if_empty = |s: String| {
+        let s: String = s.chars().filter(|c| c.is_ascii_graphic()).collect();
+        if s.is_empty() {
+            "-".to_owned()
+        } else {
+            s
+        }
+    };
+    format!(
+        "<{}>1 {} {} {} {} {} - {}",
+        SYSLOG_FACILITY * 8 + event.kind.severity(),
+        event
+            .time
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        nil_if_empty(crate::common::hostname()),
+        nil_if_empty(crate::get_app_name()),
+        std::process::id(),
+        event.kind.name(),
+        event.value
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_syslog() {
+        let event = AuditEvent {
+            kind: AuditKind::Alarm,
+            url: "".to_owned(),
+            value: json!({"typ": 1}),
+            time: chrono::DateTime::from_timestamp(0, 0).unwrap(),
+        };
+        let msg = format_syslog(&event);
+        assert!(msg.starts_with("<108>1 1970-01-01T00:00:00.000Z "));
+        assert!(msg.ends_with(" alarm - {\"typ\":1}"));
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("audit_rotate_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.jsonl");
+        let rotated = |i: usize| PathBuf::from(format!("{}.{}", path.to_string_lossy(), i));
+        let mut sink = FileSink {
+            path: path.clone(),
+            ..Default::default()
+        };
+        for i in 0..4 {
+            std::fs::write(&path, i.to_string()).unwrap();
+            sink.rotate(2).unwrap();
+        }
+        assert!(!path.exists());
+        assert_eq!(std::fs::read_to_string(rotated(1)).unwrap(), "3");
+        assert_eq!(std::fs::read_to_string(rotated(2)).unwrap(), "2");
+        assert!(!rotated(3).exists());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_http_queue() {
+        let mut queue = HttpQueue::new(PathBuf::new());
+        for i in 0..MAX_PENDING_HTTP + 2 {
+            queue.push("url".to_owned(), json!(i));
+        }
+        assert_eq!(queue.events.len(), MAX_PENDING_HTTP);
+        assert_eq!(queue.dropped, 2);
+        assert_eq!(queue.events.front().unwrap().1, json!(2));
+
+        assert_eq!(queue.failed(), MIN_RETRY_DELAY);
+        assert_eq!(queue.failed(), MIN_RETRY_DELAY * 2);
+        for _ in 0..10 {
+            queue.failed();
+        }
+        assert_eq!(queue.failed(), MAX_RETRY_DELAY);
+        queue.posted();
+        assert_eq!(queue.failed(), MIN_RETRY_DELAY);
+        assert_eq!(queue.events.len(), MAX_PENDING_HTTP - 1);
+    }
+
+    #[test]
+    fn test_http_queue_saved() {
+        let dir = std::env::temp_dir().join(format!("audit_queue_{}", std::process::id()));
+        let path = dir.join("pending.jsonl");
+        let mut queue = HttpQueue::new(path.clone());
+        queue.push("a".to_owned(), json!({"n": 1}));
+        queue.push("b".to_owned(), json!({"n": 2}));
+        queue.posted();
+        queue.failed();
+        let loaded = HttpQueue::load(path.clone());
+        assert_eq!(loaded.events, [("b".to_owned(), json!({"n": 2}))]);
+        queue.posted();
+        assert!(!path.exists());
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,226 @@
+// Per connection estimate of the video bitrate the link carries, from the frames sent on it.
+//
+// The delay of `TestDelay` includes the propagation delay: a satellite link is slow to answer
+// but carries a high bitrate, while congested Wi-Fi answers quickly until its buffers fill up.
+// Here the signal is the local send queue instead: how long a frame waits between
+// `ConnInner::send` and the end of its write to the stream. The write blocks once the socket
+// buffer is full, so the queue only grows if more is encoded than the link drains.
+//
+// Like GCC, the target is decreased multiplicatively when the queue delay is high, below the
+// rate the link drained, and increased slowly while it is low and the target is used.
+// The send rate of a window is the delivery rate of the link only if the link was the limit,
+// otherwise the encoder was.
+
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_millis(200);
+const QUEUE_DELAY_CONGESTED: Duration = Duration::from_millis(100);
+const QUEUE_DELAY_CLEAR: Duration = Duration::from_millis(30);
+// The link is the limit if the connection was busy writing at least this part of a window.
+const BUSY_LINK_LIMITED: f32 = 0.5;
+const DECREASE: f32 = 0.85;
+// About 10% a second.
+const INCREASE: f32 = 1.02;
+// Increase only if the target is used.
+const USED: f32 = 0.8;
+const MIN_KBPS: u32 = 100;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Bandwidth {
+    // None while the link has never been the limit.
+    pub kbps: Option<u32>,
+    pub congested: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct BandwidthEstimator {
+    window_start: Option<Instant>,
+    window_bytes: usize,
+    // Time spent writing, with frames waiting or blocked by the socket.
+    window_busy: Duration,
+    window_queue_delay: Duration,
+    last_sent: Option<Instant>,
+    bandwidth: Bandwidth,
+}
+
+impl BandwidthEstimator {
+    /// A frame of `bytes` queued at `queued` and written to the stream at `sent`.
+    pub fn on_sent(&mut self, bytes: usize, queued: Instant, sent: Instant) {
+        let start = match self.last_sent {
+            Some(last) if last > queued => last,
+            _ => queued,
+        };
+        self.window_busy += sent.saturating_duration_since(start);
+        self.window_bytes += bytes;
+        self.window_queue_delay = self
+            .window_queue_delay
+            .max(sent.saturating_duration_since(queued));
+        self.last_sent = Some(sent);
+        let window_start = *self.window_start.get_or_insert(queued);
+        let elapsed = sent.saturating_duration_since(window_start);
+        if elapsed >= WINDOW {
+            self.end_window(elapsed);
+            self.window_start = Some(sent);
+        }
+    }
+
+    pub fn bandwidth(&self) -> Bandwidth {
+        self.bandwidth
+    }
+
+    fn end_window(&mut self, elapsed: Duration) {
+        let rate = (self.window_bytes as f32 * 8.0 / elapsed.as_secs_f32() / 1000.0) as u32;
+        let busy = self.window_busy.as_secs_f32() / elapsed.as_secs_f32();
+        let queue_delay = self.window_queue_delay;
+        let link_limited = busy >= BUSY_LINK_LIMITED || queue_delay >= QUEUE_DELAY_CONGESTED;
+        let kbps = if queue_delay >= QUEUE_DELAY_CONGESTED {
+            // Below the rate the link drained, so that the queue shrinks.
+            self.bandwidth.congested = true;
+            let target = self.bandwidth.kbps.unwrap_or(rate).min(rate);
+            Some((target as f32 * DECREASE) as u32)
+        } else if queue_delay <= QUEUE_DELAY_CLEAR {
+            self.bandwidth.congested = false;
+            match self.bandwidth.kbps {
+                Some(kbps) if rate as f32 >= kbps as f32 * USED => {
+                    Some((kbps as f32 * INCREASE) as u32 + 1)
+                }
+                Some(kbps) => Some(kbps),
+                None if link_limited => Some(rate),
+                None => None,
+            }
+        } else {
+            self.bandwidth.kbps
+        };
+        self.bandwidth.kbps = kbps.map(|kbps| kbps.max(MIN_KBPS));
+        self.window_bytes = 0;
+        self.window_busy = Duration::ZERO;
+        self.window_queue_delay = Duration::ZERO;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FPS: u32 = 30;
+
+    // A bottleneck draining a socket buffer at `kbps`, writes block while the buffer is full.
+    struct Link {
+        kbps: u32,
+        buffer: f64,
+        level: f64,
+        time: f64,
+    }
+
+    impl Link {
+        fn new(kbps: u32) -> Self {
+            Self {
+                kbps,
+                buffer: 64.0 * 1024.0,
+                level: 0.0,
+                time: 0.0,
+            }
+        }
+
+        // When the write of `bytes` started at `t` returns.
+        fn write(&mut self, t: f64, bytes: f64) -> f64 {
+            let rate = self.kbps as f64 * 1000.0 / 8.0;
+            self.level = (self.level - rate * (t - self.time)).max(0.0);
+            self.time = t;
+            if self.level + bytes <= self.buffer {
+                self.level += bytes;
+                return t;
+            }
+            let done = t + (self.level + bytes - self.buffer) / rate;
+            self.level = self.buffer;
+            self.time = done;
+            done
+        }
+    }
+
+    struct Sim {
+        link: Link,
+        estimator: BandwidthEstimator,
+        base: Instant,
+        frame: u64,
+        last_done: f64,
+        // The bitrate the encoder wants.
+        demand_kbps: u32,
+        max_queue_delay: f64,
+    }
+
+    impl Sim {
+        fn new(link_kbps: u32, demand_kbps: u32) -> Self {
+            Self {
+                link: Link::new(link_kbps),
+                estimator: Default::default(),
+                base: Instant::now(),
+                frame: 0,
+                last_done: 0.0,
+                demand_kbps,
+                max_queue_delay: 0.0,
+            }
+        }
+
+        // The encoder follows the target, returns the average bitrate.
+        fn run(&mut self, secs: u32) -> u32 {
+            self.max_queue_delay = 0.0;
+            let mut sum = 0;
+            for _ in 0..secs * FPS {
+                let queued = self.frame as f64 / FPS as f64;
+                let kbps = match self.estimator.bandwidth().kbps {
+                    Some(kbps) => kbps.min(self.demand_kbps),
+                    None => self.demand_kbps,
+                };
+                sum += kbps;
+                let bytes = kbps as f64 * 1000.0 / 8.0 / FPS as f64;
+                let done = self.link.write(queued.max(self.last_done), bytes);
+                self.last_done = done;
+                self.max_queue_delay = self.max_queue_delay.max(done - queued);
+                self.estimator.on_sent(
+                    bytes as usize,
+                    self.base + Duration::from_secs_f64(queued),
+                    self.base + Duration::from_secs_f64(done),
+                );
+                self.frame += 1;
+            }
+            sum / (secs * FPS)
+        }
+    }
+
+    #[test]
+    fn test_high_bandwidth_link_is_not_limited() {
+        // The delay of a satellite link is not seen here.
+        let mut sim = Sim::new(20_000, 8_000);
+        assert_eq!(sim.run(20), 8_000);
+        assert_eq!(sim.estimator.bandwidth().kbps, None);
+        assert!(sim.max_queue_delay < 0.05);
+    }
+
+    #[test]
+    fn test_converge_below_link() {
+        let mut sim = Sim::new(4_000, 8_000);
+        sim.run(10);
+        let kbps = sim.run(20);
+        assert!(kbps > 2_800 && kbps <= 4_200, "{}", kbps);
+        assert!(sim.max_queue_delay < 0.5, "{}", sim.max_queue_delay);
+    }
+
+    #[test]
+    fn test_link_drop() {
+        let mut sim = Sim::new(10_000, 8_000);
+        sim.run(10);
+        assert!(!sim.estimator.bandwidth().congested);
+        sim.link.kbps = 2_000;
+        sim.run(3);
+        let kbps = sim.estimator.bandwidth().kbps.unwrap();
+        assert!(kbps <= 2_000, "{}", kbps);
+        let kbps = sim.run(20);
+        assert!(kbps > 1_400 && kbps <= 2_100, "{}", kbps);
+        assert!(sim.max_queue_delay < 0.5, "{}", sim.max_queue_delay);
+        // Recovers with the link.
+        sim.link.kbps = 10_000;
+        sim.run(20);
+        assert_eq!(sim.run(5), 8_000);
+    }
+}
@@ -0,0 +1,388 @@
+// Restrict the clipboards exchanged with the peers, beyond the on/off permission.
+//
+// `clipboard-policy` is a JSON object, all the fields are optional:
+//
+//   {"direction": "outbound",
+//    "max_size": 1048576,
+//    "block_formats": ["image", "rich"],
+//    "block_text": ["credit-card", "iban", {"name": "secret", "regex": "(?i)password:"}]}
+//
+// `direction` is `both` (default), `inbound` (from the peers only), `outbound` (to the peers
+// only) or `none`.
+// `max_size` is the largest size in bytes of a clipboard before compression, 0 for no limit.
+// `block_formats` drops the clipboards of the listed kinds: `image` (RGBA, PNG and SVG) and
+// `rich` (HTML, RTF and the special formats, e.g. Excel XML).
+// `block_text` drops the whole set of clipboards when one of its textual formats matches,
+// since the other formats usually hold the same text. `credit-card` (Luhn checked) and `iban`
+// (checksum checked) are built in, the other rules are regular expressions.
+//
+// An invalid policy blocks every clipboard.
+
+use hbb_common::{
+    bail,
+    compress::decompress,
+    config::Config,
+    log,
+    message_proto::{Clipboard, ClipboardFormat},
+    regex::Regex,
+    ResultType,
+};
+use serde_derive::Deserialize;
+
+pub const OPTION_CLIPBOARD_POLICY: &str = "clipboard-policy";
+
+const CREDIT_CARD: &str = "credit-card";
+const IBAN: &str = "iban";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    // From the peer to this side.
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Direction::Inbound => "inbound",
+            Direction::Outbound => "outbound",
+        }
+    }
+}
+
+/// A clipboard dropped by the policy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Blocked {
+    // The index in the checked clipboards.
+    pub index: usize,
+    pub format: i32,
+    pub rule: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TextRule {
+    Builtin(String),
+    Regex { name: String, regex: String },
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PolicyConfig {
+    #[serde(default)]
+    direction: String,
+    #[serde(default)]
+    max_size: usize,
+    #[serde(default)]
+    block_formats: Vec<String>,
+    #[serde(default)]
+    block_text: Vec<TextRule>,
+}
+
+struct TextMatcher {
+    name: String,
+    regex: Regex,
+    // Checks each match of `regex`, to skip the numbers which only look alike.
+    check: Option<fn(&str) -> bool>,
+}
+
+impl TextMatcher {
+    fn is_match(&self, text: &str) -> bool {
+        match self.check {
+            Some(check) => self.regex.find_iter(text).any(|m| check(m.as_str())),
+            None => self.regex.is_match(text),
+        }
+    }
+}
+
+struct Policy {
+    inbound: bool,
+    outbound: bool,
+    max_size: usize,
+    block_image: bool,
+    block_rich: bool,
+    text: Vec<TextMatcher>,
+}
+
+fn luhn(s: &str) -> bool {
+    let digits: Vec<u32> = s.chars().filter_map(|c| c.to_digit(10)).collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, d)| {
+            if i % 2 == 1 {
+                let d = d * 2;
+                if d > 9 {
+                    d - 9
+                } else {
+                    d
+                }
+            } else {
+                *d
+            }
+        })
+        .sum();
+    sum % 10 == 0
+}
+
+// ISO 13616, the remainder of the rearranged number modulo 97 is 1.
+fn iban_checksum(s: &str) -> bool {
+    let s: Vec<char> = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if s.len() < 15 {
+        return false;
+    }
+    let mut remainder = 0u32;
+    for c in s[4..].iter().chain(s[..4].iter()) {
+        let Some(v) = c.to_digit(36) else {
+            return false;
+        };
+        remainder = if v < 10 {
+            (remainder * 10 + v) % 97
+        } else {
+            (remainder * 100 + v) % 97
+        };
+    }
+    remainder == 1
+}
+
+impl Policy {
+    fn parse(s: &str) -> ResultType<Option<Self>> {
+        if s.trim().is_empty() {
+            return Ok(None);
+        }
+        let config: PolicyConfig = serde_json::from_str(s)?;
+        let (inbound, outbound) = match config.direction.to_lowercase().as_str() {
+            "" | "both" => (true, true),
+            "inbound" => (true, false),
+            "outbound" => (false, true),
+            "none" => (false, false),
+            direction => bail!("invalid direction \"{}\"", direction),
+        };
+        let mut policy = Policy {
+            inbound,
+            outbound,
+            max_size: config.max_size,
+            block_image: false,
+            block_rich: false,
+            text: vec![],
+        };
+        for format in config.block_formats.iter() {
+            match format.to_lowercase().as_str() {
+                "image" => policy.block_image = true,
+                "rich" => policy.block_rich = true,
+                format => bail!("invalid format \"{}\"", format),
+            }
+        }
+        for rule in config.block_text {
+            policy.text.push(match rule {
+                TextRule::Builtin(name) if name == CREDIT_CARD => TextMatcher {
+                    name,
+                    regex: Regex::new(r"\b\d(?:[ -]?\d){12,18}\b")?,
+                    check: Some(luhn),
+                },
+                TextRule::Builtin(name) if name == IBAN => TextMatcher {
+                    name,
+                    regex: Regex::new(r"\b[A-Z]{2}\d{2}(?: ?[A-Z0-9]){11,30}\b")?,
+                    check: Some(iban_checksum),
+                },
+                TextRule::Builtin(name) => bail!("unknown text rule \"{}\"", name),
+                TextRule::Regex { name, regex } => TextMatcher {
+                    name,
+                    regex: Regex::new(&regex)?,
+                    check: None,
+                },
+            });
+        }
+        Ok(Some(policy))
+    }
+
+    fn check(&self, direction: Direction, clipboards: &[Clipboard]) -> Vec<Blocked> {
+        let block_all = |rule: &str| {
+            clipboards
+                .iter()
+                .enumerate()
+                .map(|(index, c)| Blocked {
+                    index,
+                    format: c.format.value(),
+                    rule: rule.to_owned(),
+                })
+                .collect()
+        };
+        let allowed = match direction {
+            Direction::Inbound => self.inbound,
+            Direction::Outbound => self.outbound,
+        };
+        if !allowed {
+            return block_all("direction");
+        }
+        let mut blocked = vec![];
+        for (index, c) in clipboards.iter().enumerate() {
+            let format = c.format.enum_value_or(ClipboardFormat::Text);
+            let is_image = matches!(
+                format,
+                ClipboardFormat::ImageRgba | ClipboardFormat::ImagePng | ClipboardFormat::ImageSvg
+            );
+            let rule = if is_image && self.block_image {
+                Some("format:image")
+            } else if !is_image && format != ClipboardFormat::Text && self.block_rich {
+                Some("format:rich")
+            } else {
+                None
+            };
+            if let Some(rule) = rule {
+                blocked.push(Blocked {
+                    index,
+                    format: c.format.value(),
+                    rule: rule.to_owned(),
+                });
+                continue;
+            }
+            let check_text = !is_image && !self.text.is_empty();
+            if self.max_size == 0 && !check_text {
+                continue;
+            }
+            let content = if c.compress {
+                decompress(&c.content)
+            } else {
+                c.content.to_vec()
+            };
+            if self.max_size > 0 && content.len() > self.max_size {
+                blocked.push(Blocked {
+                    index,
+                    format: c.format.value(),
+                    rule: "max_size".to_owned(),
+                });
+                continue;
+            }
+            if check_text {
+                let text = String::from_utf8_lossy(&content);
+                if let Some(m) = self.text.iter().find(|m| m.is_match(&text)) {
+                    return block_all(&format!("text:{}", m.name));
+                }
+            }
+        }
+        blocked
+    }
+}
+
+/// Check the clipboards against `clipboard-policy`, returns the blocked ones.
+pub fn check(direction: Direction, clipboards: &[Clipboard]) -> Vec<Blocked> {
+    match Policy::parse(&Config::get_option(OPTION_CLIPBOARD_POLICY)) {
+        Ok(Some(policy)) => policy.check(direction, clipboards),
+        Ok(None) => vec![],
+        Err(e) => {
+            log::error!("Invalid {}: {}", OPTION_CLIPBOARD_POLICY, e);
+            Policy {
+                inbound: false,
+                outbound: false,
+                max_size: 0,
+                block_image: false,
+                block_rich: false,
+                text: vec![],
+            }
+            .check(direction, clipboards)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn text(s: &str, format: ClipboardFormat) -> Clipboard {
+        Clipboard {
+            content: s.as_bytes().to_vec().into(),
+            format: format.into(),
+            ..Default::default()
+        }
+    }
+
+    fn rules(blocked: Vec<Blocked>) -> Vec<(usize, String)> {
+        blocked.into_iter().map(|b| (b.index, b.rule)).collect()
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert!(luhn("4111 1111 1111 1111"));
+        assert!(!luhn("4111 1111 1111 1112"));
+        assert!(iban_checksum("DE89 3704 0044 0532 0130 00"));
+        assert!(!iban_checksum("DE88 3704 0044 0532 0130 00"));
+    }
+
+    #[test]
+    fn test_policy() {
+        assert!(Policy::parse("").unwrap().is_none());
+        assert!(Policy::parse(r#"{"direction": "up"}"#).is_err());
+        assert!(Policy::parse(r#"{"block_text": ["ssn"]}"#).is_err());
+
+        let policy = Policy::parse(r#"{"direction": "outbound"}"#)
+            .unwrap()
+            .unwrap();
+        let cbs = vec![text("a", ClipboardFormat::Text)];
+        assert!(policy.check(Direction::Outbound, &cbs).is_empty());
+        assert_eq!(
+            rules(policy.check(Direction::Inbound, &cbs)),
+            vec![(0, "direction".to_owned())]
+        );
+
+        let policy = Policy::parse(
+            r#"{"max_size": 8, "block_formats": ["image"],
+                "block_text": ["credit-card", {"name": "secret", "regex": "(?i)password:"}]}"#,
+        )
+        .unwrap()
+        .unwrap();
+        let cbs = vec![
+            text("hello", ClipboardFormat::Text),
+            text("<b>hello</b>", ClipboardFormat::Html),
+            text("png", ClipboardFormat::ImagePng),
+        ];
+        assert_eq!(
+            rules(policy.check(Direction::Inbound, &cbs)),
+            vec![(1, "max_size".to_owned()), (2, "format:image".to_owned())]
+        );
+        let cbs = vec![
+            text("1234", ClipboardFormat::Text),
+            text("4111-1111-1111-1111", ClipboardFormat::Rtf),
+        ];
+        assert_eq!(
+            rules(policy.check(Direction::Inbound, &cbs)),
+            vec![(1, "max_size".to_owned())]
+        );
+
+        let policy = Policy::parse(
+            r#"{"block_text": ["credit-card", "iban", {"name": "secret", "regex": "(?i)password:"}]}"#,
+        )
+        .unwrap()
+        .unwrap();
+        let cbs = vec![
+            text("order 1234 5678 9012 3456", ClipboardFormat::Text),
+            text("card 4111-1111-1111-1111", ClipboardFormat::Html),
+        ];
+        assert_eq!(
+            rules(policy.check(Direction::Outbound, &cbs)),
+            vec![
+                (0, "text:credit-card".to_owned()),
+                (1, "text:credit-card".to_owned())
+            ]
+        );
+        let cbs = vec![text(
+            "IBAN DE89 3704 0044 0532 0130 00",
+            ClipboardFormat::Text,
+        )];
+        assert_eq!(
+            rules(policy.check(Direction::Outbound, &cbs)),
+            vec![(0, "text:iban".to_owned())]
+        );
+        let cbs = vec![text("Password: x", ClipboardFormat::Text)];
+        assert_eq!(
+            rules(policy.check(Direction::Outbound, &cbs)),
+            vec![(0, "text:secret".to_owned())]
+        );
+        assert!(policy
+            .check(
+                Direction::Outbound,
+                &[text("nothing", ClipboardFormat::Text)]
+            )
+            .is_empty());
+    }
+}
@@ -1,4 +1,14 @@
-use super::{input_service::*, *};
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+use super::sensitive_apps;
+use super::{
+    access_schedule,
+    audit::{self, AuditKind},
+    bandwidth_estimator::BandwidthEstimator,
+    clipboard_policy,
+    input_service::*,
+    peer_rules::{self, PermissionProfile},
+    record_policy, *,
+};
 #[cfg(feature = "unix-file-copy-paste")]
 use crate::clipboard::try_empty_clipboard_files;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -36,7 +46,7 @@ use hbb_common::{
     sha2::{Digest, Sha256},
     sleep, timeout,
     tokio::{
-        net::TcpStream,
+        net::{TcpListener, TcpStream},
         sync::mpsc,
         time::{self, Duration, Instant},
     },
@@ -216,6 +226,9 @@ pub struct Connection {
     view_camera: bool,
     terminal: bool,
     port_forward_socket: Option<Framed<TcpStream, BytesCodec>>,
+    reverse_forward_port: Option<i32>,
+    udp_forward: Option<super::tunnel::UdpForward>,
+    mux_forward: bool,
     port_forward_address: String,
     tx_to_cm: mpsc::UnboundedSender<ipc::Data>,
     authorized: bool,
@@ -228,6 +241,8 @@ pub struct Connection {
     recording: bool,
     block_input: bool,
     control_permissions: Option<ControlPermissions>,
+    // from `peer_rules` at login
+    permission_profile: Option<PermissionProfile>,
     last_test_delay: Option<Instant>,
     network_delay: u32,
     lock_after_session_end: bool,
@@ -252,6 +267,7 @@ pub struct Connection {
     tx_input: std_mpsc::Sender<MessageInput>,
     // handle input messages
     video_ack_required: bool,
+    bandwidth_estimator: BandwidthEstimator,
     server_audit_conn: String,
     server_audit_file: String,
     lr: LoginRequest,
@@ -277,17 +293,22 @@ pub struct Connection {
     file_remove_log_control: FileRemoveLogControl,
     last_supported_encoding: Option<SupportedEncoding>,
     services_subed: bool,
+    // Subscribed to the video services of all displays, see `video_service::OPTION_RECORD_ALL_DISPLAYS`.
+    record_all_displays_sub: Option<ConnInner>,
+    record_decision: Option<record_policy::Decision>,
+    // The session events of file transfer and port forward sessions recorded by `record_policy`.
+    record_event_log: Option<Mutex<scrap::record::EventLog>>,
     delayed_read_dir: Option<(String, bool)>,
     #[cfg(target_os = "macos")]
     retina: Retina,
     follow_remote_cursor: bool,
     follow_remote_window: bool,
     multi_ui_session: bool,
+    // The `sensitive_apps` rule blocking the input, the peer is notified once per rule.
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    sensitive_app: Option<String>,
     tx_from_authed: mpsc::UnboundedSender<ipc::Data>,
     printer_data: Vec<(Instant, String, Vec<u8>)>,
-    // For post requests that need to be sent sequentially.
-    // eg. post_conn_audit
-    tx_post_seq: mpsc::UnboundedSender<(String, Value)>,
     // Tracks read job IDs delegated to CM process.
     // When a read job is delegated to CM (via FS::ReadFile), the job id is added here.
     // Used to filter stale responses (FileBlockFromCM, FileReadDone, etc.) for
@@ -331,9 +352,18 @@ impl Subscriber for ConnInner {
         } else {
             self.tx.as_mut()
         };
-        tx.map(|tx| {
-            allow_err!(tx.send((Instant::now(), msg)));
-        });
+        match tx {
+            Some(tx) => {
+                allow_err!(tx.send((Instant::now(), msg)));
+            }
+            // A subscriber without channels only keeps a video service recording,
+            // see `Server::add_record_all_displays`. It must not hold up the next frame.
+            None => {
+                if let Some(message::Union::VideoFrame(vf)) = &msg.union {
+                    video_service::notify_video_frame_fetched(vf.display as _, self.id, None);
+                }
+            }
+        }
     }
 }
 
@@ -381,11 +411,6 @@ impl Connection {
         let linux_headless_handle =
             LinuxHeadlessHandle::new(_rx_cm_stream_ready, _tx_desktop_ready);
 
-        let (tx_post_seq, rx_post_seq) = mpsc::unbounded_channel();
-        tokio::spawn(async move {
-            Self::post_seq_loop(rx_post_seq).await;
-        });
-
         #[cfg(not(any(target_os = "android", target_os = "ios")))]
         let tx_cloned = tx.clone();
         let mut conn = Self {
@@ -406,18 +431,38 @@ impl Connection {
             view_camera: false,
             terminal: false,
             port_forward_socket: None,
+            reverse_forward_port: None,
+            udp_forward: None,
+            mux_forward: false,
             port_forward_address: "".to_owned(),
             tx_to_cm,
             authorized: false,
-            keyboard: Self::permission(keys::OPTION_ENABLE_KEYBOARD, &control_permissions),
-            clipboard: Self::permission(keys::OPTION_ENABLE_CLIPBOARD, &control_permissions),
-            audio: Self::permission(keys::OPTION_ENABLE_AUDIO, &control_permissions),
+            keyboard: Self::permission(keys::OPTION_ENABLE_KEYBOARD, &control_permissions, &None),
+            clipboard: Self::permission(keys::OPTION_ENABLE_CLIPBOARD, &control_permissions, &None),
+            audio: Self::permission(keys::OPTION_ENABLE_AUDIO, &control_permissions, &None),
             // to-do: make sure is the option correct here
-            file: Self::permission(keys::OPTION_ENABLE_FILE_TRANSFER, &control_permissions),
-            restart: Self::permission(keys::OPTION_ENABLE_REMOTE_RESTART, &control_permissions),
-            recording: Self::permission(keys::OPTION_ENABLE_RECORD_SESSION, &control_permissions),
-            block_input: Self::permission(keys::OPTION_ENABLE_BLOCK_INPUT, &control_permissions),
+            file: Self::permission(
+                keys::OPTION_ENABLE_FILE_TRANSFER,
+                &control_permissions,
+                &None,
+            ),
+            restart: Self::permission(
+                keys::OPTION_ENABLE_REMOTE_RESTART,
+                &control_permissions,
+                &None,
+            ),
+            recording: Self::permission(
+                keys::OPTION_ENABLE_RECORD_SESSION,
+                &control_permissions,
+                &None,
+            ),
+            block_input: Self::permission(
+                keys::OPTION_ENABLE_BLOCK_INPUT,
+                &control_permissions,
+                &None,
+            ),
             control_permissions,
+            permission_profile: None,
             last_test_delay: None,
             network_delay: 0,
             lock_after_session_end: false,
@@ -425,6 +470,8 @@ impl Connection {
             follow_remote_cursor: false,
             follow_remote_window: false,
             multi_ui_session: false,
+            #[cfg(not(any(target_os = "android", target_os = "ios")))]
+            sensitive_app: None,
             ip: "".to_owned(),
             disable_audio: false,
             #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
@@ -435,6 +482,7 @@ impl Connection {
             show_my_cursor: false,
             tx_input,
             video_ack_required: false,
+            bandwidth_estimator: Default::default(),
             server_audit_conn: "".to_owned(),
             server_audit_file: "".to_owned(),
             lr: Default::default(),
@@ -466,12 +514,14 @@ impl Connection {
             file_remove_log_control: FileRemoveLogControl::new(id),
             last_supported_encoding: None,
             services_subed: false,
+            record_all_displays_sub: None,
+            record_decision: None,
+            record_event_log: None,
             delayed_read_dir: None,
             #[cfg(target_os = "macos")]
             retina: Retina::default(),
             tx_from_authed,
             printer_data: Vec::new(),
-            tx_post_seq,
             cm_read_job_ids: HashSet::new(),
             terminal_service_id: "".to_owned(),
             terminal_persistent: false,
@@ -518,7 +568,7 @@ impl Connection {
         let mut last_recv_time = Instant::now();
 
         conn.stream.set_send_timeout(
-            if conn.file_transfer.is_some() || conn.port_forward_socket.is_some() || conn.terminal {
+            if conn.file_transfer.is_some() || conn.is_port_forward() || conn.terminal {
                 SEND_TIMEOUT_OTHER
             } else {
                 SEND_TIMEOUT_VIDEO
@@ -560,7 +610,8 @@ impl Connection {
                         ipc::Data::Authorize => {
                             conn.require_2fa.take();
                             conn.send_logon_response().await;
-                            if conn.port_forward_socket.is_some() {
+                            conn.send_recording_banner();
+                            if conn.is_port_forward() {
                                 break;
                             }
                         }
@@ -579,6 +630,7 @@ impl Connection {
                             }
                         }
                         ipc::Data::ChatMessage{text} => {
+                            conn.record_event("chat", json!({"direction": "send", "text": text}));
                             let mut misc = Misc::new();
                             misc.set_chat_message(ChatMessage {
                                 text,
@@ -778,7 +830,7 @@ impl Connection {
                                     if !conn.on_message(msg_in).await {
                                         break;
                                     }
-                                    if conn.port_forward_socket.is_some() && conn.authorized {
+                                    if conn.is_port_forward() && conn.authorized {
                                         log::info!("Port forward, last_test_delay is none: {}", conn.last_test_delay.is_none());
                                         // Avoid TestDelay reply injection into rdp data stream
                                         if conn.last_test_delay.is_none() {
@@ -828,6 +880,11 @@ impl Connection {
                         conn.on_close(&err.to_string(), false).await;
                         break;
                     }
+                    let bandwidth = conn.bandwidth_estimator.bandwidth();
+                    conn.bandwidth_estimator.on_sent(value.compute_size() as _, instant.into_std(), std::time::Instant::now());
+                    if conn.bandwidth_estimator.bandwidth() != bandwidth {
+                        video_service::VIDEO_QOS.lock().unwrap().user_bandwidth(id, conn.bandwidth_estimator.bandwidth());
+                    }
                 },
                 Some((instant, value)) = rx.recv() => {
                     let latency = instant.elapsed().as_millis() as i64;
@@ -872,8 +929,19 @@ impl Connection {
                             }
                         }
                         Some(message::Union::MultiClipboards(_multi_clipboards)) => {
+                            let mut _multi_clipboards = _multi_clipboards.clone();
+                            if let Some(clipboards) = conn.check_clipboard_policy(clipboard_policy::Direction::Outbound, &_multi_clipboards.clipboards) {
+                                if clipboards.is_empty() {
+                                    continue;
+                                }
+                                _multi_clipboards.clipboards = clipboards;
+                                let mut msg_out = Message::new();
+                                msg_out.set_multi_clipboards(_multi_clipboards.clone());
+                                msg = Arc::new(msg_out);
+                            }
+                            conn.record_clipboard_event("send", &_multi_clipboards.clipboards);
                             #[cfg(not(target_os = "ios"))]
-                            if let Some(msg_out) = crate::clipboard::get_msg_if_not_support_multi_clip(&conn.lr.version, &conn.lr.my_platform, _multi_clipboards) {
+                            if let Some(msg_out) = crate::clipboard::get_msg_if_not_support_multi_clip(&conn.lr.version, &conn.lr.my_platform, &_multi_clipboards) {
                                 if let Err(err) = conn.stream.send(&msg_out).await {
                                     conn.on_close(&err.to_string(), false).await;
                                     break;
@@ -894,7 +962,7 @@ impl Connection {
                     match data {
                         #[cfg(all(target_os = "windows", feature = "flutter"))]
                         ipc::Data::PrinterData(data) => {
-                            if Self::permission(keys::OPTION_ENABLE_REMOTE_PRINTER, &conn.control_permissions) {
+                            if Self::permission(keys::OPTION_ENABLE_REMOTE_PRINTER, &conn.control_permissions, &conn.permission_profile) {
                                 conn.send_printer_request(data).await;
                             } else {
                                 conn.send_remote_printing_disallowed().await;
@@ -923,7 +991,7 @@ impl Connection {
                         break;
                     }
                     // The control end will jump out of the loop after receiving LoginResponse and will not reply to the TestDelay
-                    if conn.last_test_delay.is_none() && !(conn.port_forward_socket.is_some() && conn.authorized) {
+                    if conn.last_test_delay.is_none() && !(conn.is_port_forward() && conn.authorized) {
                         conn.last_test_delay = Some(Instant::now());
                         let mut msg_out = Message::new();
                         msg_out.set_test_delay(TestDelay{
@@ -985,6 +1053,10 @@ impl Connection {
         if let Some(s) = conn.server.upgrade() {
             let mut s = s.write().unwrap();
             s.remove_connection(&conn.inner);
+            if let Some(sub) = conn.record_all_displays_sub.take() {
+                s.remove_connection(&sub);
+            }
+            video_service::remove_record_session(conn.inner.id());
             #[cfg(not(any(target_os = "android", target_os = "ios")))]
             try_stop_record_cursor_pos();
         }
@@ -1097,18 +1169,29 @@ impl Connection {
         log::debug!("Input thread exited");
     }
 
-    async fn post_seq_loop(mut rx: mpsc::UnboundedReceiver<(String, Value)>) {
-        while let Some((url, v)) = rx.recv().await {
-            allow_err!(Self::post_audit_async(url, v).await);
-        }
-        log::debug!("post_seq_loop exited");
-    }
-
     async fn try_port_forward_loop(
         &mut self,
         rx_from_cm: &mut mpsc::UnboundedReceiver<Data>,
     ) -> ResultType<()> {
         let mut last_recv_time = Instant::now();
+        if self.authorized {
+            if let Some(udp) = self.udp_forward.take() {
+                return self.udp_forward_loop(udp, rx_from_cm).await;
+            }
+            if self.mux_forward {
+                return self.mux_forward_loop(rx_from_cm).await;
+            }
+        }
+        // Only bind the loopback listener after the peer is authorized.
+        if self.authorized && self.port_forward_socket.is_none() {
+            if let Some(port) = self.reverse_forward_port {
+                let owner = (self.lr.my_id.clone(), self.lr.session_id);
+                let listener = super::tunnel::reverse_listener(port, &owner).await?;
+                self.stream.set_raw();
+                self.port_forward_socket =
+                    self.accept_reverse_forward(&listener, rx_from_cm).await?;
+            }
+        }
         if let Some(mut forward) = self.port_forward_socket.take() {
             log::info!("Running port forwarding loop");
             self.stream.set_raw();
@@ -1160,6 +1243,166 @@ impl Connection {
         Ok(())
     }
 
+    async fn udp_forward_loop(
+        &mut self,
+        mut udp: super::tunnel::UdpForward,
+        rx_from_cm: &mut mpsc::UnboundedReceiver<Data>,
+    ) -> ResultType<()> {
+        log::info!("Running udp port forwarding loop");
+        self.stream.set_raw();
+        let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
+        let mut last_recv_time = Instant::now();
+        loop {
+            tokio::select! {
+                Some(data) = rx_from_cm.recv() => {
+                    match data {
+                        ipc::Data::Close => {
+                            bail!("Close requested from connection manager");
+                        }
+                        ipc::Data::CmErr(e) => {
+                            log::error!("Connection manager error: {e}");
+                            bail!("{e}");
+                        }
+                        _ => {}
+                    }
+                }
+                Some(frame) = udp.recv() => {
+                    last_recv_time = Instant::now();
+                    self.stream.send_bytes(frame).await?;
+                }
+                res = self.stream.next() => {
+                    if let Some(res) = res {
+                        let bytes = res?;
+                        last_recv_time = Instant::now();
+                        if let Some((flow, payload)) = super::tunnel::decode_datagram(&bytes) {
+                            if let Err(err) = udp.send(flow, payload).await {
+                                log::debug!("udp forward flow {} send: {}", flow, err);
+                            }
+                        }
+                    } else {
+                        bail!("Stream reset by the peer");
+                    }
+                },
+                _ = self.timer.tick() => {
+                    udp.remove_idle_flows();
+                    if last_recv_time.elapsed() >= H1 {
+                        bail!("Timeout");
+                    }
+                }
+                Ok(conns) = hbbs_rx.recv() => {
+                    if conns.contains(&self.inner.id) {
+                        bail!("Closed manually by the web console");
+                    }
+                }
+            }
+        }
+    }
+
+    async fn mux_forward_loop(
+        &mut self,
+        rx_from_cm: &mut mpsc::UnboundedReceiver<Data>,
+    ) -> ResultType<()> {
+        log::info!("Running multiplexed port forwarding loop");
+        self.stream.set_raw();
+        let mut mux = super::tunnel::Mux::new(true);
+        let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
+        let mut last_recv_time = Instant::now();
+        loop {
+            tokio::select! {
+                Some(data) = rx_from_cm.recv() => {
+                    match data {
+                        ipc::Data::Close => {
+                            bail!("Close requested from connection manager");
+                        }
+                        ipc::Data::CmErr(e) => {
+                            log::error!("Connection manager error: {e}");
+                            bail!("{e}");
+                        }
+                        _ => {}
+                    }
+                }
+                Some(frame) = mux.next_frame() => {
+                    timeout(SEND_TIMEOUT_OTHER, self.stream.send_bytes(frame)).await??;
+                }
+                res = self.stream.next() => {
+                    if let Some(res) = res {
+                        last_recv_time = Instant::now();
+                        mux.handle(&res?);
+                    } else {
+                        bail!("Stream reset by the peer");
+                    }
+                },
+                _ = self.timer.tick() => {
+                    if last_recv_time.elapsed() >= H1 {
+                        bail!("Timeout");
+                    }
+                }
+                Ok(conns) = hbbs_rx.recv() => {
+                    if conns.contains(&self.inner.id) {
+                        bail!("Closed manually by the web console");
+                    }
+                }
+            }
+        }
+    }
+
+    // Wait until a local client connects to the loopback listener of a reverse forward.
+    // An empty frame tells the controlling side to dial its own target.
+    async fn accept_reverse_forward(
+        &mut self,
+        listener: &TcpListener,
+        rx_from_cm: &mut mpsc::UnboundedReceiver<Data>,
+    ) -> ResultType<Option<Framed<TcpStream, BytesCodec>>> {
+        let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
+        let mut last_recv_time = Instant::now();
+        loop {
+            tokio::select! {
+                res = super::tunnel::accept_reverse(listener) => {
+                    match res {
+                        Ok((sock, addr)) => {
+                            log::info!("reverse port forwarding connection from {:?}", addr);
+                            self.stream.send_bytes(Bytes::new()).await?;
+                            return Ok(Some(Framed::new(sock, BytesCodec::new())));
+                        }
+                        Err(err) => {
+                            log::warn!("reverse port forwarding accept: {}", err);
+                        }
+                    }
+                }
+                Some(data) = rx_from_cm.recv() => {
+                    match data {
+                        ipc::Data::Close => {
+                            bail!("Close requested from connection manager");
+                        }
+                        ipc::Data::CmErr(e) => {
+                            log::error!("Connection manager error: {e}");
+                            bail!("{e}");
+                        }
+                        _ => {}
+                    }
+                }
+                res = self.stream.next() => {
+                    if let Some(res) = res {
+                        res?;
+                        last_recv_time = Instant::now();
+                    } else {
+                        bail!("Stream reset by the peer");
+                    }
+                },
+                _ = self.timer.tick() => {
+                    if last_recv_time.elapsed() >= H1 {
+                        bail!("Timeout");
+                    }
+                }
+                Ok(conns) = hbbs_rx.recv() => {
+                    if conns.contains(&self.inner.id) {
+                        bail!("Closed manually by the web console");
+                    }
+                }
+            }
+        }
+    }
+
     async fn send_permission(&mut self, permission: Permission, enabled: bool) {
         let mut misc = Misc::new();
         misc.set_permission_info(PermissionInfo {
@@ -1211,6 +1454,68 @@ impl Connection {
         true
     }
 
+    async fn check_access_schedule(&mut self) -> bool {
+        if access_schedule::is_allowed_now(&self.lr.my_id) {
+            return true;
+        }
+        self.send_login_error("Access is not allowed at this time")
+            .await;
+        Self::post_alarm_audit(
+            AlarmAuditType::OutsideAccessSchedule,
+            json!({
+                "ip": self.ip,
+                "id": self.lr.my_id.clone(),
+                "name": self.lr.my_name.clone(),
+            }),
+        );
+        sleep(1.).await;
+        false
+    }
+
+    async fn check_peer_rules(&mut self) -> bool {
+        match peer_rules::check(&self.lr.my_id) {
+            peer_rules::Decision::Allow(profile) => {
+                if let Some(profile) = &profile {
+                    log::info!(
+                        "Peer {} uses permission profile {}",
+                        self.lr.my_id,
+                        profile.name
+                    );
+                }
+                self.permission_profile = profile;
+                self.update_permissions_by_profile();
+                true
+            }
+            peer_rules::Decision::Deny => {
+                self.send_login_error("Your ID is not allowed by the peer")
+                    .await;
+                Self::post_alarm_audit(
+                    AlarmAuditType::PeerIdDenied,
+                    json!({
+                        "ip": self.ip,
+                        "id": self.lr.my_id.clone(),
+                        "name": self.lr.my_name.clone(),
+                    }),
+                );
+                sleep(1.).await;
+                false
+            }
+        }
+    }
+
+    // The flags are initialized before the peer ID is known.
+    fn update_permissions_by_profile(&mut self) {
+        let (control_permissions, profile) = (&self.control_permissions, &self.permission_profile);
+        let permission = |option| Self::permission(option, control_permissions, profile);
+        self.keyboard = permission(keys::OPTION_ENABLE_KEYBOARD);
+        self.clipboard = permission(keys::OPTION_ENABLE_CLIPBOARD);
+        self.audio = permission(keys::OPTION_ENABLE_AUDIO);
+        self.file = permission(keys::OPTION_ENABLE_FILE_TRANSFER);
+        self.restart = permission(keys::OPTION_ENABLE_REMOTE_RESTART);
+        self.recording = permission(keys::OPTION_ENABLE_RECORD_SESSION);
+        self.block_input = permission(keys::OPTION_ENABLE_BLOCK_INPUT);
+    }
+
     async fn on_open(&mut self, addr: SocketAddr) -> bool {
         log::debug!("#{} Connection opened from {}.", self.inner.id, addr);
         if !self.check_whitelist(&addr).await {
@@ -1249,10 +1554,6 @@ impl Connection {
     }
 
     fn post_conn_audit(&self, v: Value) {
-        if self.server_audit_conn.is_empty() {
-            return;
-        }
-        let url = self.server_audit_conn.clone();
         let mut v = v;
         v["id"] = json!(Config::get_id());
         //#region 获取UUID - Android平台使用export_serial_number
@@ -1270,7 +1571,79 @@ impl Connection {
         v["uuid"] = json!(uuid);
         v["conn_id"] = json!(self.inner.id);
         v["session_id"] = json!(self.lr.session_id);
-        allow_err!(self.tx_post_seq.send((url, v)));
+        audit::post(AuditKind::Conn, self.server_audit_conn.clone(), v);
+    }
+
+    // Session event in the sidecar of the incoming recordings.
+    fn record_event(&self, typ: &str, mut event: Value) {
+        event["type"] = json!(typ);
+        if let Some(log) = self.record_event_log.as_ref() {
+            let mut event = event.clone();
+            event["conn_id"] = json!(self.inner.id());
+            allow_err!(log.lock().unwrap().write(&event));
+        }
+        video_service::record_event(self.inner.id(), event);
+    }
+
+    fn record_clipboard_event(&self, direction: &str, clipboards: &[Clipboard]) {
+        let clipboards: Vec<Value> = clipboards
+            .iter()
+            .map(|c| {
+                json!({
+                    "format": c.format.value(),
+                    "size": c.content.len(),
+                    "compress": c.compress,
+                })
+            })
+            .collect();
+        self.record_event(
+            "clipboard",
+            json!({"direction": direction, "clipboards": clipboards}),
+        );
+    }
+
+    // Returns the clipboards kept by the policy, `None` if none is blocked.
+    fn check_clipboard_policy(
+        &self,
+        direction: clipboard_policy::Direction,
+        clipboards: &[Clipboard],
+    ) -> Option<Vec<Clipboard>> {
+        let blocked = clipboard_policy::check(direction, clipboards);
+        if blocked.is_empty() {
+            return None;
+        }
+        let mut rules: Vec<&str> = vec![];
+        for b in blocked.iter() {
+            if rules.contains(&b.rule.as_str()) {
+                continue;
+            }
+            rules.push(&b.rule);
+            log::info!(
+                "Clipboard {} blocked by policy rule {}, format: {}",
+                direction.name(),
+                b.rule,
+                b.format
+            );
+            Self::post_alarm_audit(
+                AlarmAuditType::ClipboardBlocked,
+                json!({
+                    "ip": self.ip,
+                    "id": self.lr.my_id.clone(),
+                    "name": self.lr.my_name.clone(),
+                    "direction": direction.name(),
+                    "rule": b.rule,
+                    "format": b.format,
+                }),
+            );
+        }
+        Some(
+            clipboards
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !blocked.iter().any(|b| b.index == *i))
+                .map(|(_, c)| c.clone())
+                .collect(),
+        )
     }
 
     fn get_files_for_audit(job_type: fs::JobType, mut files: Vec<FileEntry>) -> Vec<(String, i64)> {
@@ -1296,11 +1669,19 @@ impl Connection {
         files: Vec<(String, i64)>,
         info: Value,
     ) {
-        if self.server_audit_file.is_empty() {
-            return;
-        }
-        let url = self.server_audit_file.clone();
         let file_num = files.len();
+        self.record_event(
+            "file",
+            json!({
+                "direction": match r#type {
+                    FileAuditType::RemoteSend => "send",
+                    FileAuditType::RemoteReceive => "receive",
+                },
+                "path": path,
+                "num": file_num,
+                "size": files.iter().map(|f| f.1).sum::<i64>(),
+            }),
+        );
         let mut files = files;
         files.sort_by(|a, b| b.1.cmp(&a.1));
         files.truncate(10);
@@ -1331,9 +1712,7 @@ impl Connection {
             "is_file":is_file,
             "info":json!(info).to_string(),
         });
-        tokio::spawn(async move {
-            allow_err!(Self::post_audit_async(url, v).await);
-        });
+        audit::post(AuditKind::File, self.server_audit_file.clone(), v);
     }
 
     pub fn post_alarm_audit(typ: AlarmAuditType, info: Value) {
@@ -1342,9 +1721,6 @@ impl Connection {
             Config::get_option("custom-rendezvous-server"),
             "alarm".to_owned(),
         );
-        if url.is_empty() {
-            return;
-        }
         let mut v = Value::default();
         v["id"] = json!(Config::get_id());
         //#region 获取UUID - Android平台使用export_serial_number
@@ -1362,14 +1738,7 @@ impl Connection {
         v["uuid"] = json!(uuid);
         v["typ"] = json!(typ as i8);
         v["info"] = serde_json::Value::String(info.to_string());
-        tokio::spawn(async move {
-            allow_err!(Self::post_audit_async(url, v).await);
-        });
-    }
-
-    #[inline]
-    async fn post_audit_async(url: String, v: Value) -> ResultType<String> {
-        crate::post_request(url, v.to_string(), "").await
+        audit::post(AuditKind::Alarm, url, v);
     }
 
     async fn send_logon_response(&mut self) {
@@ -1410,7 +1779,7 @@ impl Connection {
         self.authorized = true;
         let (conn_type, auth_conn_type) = if self.file_transfer.is_some() {
             (1, AuthConnType::FileTransfer)
-        } else if self.port_forward_socket.is_some() {
+        } else if self.is_port_forward() {
             (2, AuthConnType::PortForward)
         } else if self.view_camera {
             (3, AuthConnType::ViewCamera)
@@ -1434,6 +1803,28 @@ impl Connection {
         self.post_conn_audit(
             json!({"peer": ((&self.lr.my_id, &self.lr.my_name)), "type": conn_type}),
         );
+        let conn_type_name = record_policy::CONN_TYPES[conn_type];
+        self.record_decision = record_policy::check(&self.lr.my_id, conn_type_name);
+        let record = self.record_decision.map(|d| d.record);
+        match auth_conn_type {
+            AuthConnType::Remote | AuthConnType::ViewCamera => {
+                video_service::add_record_session(self.inner.id(), record);
+            }
+            AuthConnType::FileTransfer | AuthConnType::PortForward if record == Some(true) => {
+                self.record_event_log =
+                    record_policy::new_event_log(conn_type_name).map(Mutex::new);
+            }
+            _ => {}
+        }
+        self.record_event(
+            "login",
+            json!({
+                "peer_id": self.lr.my_id,
+                "peer_name": self.lr.my_name,
+                "ip": self.ip,
+                "conn_type": conn_type,
+            }),
+        );
         #[allow(unused_mut)]
         let mut username = crate::platform::get_active_username();
         let mut res = LoginResponse::new();
@@ -1453,13 +1844,7 @@ impl Connection {
             pi.hostname = DEVICE_NAME.lock().unwrap().clone();
             pi.platform = "Android".into();
         }
-        #[cfg(all(target_os = "macos", not(feature = "unix-file-copy-paste")))]
-        let platform_additions = serde_json::Map::new();
-        #[cfg(any(
-            target_os = "windows",
-            target_os = "linux",
-            all(target_os = "macos", feature = "unix-file-copy-paste")
-        ))]
+        #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
         let mut platform_additions = serde_json::Map::new();
         #[cfg(target_os = "linux")]
         {
@@ -1511,12 +1896,21 @@ impl Connection {
             platform_additions.insert("support_view_camera".into(), json!(true));
         }
 
+        #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+        if !is_type_keystrokes_enabled() {
+            platform_additions.insert("type_keystrokes".into(), json!(false));
+        }
+
         #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
         if !platform_additions.is_empty() {
             pi.platform_additions = serde_json::to_string(&platform_additions).unwrap_or("".into());
         }
 
-        if self.port_forward_socket.is_some() {
+        if self.is_port_forward() {
+            let mut additions: serde_json::Map<String, Value> =
+                serde_json::from_str(&pi.platform_additions).unwrap_or_default();
+            additions.insert(super::tunnel::PLATFORM_ADDITION_MUX.into(), json!(true));
+            pi.platform_additions = serde_json::to_string(&additions).unwrap_or_default();
             let mut msg_out = Message::new();
             res.set_peer_info(pi);
             msg_out.set_login_response(res);
@@ -1712,11 +2106,19 @@ impl Connection {
     #[inline]
     fn is_remote(&self) -> bool {
         self.file_transfer.is_none()
-            && self.port_forward_socket.is_none()
+            && !self.is_port_forward()
             && !self.view_camera
             && !self.terminal
     }
 
+    #[inline]
+    fn is_port_forward(&self) -> bool {
+        self.port_forward_socket.is_some()
+            || self.reverse_forward_port.is_some()
+            || self.udp_forward.is_some()
+            || self.mux_forward
+    }
+
     fn try_sub_monitor_services(&mut self) {
         let is_remote = self.is_remote();
         if is_remote && !self.services_subed {
@@ -1748,6 +2150,11 @@ impl Connection {
                 self.auto_disconnect_timer = Self::get_auto_disconenct_timer();
                 s.try_add_primay_video_service();
                 s.add_connection(self.inner.clone(), &noperms);
+                if video_service::record_all_displays() {
+                    let sub = ConnInner::new(s.get_new_id(), None, None);
+                    s.add_record_all_displays(sub.clone());
+                    self.record_all_displays_sub = Some(sub);
+                }
             }
         }
     }
@@ -1851,6 +2258,18 @@ impl Connection {
             block_input: self.block_input,
             from_switch: self.from_switch,
         });
+        if authorized {
+            self.send_recording_banner();
+        }
+    }
+
+    // Tell the local user that the session is recorded, after the CM has the client.
+    fn send_recording_banner(&mut self) {
+        if let Some(decision) = self.record_decision {
+            if self.authorized && decision.banner {
+                self.send_to_cm(ipc::Data::RecordingBanner(true));
+            }
+        }
     }
 
     #[inline]
@@ -1892,10 +2311,46 @@ impl Connection {
         s.send((Instant::now(), Arc::new(msg_out))).ok();
     }
 
+    // Whether the input is dropped because a sensitive application is focused.
+    // The key and button releases are let through, not to leave them down.
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    fn is_blocked_by_sensitive_app(&mut self, is_release: bool) -> bool {
+        let Some(name) = sensitive_apps::focused() else {
+            self.sensitive_app = None;
+            return false;
+        };
+        if self.sensitive_app.as_ref() != Some(&name) {
+            log::info!(
+                "Remote input blocked, sensitive application focused: {}",
+                name
+            );
+            Self::post_alarm_audit(
+                AlarmAuditType::SensitiveAppInputBlocked,
+                json!({
+                    "ip": self.ip,
+                    "id": self.lr.my_id.clone(),
+                    "name": self.lr.my_name.clone(),
+                    "app": name,
+                }),
+            );
+            let mut msg_out = Message::new();
+            msg_out.set_message_box(MessageBox {
+                msgtype: "custom-nook-nocancel-hasclose".to_owned(),
+                title: "sensitive-app-input-blocked-title-tip".to_owned(),
+                text: "sensitive-app-input-blocked-text-tip".to_owned(),
+                link: "".to_owned(),
+                ..Default::default()
+            });
+            self.inner.send(msg_out.into());
+            self.sensitive_app = Some(name);
+        }
+        !is_release
+    }
+
     #[inline]
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
     fn input_mouse(
-        &self,
+        &mut self,
         msg: MouseEvent,
         conn_id: i32,
         username: String,
@@ -1903,6 +2358,13 @@ impl Connection {
         simulate: bool,
         show_cursor: bool,
     ) {
+        if simulate
+            && self.is_blocked_by_sensitive_app(
+                msg.mask & crate::input::MOUSE_TYPE_MASK == crate::input::MOUSE_TYPE_UP,
+            )
+        {
+            return;
+        }
         self.tx_input
             .send(MessageInput::Mouse(InputMouse {
                 msg,
@@ -1917,7 +2379,10 @@ impl Connection {
 
     #[inline]
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
-    fn input_pointer(&self, msg: PointerDeviceEvent, conn_id: i32) {
+    fn input_pointer(&mut self, msg: PointerDeviceEvent, conn_id: i32) {
+        if self.is_blocked_by_sensitive_app(false) {
+            return;
+        }
         self.tx_input
             .send(MessageInput::Pointer((msg, conn_id)))
             .ok();
@@ -1925,7 +2390,10 @@ impl Connection {
 
     #[inline]
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
-    fn input_key(&self, msg: KeyEvent, press: bool) {
+    fn input_key(&mut self, msg: KeyEvent, press: bool) {
+        if self.is_blocked_by_sensitive_app(!msg.down && !press) {
+            return;
+        }
         // to-do: if is the legacy mode, and the key is function key "LockScreen".
         // Switch to the primary display.
         self.tx_input.send(MessageInput::Key((msg, press))).ok();
@@ -2008,8 +2476,14 @@ impl Connection {
     fn permission(
         enable_prefix_option: &str,
         control_permissions: &Option<ControlPermissions>,
+        permission_profile: &Option<PermissionProfile>,
     ) -> bool {
         use hbb_common::rendezvous_proto::control_permissions::Permission;
+        if let Some(profile) = permission_profile {
+            if !profile.allows(enable_prefix_option) {
+                return false;
+            }
+        }
         if let Some(control_permissions) = control_permissions {
             let permission = match enable_prefix_option {
                 keys::OPTION_ENABLE_KEYBOARD => Some(Permission::keyboard),
@@ -2129,11 +2603,18 @@ impl Connection {
             if self.authorized {
                 return true;
             }
+            if !self.check_access_schedule().await {
+                return false;
+            }
+            if !self.check_peer_rules().await {
+                return false;
+            }
             match lr.union {
                 Some(login_request::Union::FileTransfer(ft)) => {
                     if !Self::permission(
                         keys::OPTION_ENABLE_FILE_TRANSFER,
                         &self.control_permissions,
+                        &self.permission_profile,
                     ) {
                         self.send_login_error("No permission of file transfer")
                             .await;
@@ -2143,7 +2624,11 @@ impl Connection {
                     self.file_transfer = Some((ft.dir, ft.show_hidden));
                 }
                 Some(login_request::Union::ViewCamera(_vc)) => {
-                    if !Self::permission(keys::OPTION_ENABLE_CAMERA, &self.control_permissions) {
+                    if !Self::permission(
+                        keys::OPTION_ENABLE_CAMERA,
+                        &self.control_permissions,
+                        &self.permission_profile,
+                    ) {
                         self.send_login_error("No permission of viewing camera")
                             .await;
                         sleep(1.).await;
@@ -2152,7 +2637,11 @@ impl Connection {
                     self.view_camera = true;
                 }
                 Some(login_request::Union::Terminal(terminal)) => {
-                    if !Self::permission(keys::OPTION_ENABLE_TERMINAL, &self.control_permissions) {
+                    if !Self::permission(
+                        keys::OPTION_ENABLE_TERMINAL,
+                        &self.control_permissions,
+                        &self.permission_profile,
+                    ) {
                         self.send_login_error("No permission of terminal").await;
                         sleep(1.).await;
                         return false;
@@ -2208,7 +2697,11 @@ impl Connection {
                         log::info!("Android target: bypass OPTION_ENABLE_TUNNEL check for port forwarding");
                         true
                     } else {
-                        Self::permission(keys::OPTION_ENABLE_TUNNEL, &self.control_permissions)
+                        Self::permission(
+                            keys::OPTION_ENABLE_TUNNEL,
+                            &self.control_permissions,
+                            &self.permission_profile,
+                        )
                     };
                     if !permitted {
                         self.send_login_error("No permission of IP tunneling").await;
@@ -2216,32 +2709,71 @@ impl Connection {
                         return false;
                     }
                     //#endregion
-                    let mut is_rdp = false;
-                    if pf.host == "RDP" && pf.port == 0 {
-                        pf.host = "localhost".to_owned();
-                        pf.port = 3389;
-                        is_rdp = true;
-                    }
-                    if pf.host.is_empty() {
-                        pf.host = "localhost".to_owned();
-                    }
-                    let mut addr = format!("{}:{}", pf.host, pf.port);
-                    self.port_forward_address = addr.clone();
-                    match timeout(3000, TcpStream::connect(&addr)).await {
-                        Ok(Ok(sock)) => {
-                            self.port_forward_socket = Some(Framed::new(sock, BytesCodec::new()));
+                    if pf.host == super::tunnel::REVERSE_HOST {
+                        if pf.port <= 0 || pf.port > u16::MAX as i32 {
+                            self.send_login_error(format!("Invalid port {}", pf.port))
+                                .await;
+                            return false;
                         }
-                        _ => {
-                            if is_rdp {
-                                addr = "RDP".to_owned();
-                            }
+                        let owner = (self.lr.my_id.clone(), self.lr.session_id);
+                        if super::tunnel::is_reverse_port_taken(pf.port, &owner) {
                             self.send_login_error(format!(
-                                "Failed to access remote {}, please make sure if it is open",
-                                addr
+                                "Port {} is already forwarded by another session",
+                                pf.port
                             ))
                             .await;
                             return false;
                         }
+                        self.port_forward_address = format!("127.0.0.1:{} (reverse)", pf.port);
+                        self.reverse_forward_port = Some(pf.port);
+                    } else if pf.host == super::tunnel::MUX_HOST {
+                        self.port_forward_address = pf.host.clone();
+                        self.mux_forward = true;
+                    } else if let Some(host) = super::tunnel::udp_host(&pf.host) {
+                        self.port_forward_address = format!("{}:{}", pf.host, pf.port);
+                        match timeout(3000, super::tunnel::resolve_udp_target(host, pf.port)).await
+                        {
+                            Ok(Ok(target)) => {
+                                self.udp_forward = Some(super::tunnel::UdpForward::new(target));
+                            }
+                            _ => {
+                                self.send_login_error(format!(
+                                    "Failed to access remote {}, please make sure if it is open",
+                                    self.port_forward_address
+                                ))
+                                .await;
+                                return false;
+                            }
+                        }
+                    } else {
+                        let mut is_rdp = false;
+                        if pf.host == "RDP" && pf.port == 0 {
+                            pf.host = "localhost".to_owned();
+                            pf.port = 3389;
+                            is_rdp = true;
+                        }
+                        if pf.host.is_empty() {
+                            pf.host = "localhost".to_owned();
+                        }
+                        let mut addr = format!("{}:{}", pf.host, pf.port);
+                        self.port_forward_address = addr.clone();
+                        match timeout(3000, TcpStream::connect(&addr)).await {
+                            Ok(Ok(sock)) => {
+                                self.port_forward_socket =
+                                    Some(Framed::new(sock, BytesCodec::new()));
+                            }
+                            _ => {
+                                if is_rdp {
+                                    addr = "RDP".to_owned();
+                                }
+                                self.send_login_error(format!(
+                                    "Failed to access remote {}, please make sure if it is open",
+                                    addr
+                                ))
+                                .await;
+                                return false;
+                            }
+                        }
                     }
                 }
                 _ => {
@@ -2431,7 +2963,7 @@ impl Connection {
                 }
             }
         } else if self.authorized {
-            if self.port_forward_socket.is_some() {
+            if self.is_port_forward() {
                 return true;
             }
             match msg.union {
@@ -2576,6 +3108,10 @@ impl Connection {
                     if self.is_authed_view_camera_conn() {
                         return true;
                     }
+                    if crate::keyboard::is_typed_key_event(&me) && !is_type_keystrokes_enabled() {
+                        log::debug!("Refuse the typed keystrokes");
+                        return true;
+                    }
                     if self.peer_keyboard_enabled() {
                         if is_enter(&me) {
                             CLICK_TIME.store(get_time(), Ordering::SeqCst);
@@ -2633,7 +3169,15 @@ impl Connection {
                     self.update_auto_disconnect_timer();
                 }
                 Some(message::Union::Clipboard(cb)) => {
-                    if self.clipboard {
+                    if self.clipboard
+                        && self
+                            .check_clipboard_policy(
+                                clipboard_policy::Direction::Inbound,
+                                std::slice::from_ref(&cb),
+                            )
+                            .is_none()
+                    {
+                        self.record_clipboard_event("receive", std::slice::from_ref(&cb));
                         #[cfg(not(any(target_os = "android", target_os = "ios")))]
                         update_clipboard(vec![cb], ClipboardSide::Host);
                         // ios as the controlled side is actually not supported for now.
@@ -2660,7 +3204,19 @@ impl Connection {
                         crate::clipboard::handle_msg_clipboard(cb);
                     }
                 }
-                Some(message::Union::MultiClipboards(_mcb)) => {
+                Some(message::Union::MultiClipboards(mut _mcb)) => {
+                    if self.clipboard {
+                        if let Some(clipboards) = self.check_clipboard_policy(
+                            clipboard_policy::Direction::Inbound,
+                            &_mcb.clipboards,
+                        ) {
+                            if clipboards.is_empty() {
+                                return true;
+                            }
+                            _mcb.clipboards = clipboards;
+                        }
+                        self.record_clipboard_event("receive", &_mcb.clipboards);
+                    }
                     #[cfg(not(any(target_os = "android", target_os = "ios")))]
                     if self.clipboard {
                         update_clipboard(_mcb.clipboards, ClipboardSide::Host);
@@ -3054,6 +3610,7 @@ impl Connection {
                         self.toggle_privacy_mode(t).await;
                     }
                     Some(misc::Union::ChatMessage(c)) => {
+                        self.record_event("chat", json!({"direction": "receive", "text": c.text}));
                         self.send_to_cm(ipc::Data::ChatMessage { text: c.text });
                         self.chat_unanswered = true;
                         self.update_auto_disconnect_timer();
@@ -3511,6 +4068,10 @@ impl Connection {
     async fn handle_switch_display(&mut self, s: SwitchDisplay) {
         let display_idx = s.display as usize;
         if self.display_idx != display_idx {
+            self.record_event(
+                "switch_display",
+                json!({"from": self.display_idx, "to": display_idx}),
+            );
             if let Some(server) = self.server.upgrade() {
                 self.switch_display_to(display_idx, server.clone());
 
@@ -3669,6 +4230,7 @@ impl Connection {
     }
 
     async fn toggle_privacy_mode(&mut self, t: TogglePrivacyMode) {
+        self.record_event("privacy_mode", json!({"on": t.on, "impl_key": t.impl_key}));
         if t.on {
             self.turn_on_privacy(t.impl_key).await;
         } else {
@@ -4136,6 +4698,9 @@ impl Connection {
             return;
         }
         self.closed = true;
+        if self.authorized {
+            self.record_event("close", json!({ "reason": reason }));
+        }
         // If voice A,B -> C, and A,B has voice call
         // B disconnects, C will reset the voice call input.
         //
@@ -4161,6 +4726,9 @@ impl Connection {
         let data = ipc::Data::Close;
         self.tx_to_cm.send(data).ok();
         self.port_forward_socket.take();
+        self.reverse_forward_port.take();
+        self.udp_forward.take();
+        self.mux_forward = false;
     }
 
     // The `reason` should be consistent with `check_if_retry` if not empty
@@ -4717,6 +5285,37 @@ impl Connection {
 
     #[cfg(not(target_os = "ios"))]
     async fn handle_terminal_action(&mut self, action: TerminalAction) -> ResultType<()> {
+        if let Some(terminal_action::Union::Data(data)) = &action.union {
+            if terminal_service::is_exec_id(data.terminal_id) {
+                if !self.terminal
+                    || !Self::permission(
+                        keys::OPTION_ENABLE_TERMINAL,
+                        &self.control_permissions,
+                        &self.permission_profile,
+                    )
+                {
+                    let mut response = TerminalResponse::new();
+                    let mut error = TerminalError::new();
+                    error.terminal_id = data.terminal_id;
+                    error.message = "No permission of terminal".to_owned();
+                    response.set_error(error);
+                    let mut msg_out = Message::new();
+                    msg_out.set_terminal_response(response);
+                    self.send(msg_out).await;
+                    return Ok(());
+                }
+                let command = if data.compressed {
+                    hbb_common::compress::decompress(&data.data)
+                } else {
+                    data.data.to_vec()
+                };
+                self.post_conn_audit(json!({
+                    "peer": ((&self.lr.my_id, &self.lr.my_name)),
+                    "action": "exec",
+                    "command": String::from_utf8_lossy(&command),
+                }));
+            }
+        }
         #[cfg(not(target_os = "android"))]
         let mut proxy = {
             debug_assert!(self.terminal_user_token.is_some());
@@ -4736,6 +5335,7 @@ impl Connection {
             Some(self.terminal_persistent),
             None,
         );
+        proxy.set_record(self.record_decision.map(|d| d.record));
 
         match proxy.handle_action(&action) {
             Ok(Some(response)) => {
@@ -4947,6 +5547,12 @@ pub enum AlarmAuditType {
     // MultipleLoginsAttemptsWithinOneMinute = 4,
     // MultipleLoginsAttemptsWithinOneHour = 5,
     ExceedIPv6PrefixAttempts = 6,
+    AuditEventsDropped = 7,
+    OutsideAccessSchedule = 8,
+    PeerIdDenied = 9,
+    RecordSkippedNoSpace = 10,
+    ClipboardBlocked = 11,
+    SensitiveAppInputBlocked = 12,
 }
 
 pub enum FileAuditType {
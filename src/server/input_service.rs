@@ -296,6 +296,19 @@ fn should_disable_numlock(evt: &KeyEvent) -> bool {
 pub const NAME_CURSOR: &'static str = "mouse_cursor";
 pub const NAME_POS: &'static str = "mouse_pos";
 pub const NAME_WINDOW_FOCUS: &'static str = "window_focus";
+/// Set to "N" to refuse the text typed as keystrokes by the controlling side.
+///
+/// A cooperative hint, not an enforcement: the typed keys are only recognized by the clicks
+/// (`press`) of map and translate mode that `client::type_keys` sends. The same text sent as
+/// key down and up pairs, by older or third-party clients or a replayed macro, can not be told
+/// apart from real typing and is let through.
+pub const OPTION_ENABLE_TYPE_KEYSTROKES: &str = "enable-type-keystrokes";
+
+#[inline]
+pub fn is_type_keystrokes_enabled() -> bool {
+    Config::get_option(OPTION_ENABLE_TYPE_KEYSTROKES) != "N"
+}
+
 #[derive(Clone)]
 pub struct MouseCursorService {
     pub sp: ServiceTmpl<MouseCursorSub>,
@@ -0,0 +1,208 @@
+// Allow or deny incoming connections by the RustDesk ID of the connecting peer, and limit
+// what an allowed peer may do with a named permission profile.
+//
+// `peer-rules` is a JSON list checked in order, the first rule whose `id` matches wins:
+//
+//   [{"id": "123456789", "profile": "admins"},
+//    {"id": "8*", "profile": "helpdesk"},
+//    {"id": "*", "action": "deny"}]
+//
+// `id` may contain `*` wildcards, `action` is `allow` (default) or `deny`.
+// A peer matching no rule is allowed with the global permissions.
+//
+// `permission-profiles` is a JSON object from profile name to the permissions it keeps:
+//
+//   {"helpdesk": [], "support": ["clipboard", "file"], "admins": ["*"]}
+//
+// A profile only takes permissions away, a permission it keeps is still subject to the
+// global options and the control permissions from the server.
+
+use hbb_common::{
+    bail,
+    config::{keys, Config},
+    log, ResultType,
+};
+use serde_derive::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+pub const OPTION_PEER_RULES: &str = "peer-rules";
+pub const OPTION_PERMISSION_PROFILES: &str = "permission-profiles";
+
+// Names used in `permission-profiles`, same as the permission names in `ipc::Data::SwitchPermission`
+// where both exist.
+const PERMISSIONS: [(&str, &str); 11] = [
+    ("keyboard", keys::OPTION_ENABLE_KEYBOARD),
+    ("clipboard", keys::OPTION_ENABLE_CLIPBOARD),
+    ("audio", keys::OPTION_ENABLE_AUDIO),
+    ("file", keys::OPTION_ENABLE_FILE_TRANSFER),
+    ("camera", keys::OPTION_ENABLE_CAMERA),
+    ("terminal", keys::OPTION_ENABLE_TERMINAL),
+    ("tunnel", keys::OPTION_ENABLE_TUNNEL),
+    ("restart", keys::OPTION_ENABLE_REMOTE_RESTART),
+    ("recording", keys::OPTION_ENABLE_RECORD_SESSION),
+    ("block_input", keys::OPTION_ENABLE_BLOCK_INPUT),
+    ("printer", keys::OPTION_ENABLE_REMOTE_PRINTER),
+];
+
+#[derive(Debug, Deserialize)]
+struct Rule {
+    id: String,
+    #[serde(default)]
+    action: String,
+    #[serde(default)]
+    profile: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PermissionProfile {
+    pub name: String,
+    // `enable-*` options kept by the profile.
+    options: HashSet<&'static str>,
+}
+
+impl PermissionProfile {
+    fn parse(name: &str, permissions: &[String]) -> ResultType<Self> {
+        let mut options = HashSet::new();
+        for permission in permissions {
+            if permission == "*" {
+                options.extend(PERMISSIONS.iter().map(|(_, option)| *option));
+                continue;
+            }
+            match PERMISSIONS.iter().find(|(n, _)| n == permission) {
+                Some((_, option)) => {
+                    options.insert(*option);
+                }
+                None => bail!("unknown permission \"{}\" in profile {}", permission, name),
+            }
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            options,
+        })
+    }
+
+    /// Whether the profile keeps the permission of `enable_prefix_option`.
+    /// Options which are not permissions are not affected.
+    pub fn allows(&self, enable_prefix_option: &str) -> bool {
+        !PERMISSIONS
+            .iter()
+            .any(|(_, option)| *option == enable_prefix_option)
+            || self.options.contains(enable_prefix_option)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Allow(Option<PermissionProfile>),
+    Deny,
+}
+
+// `*` matches any sequence of characters.
+pub(super) fn wildcard_match(pattern: &str, id: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == id;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !id.starts_with(first) || id.len() < first.len() + last.len() || !id.ends_with(last) {
+        return false;
+    }
+    let mut rest = &id[first.len()..id.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+fn decide(rules: &str, profiles: &str, peer_id: &str) -> ResultType<Decision> {
+    if rules.trim().is_empty() {
+        return Ok(Decision::Allow(None));
+    }
+    let rules: Vec<Rule> = serde_json::from_str(rules)?;
+    let Some(rule) = rules.iter().find(|r| wildcard_match(r.id.trim(), peer_id)) else {
+        return Ok(Decision::Allow(None));
+    };
+    match rule.action.to_lowercase().as_str() {
+        "" | "allow" => {}
+        "deny" => return Ok(Decision::Deny),
+        action => bail!("invalid action \"{}\"", action),
+    }
+    if rule.profile.is_empty() {
+        return Ok(Decision::Allow(None));
+    }
+    let profiles: HashMap<String, Vec<String>> = if profiles.trim().is_empty() {
+        Default::default()
+    } else {
+        serde_json::from_str(profiles)?
+    };
+    match profiles.get(&rule.profile) {
+        Some(permissions) => Ok(Decision::Allow(Some(PermissionProfile::parse(
+            &rule.profile,
+            permissions,
+        )?))),
+        None => bail!("unknown profile \"{}\"", rule.profile),
+    }
+}
+
+/// Check the peer against `peer-rules`. Invalid rules or profiles deny every connection.
+pub fn check(peer_id: &str) -> Decision {
+    let rules = Config::get_option(OPTION_PEER_RULES);
+    let profiles = Config::get_option(OPTION_PERMISSION_PROFILES);
+    match decide(&rules, &profiles, peer_id) {
+        Ok(decision) => decision,
+        Err(e) => {
+            log::error!("Invalid {}: {}", OPTION_PEER_RULES, e);
+            Decision::Deny
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("123", "123"));
+        assert!(!wildcard_match("123", "1234"));
+        assert!(wildcard_match("*", "123"));
+        assert!(wildcard_match("12*", "123"));
+        assert!(wildcard_match("*23", "123"));
+        assert!(wildcard_match("1*3*5", "12345"));
+        assert!(!wildcard_match("12*23", "123"));
+        assert!(!wildcard_match("1*6", "12345"));
+    }
+
+    #[test]
+    fn test_decide() {
+        let rules = r#"[{"id": "1", "profile": "admins"},
+            {"id": "8*", "profile": "helpdesk"},
+            {"id": "9*"},
+            {"id": "*", "action": "deny"}]"#;
+        let profiles = r#"{"helpdesk": ["clipboard"], "admins": ["*"]}"#;
+        assert_eq!(decide("", "", "1").unwrap(), Decision::Allow(None));
+        assert_eq!(decide(rules, profiles, "2").unwrap(), Decision::Deny);
+        assert_eq!(decide(rules, profiles, "9").unwrap(), Decision::Allow(None));
+
+        let Decision::Allow(Some(admins)) = decide(rules, profiles, "1").unwrap() else {
+            panic!("admins");
+        };
+        assert!(admins.allows(keys::OPTION_ENABLE_KEYBOARD));
+        assert!(admins.allows(keys::OPTION_ENABLE_TERMINAL));
+
+        let Decision::Allow(Some(helpdesk)) = decide(rules, profiles, "88").unwrap() else {
+            panic!("helpdesk");
+        };
+        assert!(helpdesk.allows(keys::OPTION_ENABLE_CLIPBOARD));
+        assert!(!helpdesk.allows(keys::OPTION_ENABLE_KEYBOARD));
+        assert!(!helpdesk.allows(keys::OPTION_ENABLE_FILE_TRANSFER));
+        assert!(helpdesk.allows(keys::OPTION_ENABLE_TRUSTED_DEVICES));
+
+        assert!(decide(rules, "{}", "1").is_err());
+        assert!(decide(rules, r#"{"admins": ["mouse"]}"#, "1").is_err());
+        assert!(decide(r#"[{"id": "*", "action": "block"}]"#, "", "1").is_err());
+    }
+}
@@ -0,0 +1,237 @@
+// Decide per session whether it is recorded, without asking the controlling side.
+//
+// `record-policy` is a JSON list checked in order, the first rule matching the session wins:
+//
+//   [{"id": "8*", "banner": true},
+//    {"in_address_book": false},
+//    {"conn_type": ["terminal", "file-transfer"], "schedule": "mon-fri 18:00-08:00"},
+//    {"record": false}]
+//
+// All conditions of a rule must match, a rule without conditions matches every session:
+// - `id`: the peer ID, `*` wildcards as in `peer-rules`.
+// - `conn_type`: `remote`, `file-transfer`, `port-forward`, `camera` or `terminal`.
+// - `in_address_book`: whether the peer is in the cached address book.
+// - `schedule`: an access schedule, e.g. `mon-fri 08:00-18:00`.
+//
+// `record` (default true) records the session: the screen of remote and camera sessions,
+// the terminal of terminal sessions, and the session events of the others. `banner` tells
+// the local user through the connection manager that the session is recorded.
+// Without a matching rule the `allow-auto-record-*` options apply.
+
+use super::{access_schedule, peer_rules::wildcard_match};
+use hbb_common::{
+    bail,
+    config::{Ab, Config},
+    log, ResultType,
+};
+use scrap::record::{EventLog, EVENTS_EXTENSION};
+use serde_derive::Deserialize;
+
+pub const OPTION_RECORD_POLICY: &str = "record-policy";
+
+pub const CONN_TYPES: [&str; 5] = [
+    "remote",
+    "file-transfer",
+    "port-forward",
+    "camera",
+    "terminal",
+];
+
+#[derive(Debug, Deserialize)]
+struct Rule {
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    conn_type: Vec<String>,
+    #[serde(default)]
+    in_address_book: Option<bool>,
+    #[serde(default)]
+    schedule: String,
+    #[serde(default = "default_record")]
+    record: bool,
+    #[serde(default)]
+    banner: bool,
+}
+
+fn default_record() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decision {
+    pub record: bool,
+    pub banner: bool,
+}
+
+impl Rule {
+    fn matches(
+        &self,
+        peer_id: &str,
+        conn_type: &str,
+        in_address_book: &mut dyn FnMut() -> bool,
+        schedule_matches: &dyn Fn(&str) -> ResultType<bool>,
+    ) -> ResultType<bool> {
+        if let Some(t) = self
+            .conn_type
+            .iter()
+            .find(|t| !CONN_TYPES.contains(&t.as_str()))
+        {
+            bail!("unknown conn_type \"{}\"", t);
+        }
+        if !self.id.trim().is_empty() && !wildcard_match(self.id.trim(), peer_id) {
+            return Ok(false);
+        }
+        if !self.conn_type.is_empty() && !self.conn_type.iter().any(|t| t == conn_type) {
+            return Ok(false);
+        }
+        if let Some(in_ab) = self.in_address_book {
+            if in_address_book() != in_ab {
+                return Ok(false);
+            }
+        }
+        if !self.schedule.trim().is_empty() && !schedule_matches(&self.schedule)? {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+fn decide(
+    policy: &str,
+    peer_id: &str,
+    conn_type: &str,
+    in_address_book: &mut dyn FnMut() -> bool,
+    schedule_matches: &dyn Fn(&str) -> ResultType<bool>,
+) -> ResultType<Option<Decision>> {
+    if policy.trim().is_empty() {
+        return Ok(None);
+    }
+    let rules: Vec<Rule> = serde_json::from_str(policy)?;
+    for rule in rules.iter() {
+        if rule.matches(peer_id, conn_type, in_address_book, schedule_matches)? {
+            return Ok(Some(Decision {
+                record: rule.record,
+                banner: rule.record && rule.banner,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+fn is_in_address_book(peer_id: &str) -> bool {
+    Ab::load()
+        .ab_entries
+        .iter()
+        .any(|ab| ab.peers.iter().any(|p| p.id == peer_id))
+}
+
+/// The decision for a session, `None` if no rule matches.
+/// An invalid policy records every session and shows the banner.
+pub fn check(peer_id: &str, conn_type: &str) -> Option<Decision> {
+    let policy = Config::get_option(OPTION_RECORD_POLICY);
+    let mut cached = None;
+    let mut in_address_book = || *cached.get_or_insert_with(|| is_in_address_book(peer_id));
+    match decide(
+        &policy,
+        peer_id,
+        conn_type,
+        &mut in_address_book,
+        &access_schedule::matches_now,
+    ) {
+        Ok(decision) => decision,
+        Err(e) => {
+            log::error!("Invalid {}: {}", OPTION_RECORD_POLICY, e);
+            Some(Decision {
+                record: true,
+                banner: true,
+            })
+        }
+    }
+}
+
+/// The session events log of a recorded session without screen, e.g. a file transfer,
+/// next to the incoming recordings.
+pub fn new_event_log(conn_type: &str) -> Option<EventLog> {
+    #[cfg(windows)]
+    let root = crate::platform::is_root();
+    #[cfg(not(windows))]
+    let root = false;
+    let dir = crate::ui_interface::video_save_directory(root);
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        log::error!("Failed to create {}: {}", dir, e);
+        return None;
+    }
+    if !super::prepare_record_dir(&dir) {
+        return None;
+    }
+    let file = format!(
+        "incoming_{}_{}_{}{}",
+        Config::get_id(),
+        hbb_common::chrono::Local::now().format("%Y%m%d%H%M%S%3f"),
+        conn_type,
+        EVENTS_EXTENSION
+    );
+    let filename = std::path::PathBuf::from(dir).join(file);
+    match EventLog::new(
+        &filename.to_string_lossy(),
+        &Config::get_option(scrap::record_crypt::OPTION_RECORD_PUBLIC_KEY),
+        None,
+    ) {
+        Ok(log) => {
+            log::info!("Record {} session events to {}", conn_type, log.filename());
+            Some(log)
+        }
+        Err(e) => {
+            log::error!("Failed to record {} session events: {}", conn_type, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decide() {
+        let policy = r#"[{"id": "8*", "banner": true},
+            {"in_address_book": false},
+            {"conn_type": ["terminal", "file-transfer"], "schedule": "night"},
+            {"record": false, "banner": true}]"#;
+        let schedule = |s: &str| -> ResultType<bool> {
+            match s {
+                "night" => Ok(true),
+                "day" => Ok(false),
+                _ => bail!("invalid schedule"),
+            }
+        };
+        let decide = |policy: &str, peer_id: &str, conn_type: &str, in_ab: bool| {
+            decide(policy, peer_id, conn_type, &mut || in_ab, &schedule)
+        };
+        let record = |banner| {
+            Some(Decision {
+                record: true,
+                banner,
+            })
+        };
+        let no_record = Some(Decision {
+            record: false,
+            banner: false,
+        });
+
+        assert_eq!(decide("", "1", "remote", true).unwrap(), None);
+        assert_eq!(decide(policy, "81", "remote", true).unwrap(), record(true));
+        assert_eq!(decide(policy, "1", "remote", false).unwrap(), record(false));
+        assert_eq!(
+            decide(policy, "1", "terminal", true).unwrap(),
+            record(false)
+        );
+        assert_eq!(decide(policy, "1", "remote", true).unwrap(), no_record);
+        let day = policy.replace("night", "day");
+        assert_eq!(decide(&day, "1", "terminal", true).unwrap(), no_record);
+
+        assert!(decide(r#"[{"conn_type": ["desktop"]}]"#, "1", "remote", true).is_err());
+        assert!(decide(r#"[{"schedule": "x"}]"#, "1", "remote", true).is_err());
+        assert!(decide("{}", "1", "remote", true).is_err());
+    }
+}
@@ -0,0 +1,220 @@
+// Block the remote input while a sensitive application is focused, e.g. a password manager,
+// a banking application or the UAC / polkit dialog.
+//
+// `sensitive-apps` is a JSON list, a rule matches when all its fields match:
+//
+//   [{"process": "KeePassXC"},
+//    {"process": "consent.exe", "name": "UAC"},
+//    {"process": "polkit-gnome-authentication-agent-1", "name": "polkit"},
+//    {"title": "(?i)online banking"},
+//    {"secure_desktop": true, "name": "UAC"}]
+//
+// `process` is the executable name of the focused window, case insensitive, `.exe` is optional.
+// `title` is a regular expression on the title of the focused window.
+// `secure_desktop` matches on Windows while the input desktop is not the default one, e.g. the
+// UAC prompt, whose windows are unknown from the other desktops. The lock and login screens are
+// on the same desktop, so such a rule also blocks unlocking the machine remotely.
+// `consent.exe` only matches when UAC prompts on the default desktop.
+// `name` is the name in the logs and the audit, `process`, `title` or `secure desktop` by default.
+//
+// Wayland does not tell which window is focused, so there the rules with only a `process`
+// match while the process is running, even in the background or the tray, and the other rules
+// never match. A warning is logged at startup for such rules.
+//
+// Invalid rules block all the input.
+
+use hbb_common::{bail, config::Config, log, regex::Regex, ResultType};
+use serde_derive::Deserialize;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub const OPTION_SENSITIVE_APPS: &str = "sensitive-apps";
+
+// The focused window is checked on input, at most once per interval.
+const CHECK_INTERVAL: Duration = Duration::from_millis(300);
+
+lazy_static::lazy_static! {
+    static ref LAST_CHECK: Mutex<Option<(Instant, Option<String>)>> = Default::default();
+}
+
+#[derive(Debug, Deserialize)]
+struct RuleConfig {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    process: String,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    secure_desktop: bool,
+}
+
+struct Rule {
+    name: String,
+    // Normalized by `process_name`, empty to match any process.
+    process: String,
+    title: Option<Regex>,
+    // Matches the secure desktop only, never a window.
+    secure_desktop: bool,
+}
+
+fn process_name(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.strip_suffix(".exe") {
+        Some(name) => name.to_owned(),
+        None => name,
+    }
+}
+
+impl Rule {
+    fn matches(&self, process: &str, title: &str) -> bool {
+        !self.secure_desktop
+            && (self.process.is_empty() || process_name(process) == self.process)
+            && self.title.as_ref().map_or(true, |r| r.is_match(title))
+    }
+}
+
+fn parse(s: &str) -> ResultType<Vec<Rule>> {
+    if s.trim().is_empty() {
+        return Ok(vec![]);
+    }
+    let configs: Vec<RuleConfig> = serde_json::from_str(s)?;
+    let mut rules = vec![];
+    for c in configs {
+        if c.secure_desktop {
+            if !c.process.is_empty() || !c.title.is_empty() {
+                bail!("secure_desktop rule with process or title");
+            }
+        } else if c.process.is_empty() && c.title.is_empty() {
+            bail!("rule without process or title");
+        }
+        let name = if !c.name.is_empty() {
+            c.name
+        } else if c.secure_desktop {
+            "secure desktop".to_owned()
+        } else if !c.process.is_empty() {
+            c.process.clone()
+        } else {
+            c.title.clone()
+        };
+        let title = if c.title.is_empty() {
+            None
+        } else {
+            Some(Regex::new(&c.title)?)
+        };
+        rules.push(Rule {
+            name,
+            process: process_name(&c.process),
+            title,
+            secure_desktop: c.secure_desktop,
+        });
+    }
+    Ok(rules)
+}
+
+#[cfg(target_os = "linux")]
+fn is_process_only(rule: &Rule) -> bool {
+    rule.title.is_none() && !rule.secure_desktop
+}
+
+/// Warn at startup that on Wayland the rules with only a `process` block all the input
+/// while the process is running.
+#[cfg(target_os = "linux")]
+pub fn warn_wayland_process_rules() {
+    if crate::platform::is_x11() {
+        return;
+    }
+    if let Ok(rules) = parse(&Config::get_option(OPTION_SENSITIVE_APPS)) {
+        for rule in rules.iter().filter(|r| is_process_only(r)) {
+            log::warn!(
+                "Wayland: sensitive app {} blocks all the input while {} is running",
+                rule.name,
+                rule.process
+            );
+        }
+    }
+}
+
+// The name of the first rule matching the focused window.
+fn check_rules(rules: &[Rule]) -> Option<String> {
+    if rules.is_empty() {
+        return None;
+    }
+    #[cfg(target_os = "linux")]
+    if !crate::platform::is_x11() {
+        let running: Vec<String> = crate::platform::get_process_names()
+            .iter()
+            .map(|n| process_name(n))
+            .collect();
+        return rules
+            .iter()
+            .find(|r| is_process_only(r) && running.contains(&r.process))
+            .map(|r| r.name.clone());
+    }
+    #[cfg(windows)]
+    if let Some(rule) = rules.iter().find(|r| r.secure_desktop) {
+        if !crate::platform::is_input_desktop_default() {
+            return Some(rule.name.clone());
+        }
+    }
+    let (process, title) = crate::platform::get_focused_window()?;
+    rules
+        .iter()
+        .find(|r| r.matches(&process, &title))
+        .map(|r| r.name.clone())
+}
+
+/// The name of the `sensitive-apps` rule matching the focused window, `None` if the input is allowed.
+pub fn focused() -> Option<String> {
+    let mut last = LAST_CHECK.lock().unwrap();
+    if let Some((time, name)) = last.as_ref() {
+        if time.elapsed() < CHECK_INTERVAL {
+            return name.clone();
+        }
+    }
+    let name = match parse(&Config::get_option(OPTION_SENSITIVE_APPS)) {
+        Ok(rules) => check_rules(&rules),
+        Err(e) => {
+            log::error!("Invalid {}: {}", OPTION_SENSITIVE_APPS, e);
+            Some(OPTION_SENSITIVE_APPS.to_owned())
+        }
+    };
+    *last = Some((Instant::now(), name.clone()));
+    name
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rules() {
+        assert!(parse("").unwrap().is_empty());
+        assert!(parse(r#"[{"name": "empty"}]"#).is_err());
+        assert!(parse(r#"[{"title": "("}]"#).is_err());
+
+        let rules = parse(
+            r#"[{"process": "KeePassXC"},
+                {"process": "consent.exe", "name": "UAC"},
+                {"process": "firefox", "title": "(?i)online banking"}]"#,
+        )
+        .unwrap();
+        let names: Vec<&str> = rules.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["KeePassXC", "UAC", "firefox"]);
+        assert!(rules[0].matches("keepassxc", ""));
+        assert!(rules[0].matches("KeePassXC.exe", "Passwords.kdbx"));
+        assert!(!rules[0].matches("keepass", ""));
+        assert!(rules[1].matches("Consent.exe", ""));
+        assert!(rules[1].matches("consent", ""));
+        assert!(rules[2].matches("firefox", "My Online Banking - Mozilla Firefox"));
+        assert!(!rules[2].matches("firefox", "News - Mozilla Firefox"));
+        assert!(!rules[2].matches("chrome", "Online Banking"));
+
+        let rules = parse(r#"[{"secure_desktop": true}]"#).unwrap();
+        assert_eq!(rules[0].name, "secure desktop");
+        assert!(!rules[0].matches("consent.exe", ""));
+        assert!(parse(r#"[{"secure_desktop": true, "process": "consent"}]"#).is_err());
+    }
+}
@@ -242,6 +242,20 @@ impl<T: Subscriber + From<ConnInner>> ServiceTmpl<T> {
         conn_ids
     }
 
+    // Send to the subscribers for which `to` returns true, return their ids.
+    pub fn send_video_frame_to(&self, msg: Message, to: impl Fn(i32) -> bool) -> HashSet<i32> {
+        let mut conn_ids = HashSet::new();
+        let mut lock = self.0.write().unwrap();
+        let msg = Arc::new(msg);
+        for s in lock.subscribes.values_mut() {
+            if to(s.id()) {
+                s.send(msg.clone());
+                conn_ids.insert(s.id());
+            }
+        }
+        conn_ids
+    }
+
     pub fn send_without(&self, msg: Message, sub: i32) {
         let mut lock = self.0.write().unwrap();
         let msg = Arc::new(msg);
@@ -43,8 +43,8 @@ use windows::{
     core::{PCWSTR, PWSTR},
     Win32::{
         Foundation::{
-            CloseHandle, LocalFree, ERROR_IO_PENDING, ERROR_PIPE_CONNECTED, HANDLE, HLOCAL,
-            INVALID_HANDLE_VALUE, WAIT_OBJECT_0,
+            CloseHandle, LocalFree, SetHandleInformation, ERROR_IO_PENDING, ERROR_PIPE_CONNECTED,
+            HANDLE, HANDLE_FLAGS, HANDLE_FLAG_INHERIT, HLOCAL, INVALID_HANDLE_VALUE, WAIT_OBJECT_0,
         },
         Security::{
             Authorization::{
@@ -63,12 +63,13 @@ use windows::{
         System::{
             Environment::{CreateEnvironmentBlock, DestroyEnvironmentBlock},
             Pipes::{
-                ConnectNamedPipe, CreateNamedPipeW, PIPE_READMODE_BYTE, PIPE_TYPE_BYTE, PIPE_WAIT,
+                ConnectNamedPipe, CreateNamedPipeW, CreatePipe, PIPE_READMODE_BYTE, PIPE_TYPE_BYTE,
+                PIPE_WAIT,
             },
             Threading::{
-                CreateEventW, CreateProcessAsUserW, WaitForSingleObject, CREATE_NO_WINDOW,
-                CREATE_UNICODE_ENVIRONMENT, PROCESS_CREATION_FLAGS, PROCESS_INFORMATION,
-                STARTUPINFOW,
+                CreateEventW, CreateProcessAsUserW, GetExitCodeProcess, TerminateProcess,
+                WaitForSingleObject, CREATE_NO_WINDOW, CREATE_UNICODE_ENVIRONMENT,
+                PROCESS_CREATION_FLAGS, PROCESS_INFORMATION, STARTF_USESTDHANDLES, STARTUPINFOW,
             },
             IO::{GetOverlappedResult, OVERLAPPED},
         },
@@ -776,6 +777,170 @@ pub fn launch_terminal_helper_with_token(
     })
 }
 
+/// A command started as the logged-in user with its own stdout and stderr pipes.
+///
+/// ConPTY can't be used for exec: it merges stderr into stdout, adds VT sequences,
+/// and hangs when created for a user token.
+pub struct ExecProcess {
+    handle: OwnedHandle,
+    pub pid: u32,
+}
+
+// The process handle is only used through `&mut self`.
+unsafe impl Send for ExecProcess {}
+
+impl ExecProcess {
+    pub fn try_wait(&mut self) -> std::io::Result<Option<i32>> {
+        if unsafe { WaitForSingleObject(self.handle.as_raw(), 0) } != WAIT_OBJECT_0 {
+            return Ok(None);
+        }
+        let mut code = 0u32;
+        unsafe { GetExitCodeProcess(self.handle.as_raw(), &mut code) }
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(Some(code as i32))
+    }
+
+    pub fn kill(&mut self) {
+        if let Ok(None) = self.try_wait() {
+            unsafe {
+                let _ = TerminateProcess(self.handle.as_raw(), 1);
+                let _ = WaitForSingleObject(self.handle.as_raw(), 1000);
+            }
+        }
+    }
+}
+
+/// Quote one argument of a command line the way `CommandLineToArgvW` splits it.
+fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_owned();
+    }
+    let mut quoted = String::from("\"");
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        if c == '\\' {
+            backslashes += 1;
+        } else {
+            if c == '"' {
+                quoted.extend(std::iter::repeat('\\').take(backslashes + 1));
+            }
+            backslashes = 0;
+        }
+        quoted.push(c);
+    }
+    quoted.extend(std::iter::repeat('\\').take(backslashes));
+    quoted.push('"');
+    quoted
+}
+
+/// Create an anonymous pipe, the end given to the child is inheritable, the other one is not.
+fn create_exec_pipe(child_writes: bool) -> Result<(OwnedHandle, OwnedHandle)> {
+    let sa = SECURITY_ATTRIBUTES {
+        nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+        lpSecurityDescriptor: ptr::null_mut(),
+        bInheritHandle: true.into(),
+    };
+    let mut read = HANDLE::default();
+    let mut write = HANDLE::default();
+    unsafe { CreatePipe(&mut read, &mut write, Some(&sa as *const _), 0) }
+        .map_err(|e| anyhow!("Failed to create pipe: {}", e))?;
+    let (read, write) = (OwnedHandle::new(read), OwnedHandle::new(write));
+    let ours = if child_writes { &read } else { &write };
+    unsafe { SetHandleInformation(ours.as_raw(), HANDLE_FLAG_INHERIT.0, HANDLE_FLAGS(0)) }
+        .map_err(|e| anyhow!("Failed to set pipe handle information: {}", e))?;
+    Ok((read, write))
+}
+
+/// Run `shell args` as the logged-in user, returns the process and the read ends of stdout and stderr.
+/// Stdin is an empty pipe.
+pub fn launch_exec_with_token(
+    user_token: UserToken,
+    shell: &str,
+    args: &[String],
+) -> Result<(ExecProcess, File, File)> {
+    let (stdin_read, stdin_write) = create_exec_pipe(false)?;
+    let (stdout_read, stdout_write) = create_exec_pipe(true)?;
+    let (stderr_read, stderr_write) = create_exec_pipe(true)?;
+
+    let cmd_line = std::iter::once(shell)
+        .chain(args.iter().map(|a| a.as_str()))
+        .map(quote_arg)
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut cmd_wide: Vec<u16> = OsStr::new(&cmd_line)
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect();
+
+    let mut si: STARTUPINFOW = unsafe { std::mem::zeroed() };
+    si.cb = std::mem::size_of::<STARTUPINFOW>() as u32;
+    si.dwFlags = STARTF_USESTDHANDLES;
+    si.hStdInput = stdin_read.as_raw();
+    si.hStdOutput = stdout_write.as_raw();
+    si.hStdError = stderr_write.as_raw();
+
+    let mut pi: PROCESS_INFORMATION = unsafe { std::mem::zeroed() };
+
+    let mut environment: *mut c_void = ptr::null_mut();
+    let env_ok = unsafe {
+        CreateEnvironmentBlock(
+            &mut environment,
+            Some(HANDLE(user_token.as_raw() as _)),
+            true,
+        )
+    }
+    .is_ok();
+    let _env_guard = if env_ok && !environment.is_null() {
+        Some(EnvironmentBlockGuard { ptr: environment })
+    } else {
+        if !env_ok {
+            log::warn!("Failed to create environment block, using default");
+        }
+        None
+    };
+    let creation_flags = CREATE_NO_WINDOW
+        | if env_ok {
+            CREATE_UNICODE_ENVIRONMENT
+        } else {
+            PROCESS_CREATION_FLAGS(0)
+        };
+
+    let result = unsafe {
+        CreateProcessAsUserW(
+            Some(HANDLE(user_token.as_raw() as _)),
+            PCWSTR::null(),
+            Some(PWSTR::from_raw(cmd_wide.as_mut_ptr())),
+            None,
+            None,
+            true, // Inherit the child ends of the pipes
+            creation_flags,
+            if env_ok { Some(environment) } else { None },
+            PCWSTR::null(),
+            &si,
+            &mut pi,
+        )
+    };
+    if let Err(e) = result {
+        log::error!("CreateProcessAsUserW failed: {}", e);
+        return Err(anyhow!("Failed to spawn command: {}", e));
+    }
+    unsafe {
+        let _ = CloseHandle(pi.hThread);
+    }
+    // Only the child holds the write ends now, so the reads get EOF once it exits.
+    drop((stdin_read, stdin_write, stdout_write, stderr_write));
+    let stdout = unsafe { File::from_raw_handle(stdout_read.into_raw().0 as RawHandle) };
+    let stderr = unsafe { File::from_raw_handle(stderr_read.into_raw().0 as RawHandle) };
+    Ok((
+        ExecProcess {
+            handle: OwnedHandle::new(pi.hProcess),
+            pid: pi.dwProcessId,
+        },
+        stdout,
+        stderr,
+    ))
+}
+
 /// Check if a helper process is still running.
 /// Returns true if the process is running, false if it has exited.
 pub fn is_helper_process_running(handle: HANDLE) -> bool {
@@ -0,0 +1,197 @@
+// Asciicast v2 recording of terminal sessions, https://docs.asciinema.org/manual/asciicast/v2/
+//
+// The files are saved next to the video recordings, encrypted and uploaded by `record_upload`
+// the same way.
+//
+// The input is only recorded with `record-terminal-input`, like `asciinema rec --stdin`,
+// as it holds the passwords typed at prompts without echo.
+
+use hbb_common::{
+    bail, chrono,
+    config::{self, Config},
+    log, ResultType,
+};
+use scrap::{
+    record::RecordState,
+    record_crypt::{self, EncryptedWriter},
+};
+use serde_json::json;
+use std::{
+    fs::File,
+    io::Write,
+    path::PathBuf,
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
+pub const OPTION_ALLOW_AUTO_RECORD_TERMINAL: &str = "allow-auto-record-terminal";
+pub const OPTION_RECORD_TERMINAL_INPUT: &str = "record-terminal-input";
+const UPLOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn is_enabled() -> bool {
+    config::option2bool(
+        OPTION_ALLOW_AUTO_RECORD_TERMINAL,
+        &Config::get_option(OPTION_ALLOW_AUTO_RECORD_TERMINAL),
+    )
+}
+
+pub struct TerminalRecorder {
+    file: Box<dyn Write + Send>,
+    filename: String,
+    start: Instant,
+    // Incomplete UTF-8 sequences at the end of the last output and input.
+    output_pending: Vec<u8>,
+    input_pending: Vec<u8>,
+    record_input: bool,
+    tx: Option<Sender<RecordState>>,
+    last_upload: Instant,
+}
+
+impl TerminalRecorder {
+    pub fn new(terminal_id: i32, cols: u16, rows: u16, shell: &str) -> ResultType<Self> {
+        #[cfg(windows)]
+        let root = crate::platform::is_root();
+        #[cfg(not(windows))]
+        let root = false;
+        let dir = crate::ui_interface::video_save_directory(root);
+        if !PathBuf::from(&dir).exists() {
+            std::fs::create_dir_all(&dir)?;
+        }
+        if !super::prepare_record_dir(&dir) {
+            bail!("Not enough disk space");
+        }
+        let public_key = record_crypt::parse_public_key(&Config::get_option(
+            record_crypt::OPTION_RECORD_PUBLIC_KEY,
+        ))?;
+        let filename = PathBuf::from(&dir)
+            .join(format!(
+                "incoming_{}{}terminal{}.cast{}",
+                Config::get_id(),
+                chrono::Local::now().format("_%Y%m%d%H%M%S%3f_"),
+                terminal_id,
+                if public_key.is_some() {
+                    record_crypt::EXTENSION
+                } else {
+                    ""
+                }
+            ))
+            .to_string_lossy()
+            .to_string();
+        let mut file: Box<dyn Write + Send> = match public_key {
+            Some(pk) => Box::new(EncryptedWriter::new(File::create(&filename)?, &pk)?),
+            None => Box::new(File::create(&filename)?),
+        };
+        let header = json!({
+            "version": 2,
+            "width": cols,
+            "height": rows,
+            "timestamp": chrono::Utc::now().timestamp(),
+            "env": { "SHELL": shell, "TERM": "xterm-256color" },
+            "title": format!("Terminal {}", terminal_id),
+        });
+        writeln!(file, "{}", header)?;
+        let tx = if crate::hbbs_http::record_upload::is_enable() {
+            let (tx, rx) = std::sync::mpsc::channel();
+            crate::hbbs_http::record_upload::run(rx);
+            tx.send(RecordState::NewFile(filename.clone())).ok();
+            Some(tx)
+        } else {
+            None
+        };
+        scrap::record::set_writing(&filename, true);
+        log::info!("Terminal {} recording to {}", terminal_id, filename);
+        Ok(Self {
+            file,
+            filename,
+            start: Instant::now(),
+            output_pending: Vec::new(),
+            input_pending: Vec::new(),
+            record_input: Config::get_option(OPTION_RECORD_TERMINAL_INPUT) == "Y",
+            tx,
+            last_upload: Instant::now(),
+        })
+    }
+
+    pub fn write_output(&mut self, data: &[u8]) {
+        let text = take_utf8(&mut self.output_pending, data);
+        self.write_event("o", &text);
+    }
+
+    pub fn write_input(&mut self, data: &[u8]) {
+        if !self.record_input {
+            return;
+        }
+        let text = take_utf8(&mut self.input_pending, data);
+        self.write_event("i", &text);
+    }
+
+    pub fn write_resize(&mut self, cols: u16, rows: u16) {
+        self.write_event("r", &format!("{}x{}", cols, rows));
+    }
+
+    fn write_event(&mut self, code: &str, data: &str) {
+        if data.is_empty() {
+            return;
+        }
+        let event = json!([self.start.elapsed().as_secs_f64(), code, data]);
+        if let Err(e) = writeln!(self.file, "{}", event) {
+            log::error!(
+                "Failed to write terminal recording {}: {}",
+                self.filename,
+                e
+            );
+            return;
+        }
+        if self.last_upload.elapsed() >= UPLOAD_INTERVAL {
+            self.last_upload = Instant::now();
+            self.file.flush().ok();
+            self.send_state(RecordState::NewFrame);
+        }
+    }
+
+    fn send_state(&self, state: RecordState) {
+        if let Some(tx) = &self.tx {
+            tx.send(state).ok();
+        }
+    }
+}
+
+impl Drop for TerminalRecorder {
+    fn drop(&mut self) {
+        self.file.flush().ok();
+        self.send_state(RecordState::WriteTail);
+        scrap::record::set_writing(&self.filename, false);
+        log::info!("Terminal recording {} finished", self.filename);
+    }
+}
+
+// Decode `data` appended to `pending`, an incomplete sequence at the end is kept for the next call.
+fn take_utf8(pending: &mut Vec<u8>, data: &[u8]) -> String {
+    pending.extend_from_slice(data);
+    let valid = match std::str::from_utf8(pending) {
+        Ok(_) => pending.len(),
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => pending.len(),
+    };
+    let rest = pending.split_off(valid);
+    let text = String::from_utf8_lossy(pending).to_string();
+    *pending = rest;
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_take_utf8() {
+        let mut pending = Vec::new();
+        let s = "a€b".as_bytes();
+        assert_eq!(take_utf8(&mut pending, &s[..2]), "a");
+        assert_eq!(pending.len(), 1);
+        assert_eq!(take_utf8(&mut pending, &s[2..]), "€b");
+        assert!(pending.is_empty());
+        assert_eq!(take_utf8(&mut pending, b"\xffc"), "\u{fffd}c");
+        assert!(pending.is_empty());
+    }
+}
@@ -1,4 +1,7 @@
-use super::*;
+use super::{
+    terminal_record::{self, TerminalRecorder},
+    *,
+};
 use hbb_common::{
     anyhow::{anyhow, Context, Result},
     compress,
@@ -30,9 +33,10 @@ use std::os::unix::process::ExitStatusExt;
 #[cfg(target_os = "windows")]
 use super::terminal_helper::{
     create_named_pipe_server, encode_helper_message, encode_resize_message,
-    is_helper_process_running, launch_terminal_helper_with_token, wait_for_pipe_connection,
-    HelperProcessGuard, OwnedHandle, SendableHandle, WinCloseHandle, WinTerminateProcess,
-    WinWaitForSingleObject, MSG_TYPE_DATA, PIPE_CONNECTION_TIMEOUT_MS, WIN_WAIT_OBJECT_0,
+    is_helper_process_running, launch_exec_with_token, launch_terminal_helper_with_token,
+    wait_for_pipe_connection, ExecProcess, HelperProcessGuard, OwnedHandle, SendableHandle,
+    WinCloseHandle, WinTerminateProcess, WinWaitForSingleObject, MSG_TYPE_DATA,
+    PIPE_CONNECTION_TIMEOUT_MS, WIN_WAIT_OBJECT_0,
 };
 
 const MAX_OUTPUT_BUFFER_SIZE: usize = 1024 * 1024; // 1MB per terminal
@@ -41,6 +45,7 @@ const MAX_SERVICES: usize = 100; // Maximum number of persistent terminal servic
 const SERVICE_IDLE_TIMEOUT: Duration = Duration::from_secs(3600); // 1 hour idle timeout
 const CHANNEL_BUFFER_SIZE: usize = 100; // Number of messages to buffer in channel
 const COMPRESS_THRESHOLD: usize = 512; // Compress terminal data larger than this
+const EXEC_STDERR_OFFSET: i32 = 1 << 30; // Exec stderr is sent on the negative exec id shifted by this
 
 lazy_static::lazy_static! {
     // Global registry of persistent terminal services indexed by service_id
@@ -188,6 +193,16 @@ pub struct ServiceMetadata {
     pub is_persistent: bool,
 }
 
+/// Whether the terminal id refers to a non-interactive exec request rather than a PTY.
+pub fn is_exec_id(terminal_id: i32) -> bool {
+    terminal_id < 0 && terminal_id > -EXEC_STDERR_OFFSET
+}
+
+/// The terminal id on which stderr of the exec request is sent.
+pub fn exec_stderr_id(terminal_id: i32) -> i32 {
+    terminal_id - EXEC_STDERR_OFFSET
+}
+
 /// Generate a new persistent service ID
 pub fn generate_service_id() -> String {
     format!("ts_{}", uuid::Uuid::new_v4())
@@ -240,6 +255,20 @@ fn get_default_shell() -> String {
     }
 }
 
+// Arguments to make the shell run a single command line.
+fn get_shell_exec_args(shell: &str, command: &str) -> Vec<String> {
+    let name = std::path::Path::new(shell)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let flag = match name.as_str() {
+        "cmd" => "/C",
+        "powershell" | "pwsh" => "-Command",
+        _ => "-c",
+    };
+    vec![flag.to_owned(), command.to_owned()]
+}
+
 pub fn is_service_specified_user(service_id: &str) -> Option<bool> {
     get_service(service_id).map(|s| s.lock().unwrap().is_specified_user)
 }
@@ -295,6 +324,7 @@ fn remove_service(service_id: &str) {
             let mut session = session.lock().unwrap();
             session.stop();
         }
+        service.lock().unwrap().exec_sessions.clear();
     }
 }
 
@@ -632,6 +662,7 @@ pub struct TerminalSession {
     // Handle to helper process for termination when session closes
     #[cfg(target_os = "windows")]
     helper_process_handle: Option<SendableHandle>,
+    recorder: Option<TerminalRecorder>,
 }
 
 impl TerminalSession {
@@ -660,6 +691,7 @@ impl TerminalSession {
             is_helper_mode: false,
             #[cfg(target_os = "windows")]
             helper_process_handle: None,
+            recorder: None,
         }
     }
 
@@ -667,6 +699,17 @@ impl TerminalSession {
         self.last_activity = Instant::now();
     }
 
+    // `record` is the decision of `record_policy`, `None` to follow the option.
+    fn start_recording(&mut self, terminal_id: i32, record: Option<bool>) {
+        if !record.unwrap_or_else(terminal_record::is_enabled) {
+            return;
+        }
+        match TerminalRecorder::new(terminal_id, self.cols, self.rows, &get_default_shell()) {
+            Ok(recorder) => self.recorder = Some(recorder),
+            Err(e) => log::error!("Failed to record terminal {}: {}", terminal_id, e),
+        }
+    }
+
     // This helper function is to ensure that the threads are joined before the child process is dropped.
     // Though this is not strictly necessary on macOS.
     fn stop(&mut self) {
@@ -768,6 +811,9 @@ impl TerminalSession {
             let _ = child.kill();
             add_to_reaper(child);
         }
+
+        // Finish the recording and upload the tail.
+        self.recorder = None;
     }
 }
 
@@ -778,10 +824,116 @@ impl Drop for TerminalSession {
     }
 }
 
+enum ExecChild {
+    Process(std::process::Child),
+    // `std::process::Command` can't start a process with a user token.
+    #[cfg(target_os = "windows")]
+    Token(ExecProcess),
+}
+
+impl ExecChild {
+    fn try_wait(&mut self) -> std::io::Result<Option<i32>> {
+        match self {
+            ExecChild::Process(child) => Ok(child.try_wait()?.map(|status| {
+                #[cfg(unix)]
+                if let Some(sig) = status.signal() {
+                    return -(sig as i32);
+                }
+                status.code().unwrap_or(-1)
+            })),
+            #[cfg(target_os = "windows")]
+            ExecChild::Token(child) => child.try_wait(),
+        }
+    }
+}
+
+/// A command started by an exec request, it lives until the command exits or is closed.
+struct ExecSession {
+    child: Option<ExecChild>,
+    // (is_stderr, data)
+    output_rx: Receiver<(bool, Vec<u8>)>,
+    reader_threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl ExecSession {
+    fn is_output_finished(&self) -> bool {
+        self.reader_threads.iter().all(|t| t.is_finished())
+    }
+}
+
+impl Drop for ExecSession {
+    fn drop(&mut self) {
+        match self.child.take() {
+            Some(ExecChild::Process(mut child)) => {
+                if let Ok(None) = child.try_wait() {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                }
+            }
+            #[cfg(target_os = "windows")]
+            Some(ExecChild::Token(mut child)) => {
+                child.kill();
+            }
+            None => {}
+        }
+    }
+}
+
+fn spawn_exec_reader(
+    terminal_id: i32,
+    is_stderr: bool,
+    mut reader: impl Read + Send + 'static,
+    output_tx: SyncSender<(bool, Vec<u8>)>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut buf = vec![0u8; 4096];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    // Block instead of dropping, the caller needs the complete output.
+                    if output_tx.send((is_stderr, buf[..n].to_vec())).is_err() {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    log::debug!("Exec {} read error: {}", terminal_id, e);
+                    break;
+                }
+            }
+        }
+    })
+}
+
+fn new_data_response(terminal_id: i32, data: Vec<u8>) -> TerminalResponse {
+    let mut response = TerminalResponse::new();
+    let mut terminal_data = TerminalData::new();
+    terminal_data.terminal_id = terminal_id;
+
+    // Compress data if it exceeds threshold
+    if data.len() > COMPRESS_THRESHOLD {
+        let compressed = compress::compress(&data);
+        if compressed.len() < data.len() {
+            terminal_data.data = bytes::Bytes::from(compressed);
+            terminal_data.compressed = true;
+        } else {
+            // Compression didn't help, send uncompressed
+            terminal_data.data = bytes::Bytes::from(data);
+        }
+    } else {
+        terminal_data.data = bytes::Bytes::from(data);
+    }
+
+    response.set_data(terminal_data);
+    response
+}
+
 /// Persistent terminal service that can survive connection drops
 pub struct PersistentTerminalService {
     service_id: String,
     sessions: HashMap<i32, Arc<Mutex<TerminalSession>>>,
+    exec_sessions: HashMap<i32, ExecSession>,
     pub created_at: Instant,
     last_activity: Instant,
     pub is_persistent: bool,
@@ -794,6 +946,7 @@ impl PersistentTerminalService {
         Self {
             service_id,
             sessions: HashMap::new(),
+            exec_sessions: HashMap::new(),
             created_at: Instant::now(),
             last_activity: Instant::now(),
             is_persistent,
@@ -839,12 +992,14 @@ impl PersistentTerminalService {
 
     /// Check if service has active terminals
     pub fn has_active_terminals(&self) -> bool {
-        !self.sessions.is_empty()
+        !self.sessions.is_empty() || !self.exec_sessions.is_empty()
     }
 
     fn reset_status(&mut self, is_persistent: bool) {
         self.is_persistent = is_persistent;
         self.needs_session_sync = true;
+        // The output of exec requests only belongs to the connection which started them.
+        self.exec_sessions.clear();
         for session in self.sessions.values() {
             let mut session = session.lock().unwrap();
             session.is_opened = false;
@@ -857,6 +1012,7 @@ pub struct TerminalServiceProxy {
     is_persistent: bool,
     #[cfg(target_os = "windows")]
     user_token: Option<UserToken>,
+    record: Option<bool>,
 }
 
 pub fn set_persistent(service_id: &str, is_persistent: bool) -> Result<()> {
@@ -886,9 +1042,15 @@ impl TerminalServiceProxy {
             is_persistent,
             #[cfg(target_os = "windows")]
             user_token: _user_token,
+            record: None,
         }
     }
 
+    /// Whether the terminals opened by this proxy are recorded, `None` to follow the option.
+    pub fn set_record(&mut self, record: Option<bool>) {
+        self.record = record;
+    }
+
     pub fn get_service_id(&self) -> &str {
         &self.service_id
     }
@@ -918,6 +1080,9 @@ impl TerminalServiceProxy {
                     .cloned();
                 self.handle_resize(session, resize)
             }
+            Some(terminal_action::Union::Data(data)) if is_exec_id(data.terminal_id) => {
+                self.handle_exec(&mut service.lock().unwrap(), data)
+            }
             Some(terminal_action::Union::Data(data)) => {
                 let session = service
                     .lock()
@@ -1195,6 +1360,8 @@ impl TerminalServiceProxy {
             session.pid
         );
 
+        session.start_recording(open.terminal_id, self.record);
+
         // Store the session
         service
             .sessions
@@ -1412,6 +1579,8 @@ impl TerminalServiceProxy {
             session.pid
         );
 
+        session.start_recording(open.terminal_id, self.record);
+
         service
             .sessions
             .insert(open.terminal_id, Arc::new(Mutex::new(session)));
@@ -1429,6 +1598,9 @@ impl TerminalServiceProxy {
             session.update_activity();
             session.rows = resize.rows as u16;
             session.cols = resize.cols as u16;
+            if let Some(recorder) = session.recorder.as_mut() {
+                recorder.write_resize(resize.cols as u16, resize.rows as u16);
+            }
 
             // Windows: helper 模式与直连 PTY 模式
             #[cfg(target_os = "windows")]
@@ -1509,11 +1681,119 @@ impl TerminalServiceProxy {
                     );
                 }
             }
+            if let Some(recorder) = session.recorder.as_mut() {
+                recorder.write_input(&data.data);
+            }
         }
 
         Ok(None)
     }
 
+    /// Run one command without a PTY.
+    ///
+    /// The command line is the data of the request. Stdout is sent back on the request id,
+    /// stderr on `exec_stderr_id`, and the exit code in `TerminalClosed`.
+    fn handle_exec(
+        &self,
+        service: &mut PersistentTerminalService,
+        data: &TerminalData,
+    ) -> Result<Option<TerminalResponse>> {
+        let terminal_id = data.terminal_id;
+        let mut response = TerminalResponse::new();
+        if service.exec_sessions.contains_key(&terminal_id) {
+            let mut error = TerminalError::new();
+            error.terminal_id = terminal_id;
+            error.message = format!("Exec {} is already running", terminal_id);
+            response.set_error(error);
+            return Ok(Some(response));
+        }
+        let command = if data.compressed {
+            compress::decompress(&data.data)
+        } else {
+            data.data.to_vec()
+        };
+        let command = String::from_utf8_lossy(&command).to_string();
+        let shell = get_default_shell();
+        log::info!(
+            "Exec {} for service {} with {}: {}",
+            terminal_id,
+            service.service_id,
+            shell,
+            command
+        );
+        let (output_tx, output_rx) = mpsc::sync_channel::<(bool, Vec<u8>)>(CHANNEL_BUFFER_SIZE);
+
+        #[cfg(target_os = "windows")]
+        if let Some(token) = &self.user_token {
+            let (child, stdout, stderr) =
+                launch_exec_with_token(*token, &shell, &get_shell_exec_args(&shell, &command))?;
+            let pid = child.pid;
+            service.exec_sessions.insert(
+                terminal_id,
+                ExecSession {
+                    child: Some(ExecChild::Token(child)),
+                    output_rx,
+                    reader_threads: vec![
+                        spawn_exec_reader(terminal_id, false, stdout, output_tx.clone()),
+                        spawn_exec_reader(terminal_id, true, stderr, output_tx),
+                    ],
+                },
+            );
+            return Ok(Some(Self::new_exec_opened(terminal_id, pid, service)));
+        }
+
+        let mut cmd = std::process::Command::new(&shell);
+        cmd.args(get_shell_exec_args(&shell, &command))
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped());
+        #[cfg(target_os = "windows")]
+        {
+            use std::os::windows::process::CommandExt;
+            const CREATE_NO_WINDOW: u32 = 0x08000000;
+            cmd.creation_flags(CREATE_NO_WINDOW);
+        }
+        let mut child = cmd.spawn().context("Failed to spawn command")?;
+        let mut reader_threads = Vec::new();
+        if let Some(stdout) = child.stdout.take() {
+            reader_threads.push(spawn_exec_reader(
+                terminal_id,
+                false,
+                stdout,
+                output_tx.clone(),
+            ));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            reader_threads.push(spawn_exec_reader(terminal_id, true, stderr, output_tx));
+        }
+        let pid = child.id();
+        service.exec_sessions.insert(
+            terminal_id,
+            ExecSession {
+                child: Some(ExecChild::Process(child)),
+                output_rx,
+                reader_threads,
+            },
+        );
+        Ok(Some(Self::new_exec_opened(terminal_id, pid, service)))
+    }
+
+    fn new_exec_opened(
+        terminal_id: i32,
+        pid: u32,
+        service: &PersistentTerminalService,
+    ) -> TerminalResponse {
+        let mut response = TerminalResponse::new();
+        let mut opened = TerminalOpened::new();
+        opened.terminal_id = terminal_id;
+        opened.success = true;
+        opened.message = "Command started".to_string();
+        opened.pid = pid;
+        opened.service_id = service.service_id.clone();
+        response.set_opened(opened);
+        response
+    }
+
     fn handle_close(
         &self,
         service: &mut PersistentTerminalService,
@@ -1521,6 +1801,18 @@ impl TerminalServiceProxy {
     ) -> Result<Option<TerminalResponse>> {
         let mut response = TerminalResponse::new();
 
+        if is_exec_id(close.terminal_id) {
+            // Dropping the session kills the command.
+            if service.exec_sessions.remove(&close.terminal_id).is_none() {
+                return Ok(None);
+            }
+            let mut closed = TerminalClosed::new();
+            closed.terminal_id = close.terminal_id;
+            closed.exit_code = -1;
+            response.set_closed(closed);
+            return Ok(Some(response));
+        }
+
         // Always close and remove the terminal
         if let Some(session_arc) = service.sessions.remove(&close.terminal_id) {
             let mut session = session_arc.lock().unwrap();
@@ -1602,30 +1894,14 @@ impl TerminalServiceProxy {
                 // Update buffer after reading
                 for data in &received_data {
                     session.output_buffer.append(data);
+                    if let Some(recorder) = session.recorder.as_mut() {
+                        recorder.write_output(data);
+                    }
                 }
 
                 // Process received data for responses
                 for data in received_data {
-                    let mut response = TerminalResponse::new();
-                    let mut terminal_data = TerminalData::new();
-                    terminal_data.terminal_id = terminal_id;
-
-                    // Compress data if it exceeds threshold
-                    if data.len() > COMPRESS_THRESHOLD {
-                        let compressed = compress::compress(&data);
-                        if compressed.len() < data.len() {
-                            terminal_data.data = bytes::Bytes::from(compressed);
-                            terminal_data.compressed = true;
-                        } else {
-                            // Compression didn't help, send uncompressed
-                            terminal_data.data = bytes::Bytes::from(data);
-                        }
-                    } else {
-                        terminal_data.data = bytes::Bytes::from(data);
-                    }
-
-                    response.set_data(terminal_data);
-                    responses.push(response);
+                    responses.push(new_data_response(terminal_id, data));
                 }
 
                 if has_activity {
@@ -1634,6 +1910,8 @@ impl TerminalServiceProxy {
             }
         }
 
+        responses.extend(Self::read_exec_outputs(&mut service.lock().unwrap()));
+
         // Clean up closed terminals (requires service lock briefly)
         if !closed_terminals.is_empty() {
             let mut sessions = service.lock().unwrap().sessions.clone();
@@ -1679,6 +1957,45 @@ impl TerminalServiceProxy {
         responses
     }
 
+    fn read_exec_outputs(service: &mut PersistentTerminalService) -> Vec<TerminalResponse> {
+        let mut responses = Vec::new();
+        let mut exited = Vec::new();
+        for (terminal_id, session) in service.exec_sessions.iter_mut() {
+            // Checked before draining, so no output can be left behind once the command exits.
+            let output_finished = session.is_output_finished();
+            while let Ok((is_stderr, data)) = session.output_rx.try_recv() {
+                let id = if is_stderr {
+                    exec_stderr_id(*terminal_id)
+                } else {
+                    *terminal_id
+                };
+                responses.push(new_data_response(id, data));
+            }
+            if !output_finished {
+                continue;
+            }
+            let exit_code = match session.child.as_mut().map(|c| c.try_wait()) {
+                Some(Ok(None)) => continue,
+                Some(Ok(Some(code))) => code,
+                _ => -1,
+            };
+            // Already reaped.
+            session.child = None;
+            exited.push((*terminal_id, exit_code));
+        }
+        for (terminal_id, exit_code) in exited {
+            log::info!("Exec {} exited with {}", terminal_id, exit_code);
+            service.exec_sessions.remove(&terminal_id);
+            let mut response = TerminalResponse::new();
+            let mut closed = TerminalClosed::new();
+            closed.terminal_id = terminal_id;
+            closed.exit_code = exit_code;
+            response.set_closed(closed);
+            responses.push(response);
+        }
+        responses
+    }
+
     /// Cleanup when connection drops
     pub fn on_disconnect(&self) {
         if !self.is_persistent {
@@ -0,0 +1,695 @@
+// Server side of the IP tunnel modes that do not fit the plain
+// "dial `host:port` once at login" port forwarding.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use hbb_common::{
+    bail, log, tcp, timeout,
+    tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{lookup_host, TcpListener, TcpStream, UdpSocket},
+        sync::mpsc,
+        task::JoinHandle,
+        time::{Duration, Instant},
+    },
+    ResultType,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, Weak},
+};
+
+/// `PortForward.host` of a reverse (remote-to-local) forward, `PortForward.port` is the
+/// port to listen on the loopback of the controlled side.
+pub const REVERSE_HOST: &str = "REVERSE";
+/// `PortForward.host` of a multiplexed forward, every logical stream names its own destination.
+pub const MUX_HOST: &str = "MUX";
+/// Key of `PeerInfo.platform_additions` telling the controlling side the peer supports `MUX_HOST`.
+pub const PLATFORM_ADDITION_MUX: &str = "port_forward_mux";
+/// Prefix of `PortForward.host` of a UDP forward, e.g. `udp:10.0.0.53`.
+pub const UDP_HOST_PREFIX: &str = "udp:";
+/// A UDP flow without any datagram in either direction for this long is dropped.
+pub const UDP_FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+const UDP_MAX_DATAGRAM: usize = 65536;
+/// Concurrent flows (and sockets) of one UDP forward, the least recently active flow is evicted beyond it.
+const UDP_MAX_FLOWS: usize = 256;
+
+/// The controlling session a reverse forward listener belongs to, peer ID and session ID.
+pub type ReverseOwner = (String, u64);
+
+lazy_static::lazy_static! {
+    static ref REVERSE_LISTENERS: Mutex<HashMap<u16, (ReverseOwner, Weak<TcpListener>)>> = Default::default();
+}
+
+/// Get the loopback listener of a reverse forward.
+///
+/// The sessions of one controlling session forwarding the same port share one listener and take turns
+/// to accept, the listener is closed once the last session holding it is gone.
+/// Another controlling session can not take the port over while it is bound, otherwise local
+/// connections could be tunnelled to a controller other than the one which asked for the forward.
+pub async fn reverse_listener(port: i32, owner: &ReverseOwner) -> ResultType<Arc<TcpListener>> {
+    if port <= 0 || port > u16::MAX as i32 {
+        bail!("Invalid reverse forward port {}", port);
+    }
+    let port = port as u16;
+    if let Some(listener) = get_reverse_listener(port, owner)? {
+        return Ok(listener);
+    }
+    let listener = Arc::new(tcp::new_listener(format!("127.0.0.1:{}", port), false).await?);
+    let mut lock = REVERSE_LISTENERS.lock().unwrap();
+    lock.retain(|_, (_, l)| l.strong_count() > 0);
+    if let Some((existing_owner, existing)) = lock.get(&port) {
+        if let Some(existing) = existing.upgrade() {
+            if existing_owner != owner {
+                bail!("Reverse forward port {} is used by another session", port);
+            }
+            return Ok(existing);
+        }
+    }
+    log::info!("reverse port forwarding listening on 127.0.0.1:{}", port);
+    lock.insert(port, (owner.clone(), Arc::downgrade(&listener)));
+    Ok(listener)
+}
+
+/// Whether the loopback port of a reverse forward is bound for another controlling session.
+pub fn is_reverse_port_taken(port: i32, owner: &ReverseOwner) -> bool {
+    if port <= 0 || port > u16::MAX as i32 {
+        return false;
+    }
+    get_reverse_listener(port as u16, owner).is_err()
+}
+
+fn get_reverse_listener(port: u16, owner: &ReverseOwner) -> ResultType<Option<Arc<TcpListener>>> {
+    let lock = REVERSE_LISTENERS.lock().unwrap();
+    match lock.get(&port) {
+        Some((existing_owner, l)) => match l.upgrade() {
+            Some(_) if existing_owner != owner => {
+                bail!("Reverse forward port {} is used by another session", port)
+            }
+            listener => Ok(listener),
+        },
+        None => Ok(None),
+    }
+}
+
+pub async fn accept_reverse(listener: &TcpListener) -> ResultType<(TcpStream, SocketAddr)> {
+    let (stream, addr) = listener.accept().await?;
+    if !addr.ip().is_loopback() {
+        bail!(
+            "Reject non-loopback reverse forward connection from {}",
+            addr
+        );
+    }
+    Ok((stream, addr))
+}
+
+#[inline]
+pub fn udp_host(host: &str) -> Option<&str> {
+    host.strip_prefix(UDP_HOST_PREFIX)
+}
+
+/// One datagram of a UDP forward in the raw peer stream: 4 bytes big endian flow id, then the payload.
+/// The stream framing keeps the datagram boundaries.
+pub fn encode_datagram(flow: u32, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(4 + payload.len());
+    buf.put_u32(flow);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+pub fn decode_datagram(frame: &[u8]) -> Option<(u32, &[u8])> {
+    if frame.len() < 4 {
+        return None;
+    }
+    let flow = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]);
+    Some((flow, &frame[4..]))
+}
+
+pub async fn resolve_udp_target(host: &str, port: i32) -> ResultType<SocketAddr> {
+    if port <= 0 || port > u16::MAX as i32 {
+        bail!("Invalid port {}", port);
+    }
+    let host = if host.is_empty() { "localhost" } else { host };
+    match lookup_host((host, port as u16)).await?.next() {
+        Some(addr) => Ok(addr),
+        None => bail!("Failed to resolve {}", host),
+    }
+}
+
+struct UdpFlow {
+    socket: Arc<UdpSocket>,
+    last_active: Instant,
+    task: JoinHandle<()>,
+}
+
+impl Drop for UdpFlow {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Controlled side of a UDP forward, one local socket per flow of the controlling side,
+/// so replies of the target go back to the source address they belong to.
+pub struct UdpForward {
+    target: SocketAddr,
+    flows: HashMap<u32, UdpFlow>,
+    tx: mpsc::UnboundedSender<(u32, Bytes)>,
+    rx: mpsc::UnboundedReceiver<(u32, Bytes)>,
+}
+
+impl UdpForward {
+    pub fn new(target: SocketAddr) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self {
+            target,
+            flows: Default::default(),
+            tx,
+            rx,
+        }
+    }
+
+    /// Send a datagram of `flow` to the target, the flow socket is created on its first datagram.
+    pub async fn send(&mut self, flow: u32, payload: &[u8]) -> ResultType<()> {
+        if !self.flows.contains_key(&flow) {
+            if self.flows.len() >= UDP_MAX_FLOWS {
+                self.remove_idle_flows();
+            }
+            if self.flows.len() >= UDP_MAX_FLOWS {
+                if let Some(oldest) = self
+                    .flows
+                    .iter()
+                    .min_by_key(|(_, f)| f.last_active)
+                    .map(|(id, _)| *id)
+                {
+                    log::debug!("too many udp forward flows, evict flow {}", oldest);
+                    self.flows.remove(&oldest);
+                }
+            }
+            let bind_addr = if self.target.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            let socket = Arc::new(UdpSocket::bind(bind_addr).await?);
+            socket.connect(self.target).await?;
+            log::debug!("new udp forward flow {} to {}", flow, self.target);
+            let task = tokio::spawn(Self::recv_loop(flow, socket.clone(), self.tx.clone()));
+            self.flows.insert(
+                flow,
+                UdpFlow {
+                    socket,
+                    last_active: Instant::now(),
+                    task,
+                },
+            );
+        }
+        if let Some(f) = self.flows.get_mut(&flow) {
+            f.last_active = Instant::now();
+            f.socket.send(payload).await?;
+        }
+        Ok(())
+    }
+
+    async fn recv_loop(flow: u32, socket: Arc<UdpSocket>, tx: mpsc::UnboundedSender<(u32, Bytes)>) {
+        let mut buf = vec![0u8; UDP_MAX_DATAGRAM];
+        loop {
+            match socket.recv(&mut buf).await {
+                Ok(n) => {
+                    if tx.send((flow, encode_datagram(flow, &buf[..n]))).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    // e.g. ICMP port unreachable of the connected socket, keep the flow until it is idle
+                    log::debug!("udp forward flow {} recv: {}", flow, err);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    }
+
+    /// The next datagram from the target, already encoded for the peer stream.
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        let (flow, frame) = self.rx.recv().await?;
+        if let Some(f) = self.flows.get_mut(&flow) {
+            f.last_active = Instant::now();
+        }
+        Some(frame)
+    }
+
+    pub fn remove_idle_flows(&mut self) {
+        self.flows.retain(|flow, f| {
+            let alive = f.last_active.elapsed() < UDP_FLOW_TIMEOUT;
+            if !alive {
+                log::debug!("udp forward flow {} timed out", flow);
+            }
+            alive
+        });
+    }
+}
+
+const MUX_OPEN: u8 = 1;
+const MUX_OPEN_OK: u8 = 2;
+const MUX_DATA: u8 = 3;
+const MUX_CLOSE: u8 = 4;
+const MUX_RESET: u8 = 5;
+const MUX_WINDOW: u8 = 6;
+/// Bytes a side may send on one stream before the other side acknowledges them with a window update.
+const MUX_INITIAL_WINDOW: usize = 256 * 1024;
+const MUX_MAX_CHUNK: usize = 16 * 1024;
+
+/// Frame of a multiplexed forward: 1 byte type, 4 bytes big endian stream id, then the payload.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MuxFrame {
+    /// Open a stream to `host:port`, only sent by the controlling side.
+    Open(u32, String),
+    OpenOk(u32),
+    Data(u32, Bytes),
+    /// No more data from the sender, the stream is gone once both sides closed.
+    Close(u32),
+    /// Abort the stream in both directions.
+    Reset(u32),
+    /// The receiver consumed this many bytes, the sender may send as many more.
+    Window(u32, u32),
+}
+
+impl MuxFrame {
+    pub fn encode(&self) -> Bytes {
+        let t = match self {
+            MuxFrame::Open(..) => MUX_OPEN,
+            MuxFrame::OpenOk(..) => MUX_OPEN_OK,
+            MuxFrame::Data(..) => MUX_DATA,
+            MuxFrame::Close(..) => MUX_CLOSE,
+            MuxFrame::Reset(..) => MUX_RESET,
+            MuxFrame::Window(..) => MUX_WINDOW,
+        };
+        let mut buf = BytesMut::with_capacity(9);
+        buf.put_u8(t);
+        buf.put_u32(self.id());
+        match self {
+            MuxFrame::Open(_, addr) => buf.put_slice(addr.as_bytes()),
+            MuxFrame::Data(_, data) => buf.put_slice(data),
+            MuxFrame::Window(_, n) => buf.put_u32(*n),
+            _ => {}
+        }
+        buf.freeze()
+    }
+
+    pub fn decode(frame: &[u8]) -> Option<Self> {
+        if frame.len() < 5 {
+            return None;
+        }
+        let mut buf = frame;
+        let t = buf.get_u8();
+        let id = buf.get_u32();
+        Some(match t {
+            MUX_OPEN => MuxFrame::Open(id, String::from_utf8(buf.to_vec()).ok()?),
+            MUX_OPEN_OK => MuxFrame::OpenOk(id),
+            MUX_DATA => MuxFrame::Data(id, Bytes::copy_from_slice(buf)),
+            MUX_CLOSE => MuxFrame::Close(id),
+            MUX_RESET => MuxFrame::Reset(id),
+            MUX_WINDOW if buf.len() >= 4 => MuxFrame::Window(id, buf.get_u32()),
+            _ => return None,
+        })
+    }
+
+    fn id(&self) -> u32 {
+        match self {
+            MuxFrame::Open(id, _)
+            | MuxFrame::OpenOk(id)
+            | MuxFrame::Data(id, _)
+            | MuxFrame::Close(id)
+            | MuxFrame::Reset(id)
+            | MuxFrame::Window(id, _) => *id,
+        }
+    }
+}
+
+/// Bytes written to the local socket of a stream once the peer accepted or refused to open it,
+/// e.g. the SOCKS5 reply.
+pub struct MuxOpenReply {
+    pub ok: Bytes,
+    pub err: Bytes,
+}
+
+enum MuxEvent {
+    Frame(MuxFrame),
+    Finished(u32),
+}
+
+/// Many logical TCP streams over one raw peer stream, with per-stream flow control.
+///
+/// The controlling side opens streams for its accepted sockets, the controlled side (`dial`)
+/// connects to the destination of every open request.
+pub struct Mux {
+    dial: bool,
+    next_id: u32,
+    streams: HashMap<u32, mpsc::UnboundedSender<MuxFrame>>,
+    tx: mpsc::UnboundedSender<MuxEvent>,
+    rx: mpsc::UnboundedReceiver<MuxEvent>,
+}
+
+impl Mux {
+    pub fn new(dial: bool) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self {
+            dial,
+            next_id: 0,
+            streams: Default::default(),
+            tx,
+            rx,
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+
+    /// Open a stream carrying `socket` to `addr` of the peer, returns the frame to send.
+    pub fn open(&mut self, addr: String, socket: TcpStream, reply: Option<MuxOpenReply>) -> Bytes {
+        self.next_id = self.next_id.wrapping_add(1);
+        let id = self.next_id;
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.streams.insert(id, tx);
+        tokio::spawn(Self::run_stream(
+            id,
+            MuxSocket::Accepted(socket, reply),
+            rx,
+            self.tx.clone(),
+        ));
+        MuxFrame::Open(id, addr).encode()
+    }
+
+    /// Handle a frame from the peer.
+    pub fn handle(&mut self, frame: &[u8]) {
+        let frame = match MuxFrame::decode(frame) {
+            Some(frame) => frame,
+            None => {
+                log::debug!("invalid mux frame");
+                return;
+            }
+        };
+        let id = frame.id();
+        if let MuxFrame::Open(_, addr) = &frame {
+            if !self.dial || self.streams.contains_key(&id) {
+                self.tx.send(MuxEvent::Frame(MuxFrame::Reset(id))).ok();
+                return;
+            }
+            let (tx, rx) = mpsc::unbounded_channel();
+            self.streams.insert(id, tx);
+            tokio::spawn(Self::run_stream(
+                id,
+                MuxSocket::Dial(addr.clone()),
+                rx,
+                self.tx.clone(),
+            ));
+            return;
+        }
+        match self.streams.get(&id) {
+            Some(tx) => {
+                tx.send(frame).ok();
+            }
+            None => {
+                if !matches!(frame, MuxFrame::Reset(_) | MuxFrame::Close(_)) {
+                    self.tx.send(MuxEvent::Frame(MuxFrame::Reset(id))).ok();
+                }
+            }
+        }
+    }
+
+    /// The next frame to send to the peer.
+    pub async fn next_frame(&mut self) -> Option<Bytes> {
+        loop {
+            match self.rx.recv().await? {
+                MuxEvent::Frame(frame) => return Some(frame.encode()),
+                MuxEvent::Finished(id) => {
+                    self.streams.remove(&id);
+                    log::debug!("mux stream {} finished, {} left", id, self.streams.len());
+                }
+            }
+        }
+    }
+
+    async fn run_stream(
+        id: u32,
+        socket: MuxSocket,
+        rx: mpsc::UnboundedReceiver<MuxFrame>,
+        tx: mpsc::UnboundedSender<MuxEvent>,
+    ) {
+        if let Err(err) = Self::pump(id, socket, rx, &tx).await {
+            log::debug!("mux stream {}: {}", id, err);
+            tx.send(MuxEvent::Frame(MuxFrame::Reset(id))).ok();
+        }
+        tx.send(MuxEvent::Finished(id)).ok();
+    }
+
+    async fn pump(
+        id: u32,
+        socket: MuxSocket,
+        mut rx: mpsc::UnboundedReceiver<MuxFrame>,
+        tx: &mpsc::UnboundedSender<MuxEvent>,
+    ) -> ResultType<()> {
+        let send = |frame: MuxFrame| tx.send(MuxEvent::Frame(frame)).ok();
+        let socket = match socket {
+            MuxSocket::Dial(addr) => {
+                let addr = if addr.starts_with(':') {
+                    format!("localhost{}", addr)
+                } else {
+                    addr
+                };
+                let socket = match timeout(3000, TcpStream::connect(&addr)).await {
+                    Ok(Ok(socket)) => socket,
+                    Ok(Err(err)) => bail!("Failed to connect to {}: {}", addr, err),
+                    Err(_) => bail!("Timeout connecting to {}", addr),
+                };
+                log::info!("mux stream {} connected to {}", id, addr);
+                send(MuxFrame::OpenOk(id));
+                socket
+            }
+            MuxSocket::Accepted(mut socket, reply) => {
+                let opened = loop {
+                    match rx.recv().await {
+                        Some(MuxFrame::OpenOk(_)) => break true,
+                        Some(MuxFrame::Reset(_)) | Some(MuxFrame::Close(_)) | None => break false,
+                        _ => {}
+                    }
+                };
+                if let Some(reply) = reply {
+                    socket
+                        .write_all(if opened { &reply.ok } else { &reply.err })
+                        .await?;
+                }
+                if !opened {
+                    return Ok(());
+                }
+                socket
+            }
+        };
+        let (mut reader, mut writer) = socket.into_split();
+        let mut buf = vec![0u8; MUX_MAX_CHUNK];
+        let mut credit = MUX_INITIAL_WINDOW;
+        let (mut local_closed, mut remote_closed) = (false, false);
+        while !(local_closed && remote_closed) {
+            tokio::select! {
+                res = reader.read(&mut buf[..credit.min(MUX_MAX_CHUNK)]), if credit > 0 && !local_closed => {
+                    let n = res?;
+                    if n == 0 {
+                        local_closed = true;
+                        send(MuxFrame::Close(id));
+                    } else {
+                        credit -= n;
+                        send(MuxFrame::Data(id, Bytes::copy_from_slice(&buf[..n])));
+                    }
+                }
+                frame = rx.recv() => {
+                    match frame {
+                        Some(MuxFrame::Data(_, data)) => {
+                            writer.write_all(&data).await?;
+                            send(MuxFrame::Window(id, data.len() as _));
+                        }
+                        Some(MuxFrame::Window(_, n)) => {
+                            credit = credit.saturating_add(n as _);
+                        }
+                        Some(MuxFrame::Close(_)) => {
+                            remote_closed = true;
+                            writer.shutdown().await.ok();
+                        }
+                        Some(MuxFrame::Reset(_)) | None => {
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+enum MuxSocket {
+    Dial(String),
+    Accepted(TcpStream, Option<MuxOpenReply>),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_datagram_framing() {
+        let frame = encode_datagram(0x01020304, b"dns");
+        assert_eq!(&frame[..4], &[1, 2, 3, 4]);
+        assert_eq!(decode_datagram(&frame), Some((0x01020304, &b"dns"[..])));
+        assert_eq!(
+            decode_datagram(&encode_datagram(7, b"")),
+            Some((7, &b""[..]))
+        );
+        assert_eq!(decode_datagram(&[0, 1, 2]), None);
+        assert_eq!(udp_host("udp:10.0.0.53"), Some("10.0.0.53"));
+        assert_eq!(udp_host("10.0.0.53"), None);
+    }
+
+    #[tokio::test]
+    async fn test_udp_flow_limit() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut udp = UdpForward::new(target.local_addr().unwrap());
+        for flow in 0..UDP_MAX_FLOWS as u32 + 10 {
+            udp.send(flow, b"ping").await.unwrap();
+        }
+        assert_eq!(udp.flows.len(), UDP_MAX_FLOWS);
+        assert!(udp.flows.contains_key(&(UDP_MAX_FLOWS as u32 + 9)));
+    }
+
+    #[tokio::test]
+    async fn test_reverse_listener_owner() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port() as i32;
+        let a = ("123456789".to_owned(), 1);
+        let b = ("987654321".to_owned(), 2);
+        let listener = reverse_listener(port, &a).await.unwrap();
+        assert!(Arc::ptr_eq(
+            &listener,
+            &reverse_listener(port, &a).await.unwrap()
+        ));
+        assert!(reverse_listener(port, &b).await.is_err());
+        assert!(is_reverse_port_taken(port, &b));
+        assert!(!is_reverse_port_taken(port, &a));
+        drop(listener);
+        assert!(!is_reverse_port_taken(port, &b));
+        assert!(reverse_listener(port, &b).await.is_ok());
+    }
+
+    #[test]
+    fn test_mux_framing() {
+        for frame in [
+            MuxFrame::Open(1, "localhost:80".to_owned()),
+            MuxFrame::OpenOk(2),
+            MuxFrame::Data(3, Bytes::from_static(b"GET / HTTP/1.1")),
+            MuxFrame::Data(3, Bytes::new()),
+            MuxFrame::Close(4),
+            MuxFrame::Reset(5),
+            MuxFrame::Window(u32::MAX, 65536),
+        ] {
+            assert_eq!(MuxFrame::decode(&frame.encode()), Some(frame));
+        }
+        assert_eq!(MuxFrame::decode(&[MUX_WINDOW, 0, 0, 0, 1]), None);
+        assert_eq!(MuxFrame::decode(&[0xff, 0, 0, 0, 1]), None);
+        assert_eq!(MuxFrame::decode(&[MUX_DATA, 0, 0]), None);
+    }
+
+    // Relay the frames between both ends of a multiplexed forward until every stream is gone.
+    async fn relay_mux(mut rx_open: mpsc::UnboundedReceiver<(String, TcpStream, MuxOpenReply)>) {
+        let mut client = Mux::new(false);
+        let mut server = Mux::new(true);
+        let mut listening = true;
+        loop {
+            tokio::select! {
+                res = rx_open.recv(), if listening => match res {
+                    Some((addr, socket, reply)) => server.handle(&client.open(addr, socket, Some(reply))),
+                    None => listening = false,
+                },
+                Some(frame) = client.next_frame() => server.handle(&frame),
+                Some(frame) = server.next_frame() => client.handle(&frame),
+                _ = tokio::time::sleep(Duration::from_millis(100)), if !listening => {}
+            }
+            if !listening && client.is_empty() && server.is_empty() {
+                break;
+            }
+        }
+    }
+
+    async fn accept_pair(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let (client, accepted) = tokio::join!(
+            TcpStream::connect(listener.local_addr().unwrap()),
+            listener.accept()
+        );
+        (client.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn test_mux_streams() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = echo.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = socket.into_split();
+                    tokio::io::copy(&mut reader, &mut writer).await.ok();
+                    writer.shutdown().await.ok();
+                });
+            }
+        });
+        let refused_addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reply = || MuxOpenReply {
+            ok: Bytes::from_static(b"ok"),
+            err: Bytes::from_static(b"err"),
+        };
+        let (tx_open, rx_open) = mpsc::unbounded_channel();
+        let relay = tokio::spawn(relay_mux(rx_open));
+
+        // Several times the window in both directions, the stream stalls without window updates.
+        let data: Vec<u8> = (0..MUX_INITIAL_WINDOW * 4).map(|i| i as u8).collect();
+        let (mut client, accepted) = accept_pair(&local).await;
+        tx_open.send((echo_addr, accepted, reply())).unwrap();
+        let mut ok = [0u8; 2];
+        client.read_exact(&mut ok).await.unwrap();
+        assert_eq!(&ok, b"ok");
+        let (mut reader, mut writer) = client.into_split();
+        let sent = data.clone();
+        let write = tokio::spawn(async move {
+            writer.write_all(&sent).await.unwrap();
+            writer.shutdown().await.unwrap();
+        });
+        let mut echoed = vec![];
+        timeout(10_000, reader.read_to_end(&mut echoed))
+            .await
+            .unwrap()
+            .unwrap();
+        write.await.unwrap();
+        assert_eq!(echoed, data);
+
+        let (mut client, accepted) = accept_pair(&local).await;
+        tx_open.send((refused_addr, accepted, reply())).unwrap();
+        let mut err = vec![];
+        timeout(10_000, client.read_to_end(&mut err))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(err, b"err");
+
+        // Both streams are closed on both ends.
+        drop(tx_open);
+        timeout(10_000, relay).await.unwrap().unwrap();
+    }
+}
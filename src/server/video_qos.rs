@@ -1,7 +1,7 @@
-use super::*;
+use super::{bandwidth_estimator::Bandwidth, *};
 use scrap::codec::{Quality, BR_BALANCED, BR_BEST, BR_SPEED};
 use std::{
-    collections::VecDeque,
+    collections::{HashSet, VecDeque},
     time::{Duration, Instant},
 };
 
@@ -25,8 +25,25 @@ adjust between FPS and ratio:
 
 delay:
     use delay minus RTT as the actual network delay
+
+bandwidth:
+    each connection measures its send queue and throughput, see `bandwidth_estimator`
+    When the send queue of a user is not growing, its delay is capped below DELAY_THRESHOLD_150MS,
+    a long link is not a congested one;
+    ratio is capped to the bitrate the most limited user carries, and decreased at once when a user is congested.
+
+simulcast:
+    With OPTION_VIDEO_SIMULCAST, the users with less than half the fps or bitrate of the others are moved
+    to a low tier, encoded by a second encoder with its own fps and ratio, so they do not slow down the others.
+    They are moved back when they reach 3/4 of it. fps and ratio above are adjusted for the high tier users only.
+    The low tier is given up if its encoder is over the CPU budget, until the users change.
 */
 
+/// Encode a second stream of lower quality for the users much slower than the others.
+pub const OPTION_VIDEO_SIMULCAST: &str = "video-simulcast";
+/// The percentage of a CPU core the low tier encoder of a display may use, 50 by default.
+pub const OPTION_SIMULCAST_CPU_BUDGET: &str = "simulcast-cpu-budget";
+
 // Constants
 pub const FPS: u32 = 30;
 pub const MIN_FPS: u32 = 1;
@@ -43,6 +60,8 @@ const HISTORY_DELAY_LEN: usize = 2;
 const ADJUST_RATIO_INTERVAL: usize = 3; // Adjust quality ratio every 3 seconds
 const DYNAMIC_SCREEN_THRESHOLD: usize = 2; // Allow increase quality ratio if encode more than 2 times in one second
 const DELAY_THRESHOLD_150MS: u32 = 150; // 150ms is the threshold for good network condition
+const LOW_TIER_ENTER: f32 = 0.5; // Move to the low tier below half the fps or bitrate of the high tier
+const LOW_TIER_LEAVE: f32 = 0.75; // Move back to the high tier from 3/4 of it
 
 #[derive(Default, Debug, Clone)]
 struct UserDelay {
@@ -52,6 +71,7 @@ struct UserDelay {
     rtt_calculator: RttCalculator,
     quick_increase_fps_count: usize,
     increase_fps_count: usize,
+    bandwidth: Option<Bandwidth>,
 }
 
 impl UserDelay {
@@ -83,6 +103,22 @@ impl UserDelay {
             DELAY_THRESHOLD_150MS
         }
     }
+
+    // The delay used to adjust fps and ratio.
+    fn qos_delay(&self) -> u32 {
+        let delay = self.avg_delay();
+        match self.bandwidth {
+            Some(bandwidth) if !bandwidth.congested => delay.min(DELAY_THRESHOLD_150MS - 1),
+            _ => delay,
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+enum Tier {
+    #[default]
+    High,
+    Low,
 }
 
 // User session data structure
@@ -93,6 +129,7 @@ struct UserData {
     quality: Option<(i64, Quality)>, // (time, quality)
     delay: UserDelay,
     record: bool,
+    tier: Tier,
 }
 
 #[derive(Default, Debug, Clone)]
@@ -104,6 +141,7 @@ struct DisplayData {
 // Main QoS controller structure
 pub struct VideoQoS {
     fps: u32,
+    low_fps: u32,
     ratio: f32,
     users: HashMap<i32, UserData>,
     displays: HashMap<String, DisplayData>,
@@ -111,12 +149,16 @@ pub struct VideoQoS {
     adjust_ratio_instant: Instant,
     abr_config: bool,
     new_user_instant: Instant,
+    simulcast_config: bool,
+    simulcast_disabled: bool,
+    assign_tiers_instant: Instant,
 }
 
 impl Default for VideoQoS {
     fn default() -> Self {
         VideoQoS {
             fps: FPS,
+            low_fps: INIT_FPS,
             ratio: BR_BALANCED,
             users: Default::default(),
             displays: Default::default(),
@@ -124,6 +166,9 @@ impl Default for VideoQoS {
             adjust_ratio_instant: Instant::now(),
             abr_config: true,
             new_user_instant: Instant::now(),
+            simulcast_config: false,
+            simulcast_disabled: false,
+            assign_tiers_instant: Instant::now(),
         }
     }
 }
@@ -180,12 +225,113 @@ impl VideoQoS {
     }
 }
 
+// Simulcast
+impl VideoQoS {
+    // Users of the low tier, empty if there is no low tier
+    pub fn low_tier_users(&self) -> HashSet<i32> {
+        self.users
+            .iter()
+            .filter(|u| u.1.tier == Tier::Low)
+            .map(|u| *u.0)
+            .collect()
+    }
+
+    pub fn low_tier_spf(&self) -> Duration {
+        Duration::from_secs_f32(1. / (self.low_fps.clamp(MIN_FPS, MAX_FPS) as f32))
+    }
+
+    // Below the ratio of the high tier, as much as the most limited low tier user carries
+    pub fn low_tier_ratio(&mut self) -> f32 {
+        let ratio = self.ratio();
+        let bitrate = self.bitrate();
+        let min_bandwidth = self
+            .users
+            .iter()
+            .filter(|u| u.1.tier == Tier::Low)
+            .filter_map(|u| u.1.delay.bandwidth.and_then(|b| b.kbps))
+            .min();
+        let v = match min_bandwidth {
+            Some(kbps) if bitrate > 0 => ratio * kbps as f32 / bitrate as f32,
+            _ => ratio * LOW_TIER_ENTER,
+        };
+        v.clamp(BR_MIN_HIGH_RESOLUTION.min(ratio), ratio)
+    }
+
+    // The part of a CPU core the low tier encoder of a display may use
+    pub fn simulcast_cpu_budget() -> f32 {
+        Config::get_option(OPTION_SIMULCAST_CPU_BUDGET)
+            .parse::<u32>()
+            .unwrap_or(50)
+            .clamp(1, 100) as f32
+            / 100.0
+    }
+
+    // The low tier encoder is over the CPU budget or failed
+    pub fn disable_simulcast(&mut self) {
+        self.simulcast_disabled = true;
+        self.assign_tiers();
+    }
+
+    fn assign_tiers(&mut self) {
+        self.assign_tiers_instant = Instant::now();
+        if !self.simulcast_config || self.simulcast_disabled || self.users.len() < 2 {
+            self.users.iter_mut().for_each(|u| u.1.tier = Tier::High);
+            self.adjust_fps();
+            return;
+        }
+        let best_fps = self
+            .users
+            .iter()
+            .map(|u| u.1.delay.fps.unwrap_or(INIT_FPS))
+            .max()
+            .unwrap_or(INIT_FPS);
+        let bitrate = self.bitrate();
+        let tiers: HashMap<i32, Tier> = self
+            .users
+            .iter()
+            .map(|(id, u)| {
+                let threshold = match u.tier {
+                    Tier::High => LOW_TIER_ENTER,
+                    Tier::Low => LOW_TIER_LEAVE,
+                };
+                let slow_fps =
+                    (u.delay.fps.unwrap_or(INIT_FPS) as f32) < best_fps as f32 * threshold;
+                let slow_bandwidth = match u.delay.bandwidth.and_then(|b| b.kbps) {
+                    Some(kbps) if bitrate > 0 => (kbps as f32) < bitrate as f32 * threshold,
+                    _ => false,
+                };
+                let tier = if slow_fps || slow_bandwidth {
+                    Tier::Low
+                } else {
+                    Tier::High
+                };
+                (*id, tier)
+            })
+            .collect();
+        // Nobody is slower than the others
+        let all_low = tiers.iter().all(|t| *t.1 == Tier::Low);
+        for (id, u) in self.users.iter_mut() {
+            let tier = if all_low { Tier::High } else { tiers[id] };
+            if u.tier != tier {
+                log::info!("move user {} to {:?} tier", id, tier);
+                u.tier = tier;
+            }
+        }
+        self.adjust_fps();
+    }
+}
+
 // User session management
 impl VideoQoS {
     // Initialize new user session
     pub fn on_connection_open(&mut self, id: i32) {
         self.users.insert(id, UserData::default());
         self.abr_config = Config::get_option("enable-abr") != "N";
+        self.simulcast_config = hbb_common::config::option2bool(
+            OPTION_VIDEO_SIMULCAST,
+            &Config::get_option(OPTION_VIDEO_SIMULCAST),
+        );
+        self.simulcast_disabled = false;
         self.new_user_instant = Instant::now();
     }
 
@@ -194,6 +340,9 @@ impl VideoQoS {
         self.users.remove(&id);
         if self.users.is_empty() {
             *self = Default::default();
+        } else {
+            self.simulcast_disabled = false;
+            self.assign_tiers();
         }
     }
 
@@ -262,11 +411,14 @@ impl VideoQoS {
         let mut adjust_ratio = false;
         if let Some(user) = self.users.get_mut(&id) {
             let delay = delay.max(10);
-            let old_avg_delay = user.delay.avg_delay();
+            let old_avg_delay = user.delay.qos_delay();
             user.delay.add_delay(delay);
-            let mut avg_delay = user.delay.avg_delay();
+            let mut avg_delay = user.delay.qos_delay();
             avg_delay = avg_delay.max(10);
-            let mut fps = self.fps;
+            let mut fps = match user.tier {
+                Tier::High => self.fps,
+                Tier::Low => self.low_fps,
+            };
 
             // Adaptive FPS adjustment based on network delay:
             if avg_delay < 50 {
@@ -333,6 +485,18 @@ impl VideoQoS {
         }
     }
 
+    pub fn user_bandwidth(&mut self, id: i32, bandwidth: Bandwidth) {
+        let mut became_congested = false;
+        if let Some(user) = self.users.get_mut(&id) {
+            became_congested =
+                bandwidth.congested && !user.delay.bandwidth.map(|b| b.congested).unwrap_or(false);
+            user.delay.bandwidth = Some(bandwidth);
+        }
+        if became_congested {
+            self.adjust_ratio(false);
+        }
+    }
+
     pub fn user_delay_response_elapsed(&mut self, id: i32, elapsed: u128) {
         if let Some(user) = self.users.get_mut(&id) {
             user.delay.response_delayed = elapsed > 2000;
@@ -359,6 +523,9 @@ impl VideoQoS {
         if let Some(display) = self.displays.get_mut(video_service_name) {
             display.send_counter += send_counter;
         }
+        if self.assign_tiers_instant.elapsed().as_secs() >= ADJUST_RATIO_INTERVAL as u64 {
+            self.assign_tiers();
+        }
         self.adjust_fps();
         let abr_enabled = self.in_vbr_state();
         if abr_enabled {
@@ -417,8 +584,13 @@ impl VideoQoS {
         if !self.in_vbr_state() {
             return;
         }
-        // Get maximum delay from all users
-        let max_delay = self.users.iter().map(|u| u.1.delay.avg_delay()).max();
+        // Get maximum delay from all high tier users
+        let max_delay = self
+            .users
+            .iter()
+            .filter(|u| u.1.tier == Tier::High)
+            .map(|u| u.1.delay.qos_delay())
+            .max();
         let Some(max_delay) = max_delay else {
             return;
         };
@@ -503,22 +675,39 @@ impl VideoQoS {
             }
         }
 
+        // Not more than the most limited high tier user carries
+        let min_bandwidth = self
+            .users
+            .iter()
+            .filter(|u| u.1.tier == Tier::High)
+            .filter_map(|u| u.1.delay.bandwidth.and_then(|b| b.kbps))
+            .min();
+        if let Some(kbps) = min_bandwidth {
+            if current_bitrate > 0 {
+                v = v.min(current_ratio * kbps as f32 / current_bitrate as f32);
+            }
+        }
+
         self.ratio = v.clamp(min, max);
         self.adjust_ratio_instant = Instant::now();
     }
 
     // Adjust fps based on network delay and user response time
     fn adjust_fps(&mut self) {
+        self.fps = self.tier_fps(Tier::High);
+        self.low_fps = self.tier_fps(Tier::Low);
+    }
+
+    fn tier_fps(&self, tier: Tier) -> u32 {
         let highest_fps = self.highest_fps();
-        // Get minimum fps from all users
-        let mut fps = self
-            .users
-            .iter()
+        let users = || self.users.iter().filter(|u| u.1.tier == tier);
+        // Get minimum fps from all users of the tier
+        let mut fps = users()
             .map(|u| u.1.delay.fps.unwrap_or(INIT_FPS))
             .min()
             .unwrap_or(INIT_FPS);
 
-        if self.users.iter().any(|u| u.1.delay.response_delayed) {
+        if users().any(|u| u.1.delay.response_delayed) {
             if fps > MIN_FPS + 1 {
                 fps = MIN_FPS + 1;
             }
@@ -532,7 +721,7 @@ impl VideoQoS {
         }
 
         // Ensure fps stays within valid range
-        self.fps = fps.clamp(MIN_FPS, highest_fps);
+        fps.clamp(MIN_FPS, highest_fps)
     }
 }
 
@@ -593,3 +782,70 @@ impl RttCalculator {
         None
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_qos(fps: &[u32]) -> VideoQoS {
+        let mut qos = VideoQoS::default();
+        qos.simulcast_config = true;
+        qos.new_user_instant = Instant::now() - Duration::from_secs(2);
+        for (id, fps) in fps.iter().enumerate() {
+            let mut user = UserData::default();
+            user.delay.fps = Some(*fps);
+            qos.users.insert(id as _, user);
+        }
+        qos
+    }
+
+    fn set_fps(qos: &mut VideoQoS, id: i32, fps: u32) {
+        qos.users.get_mut(&id).unwrap().delay.fps = Some(fps);
+        qos.assign_tiers();
+    }
+
+    #[test]
+    fn test_assign_tiers() {
+        let mut qos = new_qos(&[30, 28, 10]);
+        qos.assign_tiers();
+        assert_eq!(qos.low_tier_users(), HashSet::from([2]));
+        assert_eq!((qos.fps, qos.low_fps), (28, 10));
+
+        // Back from 3/4 of the best fps only.
+        set_fps(&mut qos, 2, 20);
+        assert_eq!(qos.low_tier_users(), HashSet::from([2]));
+        set_fps(&mut qos, 2, 24);
+        assert!(qos.low_tier_users().is_empty());
+        assert_eq!(qos.fps, 24);
+
+        // Bitrate the link of a user carries.
+        qos.store_bitrate(4000);
+        qos.users.get_mut(&1).unwrap().delay.bandwidth = Some(Bandwidth {
+            kbps: Some(1000),
+            congested: false,
+        });
+        qos.assign_tiers();
+        assert_eq!(qos.low_tier_users(), HashSet::from([1]));
+        let ratio = qos.ratio();
+        assert_eq!(qos.low_tier_ratio(), ratio / 4.0);
+
+        qos.disable_simulcast();
+        assert!(qos.low_tier_users().is_empty());
+    }
+
+    #[test]
+    fn test_no_low_tier() {
+        // Nobody is slower than the others.
+        let mut qos = new_qos(&[10, 8]);
+        qos.assign_tiers();
+        assert!(qos.low_tier_users().is_empty());
+        let mut qos = new_qos(&[30]);
+        qos.assign_tiers();
+        assert!(qos.low_tier_users().is_empty());
+        // Not enabled.
+        let mut qos = new_qos(&[30, 10]);
+        qos.simulcast_config = false;
+        qos.assign_tiers();
+        assert!(qos.low_tier_users().is_empty());
+    }
+}
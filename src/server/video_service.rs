@@ -47,6 +47,7 @@ use scrap::Capturer;
 use scrap::{
     aom::AomEncoderConfig,
     codec::{Encoder, EncoderCfg},
+    dirty_rect::DirtyTracker,
     record::{Recorder, RecorderContext},
     vpxcodec::{VpxEncoderConfig, VpxVideoCodecId},
     CodecFormat, Display, EncodeInput, TraitCapturer, TraitPixelBuffer,
@@ -61,6 +62,10 @@ use std::{
 };
 
 pub const OPTION_REFRESH: &'static str = "refresh";
+/// Record every display while a remote session is recorded, not only the viewed ones.
+pub const OPTION_RECORD_ALL_DISPLAYS: &str = "record-all-displays";
+/// Let the encoders skip the blocks unchanged since the last frame, on by default.
+pub const OPTION_ENABLE_DIRTY_RECT: &str = "enable-dirty-rect";
 
 type FrameFetchedNotifierSender = UnboundedSender<(i32, Option<Instant>)>;
 type FrameFetchedNotifierReceiver = Arc<TokioMutex<UnboundedReceiver<(i32, Option<Instant>)>>>;
@@ -78,6 +83,27 @@ lazy_static::lazy_static! {
     pub static ref IS_UAC_RUNNING: Arc<Mutex<bool>> = Default::default();
     pub static ref IS_FOREGROUND_WINDOW_ELEVATED: Arc<Mutex<bool>> = Default::default();
     static ref SCREENSHOTS: Mutex<HashMap<usize, Screenshot>> = Default::default();
+    // Incoming recorders of all displays, they also record the audio from `audio_service`.
+    static ref RECORDERS: Mutex<Vec<std::sync::Weak<Mutex<Option<Recorder>>>>> = Default::default();
+    // conn id -> login event, written at the start of every recording file until the connection closes.
+    static ref RECORD_LOGINS: Mutex<HashMap<i32, serde_json::Value>> = Default::default();
+    // The files of all displays are listed in the manifest of the session, which lasts while
+    // a connection is logged in.
+    static ref RECORD_SESSION: Mutex<Option<RecordSession>> = Default::default();
+    // conn id -> whether the remote or camera session is recorded by `record_policy`,
+    // `None` to follow the option.
+    static ref RECORD_POLICY_SESSIONS: Mutex<HashMap<i32, Option<bool>>> = Default::default();
+}
+
+struct RecordSession {
+    id: String,
+    manifest: String,
+}
+
+impl Drop for RecordSession {
+    fn drop(&mut self) {
+        scrap::record::set_writing(&self.manifest, false);
+    }
 }
 
 struct Screenshot {
@@ -571,10 +597,7 @@ fn run(vs: VideoService) -> ResultType<()> {
     let mut video_qos = VIDEO_QOS.lock().unwrap();
     let mut spf = video_qos.spf();
     let mut quality = video_qos.ratio();
-    let record_incoming = config::option2bool(
-        "allow-auto-record-incoming",
-        &Config::get_option("allow-auto-record-incoming"),
-    );
+    let record_incoming = should_record_incoming();
     let client_record = video_qos.record();
     drop(video_qos);
     let (mut encoder, encoder_cfg, codec_format, use_i444, recorder) = match setup_encoder(
@@ -611,6 +634,13 @@ fn run(vs: VideoService) -> ResultType<()> {
     };
     #[cfg(feature = "vram")]
     c.set_output_texture(encoder.input_texture());
+    let mut low_tier = setup_low_tier(&encoder, &encoder_cfg, use_i444);
+    let mut dirty_tracker =
+        if encoder.support_active_map() && Config::get_option(OPTION_ENABLE_DIRTY_RECT) != "N" {
+            Some(DirtyTracker::default())
+        } else {
+            None
+        };
     #[cfg(target_os = "android")]
     if vs.source.is_monitor() {
         if let Err(e) = check_change_scale(encoder.is_hardware()) {
@@ -657,6 +687,7 @@ fn run(vs: VideoService) -> ResultType<()> {
         check_uac_switch(c.privacy_mode_id, c._capturer_privacy_mode_id)?;
         check_qos(
             &mut encoder,
+            &mut low_tier,
             &mut quality,
             &mut spf,
             client_record,
@@ -671,6 +702,10 @@ fn run(vs: VideoService) -> ResultType<()> {
             log::info!("switch to refresh");
             bail!("SWITCH");
         }
+        if record_incoming != should_record_incoming() {
+            log::info!("switch due to incoming record changed");
+            bail!("SWITCH");
+        }
         if codec_format != Encoder::negotiated_codec() {
             log::info!(
                 "switch due to codec changed, {:?} -> {:?}",
@@ -764,6 +799,15 @@ fn run(vs: VideoService) -> ResultType<()> {
                         }
                     }
 
+                    if let (Some(tracker), scrap::Frame::PixelBuffer(f)) =
+                        (dirty_tracker.as_mut(), &frame)
+                    {
+                        let map = tracker.update(f);
+                        if let Err(e) = encoder.set_active_map(map.as_ref()) {
+                            log::error!("Failed to set active map: {e:?}");
+                            dirty_tracker = None;
+                        }
+                    }
                     let frame = frame.to(encoder.yuvfmt(), &mut yuv, &mut mid_data)?;
                     let send_conn_ids = handle_one_frame(
                         display_idx,
@@ -771,6 +815,7 @@ fn run(vs: VideoService) -> ResultType<()> {
                         frame,
                         ms,
                         &mut encoder,
+                        low_tier.as_ref(),
                         recorder.clone(),
                         &mut encode_fail_counter,
                         &mut first_frame,
@@ -778,6 +823,9 @@ fn run(vs: VideoService) -> ResultType<()> {
                         capture_height,
                     )?;
                     frame_controller.set_send(now, send_conn_ids);
+                    if let Some(low_tier) = low_tier.as_mut() {
+                        handle_low_tier_frame(display_idx, &sp, low_tier, &yuv, now, ms)?;
+                    }
                     send_counter += 1;
                 }
                 #[cfg(windows)]
@@ -830,6 +878,7 @@ fn run(vs: VideoService) -> ResultType<()> {
                             EncodeInput::YUV(&yuv),
                             ms,
                             &mut encoder,
+                            low_tier.as_ref(),
                             recorder.clone(),
                             &mut encode_fail_counter,
                             &mut first_frame,
@@ -837,6 +886,9 @@ fn run(vs: VideoService) -> ResultType<()> {
                             capture_height,
                         )?;
                         frame_controller.set_send(now, send_conn_ids);
+                        if let Some(low_tier) = low_tier.as_mut() {
+                            handle_low_tier_frame(display_idx, &sp, low_tier, &yuv, now, ms)?;
+                        }
                         send_counter += 1;
                     }
                 }
@@ -872,12 +924,20 @@ fn run(vs: VideoService) -> ResultType<()> {
                 check_privacy_mode_changed(&sp, display_idx, &c)?;
             }
             frame_controller.try_wait_next(&mut fetched_conn_ids, 300);
-            // break if all connections have received current frame
-            if fetched_conn_ids.len() >= frame_controller.send_conn_ids.len() {
+            // break if all connections have received current frame,
+            // the low tier users are not waited for
+            if frame_controller
+                .send_conn_ids
+                .iter()
+                .all(|id| fetched_conn_ids.contains(id))
+            {
                 break;
             }
         }
         DISPLAY_CONN_IDS.lock().unwrap().remove(&display_idx);
+        if let Some(low_tier) = low_tier.as_mut() {
+            low_tier.pending.retain(|id| !fetched_conn_ids.contains(id));
+        }
 
         let elapsed = now.elapsed();
         // may need to enable frame(timeout)
@@ -938,6 +998,7 @@ fn setup_encoder(
     bool,
     Arc<Mutex<Option<Recorder>>>,
 )> {
+    Encoder::set_recording(client_record || record_incoming);
     let encoder_cfg = get_encoder_config(
         &c,
         name.to_string(),
@@ -954,6 +1015,60 @@ fn setup_encoder(
     Ok((encoder, encoder_cfg, codec_format, use_i444, recorder))
 }
 
+// The second encoder of simulcast, for the low tier users of `VideoQoS`.
+struct LowTier {
+    encoder: Encoder,
+    conn_ids: HashSet<i32>,
+    ratio: f32,
+    spf: Duration,
+    last: Option<Instant>,
+    // The users which have not fetched the last frame yet
+    pending: HashSet<i32>,
+    // Encoding time since the last check of the CPU budget
+    encode_time: Duration,
+    encode_fail_counter: usize,
+}
+
+fn setup_low_tier(encoder: &Encoder, encoder_cfg: &EncoderCfg, use_i444: bool) -> Option<LowTier> {
+    let mut video_qos = VIDEO_QOS.lock().unwrap();
+    let conn_ids = video_qos.low_tier_users();
+    if conn_ids.is_empty() {
+        return None;
+    }
+    // The low tier encodes the yuv of the frame again.
+    #[cfg(feature = "vram")]
+    if encoder.input_texture() {
+        log::info!("disable simulcast with texture input");
+        video_qos.disable_simulcast();
+        return None;
+    }
+    let ratio = video_qos.low_tier_ratio();
+    let spf = video_qos.low_tier_spf();
+    drop(video_qos);
+    let mut low_encoder = match Encoder::new(encoder_cfg.clone(), use_i444) {
+        Ok(encoder) => encoder,
+        Err(e) => {
+            log::error!("Failed to create low tier encoder: {e:?}");
+            VIDEO_QOS.lock().unwrap().disable_simulcast();
+            return None;
+        }
+    };
+    if encoder.support_changing_quality() {
+        allow_err!(low_encoder.set_quality(ratio));
+    }
+    log::info!("low tier users: {conn_ids:?}, quality: {ratio:?}, spf: {spf:?}");
+    Some(LowTier {
+        encoder: low_encoder,
+        conn_ids,
+        ratio,
+        spf,
+        last: None,
+        pending: Default::default(),
+        encode_time: Duration::ZERO,
+        encode_fail_counter: 0,
+    })
+}
+
 fn get_encoder_config(
     c: &CapturerInfo,
     _name: String,
@@ -1040,6 +1155,8 @@ fn get_recorder(
     let root = crate::platform::is_root();
     #[cfg(not(windows))]
     let root = false;
+    let dir = crate::ui_interface::video_save_directory(root);
+    let record_incoming = record_incoming && super::prepare_record_dir(&dir);
     let recorder = if record_incoming {
         use crate::hbbs_http::record_upload;
 
@@ -1050,22 +1167,137 @@ fn get_recorder(
         } else {
             None
         };
+        let id = Config::get_id();
+        let (session_id, manifest) = {
+            let mut session = RECORD_SESSION.lock().unwrap();
+            let session = session.get_or_insert_with(|| {
+                let session_id = hbb_common::chrono::Local::now()
+                    .format("%Y%m%d%H%M%S%3f")
+                    .to_string();
+                let manifest = scrap::record::manifest_filename(&dir, true, &id, &session_id);
+                scrap::record::set_writing(&manifest, true);
+                RecordSession {
+                    id: session_id,
+                    manifest,
+                }
+            });
+            (session.id.clone(), session.manifest.clone())
+        };
         Recorder::new(RecorderContext {
             server: true,
-            id: Config::get_id(),
-            dir: crate::ui_interface::video_save_directory(root),
+            id,
+            dir,
             display_idx,
             camera,
             tx,
+            public_key: Config::get_option(scrap::record_crypt::OPTION_RECORD_PUBLIC_KEY),
+            session_id,
+            manifest,
         })
         .map_or(Default::default(), |r| Arc::new(Mutex::new(Some(r))))
     } else {
         Default::default()
     };
+    if record_incoming {
+        if let Some(r) = recorder.lock().unwrap().as_mut() {
+            for (conn_id, login) in RECORD_LOGINS.lock().unwrap().iter() {
+                r.set_session_event(&login_event_key(*conn_id), Some(login.clone()));
+            }
+        }
+        let mut recorders = RECORDERS.lock().unwrap();
+        recorders.retain(|r| r.strong_count() > 0);
+        recorders.push(Arc::downgrade(&recorder));
+    }
 
     recorder
 }
 
+pub fn record_audio(frame: &AudioFrame) {
+    let recorders = RECORDERS.lock().unwrap();
+    for recorder in recorders.iter().filter_map(|r| r.upgrade()) {
+        recorder
+            .lock()
+            .unwrap()
+            .as_mut()
+            .map(|r| r.write_audio(frame));
+    }
+}
+
+/// Set the recording decision of a remote or camera session, before it subscribes.
+pub fn add_record_session(conn_id: i32, record: Option<bool>) {
+    RECORD_POLICY_SESSIONS
+        .lock()
+        .unwrap()
+        .insert(conn_id, record);
+}
+
+pub fn remove_record_session(conn_id: i32) {
+    RECORD_POLICY_SESSIONS.lock().unwrap().remove(&conn_id);
+}
+
+// The screen is recorded if any session is.
+fn should_record_incoming() -> bool {
+    let option = config::option2bool(
+        "allow-auto-record-incoming",
+        &Config::get_option("allow-auto-record-incoming"),
+    );
+    let sessions = RECORD_POLICY_SESSIONS.lock().unwrap();
+    if sessions.is_empty() {
+        return option;
+    }
+    sessions.values().any(|record| record.unwrap_or(option))
+}
+
+/// Whether the idle displays are also recorded, see `OPTION_RECORD_ALL_DISPLAYS`.
+pub fn record_all_displays() -> bool {
+    should_record_incoming()
+        && config::option2bool(
+            OPTION_RECORD_ALL_DISPLAYS,
+            &Config::get_option(OPTION_RECORD_ALL_DISPLAYS),
+        )
+}
+
+fn login_event_key(conn_id: i32) -> String {
+    format!("login_{}", conn_id)
+}
+
+/// Write a session event of a connection to the incoming recordings of all displays.
+/// A `login` event is repeated in every new recording file until the `close` event.
+pub fn record_event(conn_id: i32, mut event: serde_json::Value) {
+    event["conn_id"] = serde_json::json!(conn_id);
+    event["time"] = serde_json::json!(hbb_common::chrono::Local::now().to_rfc3339());
+    let key = login_event_key(conn_id);
+    let typ = event["type"].as_str().unwrap_or_default().to_owned();
+    match typ.as_str() {
+        "login" => {
+            RECORD_LOGINS.lock().unwrap().insert(conn_id, event.clone());
+        }
+        "close" => {
+            let mut logins = RECORD_LOGINS.lock().unwrap();
+            logins.remove(&conn_id);
+            if logins.is_empty() {
+                *RECORD_SESSION.lock().unwrap() = None;
+            }
+        }
+        _ => {}
+    }
+    let recorders = RECORDERS.lock().unwrap();
+    for recorder in recorders.iter().filter_map(|r| r.upgrade()) {
+        let mut recorder = recorder.lock().unwrap();
+        let Some(r) = recorder.as_mut() else {
+            continue;
+        };
+        match typ.as_str() {
+            "login" => r.set_session_event(&key, Some(event.clone())),
+            "close" => {
+                r.write_event(event.clone());
+                r.set_session_event(&key, None);
+            }
+            _ => r.write_event(event.clone()),
+        }
+    }
+}
+
 #[cfg(target_os = "android")]
 fn check_change_scale(hardware: bool) -> ResultType<()> {
     use hbb_common::config::keys::OPTION_ENABLE_ANDROID_SOFTWARE_ENCODING_HALF_SCALE as SCALE_SOFT;
@@ -1132,6 +1364,7 @@ fn handle_one_frame(
     frame: EncodeInput,
     ms: i64,
     encoder: &mut Encoder,
+    low_tier: Option<&LowTier>,
     recorder: Arc<Mutex<Option<Recorder>>>,
     encode_fail_counter: &mut usize,
     first_frame: &mut bool,
@@ -1161,7 +1394,12 @@ fn handle_one_frame(
                 .unwrap()
                 .as_mut()
                 .map(|r| r.write_message(&msg, width, height));
-            send_conn_ids = sp.send_video_frame(msg);
+            send_conn_ids = match low_tier {
+                Some(low_tier) => {
+                    sp.send_video_frame_to(msg, |id| !low_tier.conn_ids.contains(&id))
+                }
+                None => sp.send_video_frame(msg),
+            };
         }
         Err(e) => {
             *encode_fail_counter += 1;
@@ -1197,6 +1435,53 @@ fn handle_one_frame(
     Ok(send_conn_ids)
 }
 
+// Encode the frame again for the low tier users, at the fps of the low tier.
+fn handle_low_tier_frame(
+    display: usize,
+    sp: &GenericService,
+    low_tier: &mut LowTier,
+    yuv: &[u8],
+    now: Instant,
+    ms: i64,
+) -> ResultType<()> {
+    if let Some(last) = low_tier.last {
+        let elapsed = now.saturating_duration_since(last);
+        // Wait for the slow users like `VideoFrameController`, without blocking the others.
+        if elapsed < low_tier.spf || (!low_tier.pending.is_empty() && elapsed.as_secs() < 3) {
+            return Ok(());
+        }
+    }
+    let encode_begin = Instant::now();
+    let res = low_tier
+        .encoder
+        .encode_to_message(EncodeInput::YUV(yuv), ms);
+    low_tier.encode_time += encode_begin.elapsed();
+    match res {
+        Ok(mut vf) => {
+            low_tier.encode_fail_counter = 0;
+            low_tier.last = Some(now);
+            vf.display = display as _;
+            let mut msg = Message::new();
+            msg.set_video_frame(vf);
+            let conn_ids = &low_tier.conn_ids;
+            low_tier.pending = sp.send_video_frame_to(msg, |id| conn_ids.contains(&id));
+        }
+        Err(e) => {
+            low_tier.encode_fail_counter += 1;
+            log::error!(
+                "low tier encode fail: {e:?}, times: {}",
+                low_tier.encode_fail_counter
+            );
+            if low_tier.encode_fail_counter >= 3 {
+                VIDEO_QOS.lock().unwrap().disable_simulcast();
+                log::error!("switch due to low tier encoding fails");
+                bail!("SWITCH");
+            }
+        }
+    }
+    Ok(())
+}
+
 #[inline]
 pub fn refresh() {
     #[cfg(target_os = "android")]
@@ -1309,6 +1594,7 @@ pub fn make_display_changed_msg(
 
 fn check_qos(
     encoder: &mut Encoder,
+    low_tier: &mut Option<LowTier>,
     ratio: &mut f32,
     spf: &mut Duration,
     client_record: bool,
@@ -1335,7 +1621,39 @@ fn check_qos(
         log::info!("switch due to record changed");
         bail!("SWITCH");
     }
-    if second_instant.elapsed() > Duration::from_secs(1) {
+    // The users switching tiers need a new key frame.
+    let low_tier_users = video_qos.low_tier_users();
+    let tiers_changed = match low_tier.as_ref() {
+        Some(low_tier) => low_tier.conn_ids != low_tier_users,
+        None => !low_tier_users.is_empty(),
+    };
+    if tiers_changed {
+        log::info!("switch due to simulcast tiers changed");
+        bail!("SWITCH");
+    }
+    if let Some(low_tier) = low_tier.as_mut() {
+        low_tier.spf = video_qos.low_tier_spf();
+    }
+    let elapsed = second_instant.elapsed();
+    if elapsed > Duration::from_secs(1) {
+        if let Some(low_tier) = low_tier.as_mut() {
+            let budget = VideoQoS::simulcast_cpu_budget();
+            if low_tier.encode_time.as_secs_f32() > elapsed.as_secs_f32() * budget {
+                log::info!(
+                    "switch due to low tier encoder over the CPU budget, {:?} in {:?}",
+                    low_tier.encode_time,
+                    elapsed
+                );
+                video_qos.disable_simulcast();
+                bail!("SWITCH");
+            }
+            low_tier.encode_time = Duration::ZERO;
+            let low_ratio = video_qos.low_tier_ratio();
+            if low_tier.ratio != low_ratio && low_tier.encoder.support_changing_quality() {
+                low_tier.ratio = low_ratio;
+                allow_err!(low_tier.encoder.set_quality(low_ratio));
+            }
+        }
         *second_instant = Instant::now();
         video_qos.update_display_data(&name, *send_counter);
         *send_counter = 0;
@@ -70,6 +70,13 @@ impl InvokeUiCM for SciterHandler {
         );
     }
 
+    fn update_recording_banner(&self, client: &crate::ui_cm_interface::Client) {
+        self.call(
+            "updateRecordingBanner",
+            &make_args!(client.id, client.recording_banner),
+        );
+    }
+
     fn file_transfer_log(&self, _action: &str, _log: &str) {}
 }
 
@@ -145,6 +145,7 @@ pub struct Client {
     pub from_switch: bool,
     pub in_voice_call: bool,
     pub incoming_voice_call: bool,
+    pub recording_banner: bool,
     #[serde(skip)]
     #[cfg(not(any(target_os = "ios")))]
     tx: UnboundedSender<Data>,
@@ -193,6 +194,8 @@ pub trait InvokeUiCM: Send + Clone + 'static + Sized {
 
     fn update_voice_call_state(&self, client: &Client);
 
+    fn update_recording_banner(&self, client: &Client);
+
     fn file_transfer_log(&self, action: &str, log: &str);
 }
 
@@ -253,6 +256,7 @@ impl<T: InvokeUiCM> ConnectionManager<T> {
             tx,
             in_voice_call: false,
             incoming_voice_call: false,
+            recording_banner: false,
         };
         CLIENTS
             .write()
@@ -339,6 +343,13 @@ impl<T: InvokeUiCM> ConnectionManager<T> {
             self.ui_handler.update_voice_call_state(client);
         }
     }
+
+    fn recording_banner(&self, id: i32, show: bool) {
+        if let Some(client) = CLIENTS.write().unwrap().get_mut(&id) {
+            client.recording_banner = show;
+            self.ui_handler.update_recording_banner(client);
+        }
+    }
 }
 
 #[inline]
@@ -599,6 +610,9 @@ impl<T: InvokeUiCM> IpcTaskRunner<T> {
                                 Data::CloseVoiceCall(reason) => {
                                     self.cm.voice_call_closed(self.conn_id, reason.as_str());
                                 }
+                                Data::RecordingBanner(show) => {
+                                    self.cm.recording_banner(self.conn_id, show);
+                                }
                                 #[cfg(target_os = "windows")]
                                 Data::ClipboardNonFile(_) => {
                                     match crate::clipboard::check_clipboard_cm() {
@@ -883,6 +897,9 @@ pub async fn start_listen<T: InvokeUiCM>(
             Some(Data::CloseVoiceCall(reason)) => {
                 cm.voice_call_closed(current_id, reason.as_str());
             }
+            Some(Data::RecordingBanner(show)) => {
+                cm.recording_banner(current_id, show);
+            }
             None => {
                 break;
             }
@@ -23,7 +23,7 @@ use hbb_common::{
         sync::mpsc,
         time::{Duration as TokioDuration, Instant},
     },
-    whoami, Stream,
+    whoami, ResultType, Stream,
 };
 use rdev::{Event, EventType::*, KeyCode};
 #[cfg(all(feature = "vram", feature = "flutter"))]
@@ -33,7 +33,7 @@ use std::{
     ops::{Deref, DerefMut},
     str::FromStr,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc, Mutex, RwLock,
     },
     time::SystemTime,
@@ -42,9 +42,9 @@ use uuid::Uuid;
 
 use crate::client::io_loop::Remote;
 use crate::client::{
-    check_if_retry, handle_hash, handle_login_error, handle_login_from_ui, handle_test_delay,
-    input_os_password, send_mouse, send_pointer_device_event, FileManager, Key, LoginConfigHandler,
-    QualityStatus, KEY_MAP,
+    check_if_retry, clipboard_history, handle_hash, handle_login_error, handle_login_from_ui,
+    handle_test_delay, input_os_password, macros, send_mouse, send_pointer_device_event,
+    FileManager, Key, LoginConfigHandler, QualityStatus, KEY_MAP,
 };
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 use crate::common::GrabState;
@@ -72,6 +72,10 @@ pub struct Session<T: InvokeUiSession> {
     pub reconnect_count: Arc<AtomicUsize>,
     pub last_audit_note: Arc<Mutex<String>>,
     pub audit_guid: Arc<Mutex<String>>,
+    // Whether text is being typed as keystrokes, cleared to cancel.
+    pub typing: Arc<AtomicBool>,
+    pub input_macro: Arc<Mutex<macros::MacroState>>,
+    pub clipboard_history: Arc<Mutex<clipboard_history::ClipboardHistory>>,
 }
 
 #[derive(Clone)]
@@ -765,6 +769,7 @@ impl<T: InvokeUiSession> Session<T> {
     pub fn send_key_event(&self, evt: &KeyEvent) {
         // mode: legacy(0), map(1), translate(2), auto(3)
 
+        macros::record_key(self, evt);
         let mut msg = evt.clone();
         self.swap_modifier_key(&mut msg);
         let mut msg_out = Message::new();
@@ -908,6 +913,44 @@ impl<T: InvokeUiSession> Session<T> {
         self.send(Data::Message(msg_out));
     }
 
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    pub fn type_text(&self, text: &str) {
+        crate::client::type_keys::type_text(self, text);
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    pub fn cancel_typing(&self) {
+        crate::client::type_keys::cancel_typing(self);
+    }
+
+    pub fn start_macro_recording(&self, name: &str) -> ResultType<()> {
+        macros::start_recording(self, name)
+    }
+
+    pub fn stop_macro_recording(&self) -> ResultType<usize> {
+        macros::stop_recording(self)
+    }
+
+    pub fn play_macro(&self, name: &str, speed: f64) -> ResultType<()> {
+        macros::replay(self, name, speed)
+    }
+
+    pub fn abort_macro(&self) {
+        macros::abort(self);
+    }
+
+    pub fn get_clipboard_history(&self) -> String {
+        clipboard_history::list(self)
+    }
+
+    pub fn resend_clipboard_history(&self, id: u64) -> ResultType<()> {
+        clipboard_history::resend(self, id)
+    }
+
+    pub fn clear_clipboard_history(&self) {
+        clipboard_history::clear(self);
+    }
+
     #[cfg(any(target_os = "ios"))]
     pub fn handle_flutter_raw_key_event(
         &self,
@@ -1249,6 +1292,7 @@ impl<T: InvokeUiSession> Session<T> {
             }
         }
 
+        macros::record_mouse(self, mask, x, y, alt, ctrl, shift, command);
         send_mouse(mask, x, y, alt, ctrl, shift, command, self);
         // on macos, ctrl + left button down = right button down, up won't emit, so we need to
         // emit up myself if peer is not macos
@@ -1532,6 +1576,17 @@ impl<T: InvokeUiSession> Session<T> {
 
     #[inline]
     pub fn handle_peer_switch_display(&self, display: &SwitchDisplay) {
+        if display.width > 0 && display.height > 0 {
+            macros::set_display(
+                self,
+                macros::DisplayRect {
+                    x: display.x,
+                    y: display.y,
+                    width: display.width,
+                    height: display.height,
+                },
+            );
+        }
         self.ui_handler.switch_display(display);
         self.set_custom_resolution(display);
     }
@@ -1803,6 +1858,15 @@ impl<T: InvokeUiSession> Interface for Session<T> {
                 input_os_password(p, true, self.clone());
             }
             let current = &pi.displays[pi.current_display as usize];
+            macros::set_display(
+                self,
+                macros::DisplayRect {
+                    x: current.x,
+                    y: current.y,
+                    width: current.width,
+                    height: current.height,
+                },
+            );
             self.set_display(
                 current.x,
                 current.y,
@@ -1952,7 +2016,10 @@ pub async fn io_loop<T: InvokeUiSession>(handler: Session<T>, round: u32) {
             loop {
                 match receiver.recv().await {
                     Some(Data::AddPortForward((port, remote_host, remote_port))) => {
-                        if port <= 0 || remote_port <= 0 {
+                        if port <= 0
+                            || (remote_port <= 0
+                                && !crate::port_forward::is_socks5(&remote_host, remote_port))
+                        {
                             continue;
                         }
                         let (sender, receiver) = mpsc::unbounded_channel::<Data>();
@@ -1989,16 +2056,42 @@ pub async fn io_loop<T: InvokeUiSession>(handler: Session<T>, round: u32) {
                     _ => {}
                 }
             }
-        } else {
-            let port = handler.args[0].parse::<i32>().unwrap_or(0);
+        } else if let Some(remote_port) = handler.args[0].strip_prefix("R:") {
+            let remote_port = remote_port.parse::<i32>().unwrap_or(0);
             if handler.args.len() != 3
                 || handler.args[2].parse::<i32>().unwrap_or(0) <= 0
+                || remote_port <= 0
+            {
+                handler.on_error("Invalid arguments, usage:<br><br> rustdesk --port-forward remote-id R:remote-listen-port local-host local-port");
+            }
+            let local_host = handler.args[1].clone();
+            let local_port = handler.args[2].parse::<i32>().unwrap_or(0);
+            start_one_reverse_port_forward(
+                handler,
+                remote_port,
+                local_host,
+                local_port,
+                receiver,
+                &key,
+                &token,
+            )
+            .await;
+        } else {
+            let port = handler.args[0].parse::<i32>().unwrap_or(0);
+            let is_socks5 =
+                handler.args.len() == 2 && crate::port_forward::is_socks5(&handler.args[1], 0);
+            if !is_socks5
+                && (handler.args.len() != 3 || handler.args[2].parse::<i32>().unwrap_or(0) <= 0)
                 || port <= 0
             {
-                handler.on_error("Invalid arguments, usage:<br><br> rustdesk --port-forward remote-id listen-port remote-host remote-port");
+                handler.on_error("Invalid arguments, usage:<br><br> rustdesk --port-forward remote-id listen-port [udp:]remote-host remote-port<br> rustdesk --port-forward remote-id listen-port socks5");
             }
             let remote_host = handler.args[1].clone();
-            let remote_port = handler.args[2].parse::<i32>().unwrap_or(0);
+            let remote_port = if is_socks5 {
+                0
+            } else {
+                handler.args[2].parse::<i32>().unwrap_or(0)
+            };
             start_one_port_forward(
                 handler,
                 port,
@@ -2035,7 +2128,6 @@ async fn start_one_port_forward<T: InvokeUiSession>(
         receiver,
         key,
         token,
-        handler.lc.clone(),
         remote_host,
         remote_port,
     )
@@ -2046,6 +2138,37 @@ async fn start_one_port_forward<T: InvokeUiSession>(
     log::info!("port forward (:{}) exit", port);
 }
 
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+async fn start_one_reverse_port_forward<T: InvokeUiSession>(
+    handler: Session<T>,
+    remote_port: i32,
+    local_host: String,
+    local_port: i32,
+    receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+) {
+    if let Err(err) = crate::port_forward::listen_reverse(
+        handler.get_id(),
+        handler.password.clone(),
+        remote_port,
+        handler.clone(),
+        receiver,
+        key,
+        token,
+        local_host,
+        local_port,
+    )
+    .await
+    {
+        handler.on_error(&format!(
+            "Failed to forward remote port {}: {}",
+            remote_port, err
+        ));
+    }
+    log::info!("reverse port forward (remote :{}) exit", remote_port);
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn send_note(url: String, id: String, sid: u64, note: String) {
     let body = serde_json::json!({ "id": id, "session_id": sid, "note": note });